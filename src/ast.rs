@@ -1,15 +1,114 @@
+use crate::span::Span;
+use crate::types::Num;
+
 pub type Type = crate::types::Type<String>;
 
 #[derive(Debug)]
 pub struct Module {
     pub name: String,
+    //The modules whose public declarations are visible in this one
+    pub imports: Vec<Import>,
     pub decls: Vec<Decl>,
 }
 
+//    import list hiding (length, sum);
+//  The span is that of the module name
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub name: String,
+    pub hiding: Vec<String>,
+    pub span: Span,
+}
+
 #[derive(Debug)]
 pub enum Decl {
-    Extern(String, Type),
+    //    extern putchar(c: i32) -> i32;
+    //    extern list.sum(t: Tree) -> i32;
+    //  The second declares sum, a function of the module list, as the
+    //  interface of list does
+    Extern(String, Type, Option<String>, Span),
     Func(Bind),
+    Type(TypeDecl),
+    Record(RecordDecl),
+    Class(ClassDecl),
+    Instance(InstanceDecl),
+    Fixity(FixityDecl),
+    //    pub let f(x) { x }
+    Pub(Box<Decl>),
+}
+
+//Declarations are added to the environment of the repl and expressions
+//  are evaluated
+#[derive(Debug)]
+pub enum ReplInput {
+    Decls(Vec<Decl>),
+    Expr(Expr),
+}
+
+//    type Option<a> = None | Some(a)
+#[derive(Debug)]
+pub struct TypeDecl {
+    pub name: String,
+    pub params: Vec<String>,
+    pub ctors: Vec<CtorDecl>,
+    pub span: Span,
+}
+
+//    type Point = {x: i32, y: i32}
+#[derive(Debug)]
+pub struct RecordDecl {
+    pub name: String,
+    pub params: Vec<String>,
+    pub fields: Vec<(String, Type)>,
+    pub span: Span,
+}
+
+//A class constraint e.g. Eq<a>
+pub type Pred = (String, Type);
+
+//    class Eq<a> { eq_eq: (a, a) -> bool }
+#[derive(Debug)]
+pub struct ClassDecl {
+    pub name: String,
+    pub param: String,
+    pub methods: Vec<(String, Type)>,
+    pub span: Span,
+}
+
+//    instance Eq<a> => Eq<List<a>> { let eq_eq(l, r) { ... } }
+#[derive(Debug)]
+pub struct InstanceDecl {
+    pub context: Vec<Pred>,
+    pub pred: Pred,
+    pub methods: Vec<Bind>,
+    pub span: Span,
+}
+
+//    infixl 6 (+) = add;
+//  Applying the operator calls the function. Operators with a higher
+//  precedence group tighter
+#[derive(Debug)]
+pub struct FixityDecl {
+    pub op: String,
+    pub assoc: Assoc,
+    pub prec: u32,
+    pub func: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Assoc {
+    Left,
+    Right,
+    //a == b == c is an error
+    Non,
+}
+
+#[derive(Debug)]
+pub struct CtorDecl {
+    pub name: String,
+    pub args: Vec<Type>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
@@ -18,32 +117,112 @@ pub struct Lam {
     body: Expr,
 }
 
+//A type written by the programmer. The parts of a function's type that
+//  are not written are type variables without a name and are inferred
+//    let f<a: Num>(x: a, n) -> a { .. }    let x: i32 = ..
+//  The span is that of the annotation
+#[derive(Debug)]
+pub struct Annot {
+    pub tyvars: Vec<String>,
+    pub preds: Vec<Pred>,
+    pub ty: Type,
+    pub span: Span,
+}
+
+//The type parameters, their constraints, parameters and result type of a
+//  function
+//    <a: Num>(x: a, n) -> a
+#[derive(Debug)]
+pub struct Signature {
+    pub tyvars: Vec<String>,
+    pub preds: Vec<Pred>,
+    pub params: Vec<(String, Option<Type>)>,
+    pub retty: Option<Type>,
+    pub span: Span,
+}
+
+//An if without an else does nothing when the condition is false
 #[derive(Debug)]
 pub struct If {
     cond: Expr,
     texpr: Expr,
-    fexpr: Expr,
+    fexpr: Option<Expr>,
+}
+
+//A variable pattern whose name is a nullary constructor matches that
+//  constructor. This is resolved in Rename
+#[derive(Debug)]
+pub enum Pattern {
+    Wildcard,
+    Var(String),
+    Ctor(String, Vec<Pattern>),
+    UnitLit,
+    NumLit(Num),
+    BoolLit(bool),
 }
 
 #[derive(Debug)]
-pub struct Bind(pub String, pub Expr);
+pub struct Arm {
+    pub pat: Pattern,
+    pub expr: Expr,
+    pub span: Span,
+}
 
+//The span is that of the bound name
 #[derive(Debug)]
-pub enum Expr {
+pub struct Bind(pub String, pub Expr, pub Span);
+
+pub struct Expr {
+    kind: ExprKind,
+    span: Span,
+}
+
+#[derive(Debug)]
+pub enum ExprKind {
     Lam(Box<Lam>),
     App(Box<Expr>, Vec<Expr>),
     UnitLit,
-    I32Lit(i32),
+    NumLit(Num),
     BoolLit(bool),
     Var(String),
     If(Box<If>),
     Let(Box<Bind>, Box<Expr>),
+    //let rec f(x) { .. } and g(y) { .. }; e
+    LetRec(Vec<Bind>, Box<Expr>),
+    //e1; e2
+    Seq(Box<Expr>, Box<Expr>),
+    Match(Box<Expr>, Vec<Arm>),
+    //{x = 1, y = 2}
+    Record(Vec<(String, Expr)>),
+    //r.x
+    Project(Box<Expr>, String),
+    //{r with x = 2}
+    Update(Box<Expr>, Vec<(String, Expr)>),
+    //not e, written !e
+    Not(Box<Expr>),
+    //@tailcall f(x), a call that has to be compiled to a jump
+    TailCall(Box<Expr>),
+    Annot(Box<Expr>, Box<Annot>),
+    //a + b * c, the operators and their right operands in the order they
+    //  were written. They are grouped by precedence in Rename
+    Infix(Box<Expr>, Vec<((String, Span), Expr)>),
+}
+
+impl Import {
+    pub fn new(name: &str, span: Span) -> Self {
+        Self {
+            name: name.to_string(),
+            hiding: Vec::new(),
+            span,
+        }
+    }
 }
 
 impl Module {
     pub fn new(name: &str, decls: Vec<Decl>) -> Self {
         Self {
             name: name.to_string(),
+            imports: Vec::new(),
             decls,
         }
     }
@@ -52,7 +231,16 @@ impl Module {
 pub fn con(nm: &str, kind: crate::types::Kind) -> Type {
     use crate::types::TyCon::*;
     let tycon = match nm {
+        "i8" => I8,
+        "i16" => I16,
         "i32" => I32,
+        "i64" => I64,
+        "u8" => U8,
+        "u16" => U16,
+        "u32" => U32,
+        "u64" => U64,
+        "f32" => F32,
+        "f64" => F64,
         "bool" => Bool,
         "()" => Unit,
         "->" => Func,
@@ -61,11 +249,56 @@ pub fn con(nm: &str, kind: crate::types::Kind) -> Type {
     Type::Con(tycon, kind)
 }
 
+//Constraints are parsed as types, as Eq<a> is only known to be one when
+//  => follows it
+pub fn pred(ty: Type) -> Option<Pred> {
+    use crate::types::TyCon::NewType;
+    match ty {
+        Type::App(con, mut args) if args.len() == 1 => match *con {
+            Type::Con(NewType(class), _) => Some((class.to_string(), args.pop().unwrap())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 impl Decl {
-    pub fn external(name: String, params: Vec<(String, Type)>, retty: Type) -> Self {
+    pub fn external(
+        module: Option<String>,
+        name: String,
+        params: Vec<(String, Type)>,
+        retty: Type,
+        span: Span,
+    ) -> Self {
         let params_ty: Vec<Type> = params.into_iter().map(|(_, ty)| ty).collect();
         let ty = Type::func(params_ty, retty);
-        Decl::Extern(name, ty)
+        Decl::Extern(name, ty, module, span)
+    }
+
+    //The declaration without its visibility
+    pub fn inner(&self) -> &Decl {
+        match *self {
+            Decl::Pub(ref decl) => decl,
+            ref decl => decl,
+        }
+    }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+    pub fn kind(&self) -> &ExprKind {
+        &self.kind
+    }
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl std::fmt::Debug for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.kind.fmt(f)
     }
 }
 
@@ -81,8 +314,35 @@ impl Lam {
     }
 }
 
+impl Signature {
+    //The lambda is only annotated when some of its types are written
+    pub fn lam(self, body: Expr, span: Span) -> Expr {
+        let annotated = !self.tyvars.is_empty()
+            || self.retty.is_some()
+            || self.params.iter().any(|(_, ty)| ty.is_some());
+        let hole = || Type::Var(String::new());
+        let mut names = Vec::with_capacity(self.params.len());
+        let mut tys = Vec::with_capacity(self.params.len());
+        for (name, ty) in self.params {
+            names.push(name);
+            tys.push(ty.unwrap_or_else(hole));
+        }
+        let lam = Expr::new(ExprKind::Lam(Box::new(Lam::new(names, body))), span);
+        if !annotated {
+            return lam;
+        }
+        let annot = Annot {
+            tyvars: self.tyvars,
+            preds: self.preds,
+            ty: Type::func(tys, self.retty.unwrap_or_else(hole)),
+            span: self.span,
+        };
+        Expr::new(ExprKind::Annot(Box::new(lam), Box::new(annot)), span)
+    }
+}
+
 impl If {
-    pub fn new(cond: Expr, texpr: Expr, fexpr: Expr) -> Self {
+    pub fn new(cond: Expr, texpr: Expr, fexpr: Option<Expr>) -> Self {
         If { cond, texpr, fexpr }
     }
     pub fn cond(&self) -> &Expr {
@@ -91,7 +351,7 @@ impl If {
     pub fn texpr(&self) -> &Expr {
         &self.texpr
     }
-    pub fn fexpr(&self) -> &Expr {
-        &self.fexpr
+    pub fn fexpr(&self) -> Option<&Expr> {
+        self.fexpr.as_ref()
     }
 }
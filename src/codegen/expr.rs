@@ -1,26 +1,106 @@
 use std::collections::HashMap;
 
-use crate::monoir::{self, Bind, Expr, Type};
+use super::intrinsics::Intrinsic;
+use crate::monoir::{self, Bind, Expr, Symbol, Type};
+use crate::types::Num;
+use crate::utils::{Graph, SCC};
 use crate::{Error, Result, Vector};
-use cranelift::codegen::ir::{self, Function, Value};
+use cranelift::codegen::ir::{self, condcodes::IntCC, types, Function, MemFlags, Value};
 use cranelift::prelude::{FunctionBuilder, InstBuilder, Signature};
-use cranelift_module::{FuncId, Module};
+use cranelift_module::{DataId, FuncId, Module};
 
-pub(super) struct FunctionTranslator<'a> {
-    module: &'a super::module::ModuleTranslator,
+//The groups of top level functions calling each other in tail position
+//  through other functions of the group. The bodies of a group are blocks
+//  of one function, see FunctionTranslator::emit_group. Closures are called
+//  indirectly so they are in no group, and neither is main as it returns 0
+//  when the others of its group would return ()
+pub(super) fn tail_call_groups(funcs: &[Bind]) -> Vec<Vec<&Bind>> {
+    let mut graph = Graph::<u32, &Bind>::new();
+    let mut keys = HashMap::new();
+    for bind in funcs {
+        if let Expr::Lam(ref lam) = bind.expr {
+            if lam.env.is_none() && bind.sym.name.as_str() != "main" {
+                keys.insert(bind.sym.id, (graph.add_vertex(bind), &lam.body));
+            }
+        }
+    }
+    for (key, body) in keys.values() {
+        let mut callees = Vec::new();
+        tail_callees(body, &mut callees);
+        for callee in callees.iter().filter_map(|callee| keys.get(callee)) {
+            graph.add_edge(*key, callee.0);
+        }
+    }
+    let sccs = SCC::run(&graph).into_iter();
+    sccs.filter(|scc| scc.len() > 1).collect()
+}
+
+//The ids of the functions called directly in tail position
+fn tail_callees(expr: &Expr, callees: &mut Vec<u32>) {
+    match expr {
+        Expr::Let(_, body) | Expr::Seq(_, body) => tail_callees(body, callees),
+        Expr::If(if_) => {
+            tail_callees(&if_.texpr, callees);
+            tail_callees(&if_.fexpr, callees);
+        }
+        Expr::Switch(switch) => {
+            for (_, case) in &switch.cases {
+                tail_callees(case, callees);
+            }
+            if let Some(ref default) = switch.default {
+                tail_callees(default, callees);
+            }
+        }
+        Expr::App(_, callee, _) => {
+            if let Expr::Var(ref func) = **callee {
+                callees.push(func.id);
+            }
+        }
+        _ => (),
+    }
+}
+
+//The value passed for a parameter of another function of a group
+fn zero(ty: ir::Type, builder: &mut FunctionBuilder) -> Value {
+    match ty {
+        types::F32 => builder.ins().f32const(0.0),
+        types::F64 => builder.ins().f64const(0.0),
+        types::B1 => builder.ins().bconst(types::B1, false),
+        ty => builder.ins().iconst(ty, 0),
+    }
+}
+
+pub(super) struct FunctionTranslator<'a, M: Module> {
+    module: &'a super::module::ModuleTranslator<M>,
     func_ids: &'a HashMap<u32, FuncId>,
+    //Externs that are lowered to instructions where they are called
+    intrinsics: &'a HashMap<u32, Intrinsic>,
+    //Statically allocated closures, keyed by the id of their code
+    closures: &'a HashMap<u32, DataId>,
     vars: HashMap<u32, Value>,
+    //The id of the function being emitted
+    func: u32,
+    //The blocks of the bodies of the functions being emitted, which is the
+    //  function itself or all the functions of its group. Calls to them in
+    //  tail position jump to them with the arguments
+    bodies: HashMap<u32, ir::Block>,
 }
 
-impl<'a> FunctionTranslator<'a> {
+impl<'a, M: Module> FunctionTranslator<'a, M> {
     pub(super) fn new(
-        module: &'a super::module::ModuleTranslator,
+        module: &'a super::module::ModuleTranslator<M>,
         func_ids: &'a HashMap<u32, FuncId>,
+        intrinsics: &'a HashMap<u32, Intrinsic>,
+        closures: &'a HashMap<u32, DataId>,
     ) -> Self {
         Self {
             module,
             func_ids,
+            intrinsics,
+            closures,
             vars: HashMap::new(),
+            func: 0,
+            bodies: HashMap::new(),
         }
     }
 
@@ -32,62 +112,542 @@ impl<'a> FunctionTranslator<'a> {
         );
 
         let mut builder = FunctionBuilder::new(&mut func, &mut function);
+        self.func = bind.sym.id;
         self.emit(&bind.expr, &mut builder)?;
         Ok(func)
     }
 
+    //The function of a group whose bodies are all blocks of it. The first
+    //  parameter is the index of the function called, followed by the
+    //  parameters of each function of the group
+    //    f.tail(i, <params of f>, <params of g>, ..)
+    pub(super) fn emit_group(&mut self, group: &[&Bind], sig: &Signature) -> Result<Function> {
+        let mut function = cranelift::frontend::FunctionBuilderContext::new();
+        let mut func = ir::Function::with_name_signature(
+            cranelift::prelude::ExternalName::user(group[0].sym.id, 1),
+            sig.clone(),
+        );
+
+        let mut builder = FunctionBuilder::new(&mut func, &mut function);
+        let block = self.module.create_entry_block(&mut builder);
+        let params = builder.block_params(block).to_vec();
+        let mut lams = Vec::with_capacity(group.len());
+        for bind in group {
+            let lam = match bind.expr {
+                Expr::Lam(ref lam) => lam,
+                _ => return Err(Error::new(format!("{:?} is not a function", bind.sym))),
+            };
+            let body = self.body_block(lam, &mut builder);
+            self.bodies.insert(bind.sym.id, body);
+            lams.push((bind.sym.id, lam, body));
+        }
+        let (index, mut rest) = (params[0], &params[1..]);
+        for (i, (_, _, body)) in lams.iter().enumerate() {
+            let (args, others) = rest.split_at(builder.block_params(*body).len());
+            rest = others;
+            if i == lams.len() - 1 {
+                builder.ins().jump(*body, args);
+                break;
+            }
+            let next = builder.create_block();
+            let cond = builder.ins().icmp_imm(IntCC::Equal, index, i as i64);
+            builder.ins().brnz(cond, *body, args);
+            builder.ins().jump(next, &[]);
+            builder.switch_to_block(next);
+            builder.seal_block(next);
+        }
+        self.func = group[0].sym.id;
+        for (id, lam, _) in lams {
+            self.emit_body(id, lam, &mut builder)?;
+        }
+        for body in self.bodies.values() {
+            builder.seal_block(*body);
+        }
+        Ok(func)
+    }
+
+    //A function of a group calls the function of the group with its index
+    //  and its parameters. The parameters of the others are zeros
+    pub(super) fn emit_entry(
+        &mut self,
+        bind: &Bind,
+        sig: &Signature,
+        group: (FuncId, &[&Bind]),
+    ) -> Result<Function> {
+        let mut function = cranelift::frontend::FunctionBuilderContext::new();
+        let mut func = ir::Function::with_name_signature(
+            cranelift::prelude::ExternalName::user(bind.sym.id, 0),
+            sig.clone(),
+        );
+
+        let mut builder = FunctionBuilder::new(&mut func, &mut function);
+        let block = self.module.create_entry_block(&mut builder);
+        let params = builder.block_params(block).to_vec();
+        let (group_id, members) = group;
+        let index = members.iter().position(|m| m.sym.id == bind.sym.id);
+        let index = builder.ins().iconst(types::I32, index.unwrap_or(0) as i64);
+        let mut args = vec![index];
+        for member in members {
+            if member.sym.id == bind.sym.id {
+                args.extend(params.iter().copied());
+                continue;
+            }
+            for param in self.module.translate_sig(&member.sym.ty)?.params {
+                args.push(zero(param.value_type, &mut builder));
+            }
+        }
+        let module = self.module;
+        let callee = module.inner.declare_func_in_func(group_id, builder.func);
+        let call = builder.ins().call(callee, &args);
+        let res = builder.inst_results(call).to_vec();
+        builder.ins().return_(&res);
+        Ok(func)
+    }
+
+    //Call through a closure. The code pointer is the first field of the
+    //  closure record, which is passed along as the environment
     fn emit_indirect(
         &mut self,
         ty: &Type,
         var: &Expr,
         args: &[Expr],
         builder: &mut FunctionBuilder,
+    ) -> Result<Option<Value>> {
+        let closure = self.emit_value(var, builder)?;
+        let mut args = self.emit_args(args, builder)?;
+        args.insert(0, closure);
+        let sig = self.module.translate_closure_sig(ty)?;
+        let sigref = builder.import_signature(sig);
+        let ptr_ty = self.module.pointer_ty();
+        let code = builder.ins().load(ptr_ty, MemFlags::trusted(), closure, 0);
+        let call = builder.ins().call_indirect(sigref, code, &args);
+        Ok(builder.inst_results(call).first().copied())
+    }
+
+    //Arguments of type unit are evaluated for their effects but not passed
+    fn emit_args(&mut self, args: &[Expr], builder: &mut FunctionBuilder) -> Result<Vec<Value>> {
+        let mut vals = Vec::with_capacity(args.len());
+        for arg in args {
+            vals.extend(self.emit(arg, builder)?);
+        }
+        Ok(vals)
+    }
+
+    //Emit an expression that is not of type unit
+    fn emit_value(&mut self, expr: &Expr, builder: &mut FunctionBuilder) -> Result<Value> {
+        match self.emit(expr, builder)? {
+            Some(val) => Ok(val),
+            None => Err(Error::new(format!("{expr:?} has no value"))),
+        }
+    }
+
+    fn emit_closure(
+        &mut self,
+        code: &Symbol,
+        env: &[Symbol],
+        builder: &mut FunctionBuilder,
+    ) -> Result<Value> {
+        let ptr_ty = self.module.pointer_ty();
+        if let Some(data_id) = self.closures.get(&code.id) {
+            let module = self.module;
+            let gv = module.inner.declare_data_in_func(*data_id, builder.func);
+            return Ok(builder.ins().symbol_value(ptr_ty, gv));
+        }
+
+        let func_id = match self.func_ids.get(&code.id) {
+            Some(func_id) => *func_id,
+            None => return Err(Error::new(format!("Closure {code:?} could not be found"))),
+        };
+        let module = self.module;
+        let (offsets, size) = module.closure_layout(env);
+        let record = self.alloc(size, builder);
+
+        let code = module.inner.declare_func_in_func(func_id, builder.func);
+        let code = builder.ins().func_addr(ptr_ty, code);
+        builder.ins().store(MemFlags::trusted(), code, record, 0);
+        for (var, offset) in env.iter().zip(offsets) {
+            if let Some(val) = self.lookup(var)? {
+                self.store(&var.ty, val, record, offset, builder);
+            }
+        }
+        Ok(record)
+    }
+
+    //Allocate the value and store the tag followed by the fields
+    fn emit_ctor(
+        &mut self,
+        ty: &Type,
+        tag: u32,
+        args: &[Expr],
+        builder: &mut FunctionBuilder,
     ) -> Result<Value> {
-        let callee = self.emit(var, builder)?;
+        let module = self.module;
+        let fields = module.variant(ty, tag)?;
+        let (offsets, size) = module.variant_layout(fields);
         let args = Vector::map(args, |arg| self.emit(arg, builder))?;
-        let sig = self.module.translate_sig(ty)?;
-        let sigref = builder.import_signature(sig);
-        let call = builder.ins().call_indirect(sigref, callee, &args);
-        Ok(builder.inst_results(call)[0])
+        let record = self.alloc(size, builder);
+        let tag = builder.ins().iconst(types::I32, tag as i64);
+        builder.ins().store(MemFlags::trusted(), tag, record, 0);
+        for ((ty, val), offset) in fields.iter().zip(args).zip(offsets) {
+            if let Some(val) = val {
+                self.store(ty, val, record, offset, builder);
+            }
+        }
+        Ok(record)
+    }
+
+    //Fields are evaluated in the order they were written
+    fn emit_record(
+        &mut self,
+        ty: &Type,
+        fields: &[(u32, Expr)],
+        builder: &mut FunctionBuilder,
+    ) -> Result<Value> {
+        let module = self.module;
+        let tys = module.record(ty)?;
+        let (offsets, size) = module.record_layout(tys);
+        let vals = Vector::map(fields, |(_, e)| self.emit(e, builder))?;
+        let record = self.alloc(size, builder);
+        for ((idx, _), val) in fields.iter().zip(vals) {
+            if let Some(val) = val {
+                let idx = *idx as usize;
+                self.store(&tys[idx], val, record, offsets[idx], builder);
+            }
+        }
+        Ok(record)
     }
 
-    fn emit(&mut self, expr: &Expr, builder: &mut FunctionBuilder) -> Result<Value> {
+    //Compare the scrutinee with each case in turn. Matches are exhaustive
+    //  so without a default the last case needs no test
+    fn emit_switch(
+        &mut self,
+        switch: &monoir::Switch,
+        tail: bool,
+        builder: &mut FunctionBuilder,
+    ) -> Result<Option<Value>> {
+        let scrut = self.lookup_value(&switch.scrut)?;
+        let val = match switch.scrut.ty {
+            Type::Data(_) => builder
+                .ins()
+                .load(types::I32, MemFlags::trusted(), scrut, 0),
+            Type::Bool => builder.ins().bint(types::I32, scrut),
+            _ => scrut,
+        };
+        let merge_block = self.merge_block(&switch.ty, tail, builder);
+
+        let last = switch.cases.len().saturating_sub(1);
+        for (i, (key, expr)) in switch.cases.iter().enumerate() {
+            if i == last && switch.default.is_none() {
+                self.emit_branch(expr, merge_block, builder)?;
+                break;
+            }
+            let case_block = builder.create_block();
+            let next_block = builder.create_block();
+            let cond = builder.ins().icmp_imm(IntCC::Equal, val, *key);
+            builder.ins().brnz(cond, case_block, &[]);
+            builder.ins().jump(next_block, &[]);
+
+            builder.switch_to_block(case_block);
+            builder.seal_block(case_block);
+            self.emit_branch(expr, merge_block, builder)?;
+
+            builder.switch_to_block(next_block);
+            builder.seal_block(next_block);
+        }
+        if let Some(ref expr) = switch.default {
+            self.emit_branch(expr, merge_block, builder)?;
+        }
+        Ok(self.merge(merge_block, builder))
+    }
+
+    fn emit_if(
+        &mut self,
+        if_: &monoir::If,
+        tail: bool,
+        builder: &mut FunctionBuilder,
+    ) -> Result<Option<Value>> {
+        let cond = self.emit_value(&if_.cond, builder)?;
+
+        let then_block = builder.create_block();
+        let else_block = builder.create_block();
+        let merge_block = self.merge_block(&if_.ty, tail, builder);
+
+        // conditional branch to else block
+        builder.ins().brz(cond, else_block, &[]);
+        // Fall through to then block.
+        builder.ins().jump(then_block, &[]);
+
+        builder.switch_to_block(then_block);
+        builder.seal_block(then_block);
+        self.emit_branch(&if_.texpr, merge_block, builder)?;
+
+        builder.switch_to_block(else_block);
+        builder.seal_block(else_block);
+        self.emit_branch(&if_.fexpr, merge_block, builder)?;
+
+        Ok(self.merge(merge_block, builder))
+    }
+
+    //A branch in tail position returns from the function itself, other
+    //  branches pass their value to the block control flow joins at
+    fn emit_branch(
+        &mut self,
+        expr: &Expr,
+        merge_block: Option<ir::Block>,
+        builder: &mut FunctionBuilder,
+    ) -> Result<()> {
+        match merge_block {
+            Some(block) => {
+                let res = self.emit(expr, builder)?;
+                builder.ins().jump(block, res.as_slice());
+            }
+            None => self.emit_tail(expr, builder)?,
+        }
+        Ok(())
+    }
+
+    //Continue in the block the branches join at and read the value of the
+    //  expression from its parameter
+    fn merge(
+        &self,
+        merge_block: Option<ir::Block>,
+        builder: &mut FunctionBuilder,
+    ) -> Option<Value> {
+        let block = merge_block?;
+        builder.switch_to_block(block);
+        builder.seal_block(block);
+        builder.block_params(block).first().copied()
+    }
+
+    //The block control flow joins at. The value of the expression, if it is
+    //  not of type unit, is its parameter. Branches in tail position return
+    //  so they do not join
+    fn merge_block(
+        &self,
+        ty: &Type,
+        tail: bool,
+        builder: &mut FunctionBuilder,
+    ) -> Option<ir::Block> {
+        if tail {
+            return None;
+        }
+        let block = builder.create_block();
+        if let Some(ty) = self.module.translate_type(ty) {
+            builder.append_block_param(block, ty);
+        }
+        Some(block)
+    }
+
+    //Emit an expression whose value is returned by the function
+    fn emit_tail(&mut self, expr: &Expr, builder: &mut FunctionBuilder) -> Result<()> {
         use monoir::Expr::*;
         match expr {
-            I32Lit(v) => {
-                let ty = self.module.translate_type(&monoir::Type::I32);
-                Ok(builder.ins().iconst(ty, *v as i64))
-            }
             Let(bind, expr) => {
-                let var = self.module.declare_variable(&bind.sym, builder);
-                let res = self.emit(&bind.expr, builder)?;
-                builder.def_var(var, res);
-                self.vars.insert(bind.sym.id, res);
-                self.emit(expr, builder)
-            }
-            Var(v) => match self.vars.get(&v.id) {
-                Some(v) => Ok(*v),
+                if let Some(res) = self.emit(&bind.expr, builder)? {
+                    self.vars.insert(bind.sym.id, res);
+                }
+                self.emit_tail(expr, builder)
+            }
+            Seq(e1, e2) => {
+                self.emit(e1, builder)?;
+                self.emit_tail(e2, builder)
+            }
+            If(if_) => self.emit_if(if_, true, builder).map(|_| ()),
+            Switch(switch) => self.emit_switch(switch, true, builder).map(|_| ()),
+            App(_, callee, args) => match self.jump_target(callee) {
+                Some(block) => {
+                    let args = self.emit_args(args, builder)?;
+                    builder.ins().jump(block, &args);
+                    Ok(())
+                }
                 None => {
-                    // Check to see if it is a function
-                    match self.func_ids.get(&v.id) {
-                        Some(func_id) => {
-                            let module = self.module;
-                            let func_ref =
-                                module.inner.declare_func_in_func(*func_id, builder.func);
-                            let ptr_ty = self.module.pointer_ty();
-                            let val = builder.ins().func_addr(ptr_ty, func_ref);
-                            self.vars.insert(v.id, val);
-                            Ok(val)
-                        }
-                        None => Err(Error::new(format!(
-                            "Variable {v:?} could not be found {:?}",
-                            self.vars
-                        ))),
-                    }
+                    let res = self.emit(expr, builder)?;
+                    self.emit_return(res, builder);
+                    Ok(())
+                }
+            },
+            _ => {
+                let res = self.emit(expr, builder)?;
+                self.emit_return(res, builder);
+                Ok(())
+            }
+        }
+    }
+
+    //The body a call in tail position jumps to, if the function called is
+    //  the one being emitted or in its group
+    fn jump_target(&self, callee: &Expr) -> Option<ir::Block> {
+        match callee {
+            Expr::Var(func) => self.bodies.get(&func.id).copied(),
+            _ => None,
+        }
+    }
+
+    //The block of the body of a function, whose parameters are those of the
+    //  function. Functions of a group may have parameters in common, like
+    //  the variables they capture, so they are not variables
+    fn body_block(&self, lam: &monoir::Lam, builder: &mut FunctionBuilder) -> ir::Block {
+        let block = builder.create_block();
+        for param in &lam.params {
+            if let Some(ty) = self.module.translate_type(&param.ty) {
+                builder.append_block_param(block, ty);
+            }
+        }
+        block
+    }
+
+    //Continue in the block of the body of a function and bind its parameters
+    fn emit_body(
+        &mut self,
+        func: u32,
+        lam: &monoir::Lam,
+        builder: &mut FunctionBuilder,
+    ) -> Result<()> {
+        let block = self.bodies[&func];
+        builder.switch_to_block(block);
+        let params = lam.params.iter().filter(|p| p.ty != Type::Unit);
+        for (param, value) in params.zip(builder.block_params(block)) {
+            self.vars.insert(param.id, *value);
+        }
+        self.emit_tail(&lam.body, builder)
+    }
+
+    fn emit_return(&mut self, res: Option<Value>, builder: &mut FunctionBuilder) {
+        match res {
+            //A main that returns () exits with 0
+            None if !builder.func.signature.returns.is_empty() => {
+                let zero = builder.ins().iconst(types::I32, 0);
+                builder.ins().return_(&[zero]);
+            }
+            _ => {
+                builder.ins().return_(res.as_slice());
+            }
+        }
+    }
+
+    fn alloc(&self, size: u32, builder: &mut FunctionBuilder) -> Value {
+        let module = self.module;
+        let malloc = module
+            .inner
+            .declare_func_in_func(module.malloc, builder.func);
+        let size = builder.ins().iconst(module.pointer_ty(), size as i64);
+        let call = builder.ins().call(malloc, &[size]);
+        builder.inst_results(call)[0]
+    }
+
+    //Bind the captured variables of a closure to the values in its record
+    fn load_env(&mut self, env: &[Symbol], record: Value, builder: &mut FunctionBuilder) {
+        let (offsets, _) = self.module.closure_layout(env);
+        for (var, offset) in env.iter().zip(offsets) {
+            if let Some(val) = self.load(&var.ty, record, offset, builder) {
+                self.vars.insert(var.id, val);
+            }
+        }
+    }
+
+    //Booleans are stored as bytes
+    fn store(&self, ty: &Type, val: Value, ptr: Value, offset: i32, builder: &mut FunctionBuilder) {
+        let val = match ty {
+            Type::Bool => builder.ins().bint(types::I8, val),
+            _ => val,
+        };
+        builder.ins().store(MemFlags::trusted(), val, ptr, offset);
+    }
+
+    fn load(
+        &self,
+        ty: &Type,
+        ptr: Value,
+        offset: i32,
+        builder: &mut FunctionBuilder,
+    ) -> Option<Value> {
+        let val = match ty {
+            Type::Bool => {
+                let val = builder
+                    .ins()
+                    .load(types::I8, MemFlags::trusted(), ptr, offset);
+                builder.ins().icmp_imm(IntCC::NotEqual, val, 0)
+            }
+            _ => {
+                let ty = self.module.translate_type(ty)?;
+                builder.ins().load(ty, MemFlags::trusted(), ptr, offset)
+            }
+        };
+        Some(val)
+    }
+
+    fn lookup_value(&self, var: &Symbol) -> Result<Value> {
+        match self.lookup(var)? {
+            Some(val) => Ok(val),
+            None => Err(Error::new(format!("{var:?} has no value"))),
+        }
+    }
+
+    //Variables of type unit have no value
+    fn lookup(&self, var: &Symbol) -> Result<Option<Value>> {
+        if var.ty == Type::Unit {
+            return Ok(None);
+        }
+        match self.vars.get(&var.id) {
+            Some(v) => Ok(Some(*v)),
+            None => Err(Error::new(format!(
+                "Variable {var:?} could not be found {:?}",
+                self.vars
+            ))),
+        }
+    }
+
+    //Expressions of type unit have no value
+    fn emit(&mut self, expr: &Expr, builder: &mut FunctionBuilder) -> Result<Option<Value>> {
+        use monoir::Expr::*;
+        let val = match expr {
+            UnitLit => return Ok(None),
+            NumLit(n) => match (*n, n.bits()) {
+                (Num::F32(x), _) => builder.ins().f32const(x),
+                (Num::F64(x), _) => builder.ins().f64const(x),
+                (n, bits) => {
+                    let ty = self.module.translate_type(&Type::num(&n)).unwrap();
+                    builder.ins().iconst(ty, bits.unwrap_or_default())
                 }
             },
+            BoolLit(b) => builder.ins().bconst(types::B1, *b),
+            //The body of an arm of a match can be emitted more than once so
+            //  let bound values are not declared as variables
+            Let(bind, expr) => {
+                if let Some(res) = self.emit(&bind.expr, builder)? {
+                    self.vars.insert(bind.sym.id, res);
+                }
+                return self.emit(expr, builder);
+            }
+            Seq(e1, e2) => {
+                self.emit(e1, builder)?;
+                return self.emit(e2, builder);
+            }
+            Var(v) => return self.lookup(v),
+            MkClosure(code, env) => self.emit_closure(code, env, builder)?,
+            Ctor(ty, tag, args) => self.emit_ctor(ty, *tag, args, builder)?,
+            Switch(switch) => return self.emit_switch(switch, false, builder),
+            Field(var, tag, index) => {
+                let record = self.lookup_value(var)?;
+                let fields = self.module.variant(&var.ty, *tag)?;
+                let (offsets, _) = self.module.variant_layout(fields);
+                let index = *index as usize;
+                return Ok(self.load(&fields[index], record, offsets[index], builder));
+            }
+            Record(ty, fields) => self.emit_record(ty, fields, builder)?,
+            Project(ty, record, index) => {
+                let record = self.emit_value(record, builder)?;
+                let fields = self.module.record(ty)?;
+                let (offsets, _) = self.module.record_layout(fields);
+                let index = *index as usize;
+                return Ok(self.load(&fields[index], record, offsets[index], builder));
+            }
             App(ty, var, args) => match **var {
                 Var(ref func_sym) => {
+                    if let Some(intrinsic) = self.intrinsics.get(&func_sym.id) {
+                        let args = self.emit_args(args, builder)?;
+                        return Ok(Some(intrinsic.emit(builder, &args)));
+                    }
                     let func_id = match self.func_ids.get(&func_sym.id) {
                         Some(func_id) => func_id,
                         None => return self.emit_indirect(ty, var, args, builder),
@@ -97,61 +657,31 @@ impl<'a> FunctionTranslator<'a> {
                         .inner
                         .declare_func_in_func(*func_id, builder.func);
 
-                    let args = Vector::map(args, |arg| self.emit(arg, builder))?;
+                    let args = self.emit_args(args, builder)?;
                     let call = builder.ins().call(local_callee, &args);
-                    Ok(builder.inst_results(call)[0])
+                    return Ok(builder.inst_results(call).first().copied());
                 }
-                _ => self.emit_indirect(ty, var, args, builder),
+                _ => return self.emit_indirect(ty, var, args, builder),
             },
-            If(if_) => {
-                let cond = self.emit(&if_.cond, builder)?;
-                let ty = self.module.translate_type(&if_.ty);
-
-                let then_block = builder.create_block();
-                let else_block = builder.create_block();
-                let merge_block = builder.create_block();
-
-                // Add block parameter for the return value
-                builder.append_block_param(merge_block, ty);
-
-                // conditional branch to else block
-                builder.ins().brz(cond, else_block, &[]);
-                // Fall through to then block.
-                builder.ins().jump(then_block, &[]);
-
-                builder.switch_to_block(then_block);
-                builder.seal_block(then_block);
-                let t_return = self.emit(&if_.texpr, builder)?;
-                builder.ins().jump(merge_block, &[t_return]);
-
-                builder.switch_to_block(else_block);
-                builder.seal_block(else_block);
-                let f_return = self.emit(&if_.fexpr, builder)?;
-                builder.ins().jump(merge_block, &[f_return]);
-
-                // Switch to the merge block for subsequent statements.
-                builder.switch_to_block(merge_block);
-                builder.seal_block(merge_block);
-
-                // Read the value of the if-else by reading the merge block
-                // parameter.
-                let phi = builder.block_params(merge_block)[0];
-                Ok(phi)
-            }
+            If(if_) => return self.emit_if(if_, false, builder),
             Lam(lam) => {
                 //println!("{lam:#?}");
                 let block = self.module.create_entry_block(builder);
-                let vars = self.module.setup_params(builder, &lam.params, block)?;
-                for (param, var) in lam.params.iter().zip(vars) {
-                    let value = builder.use_var(var);
-                    self.vars.insert(param.id, value);
+                if let Some(env) = &lam.env {
+                    let record = builder.block_params(block)[0];
+                    self.load_env(env, record, builder);
                 }
-
-                let res = self.emit(&lam.body, builder)?;
-                builder.ins().return_(&[res]);
-                Ok(res)
+                let vars = self.module.setup_params(builder, &lam.params, block)?;
+                let args: Vec<_> = vars.iter().map(|var| builder.use_var(*var)).collect();
+                let body = self.body_block(lam, builder);
+                builder.ins().jump(body, &args);
+                self.bodies.insert(self.func, body);
+                self.emit_body(self.func, lam, builder)?;
+                //Tail calls are the other predecessors of the body
+                builder.seal_block(body);
+                return Ok(None);
             }
-            _ => Err(Error::new(format!("Can not handle {expr:#?}"))),
-        }
+        };
+        Ok(Some(val))
     }
 }
@@ -1,107 +1,815 @@
-use crate::monoir;
+use crate::monoir::Type;
 use crate::{Error, Result};
-use cranelift::codegen::ir::{self, Function, InstBuilder};
+use cranelift::codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift::codegen::ir::{self, InstBuilder, Value};
 use cranelift::frontend::FunctionBuilder;
-use cranelift::prelude::Signature;
-
-fn params(func: &monoir::Symbol) -> Result<Vec<monoir::Symbol>> {
-    match &func.ty {
-        monoir::Type::Function { params_ty, .. } => {
-            let params = params_ty
-                .iter()
-                .enumerate()
-                .map(|(id, ty)| monoir::Symbol {
-                    name: std::rc::Rc::new(format!("param {id}")),
-                    ty: ty.clone(),
-                    id: id as u32,
-                })
-                .collect::<Vec<_>>();
-            Ok(params)
-        }
-        _ => Err(Error::new(format!("{func:?} is not a function. "))),
-    }
-}
-
-pub(super) fn emit(
-    module: &super::module::ModuleTranslator,
-    sym: &monoir::Symbol,
-    sig: &Signature,
-) -> Result<Option<Function>> {
-    use cranelift::codegen::ir::condcodes::IntCC;
-
-    let mut function = cranelift::frontend::FunctionBuilderContext::new();
-    let mut func = ir::Function::with_name_signature(
-        cranelift::prelude::ExternalName::user(sym.id, 0),
-        sig.clone(),
-    );
-
-    let mut builder = FunctionBuilder::new(&mut func, &mut function);
-    match sym.name.as_str() {
-        "i32_add" => {
-            let block = module.create_entry_block(&mut builder);
-            let vars = module.setup_params(&mut builder, &params(sym)?, block)?;
-            let p0 = builder.use_var(vars[0]);
-            let p1 = builder.use_var(vars[1]);
+
+//Operations on numbers that are lowered to Cranelift instructions at the
+//  places they are called. They are declared as externs in the prelude and
+//  named after the type of their operands followed by the operation,
+//  `i32_add`, `u8_lt`, `f64_div`, `bool_not`. Conversions are named after
+//  both types, `u8_to_i64`.
+//
+//  Integer arithmetic wraps around, `_overflows` tells whether it would
+//  not and `_saturating` clamps the result to the range of the type.
+//  Division truncates towards zero and never traps, dividing by zero gives
+//  0 and the remainder is then the dividend. The smallest signed integer
+//  divided by -1 is itself. Integers are sign extended when the source is
+//  signed and truncated when narrowed. Floats are converted to integers
+//  rounding towards zero, saturating at the bounds of the integer type,
+//  NaN becomes 0
+#[derive(Clone)]
+pub(super) struct Intrinsic {
+    op: &'static Op,
+    ty: Type,
+    //The type converted to
+    to: Type,
+}
+
+//How the operands of an intrinsic are compared, divided and converted
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Signed,
+    Unsigned,
+    Float,
+    Bool,
+}
+
+const INTS: &[Kind] = &[Kind::Signed, Kind::Unsigned];
+const NUMS: &[Kind] = &[Kind::Signed, Kind::Unsigned, Kind::Float];
+const BITS: &[Kind] = &[Kind::Signed, Kind::Unsigned, Kind::Bool];
+
+//The signature of an operation on values of type t
+enum Shape {
+    //t -> t
+    Unary,
+    //(t, t) -> t
+    Binary,
+    //(t, t) -> bool
+    Compare,
+    //t -> u
+    Convert,
+}
+
+struct Op {
+    name: &'static str,
+    shape: Shape,
+    //The kinds of types the operation is defined for
+    kinds: &'static [Kind],
+    lower: fn(&mut FunctionBuilder, &Intrinsic, &[Value]) -> Value,
+}
+
+const OPS: &[Op] = &[
+    Op {
+        name: "add",
+        shape: Shape::Binary,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder.ins().fadd(args[0], args[1]),
+            _ => builder.ins().iadd(args[0], args[1]),
+        },
+    },
+    Op {
+        name: "sub",
+        shape: Shape::Binary,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder.ins().fsub(args[0], args[1]),
+            _ => builder.ins().isub(args[0], args[1]),
+        },
+    },
+    Op {
+        name: "mul",
+        shape: Shape::Binary,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder.ins().fmul(args[0], args[1]),
+            _ => builder.ins().imul(args[0], args[1]),
+        },
+    },
+    Op {
+        name: "div",
+        shape: Shape::Binary,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder.ins().fdiv(args[0], args[1]),
+            _ => divide(builder, intr, "div", args),
+        },
+    },
+    Op {
+        name: "mod",
+        shape: Shape::Binary,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => unreachable!("The remainder of floats is a libcall"),
+            _ => divide(builder, intr, "mod", args),
+        },
+    },
+    Op {
+        name: "lt",
+        shape: Shape::Compare,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder.ins().fcmp(FloatCC::LessThan, args[0], args[1]),
+            Kind::Signed => builder.ins().icmp(IntCC::SignedLessThan, args[0], args[1]),
+            _ => builder
+                .ins()
+                .icmp(IntCC::UnsignedLessThan, args[0], args[1]),
+        },
+    },
+    Op {
+        name: "gt",
+        shape: Shape::Compare,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder.ins().fcmp(FloatCC::GreaterThan, args[0], args[1]),
+            Kind::Signed => builder
+                .ins()
+                .icmp(IntCC::SignedGreaterThan, args[0], args[1]),
+            _ => builder
+                .ins()
+                .icmp(IntCC::UnsignedGreaterThan, args[0], args[1]),
+        },
+    },
+    Op {
+        name: "eq",
+        shape: Shape::Compare,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder.ins().fcmp(FloatCC::Equal, args[0], args[1]),
+            _ => builder.ins().icmp(IntCC::Equal, args[0], args[1]),
+        },
+    },
+    Op {
+        name: "add_wrapping",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, _, args| builder.ins().iadd(args[0], args[1]),
+    },
+    Op {
+        name: "sub_wrapping",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, _, args| builder.ins().isub(args[0], args[1]),
+    },
+    Op {
+        name: "mul_wrapping",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, _, args| builder.ins().imul(args[0], args[1]),
+    },
+    Op {
+        name: "add_saturating",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, intr, args| saturating(builder, intr, "add", args),
+    },
+    Op {
+        name: "sub_saturating",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, intr, args| saturating(builder, intr, "sub", args),
+    },
+    Op {
+        name: "mul_saturating",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, intr, args| saturating(builder, intr, "mul", args),
+    },
+    Op {
+        name: "add_overflows",
+        shape: Shape::Compare,
+        kinds: INTS,
+        lower: |builder, intr, args| overflows(builder, intr, "add", args),
+    },
+    Op {
+        name: "sub_overflows",
+        shape: Shape::Compare,
+        kinds: INTS,
+        lower: |builder, intr, args| overflows(builder, intr, "sub", args),
+    },
+    Op {
+        name: "mul_overflows",
+        shape: Shape::Compare,
+        kinds: INTS,
+        lower: |builder, intr, args| overflows(builder, intr, "mul", args),
+    },
+    Op {
+        name: "div_overflows",
+        shape: Shape::Compare,
+        kinds: INTS,
+        lower: |builder, intr, args| overflows(builder, intr, "div", args),
+    },
+    Op {
+        name: "mod_overflows",
+        shape: Shape::Compare,
+        kinds: INTS,
+        lower: |builder, intr, args| overflows(builder, intr, "mod", args),
+    },
+    Op {
+        name: "le",
+        shape: Shape::Compare,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder
+                .ins()
+                .fcmp(FloatCC::LessThanOrEqual, args[0], args[1]),
+            Kind::Signed => builder
+                .ins()
+                .icmp(IntCC::SignedLessThanOrEqual, args[0], args[1]),
+            _ => builder
+                .ins()
+                .icmp(IntCC::UnsignedLessThanOrEqual, args[0], args[1]),
+        },
+    },
+    Op {
+        name: "ge",
+        shape: Shape::Compare,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder
+                .ins()
+                .fcmp(FloatCC::GreaterThanOrEqual, args[0], args[1]),
+            Kind::Signed => builder
+                .ins()
+                .icmp(IntCC::SignedGreaterThanOrEqual, args[0], args[1]),
+            _ => builder
+                .ins()
+                .icmp(IntCC::UnsignedGreaterThanOrEqual, args[0], args[1]),
+        },
+    },
+    //NaN is not equal to anything, itself included
+    Op {
+        name: "ne",
+        shape: Shape::Compare,
+        kinds: NUMS,
+        lower: |builder, intr, args| match intr.kind() {
+            Kind::Float => builder.ins().fcmp(FloatCC::NotEqual, args[0], args[1]),
+            _ => builder.ins().icmp(IntCC::NotEqual, args[0], args[1]),
+        },
+    },
+    //Both operands of the boolean operations are evaluated
+    Op {
+        name: "and",
+        shape: Shape::Binary,
+        kinds: BITS,
+        lower: |builder, _, args| builder.ins().band(args[0], args[1]),
+    },
+    Op {
+        name: "or",
+        shape: Shape::Binary,
+        kinds: BITS,
+        lower: |builder, _, args| builder.ins().bor(args[0], args[1]),
+    },
+    Op {
+        name: "xor",
+        shape: Shape::Binary,
+        kinds: BITS,
+        lower: |builder, _, args| builder.ins().bxor(args[0], args[1]),
+    },
+    Op {
+        name: "not",
+        shape: Shape::Unary,
+        kinds: BITS,
+        lower: |builder, intr, args| match intr.kind() {
+            //Cranelift has no bnot for booleans
+            Kind::Bool => {
+                let t = builder.ins().bconst(ir::types::B1, true);
+                builder.ins().bxor(args[0], t)
+            }
+            _ => builder.ins().bnot(args[0]),
+        },
+    },
+    //Shift amounts are taken modulo the number of bits of the type. `shr`
+    //  shifts in the sign bit for signed types and `ushr` always shifts in
+    //  zeros
+    Op {
+        name: "shl",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, _, args| {
+            let amount = shift_amount(builder, args[1]);
+            builder.ins().ishl(args[0], amount)
+        },
+    },
+    Op {
+        name: "shr",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, intr, args| {
+            let amount = shift_amount(builder, args[1]);
+            match intr.kind() {
+                Kind::Signed => builder.ins().sshr(args[0], amount),
+                _ => builder.ins().ushr(args[0], amount),
+            }
+        },
+    },
+    Op {
+        name: "ushr",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, _, args| {
+            let amount = shift_amount(builder, args[1]);
+            builder.ins().ushr(args[0], amount)
+        },
+    },
+    Op {
+        name: "rotl",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, _, args| builder.ins().rotl(args[0], args[1]),
+    },
+    Op {
+        name: "rotr",
+        shape: Shape::Binary,
+        kinds: INTS,
+        lower: |builder, _, args| builder.ins().rotr(args[0], args[1]),
+    },
+    Op {
+        name: "popcnt",
+        shape: Shape::Unary,
+        kinds: INTS,
+        lower: |builder, _, args| builder.ins().popcnt(args[0]),
+    },
+    Op {
+        name: "clz",
+        shape: Shape::Unary,
+        kinds: INTS,
+        lower: |builder, _, args| builder.ins().clz(args[0]),
+    },
+    Op {
+        name: "ctz",
+        shape: Shape::Unary,
+        kinds: INTS,
+        lower: |builder, _, args| builder.ins().ctz(args[0]),
+    },
+    Op {
+        name: "to",
+        shape: Shape::Convert,
+        kinds: NUMS,
+        lower: convert,
+    },
+];
+
+fn num_type(name: &str) -> Option<Type> {
+    let ty = match name {
+        "i8" => Type::I8,
+        "i16" => Type::I16,
+        "i32" => Type::I32,
+        "i64" => Type::I64,
+        "u8" => Type::U8,
+        "u16" => Type::U16,
+        "u32" => Type::U32,
+        "u64" => Type::U64,
+        "f32" => Type::F32,
+        "f64" => Type::F64,
+        "bool" => Type::Bool,
+        _ => return None,
+    };
+    Some(ty)
+}
+
+fn type_name(ty: &Type) -> &'static str {
+    match ty {
+        Type::I8 => "i8",
+        Type::I16 => "i16",
+        Type::I32 => "i32",
+        Type::I64 => "i64",
+        Type::U8 => "u8",
+        Type::U16 => "u16",
+        Type::U32 => "u32",
+        Type::U64 => "u64",
+        Type::F32 => "f32",
+        Type::F64 => "f64",
+        _ => "bool",
+    }
+}
+
+impl Intrinsic {
+    pub(super) fn lookup(name: &str) -> Option<Self> {
+        let (ty, op) = name.split_once('_')?;
+        let ty = num_type(ty)?;
+        let (op, to) = match op.strip_prefix("to_") {
+            Some(to) => ("to", num_type(to).filter(|to| *to != ty)?),
+            None => (op, ty.clone()),
+        };
+        let op = OPS.iter().find(|entry| entry.name == op)?;
+        if !op.kinds.contains(&kind_of(&ty)) || !op.kinds.contains(&kind_of(&to)) {
+            return None;
+        }
+        Some(Self { op, ty, to })
+    }
+
+    pub(super) fn signature(&self) -> Type {
+        let (params_ty, return_ty) = match self.op.shape {
+            Shape::Unary => (vec![self.ty.clone()], self.ty.clone()),
+            Shape::Binary => (vec![self.ty.clone(); 2], self.ty.clone()),
+            Shape::Compare => (vec![self.ty.clone(); 2], Type::Bool),
+            Shape::Convert => (vec![self.ty.clone()], self.to.clone()),
+        };
+        let return_ty = Box::new(return_ty);
+        Type::Function {
+            params_ty,
+            return_ty,
+        }
+    }
+
+    //The extern declaring the intrinsic must have its signature
+    pub(super) fn check(&self, name: &str, ty: &Type) -> Result<()> {
+        let sig = self.signature();
+        if *ty == sig {
+            return Ok(());
+        }
+        let (params, ret) = match sig {
+            Type::Function {
+                params_ty,
+                return_ty,
+            } => (params_ty, return_ty),
+            _ => unreachable!(),
+        };
+        let params = params.iter().enumerate();
+        let params = params.map(|(i, ty)| format!("p{}: {}", i, type_name(ty)));
+        let params = params.collect::<Vec<_>>().join(", ");
+        let msg = format!(
+            "The intrinsic {} must be declared as `extern {}({}) -> {};`",
+            name,
+            name,
+            params,
+            type_name(&ret)
+        );
+        Err(Error::new(msg))
+    }
+
+    //The C function an intrinsic calls when Cranelift has no instruction
+    //  for it. The remainder of floats has the sign of the dividend
+    pub(super) fn libcall(&self) -> Option<&'static str> {
+        match (self.op.name, &self.ty) {
+            ("mod", Type::F32) => Some("fmodf"),
+            ("mod", Type::F64) => Some("fmod"),
+            _ => None,
+        }
+    }
+
+    pub(super) fn emit(&self, builder: &mut FunctionBuilder, args: &[Value]) -> Value {
+        (self.op.lower)(builder, self, args)
+    }
+
+    fn kind(&self) -> Kind {
+        kind_of(&self.ty)
+    }
+
+    fn clif_ty(&self) -> ir::Type {
+        clif_ty(&self.ty)
+    }
+}
+
+fn kind_of(ty: &Type) -> Kind {
+    match ty {
+        Type::I8 | Type::I16 | Type::I32 | Type::I64 => Kind::Signed,
+        Type::F32 | Type::F64 => Kind::Float,
+        Type::Bool => Kind::Bool,
+        _ => Kind::Unsigned,
+    }
+}
+
+fn clif_ty(ty: &Type) -> ir::Type {
+    use cranelift::codegen::ir::types;
+    match ty {
+        Type::I8 | Type::U8 => types::I8,
+        Type::I16 | Type::U16 => types::I16,
+        Type::I32 | Type::U32 => types::I32,
+        Type::F32 => types::F32,
+        Type::F64 => types::F64,
+        Type::Bool => types::B1,
+        _ => types::I64,
+    }
+}
+
+//Cranelift traps when dividing by zero or dividing the smallest signed
+//  integer by -1, these divide by 1 instead
+fn divide(builder: &mut FunctionBuilder, intr: &Intrinsic, op: &str, args: &[Value]) -> Value {
+    let (p0, p1) = (args[0], args[1]);
+    let ty = intr.clif_ty();
+    let signed = intr.kind() == Kind::Signed;
+    let zero = builder.ins().icmp_imm(IntCC::Equal, p1, 0);
+    let minus_one = builder.ins().icmp_imm(IntCC::Equal, p1, -1);
+    let special = match signed {
+        true => builder.ins().bor(zero, minus_one),
+        false => zero,
+    };
+    let one = builder.ins().iconst(ty, 1);
+    let divisor = builder.ins().select(special, one, p1);
+    match (op, signed) {
+        ("div", true) => {
+            let quot = builder.ins().sdiv(p0, divisor);
+            let neg = builder.ins().ineg(p0);
+            let quot = builder.ins().select(minus_one, neg, quot);
+            let zero_val = builder.ins().iconst(ty, 0);
+            builder.ins().select(zero, zero_val, quot)
+        }
+        ("div", false) => {
+            let quot = builder.ins().udiv(p0, divisor);
+            let zero_val = builder.ins().iconst(ty, 0);
+            builder.ins().select(zero, zero_val, quot)
+        }
+        (_, true) => {
+            let rem = builder.ins().srem(p0, divisor);
+            builder.ins().select(zero, p0, rem)
+        }
+        (_, false) => {
+            let rem = builder.ins().urem(p0, divisor);
+            builder.ins().select(zero, p0, rem)
+        }
+    }
+}
+
+//Whether the result of an integer operation does not fit in its type
+fn overflows(builder: &mut FunctionBuilder, intr: &Intrinsic, op: &str, args: &[Value]) -> Value {
+    use cranelift::codegen::ir::types::I64;
+    let (p0, p1) = (args[0], args[1]);
+    let (sign, ty) = (intr.kind(), intr.clif_ty());
+    match (op, sign) {
+        //The sign of the result differs from that of both operands
+        ("add", Kind::Signed) => {
             let res = builder.ins().iadd(p0, p1);
-            builder.ins().return_(&[res]);
+            let x0 = builder.ins().bxor(p0, res);
+            let x1 = builder.ins().bxor(p1, res);
+            let both = builder.ins().band(x0, x1);
+            builder.ins().icmp_imm(IntCC::SignedLessThan, both, 0)
         }
-        "i32_sub" => {
-            let block = module.create_entry_block(&mut builder);
-            let vars = module.setup_params(&mut builder, &params(sym)?, block)?;
-            let p0 = builder.use_var(vars[0]);
-            let p1 = builder.use_var(vars[1]);
+        ("add", _) => {
+            let res = builder.ins().iadd(p0, p1);
+            builder.ins().icmp(IntCC::UnsignedLessThan, res, p0)
+        }
+        //The operands have different signs and the result that of p1
+        ("sub", Kind::Signed) => {
             let res = builder.ins().isub(p0, p1);
-            builder.ins().return_(&[res]);
+            let x0 = builder.ins().bxor(p0, p1);
+            let x1 = builder.ins().bxor(p0, res);
+            let both = builder.ins().band(x0, x1);
+            builder.ins().icmp_imm(IntCC::SignedLessThan, both, 0)
         }
-        "i32_mul" => {
-            let block = module.create_entry_block(&mut builder);
-            let vars = module.setup_params(&mut builder, &params(sym)?, block)?;
-            let p0 = builder.use_var(vars[0]);
-            let p1 = builder.use_var(vars[1]);
+        ("sub", _) => builder.ins().icmp(IntCC::UnsignedLessThan, p0, p1),
+        ("mul", _) if ty == I64 => {
             let res = builder.ins().imul(p0, p1);
-            builder.ins().return_(&[res]);
-        }
-        "i32_div" => {
-            let block = module.create_entry_block(&mut builder);
-            let vars = module.setup_params(&mut builder, &params(sym)?, block)?;
-            let p0 = builder.use_var(vars[0]);
-            let p1 = builder.use_var(vars[1]);
-            let res = builder.ins().sdiv(p0, p1);
-            builder.ins().return_(&[res]);
-        }
-        "i32_mod" => {
-            let block = module.create_entry_block(&mut builder);
-            let vars = module.setup_params(&mut builder, &params(sym)?, block)?;
-            let p0 = builder.use_var(vars[0]);
-            let p1 = builder.use_var(vars[1]);
-            let res = builder.ins().srem(p0, p1);
-            builder.ins().return_(&[res]);
-        }
-        "i32_lt" => {
-            let block = module.create_entry_block(&mut builder);
-            let vars = module.setup_params(&mut builder, &params(sym)?, block)?;
-            let p0 = builder.use_var(vars[0]);
-            let p1 = builder.use_var(vars[1]);
-            let res = builder.ins().icmp(IntCC::SignedLessThan, p0, p1);
-            builder.ins().return_(&[res]);
-        }
-        "i32_gt" => {
-            let block = module.create_entry_block(&mut builder);
-            let vars = module.setup_params(&mut builder, &params(sym)?, block)?;
-            let p0 = builder.use_var(vars[0]);
-            let p1 = builder.use_var(vars[1]);
-            let res = builder.ins().icmp(IntCC::SignedGreaterThan, p0, p1);
-            builder.ins().return_(&[res]);
-        }
-        "i32_eq" => {
-            let block = module.create_entry_block(&mut builder);
-            let vars = module.setup_params(&mut builder, &params(sym)?, block)?;
-            let p0 = builder.use_var(vars[0]);
-            let p1 = builder.use_var(vars[1]);
-            let res = builder.ins().icmp(IntCC::Equal, p0, p1);
-            builder.ins().return_(&[res]);
-        }
-        _ => return Ok(None),
+            match sign {
+                Kind::Signed => {
+                    let high = builder.ins().smulhi(p0, p1);
+                    let sign = builder.ins().sshr_imm(res, 63);
+                    builder.ins().icmp(IntCC::NotEqual, high, sign)
+                }
+                _ => {
+                    let high = builder.ins().umulhi(p0, p1);
+                    builder.ins().icmp_imm(IntCC::NotEqual, high, 0)
+                }
+            }
+        }
+        //Narrower products are computed exactly in 64 bits
+        ("mul", Kind::Signed) => {
+            let w0 = builder.ins().sextend(I64, p0);
+            let w1 = builder.ins().sextend(I64, p1);
+            let wide = builder.ins().imul(w0, w1);
+            let res = builder.ins().ireduce(ty, wide);
+            let res = builder.ins().sextend(I64, res);
+            builder.ins().icmp(IntCC::NotEqual, wide, res)
+        }
+        ("mul", _) => {
+            let w0 = builder.ins().uextend(I64, p0);
+            let w1 = builder.ins().uextend(I64, p1);
+            let wide = builder.ins().imul(w0, w1);
+            let max = u64::MAX >> (64 - ty.bits());
+            builder
+                .ins()
+                .icmp_imm(IntCC::UnsignedGreaterThan, wide, max as i64)
+        }
+        ("div", Kind::Signed) => {
+            let zero = builder.ins().icmp_imm(IntCC::Equal, p1, 0);
+            let min = builder.ins().icmp_imm(IntCC::Equal, p0, min_value(ty));
+            let minus_one = builder.ins().icmp_imm(IntCC::Equal, p1, -1);
+            let overflow = builder.ins().band(min, minus_one);
+            builder.ins().bor(zero, overflow)
+        }
+        _ => builder.ins().icmp_imm(IntCC::Equal, p1, 0),
+    }
+}
+
+//Overflowing results are replaced by the bound of the type they went past
+fn saturating(builder: &mut FunctionBuilder, intr: &Intrinsic, op: &str, args: &[Value]) -> Value {
+    let (p0, p1) = (args[0], args[1]);
+    let overflow = overflows(builder, intr, op, args);
+    let res = match op {
+        "add" => builder.ins().iadd(p0, p1),
+        "sub" => builder.ins().isub(p0, p1),
+        _ => builder.ins().imul(p0, p1),
     };
-    Ok(Some(func))
+    let (sign, ty) = (intr.kind(), intr.clif_ty());
+    let min = builder.ins().iconst(ty, min_value(ty));
+    let max = builder.ins().bnot(min);
+    let bound = match (op, sign) {
+        ("add" | "sub", Kind::Signed) => {
+            let neg = builder.ins().icmp_imm(IntCC::SignedLessThan, p0, 0);
+            builder.ins().select(neg, min, max)
+        }
+        ("mul", Kind::Signed) => {
+            let signs = builder.ins().bxor(p0, p1);
+            let neg = builder.ins().icmp_imm(IntCC::SignedLessThan, signs, 0);
+            builder.ins().select(neg, min, max)
+        }
+        ("sub", _) => builder.ins().iconst(ty, 0),
+        _ => builder.ins().iconst(ty, -1),
+    };
+    builder.ins().select(overflow, bound, res)
+}
+
+fn convert(builder: &mut FunctionBuilder, intr: &Intrinsic, args: &[Value]) -> Value {
+    use cranelift::codegen::ir::types::{F32, F64, I64};
+    use std::cmp::Ordering;
+    let val = args[0];
+    let (sign, from) = (intr.kind(), intr.clif_ty());
+    let (to_sign, to) = (kind_of(&intr.to), clif_ty(&intr.to));
+    match (sign, to_sign) {
+        (Kind::Float, Kind::Float) => match from.bits().cmp(&to.bits()) {
+            Ordering::Less => builder.ins().fpromote(to, val),
+            Ordering::Greater => builder.ins().fdemote(to, val),
+            Ordering::Equal => val,
+        },
+        (Kind::Float, sign) => {
+            let val = match from {
+                F32 => builder.ins().fpromote(F64, val),
+                _ => val,
+            };
+            //Saturate in 64 bits then clamp to the range of the type
+            let wide = match sign {
+                Kind::Signed => builder.ins().fcvt_to_sint_sat(I64, val),
+                _ => builder.ins().fcvt_to_uint_sat(I64, val),
+            };
+            if to == I64 {
+                return wide;
+            }
+            let (min, max, lt, gt) = match sign {
+                Kind::Signed => (
+                    min_value(to),
+                    !min_value(to),
+                    IntCC::SignedLessThan,
+                    IntCC::SignedGreaterThan,
+                ),
+                _ => (
+                    0,
+                    (u64::MAX >> (64 - to.bits())) as i64,
+                    IntCC::UnsignedLessThan,
+                    IntCC::UnsignedGreaterThan,
+                ),
+            };
+            let min_val = builder.ins().iconst(I64, min);
+            let max_val = builder.ins().iconst(I64, max);
+            let below = builder.ins().icmp(lt, wide, min_val);
+            let wide = builder.ins().select(below, min_val, wide);
+            let above = builder.ins().icmp(gt, wide, max_val);
+            let wide = builder.ins().select(above, max_val, wide);
+            builder.ins().ireduce(to, wide)
+        }
+        (sign, Kind::Float) => {
+            let val = match (sign, from == I64) {
+                (Kind::Signed, false) => builder.ins().sextend(I64, val),
+                (_, false) => builder.ins().uextend(I64, val),
+                (_, true) => val,
+            };
+            match sign {
+                Kind::Unsigned if from == I64 => builder.ins().fcvt_from_uint(to, val),
+                _ => builder.ins().fcvt_from_sint(to, val),
+            }
+        }
+        (sign, _) => match from.bits().cmp(&to.bits()) {
+            Ordering::Less if sign == Kind::Signed => builder.ins().sextend(to, val),
+            Ordering::Less => builder.ins().uextend(to, val),
+            Ordering::Greater => builder.ins().ireduce(to, val),
+            Ordering::Equal => val,
+        },
+    }
+}
+
+//Cranelift leaves the amount to the machine, which only masks it to the
+//  width of 32 and 64 bit registers
+fn shift_amount(builder: &mut FunctionBuilder, amount: Value) -> Value {
+    let bits = builder.func.dfg.value_type(amount).bits();
+    builder.ins().band_imm(amount, bits as i64 - 1)
+}
+
+//The smallest signed integer of a type, sign extended to 64 bits
+fn min_value(ty: ir::Type) -> i64 {
+    i64::MIN >> (64 - ty.bits())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Intrinsic;
+    use crate::monoir::Type;
+    use crate::testing::{error, Session};
+
+    #[test]
+    fn division() {
+        let mut repl = Session::new();
+        assert_eq!(repl.value("i32_div(-7, 2)"), "-3");
+        assert_eq!(repl.value("i32_mod(-7, 2)"), "-1");
+        assert_eq!(repl.value("i32_div(5, 0)"), "0");
+        assert_eq!(repl.value("i32_mod(5, 0)"), "5");
+        assert_eq!(repl.value("i32_div(-2147483648, -1)"), "-2147483648");
+        assert_eq!(repl.value("i32_mod(-2147483648, -1)"), "0");
+        assert_eq!(repl.value("u8_div(200u8, 0u8)"), "0");
+        assert_eq!(repl.value("f64_mod(-7.5, 2.0)"), "-1.5");
+        assert_eq!(repl.value("f64_mod(100000000000000000.0, 3.0)"), "1.0");
+        assert_eq!(repl.value("f64_mod(1.0, 0.0)"), "NaN");
+        assert_eq!(repl.value("f32_mod(7.5f32, -2.0f32)"), "1.5");
+    }
+
+    #[test]
+    fn overflow() {
+        let mut repl = Session::new();
+        let checked = |repl: &mut Session, expr: &str| {
+            let src = format!("match {} {{ Overflow => -1, Exact(n) => n }}", expr);
+            repl.value(&src)
+        };
+        assert_eq!(checked(&mut repl, "i32_add_checked(2147483647, 1)"), "-1");
+        assert_eq!(
+            checked(&mut repl, "i32_mul_checked(-65536, 32768)"),
+            "-2147483648"
+        );
+        assert_eq!(checked(&mut repl, "i32_div_checked(1, 0)"), "-1");
+        assert_eq!(repl.value("i32_add_wrapping(2147483647, 1)"), "-2147483648");
+        assert_eq!(repl.value("i8_add_saturating(100i8, 100i8)"), "127");
+        assert_eq!(repl.value("i8_mul_saturating(-100i8, 100i8)"), "-128");
+        assert_eq!(repl.value("u8_sub_saturating(1u8, 2u8)"), "0");
+        assert_eq!(
+            repl.value("u64_mul_overflows(4294967296u64, 4294967296u64)"),
+            "true"
+        );
+    }
+
+    #[test]
+    fn bits() {
+        let mut repl = Session::new();
+        assert_eq!(repl.value("i32_xor(12, 10)"), "6");
+        assert_eq!(repl.value("i32_shl(1, 33)"), "2");
+        assert_eq!(repl.value("i8_shl(1i8, 9i8)"), "2");
+        assert_eq!(repl.value("u8_shr(128u8, 9u8)"), "64");
+        assert_eq!(repl.value("i16_shl(1i16, 17i16)"), "2");
+        assert_eq!(repl.value("i16_shr(-32768i16, 17i16)"), "-16384");
+        assert_eq!(repl.value("u16_ushr(32768u16, 17u16)"), "16384");
+        assert_eq!(repl.value("i32_shr(-16, 2)"), "-4");
+        assert_eq!(repl.value("i32_ushr(-16, 28)"), "15");
+        assert_eq!(repl.value("u8_rotl(129u8, 1u8)"), "3");
+        assert_eq!(repl.value("i64_popcnt(-1i64)"), "64");
+        assert_eq!(repl.value("u8_clz(1u8)"), "7");
+        assert_eq!(repl.value("u8_ctz(0u8)"), "8");
+        assert_eq!(repl.value("f64_ne(f64_div(0.0, 0.0), 0.0)"), "true");
+        assert_eq!(repl.value("bool_not(bool_and(true, false))"), "true");
+    }
+
+    #[test]
+    fn conversions() {
+        let mut repl = Session::new();
+        assert_eq!(repl.value("i8_to_u32(-1i8)"), "4294967295");
+        assert_eq!(repl.value("u8_to_i32(255u8)"), "255");
+        assert_eq!(repl.value("i64_to_i8(300i64)"), "44");
+        assert_eq!(repl.value("f64_to_i32(-2.7)"), "-2");
+        assert_eq!(repl.value("f64_to_u8(1000.0)"), "255");
+        assert_eq!(repl.value("f64_to_i32(f64_div(0.0, 0.0))"), "0");
+        assert_eq!(
+            repl.value("u64_to_f64(18446744073709551615u64)"),
+            "1.8446744073709552e19"
+        );
+        assert_eq!(repl.value("i32_to_f32(3)"), "3.0");
+    }
+
+    #[test]
+    fn signatures() {
+        let func = |params_ty: Vec<Type>, ret: Type| Type::Function {
+            params_ty,
+            return_ty: Box::new(ret),
+        };
+        assert!(Intrinsic::lookup("i32_to_i32").is_none());
+        assert!(Intrinsic::lookup("f64_and").is_none());
+        assert!(Intrinsic::lookup("i32_frobnicate").is_none());
+        let add = Intrinsic::lookup("i32_add").unwrap();
+        assert!(add
+            .check("i32_add", &func(vec![Type::I32; 2], Type::I32))
+            .is_ok());
+        let res = add.check("i32_add", &func(vec![Type::I32, Type::I64], Type::I32));
+        assert_eq!(
+            error(res),
+            "The intrinsic i32_add must be declared as `extern i32_add(p0: i32, p1: i32) -> i32;`"
+        );
+        let lt = Intrinsic::lookup("u8_lt").unwrap();
+        let res = lt.check("u8_lt", &func(vec![Type::U8; 2], Type::U8));
+        assert_eq!(
+            error(res),
+            "The intrinsic u8_lt must be declared as `extern u8_lt(p0: u8, p1: u8) -> bool;`"
+        );
+        let to = Intrinsic::lookup("u8_to_f32").unwrap();
+        let res = to.check("u8_to_f32", &func(vec![Type::U8, Type::U8], Type::F32));
+        assert_eq!(
+            error(res),
+            "The intrinsic u8_to_f32 must be declared as `extern u8_to_f32(p0: u8) -> f32;`"
+        );
+    }
 }
@@ -1,12 +1,42 @@
 use crate::monoir;
-use crate::{Result, Vector};
+use crate::{Error, Result, Vector};
+use cranelift_module::{FuncOrDataId, Module};
+use target_lexicon::Triple;
 
 mod expr;
 mod intrinsics;
 mod module;
 
+pub struct Options {
+    pub target: Triple,
+    pub opt_level: String,
+    //Generate position independent code, as needed by shared libraries and
+    //  position independent executables
+    pub pic: bool,
+    //Write the Cranelift IR of the functions to this file
+    pub clif_file: Option<String>,
+    //Write the object file, to <module>.o if no path is given
+    pub emit_obj: bool,
+    pub object_file: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            target: Triple::host(),
+            opt_level: "speed_and_size".to_string(),
+            pic: false,
+            clif_file: None,
+            emit_obj: true,
+            object_file: None,
+        }
+    }
+}
+
 #[derive(Default)]
-pub struct CodeGen {}
+pub struct CodeGen {
+    options: Options,
+}
 
 impl crate::Pass for CodeGen {
     type Input = Vec<monoir::Module>;
@@ -19,16 +49,96 @@ impl crate::Pass for CodeGen {
 
 impl CodeGen {
     pub fn new() -> Self {
-        Self {}
+        Self::default()
+    }
+
+    pub fn with_options(options: Options) -> Self {
+        Self { options }
     }
 
     fn codegen_module(&mut self, module: monoir::Module) -> Result<String> {
         let name = module.name.to_string();
-        let cranelift_module = module::Translator::new(&name)?;
+        let cranelift_module = module::Translator::new(&name, &self.options)?;
         let module = cranelift_module.translate(module)?;
-        let bytes = module.finish().emit().unwrap();
-        let object_file = format!("{name}.o");
-        std::fs::write(&object_file, bytes).unwrap();
+        if let (Some(path), Some(clif)) = (&self.options.clif_file, &module.clif) {
+            write(path, clif.as_bytes())?;
+        }
+        let object_file = match self.options.object_file {
+            Some(ref path) => path.clone(),
+            None => format!("{name}.o"),
+        };
+        if self.options.emit_obj {
+            let bytes = module
+                .inner
+                .finish()
+                .emit()
+                .map_err(|e| Error::new(format!("Could not emit {object_file}: {e}")))?;
+            write(&object_file, &bytes)?;
+        }
         Ok(object_file)
     }
+
+    //Compile a module in memory and call `entry`, a function without
+    //  parameters, returning a rendering of its result
+    pub fn jit_run(module: monoir::Module, entry: &str) -> Result<String> {
+        let entry_ty = module.funcs.iter().find(|f| f.sym.name.as_str() == entry);
+        let return_ty = match entry_ty.map(|f| &f.sym.ty) {
+            Some(monoir::Type::Function { return_ty, .. }) => (**return_ty).clone(),
+            _ => return Err(Error::new(format!("Could not find function {entry}"))),
+        };
+        let mut jit = module::Translator::jit()?.translate(module)?.inner;
+        jit.finalize_definitions();
+        let func_id = match jit.get_name(entry) {
+            Some(FuncOrDataId::Func(func_id)) => func_id,
+            _ => return Err(Error::new(format!("Could not find function {entry}"))),
+        };
+        let code = jit.get_finalized_function(func_id);
+        //Safety: the signature of `entry` is the one implied by its type
+        let res = unsafe {
+            match return_ty {
+                monoir::Type::Unit => {
+                    let f: extern "C" fn() = std::mem::transmute(code);
+                    f();
+                    "()".to_string()
+                }
+                monoir::Type::I8 => call::<i8>(code).to_string(),
+                monoir::Type::I16 => call::<i16>(code).to_string(),
+                monoir::Type::I32 => call::<i32>(code).to_string(),
+                monoir::Type::I64 => call::<i64>(code).to_string(),
+                monoir::Type::U8 => call::<u8>(code).to_string(),
+                monoir::Type::U16 => call::<u16>(code).to_string(),
+                monoir::Type::U32 => call::<u32>(code).to_string(),
+                monoir::Type::U64 => call::<u64>(code).to_string(),
+                monoir::Type::F32 => format!("{:?}", call::<f32>(code)),
+                monoir::Type::F64 => format!("{:?}", call::<f64>(code)),
+                monoir::Type::Bool => {
+                    let f: extern "C" fn() -> u8 = std::mem::transmute(code);
+                    (f() != 0).to_string()
+                }
+                monoir::Type::Function { .. } => {
+                    let f: extern "C" fn() -> usize = std::mem::transmute(code);
+                    f();
+                    "<fun>".to_string()
+                }
+                monoir::Type::Data(_) | monoir::Type::Record(_) => {
+                    let f: extern "C" fn() -> usize = std::mem::transmute(code);
+                    f();
+                    "<abstr>".to_string()
+                }
+            }
+        };
+        //Output written with putchar would otherwise show up after the result
+        unsafe { libc::fflush(std::ptr::null_mut()) };
+        Ok(res)
+    }
+}
+
+fn write(path: &str, bytes: &[u8]) -> Result<()> {
+    std::fs::write(path, bytes).map_err(|e| Error::new(format!("Could not write {path}: {e}")))
+}
+
+//Safety: `code` must be a function without parameters returning a `T`
+unsafe fn call<T>(code: *const u8) -> T {
+    let f: extern "C" fn() -> T = std::mem::transmute(code);
+    f()
 }
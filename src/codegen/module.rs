@@ -1,103 +1,260 @@
+use super::intrinsics::Intrinsic;
 use crate::monoir;
 use crate::{Error, Result};
 use cranelift::codegen;
 use cranelift::frontend::Variable;
 use cranelift::prelude::FunctionBuilder;
-use cranelift_module::{FuncId, Module};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{DataContext, DataId, FuncId, Module};
 use cranelift_object::{ObjectBuilder, ObjectModule};
 use std::collections::HashMap;
+use std::rc::Rc;
 
-pub(super) struct ModuleTranslator {
-    pub(super) inner: ObjectModule,
+pub(super) struct ModuleTranslator<M: Module> {
+    pub(super) inner: M,
+    //Used to allocate closure records and values of data types
+    pub(super) malloc: FuncId,
+    //The field types of every variant of the tagged unions
+    unions: HashMap<Rc<String>, Vec<Vec<monoir::Type>>>,
+    //The field types of the records
+    records: HashMap<Rc<String>, Vec<monoir::Type>>,
+    //The Cranelift IR of the functions defined so far, when asked for
+    pub(super) clif: Option<String>,
 }
 
-pub(super) struct Translator {
-    pub(super) module: ModuleTranslator,
+pub(super) struct Translator<M: Module> {
+    pub(super) module: ModuleTranslator<M>,
 }
 
-impl Translator {
-    pub(super) fn new(name: &str) -> Result<Self> {
-        use codegen::settings::{self, Configurable};
+fn target_isa(
+    triple: &target_lexicon::Triple,
+    opt_level: &str,
+    pic: bool,
+) -> Result<Box<dyn codegen::isa::TargetIsa>> {
+    use codegen::settings::{self, Configurable};
 
-        let triple = target_lexicon::Triple::host();
-        let mut builder = settings::builder();
+    let mut builder = settings::builder();
+    builder
+        .set("opt_level", opt_level)
+        .map_err(|_| Error::new(format!("Could not set opt_level to {opt_level}")))?;
+    if pic {
         builder
-            .set("opt_level", "speed_and_size")
-            .map_err(|_| Error::new("Could not set opt_level"))?;
-        let flags = settings::Flags::new(builder);
-        let target_isa = codegen::isa::lookup(triple.clone())
-            .map_err(|_| Error::new(format!("Unsupported triple {triple:?}")))?
-            .finish(flags);
+            .enable("is_pic")
+            .map_err(|_| Error::new("Could not enable position independent code"))?;
+    }
+    let flags = settings::Flags::new(builder);
+    let target_isa = codegen::isa::lookup(triple.clone())
+        .map_err(|_| Error::new(format!("Unsupported target {triple}")))?
+        .finish(flags);
+    Ok(target_isa)
+}
+
+impl Translator<ObjectModule> {
+    pub(super) fn new(name: &str, options: &super::Options) -> Result<Self> {
         let builder = ObjectBuilder::new(
-            target_isa,
+            target_isa(&options.target, &options.opt_level, options.pic)?,
             format!("{}.o", name),
             cranelift_module::default_libcall_names(),
         )
         .map_err(|_| Error::new("Cannot create cranelift module"))?;
-        let inner = ObjectModule::new(builder);
-        let module = ModuleTranslator { inner };
+        let mut translator = Self::with_module(ObjectModule::new(builder))?;
+        if options.clif_file.is_some() {
+            translator.module.clif = Some(String::new());
+        }
+        Ok(translator)
+    }
+}
+
+impl Translator<JITModule> {
+    //Functions are compiled into memory and external functions are looked
+    //  up in the running process
+    pub(super) fn jit() -> Result<Self> {
+        let isa = target_isa(&target_lexicon::Triple::host(), "speed_and_size", false)?;
+        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        //The process may not link libm, `%` on floats is fmod
+        extern "C" fn fmod(a: f64, b: f64) -> f64 {
+            a % b
+        }
+        extern "C" fn fmodf(a: f32, b: f32) -> f32 {
+            a % b
+        }
+        builder.symbol("fmod", fmod as *const u8);
+        builder.symbol("fmodf", fmodf as *const u8);
+        Self::with_module(JITModule::new(builder))
+    }
+}
+
+impl<M: Module> Translator<M> {
+    fn with_module(mut inner: M) -> Result<Self> {
+        let mut sig = inner.make_signature();
+        let ptr_ty = inner.target_config().pointer_type();
+        sig.params.push(codegen::ir::AbiParam::new(ptr_ty));
+        sig.returns.push(codegen::ir::AbiParam::new(ptr_ty));
+        let malloc = inner
+            .declare_function("malloc", cranelift_module::Linkage::Import, &sig)
+            .map_err(|e| Error::new(format!(" Error {e}")))?;
+        let module = ModuleTranslator {
+            inner,
+            malloc,
+            unions: HashMap::new(),
+            records: HashMap::new(),
+            clif: None,
+        };
 
         Ok(Self { module })
     }
 
-    pub(super) fn translate(mut self, module: monoir::Module) -> Result<ObjectModule> {
+    pub(super) fn translate(mut self, mut module: monoir::Module) -> Result<ModuleTranslator<M>> {
         use cranelift_module::Linkage;
+        for union in std::mem::take(&mut module.types) {
+            self.module.unions.insert(union.name, union.variants);
+        }
+        for record in std::mem::take(&mut module.records) {
+            self.module.records.insert(record.name, record.fields);
+        }
         let mut functions: HashMap<u32, FuncId> = HashMap::new();
-        for extern_func in &module.ext_funcs {
+        let mut intrinsics: HashMap<u32, Intrinsic> = HashMap::new();
+        for (extern_func, span) in &module.ext_funcs {
+            let intrinsic = Intrinsic::lookup(&extern_func.name);
+            if let Some(ref intrinsic) = intrinsic {
+                intrinsic
+                    .check(&extern_func.name, &extern_func.ty)
+                    .map_err(|e| e.at(*span))?;
+            }
+            let libcall = intrinsic.as_ref().and_then(|intrinsic| intrinsic.libcall());
+            if let (Some(intrinsic), None) = (intrinsic, libcall) {
+                intrinsics.insert(extern_func.id, intrinsic);
+                continue;
+            }
             let sig = self.module.translate_sig(&extern_func.ty)?;
-            let intrinsic = super::intrinsics::emit(&self.module, extern_func, &sig)?;
-            let linkage = if intrinsic.is_some() {
-                Linkage::Local
-            } else {
-                Linkage::Import
+            let func_id = match libcall {
+                Some(name) => self
+                    .module
+                    .inner
+                    .declare_function(name, Linkage::Import, &sig)
+                    .map_err(|e| Error::new(format!(" Error {e}")))?,
+                None => self
+                    .module
+                    .declare_func(extern_func, Linkage::Import, sig)?,
             };
-            let func_id = self.module.declare_func(extern_func, linkage, sig)?;
             functions.insert(extern_func.id, func_id);
-            if let Some(func) = intrinsic {
-                self.module.define_function(func_id, func)?;
-            }
         }
 
         let mut funcs = Vec::new();
+        let mut closures: HashMap<u32, DataId> = HashMap::new();
         for bind in module.funcs.as_slice() {
             //println!("{bind:#?}");
             let symbol = &bind.sym;
-            let sig = self.module.translate_sig(&symbol.ty)?;
-            let func_id = self
-                .module
-                .declare_func(symbol, Linkage::Export, sig.clone())?;
+            let env = match &bind.expr {
+                monoir::Expr::Lam(lam) => lam.env.as_ref(),
+                _ => None,
+            };
+            let func_id = match env {
+                Some(env) => {
+                    let sig = self.module.translate_closure_sig(&symbol.ty)?;
+                    let func_id = self
+                        .module
+                        .declare_func(symbol, Linkage::Local, sig.clone())?;
+                    //Closures that capture nothing are allocated statically
+                    if env.is_empty() {
+                        let data_id = self.module.define_static_closure(symbol, func_id)?;
+                        closures.insert(symbol.id, data_id);
+                    }
+                    funcs.push((func_id, sig, bind));
+                    func_id
+                }
+                None => {
+                    let mut sig = self.module.translate_sig(&symbol.ty)?;
+                    let is_main = symbol.name.as_str() == "main";
+                    //A main that returns () exits with 0
+                    if is_main && sig.returns.is_empty() {
+                        sig.returns
+                            .push(codegen::ir::AbiParam::new(codegen::ir::types::I32));
+                    }
+                    let linkage = match is_main || module.exports.contains(symbol.name.as_str()) {
+                        true => Linkage::Export,
+                        false => Linkage::Local,
+                    };
+                    let func_id = self.module.declare_func(symbol, linkage, sig.clone())?;
+                    funcs.push((func_id, sig, bind));
+                    func_id
+                }
+            };
             functions.insert(symbol.id, func_id);
-            funcs.push((func_id, sig, bind));
         }
 
-        for (func_id, bind, sig) in funcs {
-            let mut trans = super::expr::FunctionTranslator::new(&self.module, &functions);
-            let func = trans.emit_func(sig, &bind)?;
+        //Each group of functions calling each other in tail position is
+        //  emitted once, as a function the functions of the group call
+        let mut groups = Vec::new();
+        let mut members = HashMap::new();
+        for group in super::expr::tail_call_groups(&module.funcs) {
+            let mut sig = self.module.translate_sig(&group[0].sym.ty)?;
+            sig.params = vec![codegen::ir::AbiParam::new(codegen::ir::types::I32)];
+            for bind in &group {
+                let params = self.module.translate_sig(&bind.sym.ty)?.params;
+                sig.params.extend(params);
+            }
+            let name = format!("{}.tail", group[0].sym.name);
+            let func_id = self
+                .module
+                .inner
+                .declare_function(&name, Linkage::Local, &sig)
+                .map_err(|e| Error::new(format!(" Error {e}")))?;
+            for bind in &group {
+                members.insert(bind.sym.id, groups.len());
+            }
+            groups.push((func_id, sig, group));
+        }
+        for (func_id, sig, bind) in funcs {
+            let mut trans = super::expr::FunctionTranslator::new(
+                &self.module,
+                &functions,
+                &intrinsics,
+                &closures,
+            );
+            let func = match members.get(&bind.sym.id) {
+                Some(group) => {
+                    let (group_id, _, ref group) = groups[*group];
+                    trans.emit_entry(bind, &sig, (group_id, group))?
+                }
+                None => trans.emit_func(bind, &sig)?,
+            };
             self.module.define_function(func_id, func)?;
         }
+        for (func_id, sig, group) in &groups {
+            let mut trans = super::expr::FunctionTranslator::new(
+                &self.module,
+                &functions,
+                &intrinsics,
+                &closures,
+            );
+            let func = trans.emit_group(group, sig)?;
+            self.module.define_function(*func_id, func)?;
+        }
 
-        Ok(self.module.inner)
+        Ok(self.module)
     }
 }
 
-impl ModuleTranslator {
-    /// Declare a single variable declaration.
+impl<M: Module> ModuleTranslator<M> {
+    /// Declare a single variable declaration. Unit has no values so
+    /// variables of type unit are not declared
     pub(super) fn declare_variable(
         &self,
         symbol: &monoir::Symbol,
         builder: &mut FunctionBuilder,
-    ) -> Variable {
-        if symbol.ty == monoir::Type::Unit {
-            panic!("{:?} is a unit type", symbol);
-        }
-        let ty = self.translate_type(&symbol.ty);
+    ) -> Option<Variable> {
+        let ty = self.translate_type(&symbol.ty)?;
         let var = Variable::with_u32(symbol.id);
         builder.declare_var(var, ty);
-        var
+        Some(var)
     }
 
     fn define_function(&mut self, funcid: FuncId, function: codegen::ir::Function) -> Result<()> {
-        //println!("{}", function);
+        if let Some(clif) = self.clif.as_mut() {
+            clif.push_str(&function.display().to_string());
+            clif.push('\n');
+        }
         use codegen::{
             binemit::{NullStackMapSink, NullTrapSink},
             settings, Context,
@@ -123,16 +280,142 @@ impl ModuleTranslator {
         self.inner.target_config().pointer_type()
     }
 
-    pub(super) fn translate_type(&self, ty: &monoir::Type) -> codegen::ir::Type {
+    //Unit is zero sized and so has no cranelift type
+    pub(super) fn translate_type(&self, ty: &monoir::Type) -> Option<codegen::ir::Type> {
         use codegen::ir::types;
-        match ty {
-            monoir::Type::Unit => todo!(),
+        let ty = match ty {
+            monoir::Type::Unit => return None,
             monoir::Type::Bool => types::B1,
-            monoir::Type::I32 => types::I32,
+            monoir::Type::I8 | monoir::Type::U8 => types::I8,
+            monoir::Type::I16 | monoir::Type::U16 => types::I16,
+            monoir::Type::I32 | monoir::Type::U32 => types::I32,
+            monoir::Type::I64 | monoir::Type::U64 => types::I64,
+            monoir::Type::F32 => types::F32,
+            monoir::Type::F64 => types::F64,
             monoir::Type::Function { .. } => self.pointer_ty(),
+            monoir::Type::Data(_) | monoir::Type::Record(_) => self.pointer_ty(),
+        };
+        Some(ty)
+    }
+
+    //Size in bytes of a value of type `ty` when stored in memory. Values are
+    //  aligned to their size.
+    pub(super) fn size_of(&self, ty: &monoir::Type) -> u32 {
+        match ty {
+            monoir::Type::Unit => 0,
+            monoir::Type::Bool => 1,
+            monoir::Type::I8 | monoir::Type::U8 => 1,
+            monoir::Type::I16 | monoir::Type::U16 => 2,
+            monoir::Type::I32 | monoir::Type::U32 | monoir::Type::F32 => 4,
+            monoir::Type::I64 | monoir::Type::U64 | monoir::Type::F64 => 8,
+            monoir::Type::Function { .. } => self.pointer_ty().bytes(),
+            monoir::Type::Data(_) | monoir::Type::Record(_) => self.pointer_ty().bytes(),
+        }
+    }
+
+    //Lay out values one after the other starting at offset `start`.
+    //   Returns the offsets of the values and the size of the record.
+    fn layout<'t>(
+        &self,
+        start: u32,
+        tys: impl Iterator<Item = &'t monoir::Type>,
+    ) -> (Vec<i32>, u32) {
+        let mut size = start;
+        let mut offsets = Vec::new();
+        for ty in tys {
+            let var_size = self.size_of(ty);
+            size = size.next_multiple_of(var_size.max(1));
+            offsets.push(size as i32);
+            size += var_size;
+        }
+        (offsets, size)
+    }
+
+    //Layout of a closure record: a pointer to the code followed by the
+    //   captured variables.
+    pub(super) fn closure_layout(&self, env: &[monoir::Symbol]) -> (Vec<i32>, u32) {
+        self.layout(self.pointer_ty().bytes(), env.iter().map(|var| &var.ty))
+    }
+
+    //The field types of a variant of a tagged union
+    pub(super) fn variant(&self, ty: &monoir::Type, tag: u32) -> Result<&[monoir::Type]> {
+        let variants = match ty {
+            monoir::Type::Data(name) => self.unions.get(name),
+            _ => None,
+        };
+        match variants.and_then(|variants| variants.get(tag as usize)) {
+            Some(fields) => Ok(fields),
+            None => Err(Error::new(format!("{ty:?} has no variant {tag}"))),
+        }
+    }
+
+    //Layout of a variant: an i32 tag followed by the fields
+    pub(super) fn variant_layout(&self, fields: &[monoir::Type]) -> (Vec<i32>, u32) {
+        self.layout(4, fields.iter())
+    }
+
+    //The field types of a record
+    pub(super) fn record(&self, ty: &monoir::Type) -> Result<&[monoir::Type]> {
+        let fields = match ty {
+            monoir::Type::Record(name) => self.records.get(name),
+            _ => None,
+        };
+        match fields {
+            Some(fields) => Ok(fields),
+            None => Err(Error::new(format!("{ty:?} is not a record"))),
         }
     }
 
+    //Layout of a record, the same as that of the C struct with the same
+    //   fields so that records can be passed to external functions
+    pub(super) fn record_layout(&self, fields: &[monoir::Type]) -> (Vec<i32>, u32) {
+        let (offsets, size) = self.layout(0, fields.iter());
+        let align = fields.iter().map(|ty| self.size_of(ty)).max().unwrap_or(1);
+        (offsets, size.next_multiple_of(align.max(1)))
+    }
+
+    //A closure record that only holds the code pointer
+    fn define_static_closure(&mut self, code: &monoir::Symbol, func_id: FuncId) -> Result<DataId> {
+        let name = format!("{}.closure", code.name);
+        let data_id = self
+            .inner
+            .declare_data(&name, cranelift_module::Linkage::Local, true, false)
+            .map_err(|e| Error::new(format!(" Error {e}")))?;
+        //Not zero initialized, relocations in .bss are not applied by the
+        //  linker and the code pointer would stay null
+        let mut data_ctx = DataContext::new();
+        data_ctx.define(vec![0; self.pointer_ty().bytes() as usize].into_boxed_slice());
+        let func_ref = self.inner.declare_func_in_data(func_id, &mut data_ctx);
+        data_ctx.write_function_addr(0, func_ref);
+        self.inner
+            .define_data(data_id, &data_ctx)
+            .map_err(|e| Error::new(format!("Could not define closure {e:?}")))?;
+        Ok(data_id)
+    }
+
+    //The code of a closure takes a pointer to the closure record as its
+    //  first argument
+    pub(super) fn translate_closure_sig(
+        &self,
+        ty: &monoir::Type,
+    ) -> Result<codegen::ir::Signature> {
+        let mut sig = self.translate_sig(ty)?;
+        let env = codegen::ir::AbiParam::new(self.pointer_ty());
+        sig.params.insert(0, env);
+        Ok(sig)
+    }
+
+    //C extends integers narrower than 32 bits when passing them
+    fn abi_param(&self, ty: &monoir::Type) -> Option<codegen::ir::AbiParam> {
+        let param = codegen::ir::AbiParam::new(self.translate_type(ty)?);
+        let param = match ty {
+            monoir::Type::I8 | monoir::Type::I16 => param.sext(),
+            monoir::Type::U8 | monoir::Type::U16 => param.uext(),
+            _ => param,
+        };
+        Some(param)
+    }
+
     pub(super) fn translate_sig(&self, ty: &monoir::Type) -> Result<codegen::ir::Signature> {
         if let monoir::Type::Function {
             params_ty,
@@ -140,16 +423,14 @@ impl ModuleTranslator {
         } = &ty
         {
             let mut sig = self.inner.make_signature();
+            //Unit parameters and return values are not passed
             for param in params_ty {
-                let ty = self.translate_type(param);
-                let param = codegen::ir::AbiParam::new(ty);
-                sig.params.push(param);
+                if let Some(param) = self.abi_param(param) {
+                    sig.params.push(param);
+                }
             }
-
-            if **return_ty != monoir::Type::Unit {
-                let ty = self.translate_type(return_ty);
-                let param = codegen::ir::AbiParam::new(ty);
-                sig.returns.push(param);
+            if let Some(ret) = self.abi_param(return_ty) {
+                sig.returns.push(ret);
             }
             Ok(sig)
         } else {
@@ -168,6 +449,7 @@ impl ModuleTranslator {
             .map_err(|e| Error::new(format!(" Error {e}")))
     }
 
+    //The variables of the parameters that are not of type unit
     pub(super) fn setup_params(
         &self,
         builder: &mut FunctionBuilder<'_>,
@@ -175,13 +457,17 @@ impl ModuleTranslator {
         block: cranelift::prelude::Block,
     ) -> Result<Vec<Variable>> {
         let mut vars = Vec::new();
-        for (i, param) in params.iter().enumerate() {
+        //The parameters are preceded by the environment in closures
+        let nparams = params.iter().filter(|p| p.ty != monoir::Type::Unit).count();
+        let offset = builder.block_params(block).len() - nparams;
+        for param in params {
             // TODO: cranelift_frontend should really have an API to make it
             // easy to set up param variables.
-            let val = builder.block_params(block)[i];
-            let var = self.declare_variable(param, builder);
-            builder.def_var(var, val);
-            vars.push(var);
+            if let Some(var) = self.declare_variable(param, builder) {
+                let val = builder.block_params(block)[offset + vars.len()];
+                builder.def_var(var, val);
+                vars.push(var);
+            }
         }
         Ok(vars)
     }
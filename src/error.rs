@@ -1,6 +1,22 @@
+use crate::span::{SourceFile, SourceMap, Span};
+use lalrpop_util::ParseError;
+use std::cell::RefCell;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
 #[derive(Debug)]
 pub struct Error {
+    level: Level,
     msg: String,
+    //Where the error happened
+    span: Option<Span>,
+    //Other locations that explain the error
+    labels: Vec<(Span, String)>,
 }
 
 impl std::fmt::Display for Error {
@@ -27,6 +43,244 @@ impl Error {
     where
         T: Into<String>,
     {
-        Error { msg: msg.into() }
+        Error {
+            level: Level::Error,
+            msg: msg.into(),
+            span: None,
+            labels: vec![],
+        }
+    }
+
+    pub fn spanned<T>(msg: T, span: Span) -> Self
+    where
+        T: Into<String>,
+    {
+        Error::new(msg).at(span)
+    }
+
+    pub fn warning<T>(msg: T, span: Span) -> Self
+    where
+        T: Into<String>,
+    {
+        let mut warning = Error::spanned(msg, span);
+        warning.level = Level::Warning;
+        warning
+    }
+
+    //Set the primary location unless a more precise one is already known
+    pub fn at(mut self, span: Span) -> Self {
+        if self.span.is_none() {
+            self.span = Some(span);
+        }
+        self
+    }
+
+    pub fn label<T>(mut self, span: Span, msg: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.labels.push((span, msg.into()));
+        self
+    }
+
+    pub fn from_parse<T: std::fmt::Display>(file: u32, err: ParseError<usize, T, Error>) -> Self {
+        let expected = |expected: Vec<String>| {
+            let expected: Vec<_> = expected.iter().map(|t| terminal(t)).collect();
+            match expected.len() {
+                0 => String::new(),
+                1 => format!(", expected {}", expected[0]),
+                _ => format!(", expected one of {}", expected.join(", ")),
+            }
+        };
+        match err {
+            ParseError::InvalidToken { location } => {
+                Error::spanned("invalid token", Span::new(file, location, location + 1))
+            }
+            ParseError::UnrecognizedEOF {
+                location,
+                expected: e,
+            } => {
+                let msg = format!("unexpected end of file{}", expected(e));
+                Error::spanned(msg, Span::new(file, location, location))
+            }
+            ParseError::UnrecognizedToken {
+                token: (lo, tok, hi),
+                expected: e,
+            } => {
+                let msg = format!("unexpected `{}`{}", tok, expected(e));
+                Error::spanned(msg, Span::new(file, lo, hi))
+            }
+            ParseError::ExtraToken {
+                token: (lo, tok, hi),
+            } => Error::spanned(format!("extra token `{}`", tok), Span::new(file, lo, hi)),
+            ParseError::User { error } => error,
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn msg(&self) -> &str {
+        &self.msg
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    //Render the error the way rustc does
+    //   error: Can not unify i32 with bool
+    //    --> foo.bs:2:8
+    //     |
+    //   2 |     if i32_add(x, 1) {
+    //     |        ^^^^^^^^^^^^^
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let level = match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        };
+        let mut out = format!("{}: {}\n", level, self.msg);
+        let (primary, file) = match self.span.and_then(|s| Some((s, sources.file(s.file)?))) {
+            Some(res) => res,
+            None => return out,
+        };
+
+        //Marks in the same file as the primary span are drawn in the
+        //   snippet. The others are listed as notes
+        let mut marks = vec![(primary, '^', "")];
+        let mut notes = Vec::new();
+        for (span, msg) in &self.labels {
+            match span.file == primary.file {
+                true => marks.push((*span, '-', msg.as_str())),
+                false => notes.push((*span, msg.as_str())),
+            }
+        }
+        let mut lines = marks
+            .iter()
+            .map(|(span, _, _)| file.loc(span.lo).line)
+            .collect::<Vec<_>>();
+        lines.sort_unstable();
+        lines.dedup();
+
+        let width = lines.last().unwrap().to_string().len();
+        let loc = file.loc(primary.lo);
+        let _ = writeln!(
+            out,
+            "{:w$}--> {}:{}:{}",
+            "",
+            file.name(),
+            loc.line,
+            loc.col,
+            w = width
+        );
+        let _ = writeln!(out, "{:w$} |", "", w = width);
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 && lines[i - 1] + 1 != *line {
+                let _ = writeln!(out, "{:w$} |", "...", w = width);
+            }
+            let _ = writeln!(out, "{:>w$} | {}", line, file.line(*line), w = width);
+            for (span, c, msg) in &marks {
+                if file.loc(span.lo).line == *line {
+                    let (col, len) = underline(file, *span);
+                    let marker = c.to_string().repeat(len);
+                    let row = format!("{:w$} | {:col$}{} {}", "", "", marker, msg, w = width);
+                    let _ = writeln!(out, "{}", row.trim_end());
+                }
+            }
+        }
+        for (span, msg) in notes {
+            if let Some(file) = sources.file(span.file) {
+                let loc = file.loc(span.lo);
+                let name = file.name();
+                let _ = writeln!(
+                    out,
+                    "{:w$} = note: {}:{}:{}: {}",
+                    "",
+                    name,
+                    loc.line,
+                    loc.col,
+                    msg,
+                    w = width
+                );
+            }
+        }
+        out
+    }
+}
+
+thread_local! {
+    static WARNINGS: RefCell<Vec<Error>> = const { RefCell::new(Vec::new()) };
+}
+
+//Passes report problems that do not stop compilation here
+pub fn warn(warning: Error) {
+    WARNINGS.with(|warnings| warnings.borrow_mut().push(warning))
+}
+
+pub fn take_warnings() -> Vec<Error> {
+    WARNINGS.with(|warnings| warnings.take())
+}
+
+//Column and length of the underline for a span. Spans covering several
+//   lines are underlined up to the end of the first one
+fn underline(file: &SourceFile, span: Span) -> (usize, usize) {
+    let lo = file.loc(span.lo);
+    let line = file.line(lo.line);
+    let col = lo.col as usize - 1;
+    let len = match file.loc(span.hi) {
+        hi if hi.line == lo.line => (hi.col - lo.col) as usize,
+        _ => line.chars().count() - col,
+    };
+    (col, std::cmp::max(len, 1))
+}
+
+//The name of a terminal of the grammar as lalrpop prints it, the regular
+//  expressions of literals are named after what they match
+//    "if" => `if`
+//    r#"[a-zA-Z][a-zA-Z0-9_]*'*"# => identifier
+fn terminal(terminal: &str) -> String {
+    match terminal.strip_prefix("r#\"") {
+        Some(regex) if regex.starts_with("[0-9]") => "number".to_string(),
+        Some(regex) if regex.starts_with("[a-zA-Z]") => "identifier".to_string(),
+        Some(regex) if regex.starts_with("[-+*/") => "operator".to_string(),
+        Some(regex) if regex.starts_with("\\\\") => "lambda".to_string(),
+        _ => format!("`{}`", terminal.trim_matches('"')),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let mut sources = SourceMap::new();
+        let src = "let main() {\n    if 1 { 2 } else { true }\n}\n";
+        let id = sources.add("foo.bs", src.to_string());
+        let err = Error::spanned("Can not unify i32 with bool", Span::new(id, 20, 21))
+            .label(Span::new(id, 35, 39), "this is bool");
+        let expected = "error: Can not unify i32 with bool
+ --> foo.bs:2:8
+  |
+2 |     if 1 { 2 } else { true }
+  |        ^
+  |                       ---- this is bool
+";
+        assert_eq!(err.render(&sources), expected);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let parse = |src| {
+            let err = crate::parser::ModuleParser::new()
+                .parse("test", 0, src)
+                .unwrap_err();
+            Error::from_parse(0, err).msg().to_string()
+        };
+        let msg = "unexpected `;`, expected one of `!`, `()`, `-`, `@tailcall`, `false`, `if`, `match`, `true`, `{`, number, identifier, lambda";
+        assert_eq!(parse("let main() { let x = ; 1 }"), msg);
+        let msg = "unexpected end of file, expected identifier";
+        assert_eq!(parse("let main() { 1 }\nlet"), msg);
     }
 }
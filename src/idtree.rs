@@ -1,9 +1,16 @@
+use crate::span::Span;
+use crate::types::{Num, TyVar};
 use std::fmt;
 use std::rc::Rc;
 
 //FIXME: for now use the same representation of type variables as is used in
 //       typechecking
 type Type = crate::types::Type<crate::types::TyVar>;
+pub type DataType = crate::types::DataType<crate::types::TyVar>;
+pub type Record = crate::types::Record<crate::types::TyVar>;
+pub type Class = crate::types::Class<crate::types::TyVar>;
+pub type Instance = crate::types::Instance<crate::types::TyVar>;
+pub type Pred = crate::types::Pred<crate::types::TyVar>;
 
 #[derive(Debug)]
 pub struct Module {
@@ -13,8 +20,17 @@ pub struct Module {
 
 #[derive(Debug)]
 pub enum Decl {
-    Extern(Symbol),
+    //An extern and the span of its name
+    Extern(Symbol, Span),
     Let(Vec<Bind>),
+    //A data type and the symbols of its constructors
+    Type(Rc<DataType>, Vec<Symbol>),
+    Record(Rc<Record>),
+    //A class and the symbols of its methods
+    Class(Rc<Class>, Vec<Symbol>),
+    //An instance and the implementations of the methods of its class, in
+    //  the order they are declared in the class
+    Instance(Rc<Instance>, Vec<Bind>),
 }
 
 #[derive(Clone, Eq, PartialEq)]
@@ -34,23 +50,81 @@ pub struct Bind {
     expr: Expr,
 }
 
+#[derive(Debug)]
+pub enum Pattern {
+    Wildcard,
+    Var(Symbol),
+    //The constructor, its tag and the patterns for its arguments
+    Ctor(Symbol, u32, Vec<Pattern>),
+    UnitLit,
+    NumLit(Num),
+    BoolLit(bool),
+}
+
+#[derive(Debug)]
+pub struct Arm {
+    pat: Pattern,
+    expr: Expr,
+    span: Span,
+}
+
+//A constructor applied to all its arguments
+#[derive(Debug)]
+pub struct Ctor {
+    symbol: Symbol,
+    tag: u32,
+    args: Vec<Expr>,
+}
+
+//The type variables of a function's annotation are rigid, they can not be
+//  instantiated by its body. Annotations in the body can refer to them.
+//  The constraints on them are added to those of the body
+#[derive(Debug)]
+pub struct Annot {
+    pub ty: Type,
+    pub rigid: Vec<(String, TyVar)>,
+    pub preds: Vec<Pred>,
+    pub span: Span,
+}
+
+//The else branch of an if without one is () and the then branch must be
+//  () as well
 #[derive(Debug)]
 pub struct If {
     cond: Expr,
     texpr: Expr,
-    fexpr: Expr,
+    fexpr: Option<Expr>,
+}
+
+pub struct Expr {
+    kind: ExprKind,
+    span: Span,
 }
 
 #[derive(Debug)]
-pub enum Expr {
+pub enum ExprKind {
     UnitLit,
-    I32Lit(i32),
+    NumLit(Num),
     BoolLit(bool),
     Var(Symbol),
     If(Box<If>),
     Let(Box<Let>),
+    //A group of mutually recursive functions
+    LetRec(Vec<Bind>, Box<Expr>),
+    //Evaluate the first expression for its effects, then the second
+    Seq(Box<Expr>, Box<Expr>),
     Lam(Vec<Symbol>, Box<Expr>),
     App(Box<Expr>, Vec<Expr>),
+    Ctor(Box<Ctor>),
+    Match(Box<Expr>, Vec<Arm>),
+    //Fields are referred to by their index in the record type. They are
+    //  in the order they were written in
+    Record(Rc<Record>, Vec<(u32, Expr)>),
+    //The records in scope declaring the fields, the last declared last. The
+    //  one used is the type of the record expression
+    Project(Box<Expr>, Vec<Rc<Record>>, Rc<String>),
+    Update(Box<Expr>, Vec<Rc<Record>>, Vec<(Rc<String>, Expr)>),
+    Annot(Box<Expr>, Box<Annot>),
 }
 
 impl Module {
@@ -99,8 +173,26 @@ impl fmt::Debug for Symbol {
     }
 }
 
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+    pub fn kind(&self) -> &ExprKind {
+        &self.kind
+    }
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
 impl If {
-    pub fn new(cond: Expr, texpr: Expr, fexpr: Expr) -> Self {
+    pub fn new(cond: Expr, texpr: Expr, fexpr: Option<Expr>) -> Self {
         If { cond, texpr, fexpr }
     }
     pub fn cond(&self) -> &Expr {
@@ -109,8 +201,38 @@ impl If {
     pub fn texpr(&self) -> &Expr {
         &self.texpr
     }
-    pub fn fexpr(&self) -> &Expr {
-        &self.fexpr
+    pub fn fexpr(&self) -> Option<&Expr> {
+        self.fexpr.as_ref()
+    }
+}
+
+impl Ctor {
+    pub fn new(symbol: Symbol, tag: u32, args: Vec<Expr>) -> Self {
+        Ctor { symbol, tag, args }
+    }
+    pub fn symbol(&self) -> &Symbol {
+        &self.symbol
+    }
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+    pub fn args(&self) -> &Vec<Expr> {
+        &self.args
+    }
+}
+
+impl Arm {
+    pub fn new(pat: Pattern, expr: Expr, span: Span) -> Self {
+        Arm { pat, expr, span }
+    }
+    pub fn pat(&self) -> &Pattern {
+        &self.pat
+    }
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+    pub fn span(&self) -> Span {
+        self.span
     }
 }
 
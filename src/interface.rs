@@ -0,0 +1,256 @@
+use crate::ast;
+use crate::idtree;
+use crate::rename::Rename;
+use crate::span::{SourceMap, Span};
+use crate::typecheck::TypeChecker;
+use crate::types::{TyVar, Type};
+use crate::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+//The interface of a module lists its imports and its public declarations in
+//  the syntax of the language, with its monomorphic functions declared as
+//  externs of the module. The interface of tree is
+//    import list;
+//    pub type Tree = Leaf | Node(Tree, i32, Tree)
+//    pub extern tree.sum(t: Tree) -> i32;
+//    pub let size(t) { fold(t, 0, \(n, _) -> { n + 1 }) }
+//  Modules that import it are compiled against the interface and linked
+//  with the object file of the module. Polymorphic functions and instances
+//  have no code of their own and are specialized by the modules using them,
+//  so their source is copied along with the private functions, types and
+//  operators it refers to. Private monomorphic functions, and the methods
+//  of instances for types without parameters, are still called in the
+//  object file.
+pub fn write(
+    parsed: &ast::Module,
+    module: &idtree::Module,
+    sources: &SourceMap,
+    rename: &Rename,
+    typecheck: &TypeChecker,
+) -> Result<String> {
+    let exports = match rename.exports(module.name()) {
+        Some(exports) => exports,
+        None => {
+            let msg = format!("Could not find module {}", module.name());
+            return Err(Error::new(msg));
+        }
+    };
+    let is_public = |sym: &idtree::Symbol| {
+        let public = exports.names.get(sym.name().as_str());
+        public.map(|public| public.id()) == Some(sym.id())
+    };
+    let mut out = String::new();
+    //The prelude is imported implicitly
+    for import in rename.imports(module.name()) {
+        match import.hiding.len() {
+            0 if import.name == crate::prelude::NAME => {}
+            0 => {
+                let _ = writeln!(out, "import {};", import.name);
+            }
+            _ => {
+                //Operators are hidden in parentheses
+                let hiding = import.hiding.iter().map(|name| {
+                    if name.starts_with(char::is_alphabetic) {
+                        name.clone()
+                    } else {
+                        format!("({})", name)
+                    }
+                });
+                let hiding = hiding.collect::<Vec<_>>().join(", ");
+                let _ = writeln!(out, "import {} hiding ({});", import.name, hiding);
+            }
+        }
+    }
+
+    let mut funcs = HashMap::new();
+    let mut externs = HashMap::new();
+    let mut work = Vec::new();
+    for decl in module.decls() {
+        match *decl {
+            idtree::Decl::Extern(ref sym, _) => {
+                externs.insert(sym.name().as_str(), sym);
+            }
+            idtree::Decl::Let(ref binds) => {
+                for bind in binds {
+                    let scheme = typecheck.lookup(bind.symbol())?;
+                    let mono = scheme.bound_vars().is_empty();
+                    if is_public(bind.symbol()) && !mono {
+                        work.push(bind.symbol().id());
+                    }
+                    funcs.insert(bind.symbol().id(), (bind, mono));
+                }
+            }
+            idtree::Decl::Instance(_, ref binds) => {
+                for bind in binds {
+                    uses(bind.expr(), &mut work);
+                }
+            }
+            _ => {}
+        }
+    }
+    let names: HashMap<_, _> = funcs
+        .values()
+        .map(|(bind, _)| (bind.symbol().name().as_str(), bind.symbol().id()))
+        .collect();
+    for decl in &parsed.decls {
+        if let ast::Decl::Fixity(ref fixity) = *decl.inner() {
+            work.extend(names.get(fixity.func.as_str()));
+        }
+    }
+    //The functions whose source is copied and the externs they call
+    let mut copied = HashSet::new();
+    let mut used = HashSet::new();
+    while let Some(id) = work.pop() {
+        used.insert(id);
+        if let Some(&(bind, mono)) = funcs.get(&id) {
+            if !(mono && is_public(bind.symbol())) && copied.insert(id) {
+                uses(bind.expr(), &mut work);
+            }
+        }
+    }
+
+    let text = |span: Span| {
+        let src = sources.file(span.file).unwrap().src();
+        &src[span.lo as usize..span.hi as usize]
+    };
+    let mut funcs_out = String::new();
+    let mut instances_out = String::new();
+    for decl in &parsed.decls {
+        let (public, decl) = match *decl {
+            ast::Decl::Pub(ref decl) => ("pub ", &**decl),
+            ref decl => ("", decl),
+        };
+        match *decl {
+            ast::Decl::Extern(ref name, _, ref linked, _) => {
+                let sym = externs[name.as_str()];
+                if !public.is_empty() || used.contains(&sym.id()) {
+                    out.push_str(public);
+                    out.push_str(&external(linked.as_deref(), sym.name(), &[], sym.ty()));
+                }
+            }
+            ast::Decl::Func(ast::Bind(ref name, ref lam, _)) => {
+                let (bind, mono) = funcs[&names[name.as_str()]];
+                if copied.contains(&bind.symbol().id()) {
+                    let _ = writeln!(funcs_out, "{}{}", public, text(lam.span()));
+                } else if mono && !public.is_empty() {
+                    let scheme = typecheck.lookup(bind.symbol())?;
+                    let mut expr = bind.expr();
+                    while let idtree::ExprKind::Annot(ref e, _) = *expr.kind() {
+                        expr = e;
+                    }
+                    let params = match *expr.kind() {
+                        idtree::ExprKind::Lam(ref params, _) => params.as_slice(),
+                        _ => &[],
+                    };
+                    funcs_out.push_str("pub ");
+                    let name = bind.symbol().name();
+                    let module = Some(module.name().as_str());
+                    funcs_out.push_str(&external(module, name, params, scheme.ty()));
+                }
+            }
+            ast::Decl::Type(ref data) => {
+                let _ = writeln!(out, "{}{}", public, text(data.span));
+            }
+            ast::Decl::Record(ref rec) => {
+                let _ = writeln!(out, "{}{}", public, text(rec.span));
+            }
+            ast::Decl::Class(ref class) => {
+                let _ = writeln!(out, "{}{}", public, text(class.span));
+            }
+            ast::Decl::Fixity(ref fixity) => {
+                let _ = writeln!(out, "{}{}", public, text(fixity.span));
+            }
+            ast::Decl::Instance(ref inst) => {
+                let _ = writeln!(instances_out, "{}", text(inst.span));
+            }
+            ast::Decl::Pub(_) => {}
+        }
+    }
+    out.push_str(&funcs_out);
+    out.push_str(&instances_out);
+    Ok(out)
+}
+
+//The variables an expression refers to
+fn uses(expr: &idtree::Expr, acc: &mut Vec<u32>) {
+    use crate::idtree::ExprKind::*;
+    match *expr.kind() {
+        UnitLit | NumLit(_) | BoolLit(_) => {}
+        Var(ref sym) => acc.push(sym.id()),
+        If(ref e) => {
+            uses(e.cond(), acc);
+            uses(e.texpr(), acc);
+            if let Some(fexpr) = e.fexpr() {
+                uses(fexpr, acc);
+            }
+        }
+        Let(ref e) => {
+            uses(e.bind().expr(), acc);
+            uses(e.expr(), acc);
+        }
+        LetRec(ref binds, ref e) => {
+            for bind in binds {
+                uses(bind.expr(), acc);
+            }
+            uses(e, acc);
+        }
+        Seq(ref e1, ref e2) => {
+            uses(e1, acc);
+            uses(e2, acc);
+        }
+        Lam(_, ref e) | Project(ref e, ..) | Annot(ref e, _) => uses(e, acc),
+        App(ref callee, ref args) => {
+            uses(callee, acc);
+            for arg in args {
+                uses(arg, acc);
+            }
+        }
+        Ctor(ref ctor) => {
+            for arg in ctor.args() {
+                uses(arg, acc);
+            }
+        }
+        Match(ref scrut, ref arms) => {
+            uses(scrut, acc);
+            for arm in arms {
+                uses(arm.expr(), acc);
+            }
+        }
+        Record(_, ref fields) => {
+            for (_, e) in fields {
+                uses(e, acc);
+            }
+        }
+        Update(ref record, _, ref fields) => {
+            uses(record, acc);
+            for (_, e) in fields {
+                uses(e, acc);
+            }
+        }
+    }
+}
+
+//An extern declaration, of a function of `module` if there is one. Types
+//  are written as they are printed
+fn external(
+    module: Option<&str>,
+    name: &str,
+    param_names: &[idtree::Symbol],
+    ty: &Type<TyVar>,
+) -> String {
+    let (params, ret) = match ty.func_parts() {
+        Some(parts) => parts,
+        None => (&[][..], ty),
+    };
+    let params = params.iter().enumerate().map(|(i, param)| {
+        let name = match param_names.get(i) {
+            Some(sym) => sym.name().to_string(),
+            None => format!("p{}", i),
+        };
+        format!("{}: {}", name, param)
+    });
+    let params = params.collect::<Vec<_>>().join(", ");
+    let module = module.map_or(String::new(), |module| format!("{}.", module));
+    format!("extern {}{}({}) -> {};\n", module, name, params, ret)
+}
@@ -1,12 +1,48 @@
+//Lambda lifting with closure conversion.
+//   Nested lambdas are hoisted to the top level. A lambda that has free
+//   variables becomes a closure: its code takes an environment record
+//   holding the captured variables. Every function value is represented
+//   uniformly as a pointer to a closure record so that it can be called
+//   without knowing where it came from
+//
+//       let add(n) { \x -> i32_add(x, n) }
+//   becomes
+//       closure @__anon_1[n](x) { i32_add(x, n) }
+//       let add(n) { mkclosure(@__anon_1, [n]) }
+//
+//   Top level functions used as values are wrapped in a closure with an
+//   empty environment. Calls to known top level functions stay direct.
+//
+//   A closure can not refer to itself, so the functions of a let rec that
+//   capture variables are lambda lifted instead: the captured variables
+//   become extra parameters which every call passes along
+//
+//       let rec f(x) { g(x) } and g(x) { if b { f(x) } else { n } }; f(1)
+//   becomes
+//       let f.1(x, b, n) { g.2(x, b, n) }
+//       let g.2(x, b, n) { if b { f.1(x, b, n) } else { n } }
+//       f.1(1, b, n)
 use crate::fresh_id;
-use crate::scoped_map::ScopedMap;
+use crate::span::Span;
+use crate::types::TyVar;
 use crate::xir::*;
-use crate::{Result, Vector};
-
+use crate::{Error, Result, Vector};
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
+type Type = crate::types::Type<TyVar>;
+
 pub struct LambdaLift {
-    map: ScopedMap<u32, Symbol>,
+    //let bound lambdas that capture nothing are lifted to plain functions.
+    //   Maps the let bound symbol to the lifted function.
+    map: HashMap<u32, Symbol>,
+    //Symbols that are visible everywhere and so never need to be captured
+    top_level: HashSet<u32>,
+    //Closures wrapping top level functions that are used as values
+    wrappers: HashMap<u32, Symbol>,
+    //Functions of a let rec that capture variables. Maps the let bound
+    //   symbol to the lifted function and the variables it captures
+    lifted: HashMap<u32, (Symbol, Vec<Symbol>)>,
 }
 
 impl Default for LambdaLift {
@@ -28,16 +64,36 @@ impl crate::Pass for LambdaLift {
 impl LambdaLift {
     pub fn new() -> Self {
         LambdaLift {
-            map: ScopedMap::new(),
+            map: HashMap::new(),
+            top_level: HashSet::new(),
+            wrappers: HashMap::new(),
+            lifted: HashMap::new(),
         }
     }
 
     fn lift_module(&mut self, module: &Module) -> Result<Module> {
         let mut decls = Vec::new();
 
+        for decl in module.decls() {
+            match *decl {
+                Decl::Extern(ref symbol, _) => {
+                    self.top_level.insert(symbol.id());
+                }
+                Decl::Let(ref binds) => {
+                    for bind in binds {
+                        self.top_level.insert(bind.symbol().id());
+                    }
+                }
+                Decl::Closure(_, ref bind) => {
+                    self.top_level.insert(bind.symbol().id());
+                }
+                Decl::Type(_) | Decl::Record(_) | Decl::Class(..) | Decl::Instance(..) => {}
+            }
+        }
+
         for decl in module.decls() {
             let decl = match *decl {
-                Decl::Extern(ref symbol) => Decl::Extern(symbol.clone()),
+                Decl::Extern(ref symbol, span) => Decl::Extern(symbol.clone(), span),
                 Decl::Let(ref bind) => {
                     let bind = bind
                         .iter()
@@ -45,102 +101,317 @@ impl LambdaLift {
                         .collect();
                     Decl::Let(bind)
                 }
+                Decl::Closure(ref env, ref bind) => {
+                    Decl::Closure(env.clone(), self.lift_bind(bind, &mut decls))
+                }
+                Decl::Type(ref data) => Decl::Type(data.clone()),
+                Decl::Record(ref rec) => Decl::Record(rec.clone()),
+                Decl::Class(..) | Decl::Instance(..) => {
+                    return Err(Error::new(
+                        "classes should have been resolved by Specialize",
+                    ));
+                }
             };
             decls.push(decl)
         }
 
-        /*
-        for decl in decls.iter() {
-            //println!("{:?}\n", decl);
-        }
-        */
-
         Ok(Module::new(module.name().clone(), decls))
     }
 
     fn lift_bind(&mut self, bind: &Bind, acc: &mut Vec<Decl>) -> Bind {
-        self.map.begin_scope();
-        let symbol = bind.symbol();
-        let expr = self.lift(bind.expr(), acc, true);
-        let bind = match expr {
-            Expr::Lam(_, _, _) if self.map.scope() > 1 => {
-                let fnid = fresh_id();
-                let fnnm = Rc::new(format!("@__fnanon_{}", fnid));
-                let fnty = symbol.ty().clone();
-                let sym = Symbol::new(fnnm, fnty, fnid);
-                let bind = Bind::new(symbol.clone(), expr);
-                self.map.insert(symbol.id(), sym.clone());
-                acc.push(Decl::Let(vec![bind]));
-                Bind::new(sym, Expr::Var(symbol.clone()))
-            }
-            _ => Bind::new(symbol.clone(), expr),
+        let expr = match *bind.expr().kind() {
+            ExprKind::Lam(ref proto, ref body, ref retty) => {
+                let body = self.lift(body, acc);
+                let lam = ExprKind::Lam(proto.clone(), Box::new(body), retty.clone());
+                Expr::new(lam, bind.expr().span())
+            }
+            _ => self.lift(bind.expr(), acc),
+        };
+        Bind::new(bind.symbol().clone(), expr)
+    }
+
+    //Lift the body of a nested lambda. Returns the variables it captures
+    //   together with the lifted lambda.
+    fn lift_lambda(
+        &mut self,
+        proto: &[Symbol],
+        body: &Expr,
+        retty: &Type,
+        span: Span,
+        acc: &mut Vec<Decl>,
+    ) -> (Vec<Symbol>, Expr) {
+        let body = self.lift(body, acc);
+        let lam = ExprKind::Lam(proto.to_vec(), Box::new(body), retty.clone());
+        let lam = Expr::new(lam, span);
+        let env = lam
+            .free_vars()
+            .into_iter()
+            .filter(|var| !self.top_level.contains(&var.id()))
+            .collect();
+        (env, lam)
+    }
+
+    //The closure for a top level function used as a value
+    fn closure_of(&mut self, func: &Symbol, span: Span, acc: &mut Vec<Decl>) -> ExprKind {
+        let code = match self.wrappers.get(&func.id()) {
+            Some(code) => code.clone(),
+            None => {
+                // λ(x1, .., xn). func(x1, .., xn)
+                let (params_ty, retty) = match func.ty().func_parts() {
+                    Some(parts) => parts,
+                    None => return ExprKind::Var(func.clone()),
+                };
+                let params = Vector::fmap(params_ty.iter().enumerate(), |(i, ty)| {
+                    Symbol::new(Rc::new(format!("x{}", i)), ty.clone(), fresh_id())
+                });
+                let var = |v: &Symbol| Expr::new(ExprKind::Var(v.clone()), span);
+                let args = Vector::fmap(params.iter(), var);
+                let body = ExprKind::App(func.ty().clone(), Box::new(var(func)), args);
+                let lam = ExprKind::Lam(params, Box::new(Expr::new(body, span)), retty.clone());
+                let lam = Expr::new(lam, span);
+
+                let fnnm = Rc::new(format!("@__clo_{}_{}", func.name(), fresh_id()));
+                let code = Symbol::new(fnnm, func.ty().clone(), fresh_id());
+                acc.push(Decl::Closure(vec![], Bind::new(code.clone(), lam)));
+                self.wrappers.insert(func.id(), code.clone());
+                code
+            }
         };
-        self.map.end_scope();
-        bind
+        ExprKind::MkClosure(code, vec![])
+    }
+
+    //The closure for a function of a let rec used as a value. Its code calls
+    //   the lifted function with the captured variables from its environment
+    fn closure_of_lifted(&mut self, var: &Symbol, span: Span, acc: &mut Vec<Decl>) -> ExprKind {
+        let (func, env) = self.lifted[&var.id()].clone();
+        let code = match self.wrappers.get(&var.id()) {
+            Some(code) => code.clone(),
+            None => {
+                let (params_ty, retty) = var.ty().func_parts().unwrap();
+                let params = Vector::fmap(params_ty.iter().enumerate(), |(i, ty)| {
+                    Symbol::new(Rc::new(format!("x{}", i)), ty.clone(), fresh_id())
+                });
+                let var_of = |v: &Symbol| Expr::new(ExprKind::Var(v.clone()), span);
+                let args = Vector::fmap(params.iter().chain(&env), var_of);
+                let body = ExprKind::App(func.ty().clone(), Box::new(var_of(&func)), args);
+                let lam = ExprKind::Lam(params, Box::new(Expr::new(body, span)), retty.clone());
+                let lam = Expr::new(lam, span);
+
+                let fnnm = Rc::new(format!("@__clo_{}_{}", var.name(), fresh_id()));
+                let code = Symbol::new(fnnm, var.ty().clone(), fresh_id());
+                acc.push(Decl::Closure(env.clone(), Bind::new(code.clone(), lam)));
+                self.wrappers.insert(var.id(), code.clone());
+                code
+            }
+        };
+        ExprKind::MkClosure(code, env)
+    }
+
+    //Lambda lift the functions of a let rec
+    fn lift_let_rec(&mut self, binds: &[Bind], acc: &mut Vec<Decl>) {
+        let group = Vector::fmap(binds.iter(), |b| b.symbol().id());
+        let mut env: Vec<Symbol> = Vec::new();
+        for bind in binds {
+            for var in bind.expr().free_vars() {
+                let var = self.lookup(&var);
+                let captured = match self.lifted.get(&var.id()) {
+                    Some((_, vars)) => vars.clone(),
+                    None if group.contains(&var.id()) => vec![],
+                    None if self.top_level.contains(&var.id()) => vec![],
+                    None => vec![var],
+                };
+                for var in captured {
+                    if !env.contains(&var) {
+                        env.push(var)
+                    }
+                }
+            }
+        }
+
+        let mut funcs = Vec::with_capacity(binds.len());
+        for bind in binds {
+            let symbol = bind.symbol();
+            let (params_ty, retty) = symbol.ty().func_parts().unwrap();
+            let params_ty = params_ty.iter().chain(env.iter().map(|v| v.ty())).cloned();
+            let fnty = Type::func(params_ty.collect(), retty.clone());
+            let fnnm = Rc::new(format!("{}.{}", symbol.name(), fresh_id()));
+            let code = Symbol::new(fnnm, fnty, fresh_id());
+            self.top_level.insert(code.id());
+            match env.is_empty() {
+                //Nothing captured, it is a plain function
+                true => {
+                    self.map.insert(symbol.id(), code.clone());
+                }
+                false => {
+                    self.lifted.insert(symbol.id(), (code.clone(), env.clone()));
+                }
+            }
+            funcs.push(code);
+        }
+
+        for (bind, code) in binds.iter().zip(funcs) {
+            let (proto, body, retty) = match *bind.expr().kind() {
+                ExprKind::Lam(ref proto, ref body, ref retty) => (proto, body, retty),
+                _ => unreachable!("the functions of a let rec are lambdas"),
+            };
+            let body = self.lift(body, acc);
+            let proto = proto.iter().chain(&env).cloned().collect();
+            let lam = ExprKind::Lam(proto, Box::new(body), retty.clone());
+            let lam = Expr::new(lam, bind.expr().span());
+            acc.push(Decl::Let(vec![Bind::new(code, lam)]));
+        }
     }
 
-    fn lift(&mut self, expr: &Expr, acc: &mut Vec<Decl>, let_bound: bool) -> Expr {
-        use self::Expr::*;
+    fn lookup(&self, var: &Symbol) -> Symbol {
+        match self.map.get(&var.id()) {
+            Some(sym) => sym.clone(),
+            None => var.clone(),
+        }
+    }
+
+    fn lift_fields(&mut self, fields: &[(u32, Expr)], acc: &mut Vec<Decl>) -> Vec<(u32, Expr)> {
+        Vector::fmap(fields.iter(), |(i, e)| (*i, self.lift(e, acc)))
+    }
+
+    fn lift(&mut self, expr: &Expr, acc: &mut Vec<Decl>) -> Expr {
+        use self::ExprKind::*;
         use crate::xir;
-        match *expr {
+        let span = expr.span();
+        let kind = match *expr.kind() {
             UnitLit => UnitLit,
-            I32Lit(n) => I32Lit(n),
+            NumLit(n) => NumLit(n),
             BoolLit(b) => BoolLit(b),
+            Var(ref id) if self.lifted.contains_key(&id.id()) => {
+                self.closure_of_lifted(id, span, acc)
+            }
             Var(ref id) => {
-                let sym = match self.map.get(&id.id()) {
-                    Some(sym) => sym.clone(),
-                    None => id.clone(),
-                };
-                Var(sym)
+                let sym = self.lookup(id);
+                match self.top_level.contains(&sym.id()) {
+                    true => self.closure_of(&sym, span, acc),
+                    false => Var(sym),
+                }
             }
             TyLam(ref t, ref e) => {
-                let e = self.lift(e, acc, false);
+                let e = self.lift(e, acc);
                 TyLam(t.clone(), Box::new(e))
             }
             TyApp(ref e, ref t) => {
-                let e = self.lift(e, acc, false);
+                let e = self.lift(e, acc);
                 TyApp(Box::new(e), t.clone())
             }
             If(ref e) => {
                 let if_expr = xir::If::new(
-                    self.lift(e.cond(), acc, false),
-                    self.lift(e.texpr(), acc, false),
-                    self.lift(e.fexpr(), acc, false),
+                    self.lift(e.cond(), acc),
+                    self.lift(e.texpr(), acc),
+                    self.lift(e.fexpr(), acc),
                     e.ty().clone(),
                 );
-                Expr::If(Box::new(if_expr))
+                If(Box::new(if_expr))
             }
             App(ref ty, ref callee, ref args) => {
-                let callee = self.lift(callee, acc, false);
+                if let Var(ref id) = *callee.kind() {
+                    if let Some((func, env)) = self.lifted.get(&id.id()).cloned() {
+                        //Direct call passing along the captured variables
+                        let mut args = Vector::fmap(args.iter(), |arg| self.lift(arg, acc));
+                        args.extend(env.iter().map(|v| Expr::new(Var(v.clone()), span)));
+                        let callee = Expr::new(Var(func.clone()), callee.span());
+                        return Expr::new(App(func.ty().clone(), Box::new(callee), args), span);
+                    }
+                }
+                let callee = match *callee.kind() {
+                    //Direct call to a known function
+                    Var(ref id) if self.top_level.contains(&self.lookup(id).id()) => {
+                        Expr::new(Var(self.lookup(id)), callee.span())
+                    }
+                    _ => self.lift(callee, acc),
+                };
                 let args = args
                     .iter()
-                    .map(|arg| self.lift(arg, acc, false))
+                    .map(|arg| self.lift(arg, acc))
                     .collect::<Vec<_>>();
                 App(ty.clone(), Box::new(callee), args)
             }
             Let(ref exp) => {
-                let bind = self.lift_bind(exp.bind(), acc);
-                let expr = self.lift(exp.expr(), acc, false);
-                let lexp = xir::Let::new(bind, expr);
-                Let(Box::new(lexp))
-            }
-            Lam(ref proto, ref body, ref retty) => {
-                let body = self.lift(body, acc, false);
-                let proto = proto.clone();
-                let lam = Lam(proto, Box::new(body), retty.clone());
-                match let_bound {
-                    true => lam,
-                    false => {
-                        //anonymous function
-                        let fnty = retty.clone();
-                        let fnnm = Rc::new(format!("@__anon_{}", fresh_id()));
-                        let sym = Symbol::new(fnnm, fnty, fresh_id());
-                        let bind = Bind::new(sym.clone(), lam);
-                        acc.push(Decl::Let(vec![bind]));
-                        Var(sym)
+                let bind = exp.bind();
+                match *bind.expr().kind() {
+                    Lam(ref proto, ref body, ref retty) => {
+                        let symbol = bind.symbol();
+                        //'.' keeps the exported name distinct from top level
+                        //   functions. The linker reads '@' as a symbol version.
+                        let fnnm = Rc::new(format!("{}.{}", symbol.name(), fresh_id()));
+                        let code = Symbol::new(fnnm, symbol.ty().clone(), fresh_id());
+                        let lam_span = bind.expr().span();
+                        let (env, lam) = self.lift_lambda(proto, body, retty, lam_span, acc);
+                        if env.is_empty() {
+                            //Nothing captured, calls to it can be direct
+                            self.top_level.insert(code.id());
+                            self.map.insert(symbol.id(), code.clone());
+                            acc.push(Decl::Let(vec![Bind::new(code, lam)]));
+                            return self.lift(exp.expr(), acc);
+                        } else {
+                            acc.push(Decl::Closure(env.clone(), Bind::new(code.clone(), lam)));
+                            let clo = Expr::new(MkClosure(code, env), lam_span);
+                            let bind = Bind::new(symbol.clone(), clo);
+                            let expr = self.lift(exp.expr(), acc);
+                            Let(Box::new(xir::Let::new(bind, expr)))
+                        }
+                    }
+                    _ => {
+                        let bind = Bind::new(bind.symbol().clone(), self.lift(bind.expr(), acc));
+                        let expr = self.lift(exp.expr(), acc);
+                        Let(Box::new(xir::Let::new(bind, expr)))
                     }
                 }
             }
-        }
+            LetRec(ref binds, ref body) => {
+                self.lift_let_rec(binds, acc);
+                return self.lift(body, acc);
+            }
+            Seq(ref e1, ref e2) => Seq(Box::new(self.lift(e1, acc)), Box::new(self.lift(e2, acc))),
+            Lam(ref proto, ref body, ref retty) => {
+                //anonymous function
+                let params_ty = Vector::fmap(proto.iter(), |p| p.ty().clone());
+                let fnty = Type::func(params_ty, retty.clone());
+                let fnnm = Rc::new(format!("@__anon_{}", fresh_id()));
+                let code = Symbol::new(fnnm, fnty, fresh_id());
+                let (env, lam) = self.lift_lambda(proto, body, retty, span, acc);
+                acc.push(Decl::Closure(env.clone(), Bind::new(code.clone(), lam)));
+                MkClosure(code, env)
+            }
+            MkClosure(ref code, ref env) => MkClosure(code.clone(), env.clone()),
+            Ctor(ref c) => {
+                let args = Vector::fmap(c.args().iter(), |arg| self.lift(arg, acc));
+                let ctor = xir::Ctor::new(c.symbol().clone(), c.tag(), args, c.ty().clone());
+                Ctor(Box::new(ctor))
+            }
+            Match(ref m) => {
+                let arms = m.arms().iter().map(|arm| {
+                    let expr = self.lift(arm.expr(), acc);
+                    xir::Arm::new(arm.pat().clone(), expr, arm.span())
+                });
+                let arms = arms.collect();
+                Match(Box::new(xir::Match::new(
+                    m.scrut().clone(),
+                    arms,
+                    m.ty().clone(),
+                )))
+            }
+            Switch(ref s) => {
+                let cases = Vector::fmap(s.cases().iter(), |(k, e)| (*k, self.lift(e, acc)));
+                let default = s.default().map(|e| self.lift(e, acc));
+                let switch = xir::Switch::new(s.scrut().clone(), cases, default, s.ty().clone());
+                Switch(Box::new(switch))
+            }
+            Field(ref var, tag, index) => Field(self.lookup(var), tag, index),
+            Record(ref ty, ref fields) => Record(ty.clone(), self.lift_fields(fields, acc)),
+            Project(ref ty, ref record, index) => {
+                Project(ty.clone(), Box::new(self.lift(record, acc)), index)
+            }
+            Update(ref ty, ref record, ref fields) => {
+                let record = Box::new(self.lift(record, acc));
+                Update(ty.clone(), record, self.lift_fields(fields, acc))
+            }
+        };
+        Expr::new(kind, span)
     }
 }
@@ -6,20 +6,28 @@ pub mod prelude;
 lalrpop_mod!(#[allow(clippy::all)] pub parser);
 pub mod codegen;
 pub mod idtree;
+pub mod interface;
 pub mod lambda_lift;
 pub mod link;
+pub mod match_compile;
 pub mod monoir;
+pub mod options;
 pub mod passes;
 pub mod rename;
+pub mod repl;
 pub(crate) mod scoped_map;
 pub mod simplify;
+pub mod span;
 pub mod specialize;
+pub mod split;
+#[cfg(test)]
+mod testing;
 pub mod typecheck;
 pub mod types;
 pub mod utils;
 pub mod xir;
 
-pub use error::Error;
+pub use error::{take_warnings, warn, Error, Level};
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub trait Pass {
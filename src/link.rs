@@ -1,13 +1,71 @@
-use crate::Result;
+use crate::{Error, Result};
 use std::process::Command;
 
+//What the object files are combined into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Executable,
+    StaticLib,
+    SharedLib,
+}
+
+pub struct Options {
+    pub kind: Kind,
+    //The C compiler used as the linker, $CC if it is set
+    pub linker: String,
+    //Libraries passed to the linker with -l and the directories to search
+    //  for them
+    pub libs: Vec<String>,
+    pub lib_dirs: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            kind: Kind::Executable,
+            linker: std::env::var("CC").unwrap_or_else(|_| "cc".to_string()),
+            libs: Vec::new(),
+            lib_dirs: Vec::new(),
+        }
+    }
+}
+
 pub struct Link {
     name: String,
+    options: Options,
 }
 
 impl Link {
     pub fn new(name: String) -> Self {
-        Self { name }
+        Self::with_options(name, Options::default())
+    }
+
+    pub fn with_options(name: String, options: Options) -> Self {
+        Self { name, options }
+    }
+
+    fn command(&self, object_files: &[String]) -> Command {
+        //Static libraries are archives of the objects, they are linked with
+        //  the libraries when used
+        if self.options.kind == Kind::StaticLib {
+            let mut command = Command::new(std::env::var("AR").unwrap_or_else(|_| "ar".into()));
+            command.arg("rcs").arg(&self.name).args(object_files);
+            return command;
+        }
+        let mut command = Command::new(&self.options.linker);
+        if self.options.kind == Kind::SharedLib {
+            command.arg("-shared");
+        }
+        command.arg("-o").arg(&self.name).args(object_files);
+        for dir in &self.options.lib_dirs {
+            command.arg(format!("-L{}", dir));
+        }
+        for lib in &self.options.libs {
+            command.arg(format!("-l{}", lib));
+        }
+        //The remainder of floats is computed by fmod
+        command.arg("-lm");
+        command
     }
 }
 
@@ -16,12 +74,25 @@ impl crate::Pass for Link {
     type Output = ();
 
     fn run(self, object_files: Self::Input) -> Result<Self::Output> {
-        let mut command = Command::new("gcc");
-        let command = command.arg("-o").arg(self.name);
-        let _ = object_files
-            .iter()
-            .fold(command, |cmd, arg| cmd.arg(arg))
-            .output();
+        let mut command = self.command(&object_files);
+        let program = command.get_program().to_string_lossy().to_string();
+        //An archive is added to rather than replaced
+        if self.options.kind == Kind::StaticLib {
+            let _ = std::fs::remove_file(&self.name);
+        }
+        let output = command
+            .output()
+            .map_err(|e| Error::new(format!("Could not run {}: {}", program, e)))?;
+        if !output.status.success() {
+            let msg = format!(
+                "Linking {} with {} failed ({})\n{}",
+                self.name,
+                program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+            return Err(Error::new(msg));
+        }
         Ok(())
     }
 }
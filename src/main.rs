@@ -1,64 +1,387 @@
-#[macro_use(passes)]
 extern crate babel;
+use std::collections::hash_map::DefaultHasher;
 use std::env;
-use std::fs::File;
-use std::path::Path;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use target_lexicon::Triple;
 
+use babel::options::{Emit, Options, USAGE};
 use babel::passes::*;
+use babel::prelude;
+use babel::span::{SourceMap, Span};
+use babel::split::Unit;
+use babel::{passes, Error, Pass, Vector};
 
-fn compile(file: File, filenm: &Path) -> babel::Result<()> {
-    use std::io::Read;
-
-    let mut file_contents = babel::prelude::PRELUDE.to_string();
-    let mut file = file;
-    let _ = file.read_to_string(&mut file_contents);
-
-    let mod_name = filenm.file_stem().unwrap().to_str().unwrap().to_string();
-
-    let rename = Rename::new();
-    let typecheck = TypeChecker::new();
-    let specialize = Specialize::new();
-    let lambda_lift = LambdaLift::new();
-    let simplify = Simplify::new();
-    let codegen = CodeGen::new();
-    let link = Link::new(mod_name.clone());
-
-    let parser = babel::parser::ModuleParser::new();
-    let module = parser
-        .parse(&mod_name, &file_contents)
-        .map_err(|lalr_err| babel::Error::new(format!("{:?}", lalr_err)))?;
-
-    let modules = vec![module];
-
-    let _ = passes![
-        modules
-        => rename
-        => typecheck
-        => specialize
-        => lambda_lift
-        => simplify
-        => codegen
-        => link
-    ];
+//Write the debug representation of the output of a stage when asked for
+fn dump<T: std::fmt::Debug>(
+    opts: &Options,
+    name: &str,
+    emit: Emit,
+    modules: &T,
+) -> babel::Result<()> {
+    if opts.emits(emit) {
+        let path = opts.output_path(name, emit);
+        std::fs::write(&path, format!("{:#?}\n", modules))
+            .map_err(|e| Error::new(format!("Could not write {}: {}", path, e)))?;
+    }
+    Ok(())
+}
+
+//A module to compile and the file it comes from. Modules with only an
+//  interface are compiled against it and linked with their object file
+struct Source {
+    module: babel::ast::Module,
+    path: PathBuf,
+    interface: bool,
+}
+
+//Parse the inputs and the modules they import, in dependency order
+struct Loader<'a> {
+    sources: &'a mut SourceMap,
+    opts: &'a Options,
+    //The modules being loaded, to find import cycles
+    loading: Vec<String>,
+    modules: Vec<Source>,
+}
+
+impl<'a> Loader<'a> {
+    fn load(&mut self) -> babel::Result<()> {
+        if self.opts.prelude {
+            let file = self.sources.add("prelude.bs", prelude::PRELUDE.to_string());
+            let module = self.parse(prelude::NAME, file)?;
+            self.modules.push(Source {
+                module,
+                path: PathBuf::from("prelude.bs"),
+                interface: false,
+            });
+        }
+        let mut names = Vec::new();
+        for input in &self.opts.inputs {
+            let name = module_name(Path::new(input))?;
+            if names.contains(&name) || (name == prelude::NAME && self.opts.prelude) {
+                return Err(Error::new(format!(
+                    "Module {} is given more than once",
+                    name
+                )));
+            }
+            names.push(name);
+        }
+        for (input, name) in self.opts.inputs.iter().zip(names) {
+            //Inputs imported by an earlier input are already loaded
+            if !self.modules.iter().any(|source| source.module.name == name) {
+                self.visit(name, PathBuf::from(input), false)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse(&mut self, name: &str, file: u32) -> babel::Result<babel::ast::Module> {
+        let src = self.sources.file(file).unwrap().src();
+        let mut module = babel::parser::ModuleParser::new()
+            .parse(name, file, src)
+            .map_err(|lalr_err| Error::from_parse(file, lalr_err))?;
+        //The prelude is imported implicitly unless it is imported explicitly
+        //  to hide some of its names
+        let imported = module
+            .imports
+            .iter()
+            .any(|import| import.name == prelude::NAME);
+        if self.opts.prelude && name != prelude::NAME && !imported {
+            let import = babel::ast::Import::new(prelude::NAME, Span::default());
+            module.imports.insert(0, import);
+        }
+        Ok(module)
+    }
+
+    fn visit(&mut self, name: String, path: PathBuf, interface: bool) -> babel::Result<()> {
+        let src = std::fs::read_to_string(&path)
+            .map_err(|e| Error::new(format!("Could not read {}: {}", path.display(), e)))?;
+        let file = self.sources.add(&path.to_string_lossy(), src);
+        let module = self.parse(&name, file)?;
+        self.loading.push(name);
+        for import in &module.imports {
+            let (import, span) = (&import.name, &import.span);
+            if self
+                .modules
+                .iter()
+                .any(|source| source.module.name == *import)
+            {
+                continue;
+            }
+            if let Some(i) = self.loading.iter().position(|name| name == import) {
+                let mut cycle = self.loading[i..].to_vec();
+                cycle.push(import.clone());
+                let msg = format!("Import cycle {}", cycle.join(" -> "));
+                return Err(Error::spanned(msg, *span));
+            }
+            let (path, interface) = match self.find(import, &path) {
+                Some(found) => found,
+                None => {
+                    let msg = format!("Could not find module {}", import);
+                    return Err(Error::spanned(msg, *span));
+                }
+            };
+            self.visit(import.clone(), path, interface)?;
+        }
+        self.loading.pop();
+        self.modules.push(Source {
+            module,
+            path,
+            interface,
+        });
+        Ok(())
+    }
+
+    //An imported module is one of the inputs or a file next to the module
+    //  importing it. Modules are compiled from source when there is one
+    fn find(&self, name: &str, importer: &Path) -> Option<(PathBuf, bool)> {
+        let input = self.opts.inputs.iter().map(PathBuf::from);
+        if let Some(path) = input
+            .into_iter()
+            .find(|path| path.file_stem().and_then(|stem| stem.to_str()) == Some(name))
+        {
+            return Some((path, false));
+        }
+        let dir = importer.parent().unwrap_or_else(|| Path::new(""));
+        let source = dir.join(format!("{}.bs", name));
+        let interface = dir.join(format!("{}.bsi", name));
+        if source.exists() {
+            Some((source, false))
+        } else if interface.exists() {
+            Some((interface, true))
+        } else {
+            None
+        }
+    }
+}
+
+//The prelude is compiled once for each target and optimization level and
+//  kept in a cache directory. A new build of the compiler does not reuse
+//  the objects of older ones
+fn cached_prelude(opts: &Options, target: &Triple) -> babel::Result<PathBuf> {
+    let dir = match env::var_os("BABEL_CACHE_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+            (Some(cache), _) => PathBuf::from(cache).join("babel"),
+            (None, Some(home)) => PathBuf::from(home).join(".cache").join("babel"),
+            (None, None) => env::temp_dir().join("babel"),
+        },
+    };
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| Error::new(format!("Could not create {}: {}", dir.display(), e)))?;
+    let built = env::current_exe()
+        .and_then(|exe| exe.metadata())
+        .and_then(|meta| meta.modified())
+        .ok();
+    let mut hasher = DefaultHasher::new();
+    (prelude::PRELUDE, &opts.opt_level, built).hash(&mut hasher);
+    Ok(dir.join(format!("prelude-{}-{:016x}.o", target, hasher.finish())))
+}
+
+//Run the passes between the typechecker and the code generator
+fn lower(unit: Unit) -> babel::Result<babel::monoir::Module> {
+    let module = passes!(vec![unit.module] =>
+        MatchCompile::new() =>
+        Specialize::new() =>
+        LambdaLift::new() =>
+        Simplify::new());
+    let mut module = module.into_iter().next().unwrap();
+    module.exports = unit.exports;
+    Ok(module)
+}
 
+fn module_name(path: &Path) -> babel::Result<String> {
+    match path.file_stem().and_then(|stem| stem.to_str()) {
+        Some(stem) => Ok(stem.to_string()),
+        None => Err(Error::new(format!("Invalid file name {}", path.display()))),
+    }
+}
+
+fn compile(sources: &mut SourceMap, opts: &Options) -> babel::Result<()> {
+    let target = match opts.target {
+        Some(ref target) => Triple::from_str(target)
+            .map_err(|e| Error::new(format!("Invalid target {}: {}", target, e)))?,
+        None => Triple::host(),
+    };
+
+    let mut loader = Loader {
+        sources,
+        opts,
+        loading: Vec::new(),
+        modules: Vec::new(),
+    };
+    loader.load()?;
+    let (modules, files): (Vec<_>, Vec<_>) = loader
+        .modules
+        .into_iter()
+        .map(|source| (source.module, (source.path, source.interface)))
+        .unzip();
+    //Outputs are named after the first input, or the module they are for
+    let first = opts.inputs.first().or_else(|| opts.objects.first());
+    let name = module_name(Path::new(first.unwrap()))?;
+    let path = |module: &str, emit: Emit| match module == name {
+        true => opts.output_path(&name, emit),
+        false => format!("{}.{}", module, emit.extension()),
+    };
+    let last = opts.last_stage();
+
+    dump(opts, &name, Emit::Ast, &modules)?;
+    if last == Emit::Ast {
+        return Ok(());
+    }
+    let mut rename = Rename::new();
+    let parsed = modules;
+    let modules = Vector::map(&parsed, |module| rename.conv_module(module))?;
+    dump(opts, &name, Emit::Idtree, &modules)?;
+    if last == Emit::Idtree {
+        return Ok(());
+    }
+    let mut typecheck = TypeChecker::new();
+    for module in &modules {
+        typecheck.add_classes(module);
+    }
+    let typed = Vector::map(&modules, |module| typecheck.tc_module(module))?;
+    dump(opts, &name, Emit::Xir, &typed)?;
+    if last == Emit::Xir {
+        return Ok(());
+    }
+
+    let codegen_options = |object_file: String| babel::codegen::Options {
+        target: target.clone(),
+        opt_level: opts.opt_level.clone(),
+        //Executables are position independent by default too
+        pic: true,
+        object_file: Some(object_file),
+        ..Default::default()
+    };
+    let mut units = Vec::new();
+    let mut object_files = Vec::new();
+    for (unit, (file, interface)) in Split::new(&rename).run(typed)?.into_iter().zip(&files) {
+        //Modules with only an interface are already compiled
+        if *interface {
+            let object_file = file.with_extension("o");
+            if !object_file.exists() {
+                let msg = format!(
+                    "Could not find {} for the interface {}",
+                    object_file.display(),
+                    file.display()
+                );
+                return Err(Error::new(msg));
+            }
+            object_files.push(object_file.to_string_lossy().to_string());
+            continue;
+        }
+        if unit.module.name() == prelude::NAME {
+            if last < Emit::Obj {
+                continue;
+            }
+            let cached = cached_prelude(opts, &target)?;
+            if !cached.exists() {
+                //Written under another name first so that a compiler running
+                //  at the same time never links a partial object
+                let partial = cached.with_extension(format!("{}.o", std::process::id()));
+                let options = codegen_options(partial.to_string_lossy().to_string());
+                CodeGen::with_options(options).run(vec![lower(unit)?])?;
+                std::fs::rename(&partial, &cached).map_err(|e| {
+                    Error::new(format!("Could not write {}: {}", cached.display(), e))
+                })?;
+            }
+            object_files.push(cached.to_string_lossy().to_string());
+            continue;
+        }
+        units.push(lower(unit)?);
+    }
+    dump(opts, &name, Emit::Monoir, &units)?;
+    if last == Emit::Monoir {
+        return Ok(());
+    }
+
+    //The objects of the modules compiled here, removed once linked
+    let mut temporaries = Vec::new();
+    for module in units {
+        let module_name = module.name.clone();
+        let codegen = CodeGen::with_options(babel::codegen::Options {
+            clif_file: opts
+                .emits(Emit::Clif)
+                .then(|| path(&module_name, Emit::Clif)),
+            emit_obj: last >= Emit::Obj,
+            ..codegen_options(path(&module_name, Emit::Obj))
+        });
+        let object_file = codegen.run(vec![module])?.remove(0);
+        //Objects compiled on their own come with an interface so that
+        //  other modules can be compiled against them
+        if last == Emit::Obj {
+            let i = modules
+                .iter()
+                .position(|m| *m.name() == module_name)
+                .unwrap();
+            let interface =
+                babel::interface::write(&parsed[i], &modules[i], sources, &rename, &typecheck)?;
+            let interface_file = Path::new(&object_file).with_extension("bsi");
+            std::fs::write(&interface_file, interface).map_err(|e| {
+                Error::new(format!(
+                    "Could not write {}: {}",
+                    interface_file.display(),
+                    e
+                ))
+            })?;
+        }
+        temporaries.push(object_file.clone());
+        object_files.push(object_file);
+    }
+    if last == Emit::Exe {
+        object_files.extend(opts.objects.iter().cloned());
+        let mut options = babel::link::Options {
+            kind: opts.kind,
+            libs: opts.libs.clone(),
+            lib_dirs: opts.lib_dirs.clone(),
+            ..Default::default()
+        };
+        if let Some(ref linker) = opts.linker {
+            options.linker = linker.clone();
+        }
+        let res = Link::with_options(opts.output_path(&name, Emit::Exe), options).run(object_files);
+        if !opts.keep_objects && !opts.emits(Emit::Obj) {
+            for file in temporaries {
+                let _ = std::fs::remove_file(file);
+            }
+        }
+        res?;
+    }
     Ok(())
 }
 
 fn main() {
-    if env::args().len() == 1 {
-        println!("No filename provided");
-        std::process::exit(2);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(|arg| arg.as_str()) == Some("repl") {
+        if let Err(e) = babel::repl::run() {
+            eprint!("{}", e.render(&SourceMap::new()));
+            std::process::exit(1);
+        }
+        return;
     }
 
-    let file_name = env::args().nth(1).unwrap();
-    let file_name = Path::new(&file_name);
-    let file = File::open(file_name).unwrap();
-
-    match compile(file, file_name) {
-        Ok(()) => (),
+    let opts = match Options::parse(args) {
+        Ok(opts) => opts,
         Err(e) => {
-            println!("ERROR: {}", e);
-            std::process::exit(1);
+            eprint!("{}", e.render(&SourceMap::new()));
+            eprint!("{}", USAGE);
+            std::process::exit(2);
         }
+    };
+    if opts.help {
+        print!("{}", USAGE);
+        return;
+    }
+
+    let mut sources = SourceMap::new();
+    let res = compile(&mut sources, &opts);
+    for warning in babel::take_warnings() {
+        eprint!("{}", warning.render(&sources));
+    }
+    if let Err(e) = res {
+        eprint!("{}", e.render(&sources));
+        std::process::exit(1);
     }
 }
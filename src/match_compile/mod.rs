@@ -0,0 +1,383 @@
+//Compilation of match expressions to decision trees
+//    "Compiling pattern matching to good decision trees" - Luc Maranget
+//
+//  Every match is checked for exhaustiveness and redundant arms first. The
+//  arms are then turned into a tree of Switch nodes that look at every part
+//  of the scrutinee at most once. Fields of constructors are read with
+//  Field and bound to new variables
+//       match l { Nil => 0, Cons(x, _) => x }
+//   becomes
+//       switch l {
+//         0 => 0,
+//         1 => let f1 = field(l, 1, 0) in let x = f1 in x
+//       }
+mod usefulness;
+
+use self::usefulness::{missing, useful, Con, Pat, Signature};
+use crate::span::Span;
+use crate::types::{Subst, TyCon, TyVar};
+use crate::xir::*;
+use crate::{fresh_id, warn, Error, Result, Vector};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+type Type = crate::types::Type<TyVar>;
+
+pub struct MatchCompile {}
+
+struct DataTypes(HashMap<Rc<String>, Rc<DataType>>);
+
+//The patterns left to test for an arm along with the variables they bound
+//  so far
+struct Row {
+    pats: Vec<Pattern>,
+    binds: Vec<(Symbol, Symbol)>,
+    arm: usize,
+}
+
+impl Default for MatchCompile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl crate::Pass for MatchCompile {
+    type Input = Vec<Module>;
+    type Output = Vec<Module>;
+
+    fn run(self, module_vec: Self::Input) -> Result<Self::Output> {
+        let mut datatypes = HashMap::new();
+        for module in &module_vec {
+            for decl in module.decls() {
+                if let Decl::Type(ref data) = *decl {
+                    datatypes.insert(data.name.clone(), data.clone());
+                }
+            }
+        }
+        let datatypes = DataTypes(datatypes);
+        let res = Vector::mapt(module_vec, |module| datatypes.compile_module(module))?;
+        Ok(res)
+    }
+}
+
+impl MatchCompile {
+    pub fn new() -> Self {
+        MatchCompile {}
+    }
+}
+
+impl Signature for DataTypes {
+    type Ty = Type;
+
+    fn ctors(&self, ty: &Type) -> Option<Vec<(Con, Vec<Type>)>> {
+        if let Type::Con(TyCon::Bool, _) = *ty {
+            return Some(vec![(Con::Bool(false), vec![]), (Con::Bool(true), vec![])]);
+        }
+        let (data, sub) = self.get(ty)?;
+        let ctors = data.ctors.iter().enumerate().map(|(tag, ctor)| {
+            let fields = Vector::fmap(ctor.args.iter(), |arg| sub.apply(arg));
+            (Con::Tag(tag as u32, ctor.name.clone()), fields)
+        });
+        Some(ctors.collect())
+    }
+}
+
+fn is_refutable(pat: &Pattern) -> bool {
+    !matches!(*pat, Pattern::Wildcard | Pattern::Var(_) | Pattern::UnitLit)
+}
+
+fn head(pat: &Pattern) -> Option<Con> {
+    match *pat {
+        Pattern::Ctor(ref ctor, tag, _) => Some(Con::Tag(tag, ctor.name().clone())),
+        Pattern::NumLit(n) => n.bits().map(Con::Int),
+        Pattern::BoolLit(b) => Some(Con::Bool(b)),
+        Pattern::Wildcard | Pattern::Var(_) | Pattern::UnitLit => None,
+    }
+}
+
+fn to_pat(pat: &Pattern) -> Pat {
+    match *pat {
+        Pattern::Ctor(_, _, ref args) => {
+            Pat::Con(head(pat).unwrap(), Vector::fmap(args.iter(), to_pat))
+        }
+        Pattern::NumLit(_) | Pattern::BoolLit(_) => Pat::Con(head(pat).unwrap(), vec![]),
+        Pattern::Wildcard | Pattern::Var(_) | Pattern::UnitLit => Pat::Wild,
+    }
+}
+
+impl DataTypes {
+    //The data type of `ty` and the substitution of its parameters
+    fn get(&self, ty: &Type) -> Option<(&DataType, Subst)> {
+        let (name, args) = match *ty {
+            Type::Con(TyCon::NewType(ref name), _) => (name, &[][..]),
+            Type::App(ref con, ref args) => match **con {
+                Type::Con(TyCon::NewType(ref name), _) => (name, &args[..]),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let data = self.0.get(name)?;
+        let mut sub = Subst::new();
+        for (param, arg) in data.params.iter().zip(args) {
+            sub.bind(param, arg.clone());
+        }
+        Some((data, sub))
+    }
+
+    fn compile_module(&self, module: Module) -> Result<Module> {
+        let modname = module.name().clone();
+        let decls = Vector::mapt(module.take_decls(), |decl| {
+            let decl = match decl {
+                Decl::Let(binds) => Decl::Let(Vector::map(&binds, |b| self.compile_bind(b))?),
+                Decl::Closure(env, bind) => Decl::Closure(env, self.compile_bind(&bind)?),
+                Decl::Instance(inst, binds) => {
+                    Decl::Instance(inst, Vector::map(&binds, |b| self.compile_bind(b))?)
+                }
+                decl @ (Decl::Extern(..) | Decl::Type(_) | Decl::Record(_) | Decl::Class(..)) => {
+                    decl
+                }
+            };
+            Ok(decl)
+        })?;
+        Ok(Module::new(modname, decls))
+    }
+
+    fn compile_bind(&self, bind: &Bind) -> Result<Bind> {
+        Ok(Bind::new(bind.symbol().clone(), self.compile(bind.expr())?))
+    }
+
+    fn compile(&self, expr: &Expr) -> Result<Expr> {
+        use self::ExprKind::*;
+        let span = expr.span();
+        let kind = match *expr.kind() {
+            ref e @ (UnitLit | NumLit(_) | BoolLit(_) | Var(_) | MkClosure(..) | Field(..)) => {
+                e.clone()
+            }
+            Lam(ref params, ref body, ref retty) => {
+                Lam(params.clone(), Box::new(self.compile(body)?), retty.clone())
+            }
+            App(ref ty, ref callee, ref args) => {
+                let callee = self.compile(callee)?;
+                let args = Vector::map(args, |arg| self.compile(arg))?;
+                App(ty.clone(), Box::new(callee), args)
+            }
+            TyLam(ref tyvars, ref body) => TyLam(tyvars.clone(), Box::new(self.compile(body)?)),
+            TyApp(ref expr, ref tys) => TyApp(Box::new(self.compile(expr)?), tys.clone()),
+            If(ref e) => If(Box::new(crate::xir::If::new(
+                self.compile(e.cond())?,
+                self.compile(e.texpr())?,
+                self.compile(e.fexpr())?,
+                e.ty().clone(),
+            ))),
+            Let(ref e) => {
+                let bind = self.compile_bind(e.bind())?;
+                Let(Box::new(crate::xir::Let::new(
+                    bind,
+                    self.compile(e.expr())?,
+                )))
+            }
+            LetRec(ref binds, ref e) => {
+                let binds = Vector::map(binds, |b| self.compile_bind(b))?;
+                LetRec(binds, Box::new(self.compile(e)?))
+            }
+            Seq(ref e1, ref e2) => Seq(Box::new(self.compile(e1)?), Box::new(self.compile(e2)?)),
+            Ctor(ref c) => {
+                let args = Vector::map(c.args(), |arg| self.compile(arg))?;
+                let ctor = crate::xir::Ctor::new(c.symbol().clone(), c.tag(), args, c.ty().clone());
+                Ctor(Box::new(ctor))
+            }
+            Switch(ref s) => {
+                let cases = Vector::map(s.cases(), |(k, e)| Ok((*k, self.compile(e)?)))?;
+                let default = match s.default() {
+                    Some(e) => Some(self.compile(e)?),
+                    None => None,
+                };
+                let switch =
+                    crate::xir::Switch::new(s.scrut().clone(), cases, default, s.ty().clone());
+                Switch(Box::new(switch))
+            }
+            Match(ref m) => return self.compile_match(m, span),
+            Record(ref ty, ref fields) => Record(ty.clone(), self.compile_fields(fields)?),
+            Project(ref ty, ref record, index) => {
+                Project(ty.clone(), Box::new(self.compile(record)?), index)
+            }
+            Update(ref ty, ref record, ref fields) => {
+                let record = Box::new(self.compile(record)?);
+                Update(ty.clone(), record, self.compile_fields(fields)?)
+            }
+        };
+        Ok(Expr::new(kind, span))
+    }
+
+    fn compile_fields(&self, fields: &[(u32, Expr)]) -> Result<Vec<(u32, Expr)>> {
+        Vector::map(fields, |(i, e)| Ok((*i, self.compile(e)?)))
+    }
+
+    fn compile_match(&self, m: &Match, span: Span) -> Result<Expr> {
+        let arms = Vector::map(m.arms(), |arm| self.compile(arm.expr()))?;
+        let tys = [m.scrut().ty().clone()];
+
+        let mut pats = Vec::with_capacity(arms.len());
+        for arm in m.arms() {
+            let pat = vec![to_pat(arm.pat())];
+            if useful(self, &pats, &pat, &tys).is_none() {
+                warn(Error::warning("unreachable pattern", arm.span()));
+            }
+            pats.push(pat);
+        }
+        if let Some(witness) = missing(self, &pats, &tys[0]) {
+            let msg = format!("non-exhaustive patterns: `{}` not covered", witness);
+            return Err(Error::spanned(msg, span));
+        }
+
+        let rows = m.arms().iter().enumerate().map(|(i, arm)| Row {
+            pats: vec![arm.pat().clone()],
+            binds: vec![],
+            arm: i,
+        });
+        let occs = [m.scrut().clone()];
+        self.decision_tree(&occs, rows.collect(), &arms, m.ty(), span)
+    }
+
+    //`occs` are the variables holding the values that the columns of
+    //  the rows are matched against
+    fn decision_tree(
+        &self,
+        occs: &[Symbol],
+        rows: Vec<Row>,
+        arms: &[Expr],
+        ty: &Type,
+        span: Span,
+    ) -> Result<Expr> {
+        let first = match rows.first() {
+            Some(first) => first,
+            None => return Err(Error::spanned("non-exhaustive patterns", span)),
+        };
+        let col = match first.pats.iter().position(is_refutable) {
+            Some(col) => col,
+            None => {
+                //The first arm matches
+                let mut binds = first.binds.clone();
+                for (pat, occ) in first.pats.iter().zip(occs) {
+                    if let Pattern::Var(ref var) = *pat {
+                        binds.push((var.clone(), occ.clone()));
+                    }
+                }
+                let mut expr = arms[first.arm].clone();
+                for (var, occ) in binds.into_iter().rev() {
+                    let occ = Expr::new(ExprKind::Var(occ), span);
+                    let let_exp = crate::xir::Let::new(Bind::new(var, occ), expr);
+                    expr = Expr::new(ExprKind::Let(Box::new(let_exp)), span);
+                }
+                return Ok(expr);
+            }
+        };
+
+        let occ = &occs[col];
+        let mut heads = Vec::new();
+        for row in &rows {
+            if let Some(con) = head(&row.pats[col]) {
+                if !heads.contains(&con) {
+                    heads.push(con);
+                }
+            }
+        }
+        let ctors = self.ctors(occ.ty());
+
+        let mut cases = Vec::with_capacity(heads.len());
+        for con in &heads {
+            let fields = ctors
+                .as_ref()
+                .and_then(|ctors| ctors.iter().find(|(c, _)| c == con))
+                .map(|(_, fields)| fields.clone())
+                .unwrap_or_default();
+            let fields = Vector::fmap(fields.into_iter(), |ty| {
+                Symbol::new(Rc::new("field".to_string()), ty, fresh_id())
+            });
+            let mut new_occs = occs[..col].to_vec();
+            new_occs.extend(fields.iter().cloned());
+            new_occs.extend(occs[col + 1..].iter().cloned());
+
+            let mut new_rows = Vec::new();
+            for row in &rows {
+                let pat = &row.pats[col];
+                let args = match *pat {
+                    Pattern::Ctor(_, _, ref args) if head(pat).as_ref() == Some(con) => {
+                        args.clone()
+                    }
+                    Pattern::NumLit(_) | Pattern::BoolLit(_) if head(pat).as_ref() == Some(con) => {
+                        vec![]
+                    }
+                    _ if is_refutable(pat) => continue,
+                    _ => vec![Pattern::Wildcard; fields.len()],
+                };
+                new_rows.push(specialize(row, col, args, occ));
+            }
+            let used = Vector::fmap(0..fields.len(), |i| {
+                let bound =
+                    |row: &Row| !matches!(row.pats[col + i], Pattern::Wildcard | Pattern::UnitLit);
+                new_rows.iter().any(bound)
+            });
+            let mut body = self.decision_tree(&new_occs, new_rows, arms, ty, span)?;
+
+            //Read the fields of the constructor
+            if let Con::Tag(tag, _) = *con {
+                for (i, field) in fields.into_iter().enumerate().rev() {
+                    if !used[i] {
+                        continue;
+                    }
+                    let expr = Expr::new(ExprKind::Field(occ.clone(), tag, i as u32), span);
+                    let let_exp = crate::xir::Let::new(Bind::new(field, expr), body);
+                    body = Expr::new(ExprKind::Let(Box::new(let_exp)), span);
+                }
+            }
+            let key = match *con {
+                Con::Tag(tag, _) => tag as i64,
+                Con::Int(n) => n,
+                Con::Bool(b) => b as i64,
+            };
+            cases.push((key, body));
+        }
+
+        let complete = match ctors {
+            Some(ref ctors) => ctors.len() == heads.len(),
+            None => false,
+        };
+        let default = match complete {
+            true => None,
+            false => {
+                let mut new_occs = occs.to_vec();
+                new_occs.remove(col);
+                let new_rows = rows
+                    .iter()
+                    .filter(|row| !is_refutable(&row.pats[col]))
+                    .map(|row| specialize(row, col, vec![], occ))
+                    .collect();
+                Some(self.decision_tree(&new_occs, new_rows, arms, ty, span)?)
+            }
+        };
+
+        //Nothing to test for types with a single constructor
+        if complete && cases.len() == 1 {
+            return Ok(cases.pop().unwrap().1);
+        }
+        let switch = crate::xir::Switch::new(occ.clone(), cases, default, ty.clone());
+        Ok(Expr::new(ExprKind::Switch(Box::new(switch)), span))
+    }
+}
+
+//Replace the pattern in column `col` of a row by `args`
+fn specialize(row: &Row, col: usize, args: Vec<Pattern>, occ: &Symbol) -> Row {
+    let mut binds = row.binds.clone();
+    if let Pattern::Var(ref var) = row.pats[col] {
+        binds.push((var.clone(), occ.clone()));
+    }
+    let mut pats = row.pats[..col].to_vec();
+    pats.extend(args);
+    pats.extend(row.pats[col + 1..].iter().cloned());
+    Row {
+        pats,
+        binds,
+        arm: row.arm,
+    }
+}
@@ -0,0 +1,258 @@
+//Exhaustiveness and redundancy checking of patterns
+//    "Warnings for pattern matching" - Luc Maranget
+//
+//  A row of patterns q is useful with respect to the rows P above it if
+//  there is a value matched by q and by none of P. An arm is redundant when
+//  its pattern is not useful and a match is exhaustive when the wildcard is
+//  not useful with respect to all its arms.
+use std::fmt;
+use std::rc::Rc;
+
+//The head of a pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Con {
+    //The tag and the name of a constructor
+    Tag(u32, Rc<String>),
+    Int(i64),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum Pat {
+    Wild,
+    Con(Con, Vec<Pat>),
+}
+
+pub(super) trait Signature {
+    type Ty;
+
+    //All the constructors of a type and the types of their fields. None
+    //   when they can not be listed e.g. for i32
+    fn ctors(&self, ty: &Self::Ty) -> Option<Vec<(Con, Vec<Self::Ty>)>>;
+}
+
+impl fmt::Display for Pat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Pat::Wild => write!(f, "_"),
+            Pat::Con(Con::Int(n), _) => write!(f, "{}", n),
+            Pat::Con(Con::Bool(b), _) => write!(f, "{}", b),
+            Pat::Con(Con::Tag(_, ref name), ref args) => {
+                write!(f, "{}", name)?;
+                if !args.is_empty() {
+                    let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                    write!(f, "({})", args.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+//Returns a value matched by `q` but by none of `rows` or None if there is
+//  no such value. `tys` are the types of the columns.
+pub(super) fn useful<S>(sig: &S, rows: &[Vec<Pat>], q: &[Pat], tys: &[S::Ty]) -> Option<Vec<Pat>>
+where
+    S: Signature,
+    S::Ty: Clone,
+{
+    let (head, rest) = match q.split_first() {
+        Some(split) => split,
+        None => return rows.is_empty().then(Vec::new),
+    };
+    let ctors = sig.ctors(&tys[0]);
+    let fields = |con: &Con| match ctors {
+        Some(ref ctors) => ctors
+            .iter()
+            .find(|(c, _)| c == con)
+            .map(|(_, fields)| fields.clone())
+            .unwrap_or_default(),
+        None => vec![],
+    };
+
+    match *head {
+        Pat::Con(ref con, ref args) => {
+            let q = args.iter().chain(rest).cloned().collect::<Vec<_>>();
+            useful_con(sig, rows, con, &q, &fields(con), tys)
+        }
+        Pat::Wild => {
+            let used = rows
+                .iter()
+                .filter_map(|row| match row[0] {
+                    Pat::Con(ref con, _) => Some(con),
+                    Pat::Wild => None,
+                })
+                .collect::<Vec<_>>();
+            match ctors {
+                Some(ref ctors) if ctors.iter().all(|(con, _)| used.contains(&con)) => {
+                    ctors.iter().find_map(|(con, fields)| {
+                        let wilds = fields.iter().map(|_| Pat::Wild);
+                        let q = wilds.chain(rest.iter().cloned()).collect::<Vec<_>>();
+                        useful_con(sig, rows, con, &q, fields, tys)
+                    })
+                }
+                _ => {
+                    let default = rows
+                        .iter()
+                        .filter(|row| matches!(row[0], Pat::Wild))
+                        .map(|row| row[1..].to_vec())
+                        .collect::<Vec<_>>();
+                    let mut witness = useful(sig, &default, rest, &tys[1..])?;
+                    //Use a constructor missing from the column if there is one
+                    let missing = ctors
+                        .as_ref()
+                        .and_then(|ctors| ctors.iter().find(|(con, _)| !used.contains(&con)));
+                    let head = match missing {
+                        Some((con, fields)) if !used.is_empty() => {
+                            Pat::Con(con.clone(), fields.iter().map(|_| Pat::Wild).collect())
+                        }
+                        _ => Pat::Wild,
+                    };
+                    witness.insert(0, head);
+                    Some(witness)
+                }
+            }
+        }
+    }
+}
+
+//A value of type `ty` matched by none of `rows`. Every constructor is
+//  missing from a match without arms, the first one is named then
+pub(super) fn missing<S>(sig: &S, rows: &[Vec<Pat>], ty: &S::Ty) -> Option<Pat>
+where
+    S: Signature,
+    S::Ty: Clone,
+{
+    let witness = useful(sig, rows, &[Pat::Wild], std::slice::from_ref(ty))?.remove(0);
+    let first = match sig.ctors(ty) {
+        Some(ctors) if rows.is_empty() => ctors.into_iter().next(),
+        _ => None,
+    };
+    match first {
+        Some((con, fields)) => Some(Pat::Con(con, fields.iter().map(|_| Pat::Wild).collect())),
+        None => Some(witness),
+    }
+}
+
+//Usefulness of `q`, whose first `fields.len()` patterns are the arguments
+//  of `con`, with respect to the rows that match `con`
+fn useful_con<S>(
+    sig: &S,
+    rows: &[Vec<Pat>],
+    con: &Con,
+    q: &[Pat],
+    fields: &[S::Ty],
+    tys: &[S::Ty],
+) -> Option<Vec<Pat>>
+where
+    S: Signature,
+    S::Ty: Clone,
+{
+    let arity = q.len() + 1 - tys.len();
+    let rows = rows
+        .iter()
+        .filter_map(|row| match row[0] {
+            Pat::Con(ref c, ref args) if c == con => {
+                Some(args.iter().chain(&row[1..]).cloned().collect::<Vec<_>>())
+            }
+            Pat::Con(..) => None,
+            Pat::Wild => {
+                let wilds = (0..arity).map(|_| Pat::Wild);
+                Some(wilds.chain(row[1..].iter().cloned()).collect())
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut field_tys = fields.to_vec();
+    field_tys.resize(arity, tys[0].clone());
+    field_tys.extend(tys[1..].iter().cloned());
+
+    let mut witness = useful(sig, &rows, q, &field_tys)?;
+    let rest = witness.split_off(arity);
+    let mut res = vec![Pat::Con(con.clone(), witness)];
+    res.extend(rest);
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //type List = Nil | Cons(bool, List)
+    #[derive(Clone)]
+    enum Ty {
+        Bool,
+        Int,
+        List,
+    }
+
+    struct Sig;
+
+    impl Signature for Sig {
+        type Ty = Ty;
+        fn ctors(&self, ty: &Ty) -> Option<Vec<(Con, Vec<Ty>)>> {
+            match *ty {
+                Ty::Bool => Some(vec![(Con::Bool(false), vec![]), (Con::Bool(true), vec![])]),
+                Ty::Int => None,
+                Ty::List => Some(vec![
+                    (nil(), vec![]),
+                    (
+                        Con::Tag(1, Rc::new("Cons".to_string())),
+                        vec![Ty::Bool, Ty::List],
+                    ),
+                ]),
+            }
+        }
+    }
+
+    fn nil() -> Con {
+        Con::Tag(0, Rc::new("Nil".to_string()))
+    }
+
+    fn cons(hd: Pat, tl: Pat) -> Pat {
+        Pat::Con(Con::Tag(1, Rc::new("Cons".to_string())), vec![hd, tl])
+    }
+
+    fn missing(rows: &[Pat], ty: Ty) -> Option<String> {
+        let rows = rows.iter().map(|p| vec![p.clone()]).collect::<Vec<_>>();
+        super::missing(&Sig, &rows, &ty).map(|w| w.to_string())
+    }
+
+    #[test]
+    fn exhaustive() {
+        let t = Pat::Con(Con::Bool(true), vec![]);
+        let f = Pat::Con(Con::Bool(false), vec![]);
+        let nil = Pat::Con(nil(), vec![]);
+        assert_eq!(missing(&[t.clone(), f.clone()], Ty::Bool), None);
+        assert_eq!(
+            missing(std::slice::from_ref(&t), Ty::Bool),
+            Some("false".to_string())
+        );
+        //An empty match names a constructor
+        assert_eq!(missing(&[], Ty::List), Some("Nil".to_string()));
+        assert_eq!(missing(&[], Ty::Int), Some("_".to_string()));
+        let rows = [nil.clone(), cons(t.clone(), Pat::Wild)];
+        assert_eq!(missing(&rows, Ty::List), Some("Cons(false, _)".to_string()));
+        let rows = [nil.clone(), cons(t, Pat::Wild), cons(Pat::Wild, nil)];
+        assert_eq!(
+            missing(&rows, Ty::List),
+            Some("Cons(false, Cons(_, _))".to_string())
+        );
+        let rows = [Pat::Con(Con::Int(1), vec![])];
+        assert_eq!(missing(&rows, Ty::Int), Some("_".to_string()));
+        assert_eq!(
+            missing(&[Pat::Con(Con::Int(1), vec![]), Pat::Wild], Ty::Int),
+            None
+        );
+        assert_eq!(missing(&[f, Pat::Wild], Ty::Bool), None);
+    }
+
+    #[test]
+    fn redundant() {
+        let t = Pat::Con(Con::Bool(true), vec![]);
+        let rows = vec![vec![cons(Pat::Wild, Pat::Wild)]];
+        let q = [cons(t, Pat::Wild)];
+        assert!(useful(&Sig, &rows, &q, &[Ty::List]).is_none());
+        let q = [Pat::Con(nil(), vec![])];
+        assert!(useful(&Sig, &rows, &q, &[Ty::List]).is_some());
+    }
+}
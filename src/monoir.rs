@@ -1,25 +1,62 @@
+use crate::span::Span;
+use crate::types::Num;
+use std::collections::HashSet;
 use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Type {
     Unit,
     Bool,
+    I8,
+    I16,
     I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
     Function {
         params_ty: Vec<Type>,
         return_ty: Box<Type>,
     },
+    //A tagged union, described by the entry of the same name in the types
+    //  of the module
+    Data(Rc<String>),
+    //A pointer to a struct described by the entry of the same name in the
+    //  records of the module
+    Record(Rc<String>),
+}
+
+//An instance of a data type. Values are pointers to a tag followed by the
+//  fields of the variant with that tag
+#[derive(Debug)]
+pub struct Union {
+    pub name: Rc<String>,
+    pub variants: Vec<Vec<Type>>,
+}
+
+//An instance of a record type. Fields are laid out as in a C struct
+#[derive(Debug)]
+pub struct Record {
+    pub name: Rc<String>,
+    pub fields: Vec<Type>,
 }
 
 #[derive(Debug)]
 pub struct Module {
     pub name: String,
-    pub types: Vec<Type>,
+    pub types: Vec<Union>,
+    pub records: Vec<Record>,
     pub funcs: Vec<Bind>,
-    pub ext_funcs: Vec<Symbol>,
+    //Externs and the spans of their names
+    pub ext_funcs: Vec<(Symbol, Span)>,
+    //Functions that can be called from other modules, besides main
+    pub exports: HashSet<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Symbol {
     pub name: Rc<String>,
     pub ty: Type,
@@ -29,7 +66,7 @@ pub struct Symbol {
 #[derive(Debug)]
 pub enum Literal {
     Unit,
-    I32(i32),
+    Num(Num),
     Bool(bool),
 }
 
@@ -43,6 +80,9 @@ pub struct Bind {
 pub struct Lam {
     pub params: Vec<Symbol>,
     pub body: Expr,
+    //The captured variables when this is the code of a closure. They are
+    //  loaded from the environment record passed as a hidden first argument
+    pub env: Option<Vec<Symbol>>,
 }
 
 #[derive(Debug)]
@@ -53,10 +93,20 @@ pub struct If {
     pub ty: Type,
 }
 
+//Branch on the tag of a data type or the value of an integer or bool. Runs
+//  `default` when none of the cases match
+#[derive(Debug)]
+pub struct Switch {
+    pub scrut: Symbol,
+    pub cases: Vec<(i64, Expr)>,
+    pub default: Option<Expr>,
+    pub ty: Type,
+}
+
 #[derive(Debug)]
 pub enum Expr {
     UnitLit,
-    I32Lit(i32),
+    NumLit(Num),
     BoolLit(bool),
     Lam(Box<Lam>),
     App(Type, Box<Expr>, Vec<Expr>),
@@ -64,6 +114,36 @@ pub enum Expr {
     If(Box<If>),
     //FIXME: introduce an Let struct to reduce number or allocations
     Let(Box<Bind>, Box<Expr>),
+    //Evaluate the first expression and throw its value away
+    Seq(Box<Expr>, Box<Expr>),
+    MkClosure(Symbol, Vec<Symbol>),
+    //Allocate a value of a data type with the given tag and fields
+    Ctor(Type, u32, Vec<Expr>),
+    Switch(Box<Switch>),
+    //Read a field of the variant with the given tag
+    Field(Symbol, u32, u32),
+    //Allocate a record initializing the fields in the given order
+    Record(Type, Vec<(u32, Expr)>),
+    //Read a field of a record
+    Project(Type, Box<Expr>, u32),
+}
+
+impl Type {
+    //The type of a number literal
+    pub fn num(n: &Num) -> Type {
+        match *n {
+            Num::I8(_) => Type::I8,
+            Num::I16(_) => Type::I16,
+            Num::I32(_) => Type::I32,
+            Num::I64(_) => Type::I64,
+            Num::U8(_) => Type::U8,
+            Num::U16(_) => Type::U16,
+            Num::U32(_) => Type::U32,
+            Num::U64(_) => Type::U64,
+            Num::F32(_) => Type::F32,
+            Num::F64(_) => Type::F64,
+        }
+    }
 }
 
 impl Module {
@@ -71,8 +151,10 @@ impl Module {
         Self {
             name,
             types: vec![],
+            records: vec![],
             funcs: vec![],
             ext_funcs: vec![],
+            exports: HashSet::new(),
         }
     }
 }
@@ -85,7 +167,19 @@ impl Bind {
 
 impl Lam {
     pub fn new(params: Vec<Symbol>, body: Expr) -> Self {
-        Lam { params, body }
+        Lam {
+            params,
+            body,
+            env: None,
+        }
+    }
+
+    pub fn closure(env: Vec<Symbol>, params: Vec<Symbol>, body: Expr) -> Self {
+        Lam {
+            params,
+            body,
+            env: Some(env),
+        }
     }
 }
 
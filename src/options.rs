@@ -0,0 +1,247 @@
+use crate::link::Kind;
+use crate::{Error, Result};
+
+pub const USAGE: &str = "\
+usage: babel [options] <file>...
+       babel repl
+
+Files ending in .o, .a or .so are linked with the program.
+
+options:
+    -o <path>              write the output to <path>
+    --emit=<kinds>         comma separated list of what to produce:
+                           ast, idtree, xir, monoir, clif, obj, exe
+    -c                     compile to an object file without linking
+    --target <triple>      generate code for <triple> instead of the host
+    --opt-level <level>    none, speed or speed_and_size (0, 1 or 2)
+    --no-prelude           do not include the prelude
+    -l <lib>               link with the library <lib>
+    -L <dir>               search <dir> for libraries
+    --linker <cmd>         link with <cmd> instead of $CC or cc
+    --lib <kind>           make a static or shared library instead of an
+                           executable
+    --keep-objects         keep the object files of the modules after linking
+    -h, --help             print this message
+";
+
+//The stages of the compiler whose output can be written out, in the order
+//  they are produced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Emit {
+    Ast,
+    Idtree,
+    Xir,
+    Monoir,
+    Clif,
+    Obj,
+    Exe,
+}
+
+impl Emit {
+    fn parse(kind: &str) -> Result<Emit> {
+        let emit = match kind {
+            "ast" => Emit::Ast,
+            "idtree" => Emit::Idtree,
+            "xir" => Emit::Xir,
+            "monoir" => Emit::Monoir,
+            "clif" => Emit::Clif,
+            "obj" => Emit::Obj,
+            "exe" => Emit::Exe,
+            _ => return Err(Error::new(format!("Unknown kind of output {}", kind))),
+        };
+        Ok(emit)
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Emit::Ast => "ast",
+            Emit::Idtree => "idtree",
+            Emit::Xir => "xir",
+            Emit::Monoir => "monoir",
+            Emit::Clif => "clif",
+            Emit::Obj => "o",
+            Emit::Exe => "",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub inputs: Vec<String>,
+    //Objects and libraries given as inputs
+    pub objects: Vec<String>,
+    pub output: Option<String>,
+    pub emit: Vec<Emit>,
+    pub target: Option<String>,
+    pub opt_level: String,
+    pub prelude: bool,
+    pub libs: Vec<String>,
+    pub lib_dirs: Vec<String>,
+    pub linker: Option<String>,
+    pub kind: Kind,
+    pub keep_objects: bool,
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            inputs: Vec::new(),
+            objects: Vec::new(),
+            output: None,
+            emit: Vec::new(),
+            target: None,
+            opt_level: "speed_and_size".to_string(),
+            prelude: true,
+            libs: Vec::new(),
+            lib_dirs: Vec::new(),
+            linker: None,
+            kind: Kind::Executable,
+            keep_objects: false,
+            help: false,
+        }
+    }
+}
+
+impl Options {
+    //Options with a value accept both `--opt value` and `--opt=value`, and
+    //  `-l`/`-L` also `-lvalue`
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options> {
+        let mut opts = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, mut inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+                _ if arg.len() > 2 && (arg.starts_with("-l") || arg.starts_with("-L")) => {
+                    (&arg[..2], Some(arg[2..].to_string()))
+                }
+                _ => (arg.as_str(), None),
+            };
+            let mut value = |args: &mut dyn Iterator<Item = String>| match inline.take() {
+                Some(value) => Ok(value),
+                None => args
+                    .next()
+                    .ok_or_else(|| Error::new(format!("Missing value for {}", name))),
+            };
+            match name {
+                "-o" => opts.output = Some(value(&mut args)?),
+                "--emit" => {
+                    for kind in value(&mut args)?.split(',') {
+                        opts.emit.push(Emit::parse(kind.trim())?);
+                    }
+                }
+                "-c" => opts.emit.push(Emit::Obj),
+                "--target" => opts.target = Some(value(&mut args)?),
+                "--opt-level" => {
+                    opts.opt_level = match value(&mut args)?.as_str() {
+                        "0" | "none" => "none",
+                        "1" | "speed" => "speed",
+                        "2" | "speed_and_size" => "speed_and_size",
+                        level => return Err(Error::new(format!("Unknown opt level {}", level))),
+                    }
+                    .to_string()
+                }
+                "--no-prelude" => opts.prelude = false,
+                "-l" => opts.libs.push(value(&mut args)?),
+                "-L" => opts.lib_dirs.push(value(&mut args)?),
+                "--linker" => opts.linker = Some(value(&mut args)?),
+                "--lib" => {
+                    opts.kind = match value(&mut args)?.as_str() {
+                        "static" => Kind::StaticLib,
+                        "shared" => Kind::SharedLib,
+                        kind => {
+                            return Err(Error::new(format!("Unknown kind of library {}", kind)))
+                        }
+                    }
+                }
+                "--keep-objects" => opts.keep_objects = true,
+                "-h" | "--help" => opts.help = true,
+                _ if name.starts_with('-') => {
+                    return Err(Error::new(format!("Unknown option {}", arg)))
+                }
+                _ if [".o", ".a", ".so"].iter().any(|ext| name.ends_with(ext)) => {
+                    opts.objects.push(arg.clone())
+                }
+                _ => opts.inputs.push(arg.clone()),
+            }
+        }
+        if opts.emit.is_empty() {
+            opts.emit.push(Emit::Exe);
+        }
+        opts.emit.sort();
+        opts.emit.dedup();
+        if opts.inputs.is_empty() && opts.objects.is_empty() && !opts.help {
+            return Err(Error::new("No input files"));
+        }
+        Ok(opts)
+    }
+
+    //The compiler stops once it has produced this
+    pub fn last_stage(&self) -> Emit {
+        *self.emit.last().unwrap()
+    }
+
+    pub fn emits(&self, emit: Emit) -> bool {
+        self.emit.contains(&emit)
+    }
+
+    //Where the output of a stage is written. `-o` applies to the output of
+    //  the last stage, the others are named after the first input
+    pub fn output_path(&self, name: &str, emit: Emit) -> String {
+        match self.output {
+            Some(ref output) if emit == self.last_stage() => output.clone(),
+            _ if emit == Emit::Exe => match self.kind {
+                Kind::Executable => name.to_string(),
+                Kind::StaticLib => format!("lib{}.a", name),
+                Kind::SharedLib => format!("lib{}.so", name),
+            },
+            _ => format!("{}.{}", name, emit.extension()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options> {
+        Options::parse(args.split_whitespace().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn emit() {
+        let opts = parse("a.bs --emit=obj,xir -o out").unwrap();
+        assert_eq!(opts.emit, vec![Emit::Xir, Emit::Obj]);
+        assert_eq!(opts.last_stage(), Emit::Obj);
+        assert_eq!(opts.output_path("a", Emit::Xir), "a.xir");
+        assert_eq!(opts.output_path("a", Emit::Obj), "out");
+
+        let opts = parse("-c a.bs b.bs -o out.o --opt-level 0").unwrap();
+        assert_eq!(opts.inputs, vec!["a.bs", "b.bs"]);
+        assert_eq!(opts.output_path("a", Emit::Obj), "out.o");
+        assert_eq!(opts.opt_level, "none");
+
+        let opts = parse("a.bs").unwrap();
+        assert_eq!(opts.output_path("a", Emit::Exe), "a");
+    }
+
+    #[test]
+    fn link() {
+        let opts = parse("a.bs b.o libc.a -lm -L /opt/lib --lib=shared").unwrap();
+        assert_eq!(opts.inputs, vec!["a.bs"]);
+        assert_eq!(opts.objects, vec!["b.o", "libc.a"]);
+        assert_eq!(opts.libs, vec!["m"]);
+        assert_eq!(opts.lib_dirs, vec!["/opt/lib"]);
+        assert_eq!(opts.output_path("a", Emit::Exe), "liba.so");
+        assert!(parse("a.bs --lib=dynamic").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("").is_err());
+        assert!(parse("-lm").is_err());
+        assert!(parse("a.bs --emit=foo").is_err());
+        assert!(parse("a.bs --target").is_err());
+        assert!(parse("a.bs --frobnicate").is_err());
+    }
+}
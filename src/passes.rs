@@ -1,9 +1,11 @@
 pub use crate::codegen::CodeGen;
 pub use crate::lambda_lift::LambdaLift;
 pub use crate::link::Link;
+pub use crate::match_compile::MatchCompile;
 pub use crate::rename::Rename;
 pub use crate::simplify::Simplify;
 pub use crate::specialize::Specialize;
+pub use crate::split::Split;
 pub use crate::typecheck::TypeChecker;
 
 #[macro_export]
@@ -1 +1,3 @@
+//The module imported by every module that does not import it explicitly
+pub const NAME: &str = "prelude";
 pub const PRELUDE: &str = include_str!("prelude.bs");
@@ -2,16 +2,19 @@ use crate::ast;
 use crate::fresh_id;
 use crate::idtree;
 use crate::scoped_map::ScopedMap;
-use crate::types::TyVar;
+use crate::span::Span;
+use crate::types::{Kind, TyCon, TyVar};
 use crate::utils::{Graph, SCC};
 use crate::{Error, Result, Vector};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 type Type = crate::types::Type<crate::types::TyVar>;
 
+#[derive(Clone)]
 struct TopLevelFunc(u32);
 
+#[derive(Clone)]
 pub struct Rename {
     names: ScopedMap<String, idtree::Symbol>,
     //Store uniq names across all scopes to reduce memory.
@@ -19,6 +22,62 @@ pub struct Rename {
     uniq_names: HashMap<String, Rc<String>>,
     call_ref_graph: Graph<u32, idtree::Symbol>,
     top_level_funcs: HashMap<u32, TopLevelFunc>,
+    //Data types and their number of parameters
+    types: HashMap<String, (Rc<String>, usize)>,
+    //Parameters of the data type being declared
+    tyvars: HashMap<String, TyVar>,
+    //Constructors and their tag and number of arguments
+    ctors: HashMap<u32, (u32, usize)>,
+    //Field names and the records declaring them, in the order they were
+    //  declared
+    labels: HashMap<String, Vec<Rc<idtree::Record>>>,
+    classes: HashMap<String, Rc<idtree::Class>>,
+    //The classes and type constructors with an instance
+    instances: HashSet<(Rc<String>, TyCon)>,
+    //Every variable referred to in the current top level declaration, used
+    //  to find the dependencies between the functions of a let rec
+    uses: Vec<u32>,
+    //The public declarations and the imports of the modules renamed so far
+    exports: HashMap<String, Exports>,
+    imports: HashMap<String, Vec<ast::Import>>,
+    //The module each imported name of the current module comes from
+    imported: HashMap<String, String>,
+    //Data types and classes are referred to by name after renaming, so their
+    //  names are unique across modules
+    tycons: HashSet<String>,
+    class_names: HashSet<String>,
+    //The operators in scope and the functions they call
+    fixities: HashMap<String, Fixity>,
+    //The calls marked @tailcall in the current top level declaration that
+    //  have not been checked yet
+    tail_calls: Vec<Span>,
+    //The functions of the module called in tail position by each function
+    //  and the marked calls, with the caller and the function called
+    tail_callees: HashMap<u32, Vec<u32>>,
+    marked: Vec<(Span, idtree::Symbol, Option<u32>)>,
+    //The externs that are functions of a module, and that module
+    linked: HashMap<u32, String>,
+}
+
+//The boolean operators and their precedence. They are not functions since
+//  their right operand is only evaluated when it decides the result
+const SHORT_CIRCUIT: [(&str, u32); 2] = [("&&", 3), ("||", 2)];
+
+#[derive(Clone)]
+pub(crate) struct Fixity {
+    pub(crate) assoc: ast::Assoc,
+    pub(crate) prec: u32,
+    pub(crate) func: idtree::Symbol,
+}
+
+//What a module makes visible to the modules that import it
+#[derive(Clone, Default)]
+pub struct Exports {
+    pub(crate) names: HashMap<String, idtree::Symbol>,
+    pub(crate) types: HashMap<String, (Rc<String>, usize)>,
+    pub(crate) labels: HashMap<String, Vec<Rc<idtree::Record>>>,
+    pub(crate) classes: HashMap<String, Rc<idtree::Class>>,
+    pub(crate) fixities: HashMap<String, Fixity>,
 }
 
 impl crate::Pass for Rename {
@@ -26,10 +85,7 @@ impl crate::Pass for Rename {
     type Output = Vec<idtree::Module>;
 
     fn run(mut self, mod_vec: Self::Input) -> Result<Self::Output> {
-        let result = Vector::map(&mod_vec, |module| self.conv_module(module));
-        let _sorted = SCC::run(&self.call_ref_graph);
-        //println!("{:#?}", sorted);
-        result
+        Vector::map(&mod_vec, |module| self.conv_module(module))
     }
 }
 
@@ -46,34 +102,92 @@ impl Rename {
             uniq_names: HashMap::new(),
             call_ref_graph: Graph::new(),
             top_level_funcs: HashMap::new(),
+            types: HashMap::new(),
+            tyvars: HashMap::new(),
+            ctors: HashMap::new(),
+            labels: HashMap::new(),
+            classes: HashMap::new(),
+            instances: HashSet::new(),
+            uses: Vec::new(),
+            exports: HashMap::new(),
+            imports: HashMap::new(),
+            imported: HashMap::new(),
+            tycons: HashSet::new(),
+            class_names: HashSet::new(),
+            fixities: HashMap::new(),
+            tail_calls: Vec::new(),
+            tail_callees: HashMap::new(),
+            marked: Vec::new(),
+            linked: HashMap::new(),
         }
     }
 
+    pub fn exports(&self, module: &str) -> Option<&Exports> {
+        self.exports.get(module)
+    }
+
+    //The module defining a function declared as an extern of it
+    pub fn linked_module(&self, id: u32) -> Option<&str> {
+        self.linked.get(&id).map(|module| module.as_str())
+    }
+
+    pub fn imports(&self, module: &str) -> &[ast::Import] {
+        self.imports
+            .get(module)
+            .map_or(&[], |imports| imports.as_slice())
+    }
+
     fn conv_ty(&mut self, ty: &ast::Type) -> Result<Type> {
         use crate::types::Type::*;
         let ty = match *ty {
             Var(ref _v) => self.new_tyvar(),
-            Con(ref tycon, ref kind) => {
-                use crate::types::TyCon::*;
-                let tycon = match tycon {
-                    I32 => I32,
-                    Bool => Bool,
-                    Unit => Unit,
-                    Func => Func,
-                    NewType(nm) => NewType(self.mk_tycon(nm)),
-                    _ => unimplemented!(),
-                };
-                Type::Con(tycon, kind.clone())
-            }
+            Con(TyCon::NewType(ref nm), _) => match self.tyvars.get(nm.as_str()) {
+                Some(tv) => Type::Var(tv.clone()),
+                None => self.conv_tycon(nm, 0)?,
+            },
+            Con(ref tycon, ref kind) => Type::Con(tycon.clone(), kind.clone()),
             App(ref con, ref args) => {
-                let con = self.conv_ty(con)?;
                 let args = Vector::map(args, |arg| self.conv_ty(arg))?;
+                let con = match **con {
+                    Con(TyCon::NewType(ref nm), _) if self.tyvars.contains_key(nm.as_str()) => {
+                        let msg = format!("Type parameter {} can not be applied to types", nm);
+                        return Err(Error::new(msg));
+                    }
+                    Con(TyCon::NewType(ref nm), _) => self.conv_tycon(nm, args.len())?,
+                    Con(TyCon::Func, _) => self.conv_ty(con)?,
+                    //The other built in types take no arguments
+                    Con(ref tycon, _) => {
+                        let msg = format!(
+                            "Type {:?} expects 0 argument(s), found {}",
+                            tycon,
+                            args.len()
+                        );
+                        return Err(Error::new(msg));
+                    }
+                    ref con => self.conv_ty(con)?,
+                };
                 Type::App(Box::new(con), args)
             }
         };
         Ok(ty)
     }
 
+    //Kind check a use of a data type. Type parameters all have kind * so
+    //  a data type has to be applied to as many types as it has parameters
+    fn conv_tycon(&mut self, nm: &str, nargs: usize) -> Result<Type> {
+        match self.types.get(nm) {
+            Some((name, arity)) if *arity == nargs => {
+                let tycon = TyCon::NewType(name.clone());
+                Ok(Type::Con(tycon, Kind::nary(nargs)))
+            }
+            Some((_, arity)) => {
+                let msg = format!("Type {} expects {} argument(s), found {}", nm, arity, nargs);
+                Err(Error::new(msg))
+            }
+            None => Err(Error::new(format!("Could not find type {}", nm))),
+        }
+    }
+
     fn add_uniq_name(&mut self, nm: &str) -> Rc<String> {
         self.uniq_names
             .entry(nm.to_string())
@@ -87,25 +201,236 @@ impl Rename {
 
     fn add_top_level(&mut self, sym: &idtree::Symbol) -> TopLevelFunc {
         let vertex_key = self.call_ref_graph.add_vertex(sym.clone());
+        self.top_level_funcs
+            .insert(sym.id(), TopLevelFunc(vertex_key));
         TopLevelFunc(vertex_key)
     }
 
-    fn add_sym(&mut self, nm: &str, ty: Type) -> Result<idtree::Symbol> {
+    fn add_sym(&mut self, nm: &str, ty: Type, span: Span) -> Result<idtree::Symbol> {
         let var_name = self.add_uniq_name(nm);
         let sym = idtree::Symbol::new(var_name, ty, fresh_id());
-        if self.names.insert(nm.to_string(), sym.clone()).is_some() {
-            //Allow duplicates at everywhere except the top level
-            if self.names.scope() == 0 {
-                let msg = Error::new(format!("Name {} already declared", nm));
-                return Err(msg);
-            }
+        //Allow duplicates at everywhere except the top level
+        if self.names.insert(nm.to_string(), sym.clone()).is_some() && self.names.scope() == 0 {
+            let msg = match self.imported.get(nm) {
+                Some(module) => format!(
+                    "{} is imported from {}, use `import {} hiding ({});` to declare it",
+                    nm, module, module, nm
+                ),
+                None => format!("Name {} already declared", nm),
+            };
+            return Err(Error::spanned(msg, span));
         }
         Ok(sym)
     }
 
-    fn conv_module(&mut self, module: &ast::Module) -> Result<idtree::Module> {
-        let decls = Vector::map(&module.decls, |decl| self.conv_decl(decl));
-        Ok(idtree::Module::new(module.name.clone(), decls?))
+    //Only the names declared in a module and the public names of the modules
+    //  it imports are in scope. Imported modules are renamed first
+    pub fn conv_module(&mut self, module: &ast::Module) -> Result<idtree::Module> {
+        self.names = ScopedMap::new();
+        self.types.clear();
+        self.labels.clear();
+        self.classes.clear();
+        self.fixities.clear();
+        self.imported.clear();
+        self.tail_callees.clear();
+        self.marked.clear();
+        for import in &module.imports {
+            self.import(import)?;
+        }
+        self.imports
+            .insert(module.name.clone(), module.imports.clone());
+
+        //Data types and records can be used before they are declared
+        for decl in &module.decls {
+            let (name, nparams, span) = match *decl.inner() {
+                ast::Decl::Type(ref data) => (&data.name, data.params.len(), data.span),
+                ast::Decl::Record(ref rec) => (&rec.name, rec.params.len(), rec.span),
+                _ => continue,
+            };
+            if !self.tycons.insert(name.clone()) {
+                let msg = format!("Type {} already declared", name);
+                return Err(Error::spanned(msg, span));
+            }
+            let entry = (self.mk_tycon(name), nparams);
+            self.types.insert(name.clone(), entry);
+        }
+        //Functions, externs and classes can be used before they are declared
+        for decl in &module.decls {
+            match *decl.inner() {
+                ast::Decl::Func(ast::Bind(ref name, _, span)) => {
+                    let ty = self.new_tyvar();
+                    let sym = self.add_sym(name, ty, span)?;
+                    self.add_top_level(&sym);
+                }
+                ast::Decl::Extern(ref name, ref ty, ref module, span) => {
+                    let ty = self.conv_ty(ty)?;
+                    let sym = self.add_sym(name, ty, span)?;
+                    self.add_top_level(&sym);
+                    if let Some(module) = module {
+                        self.linked.insert(sym.id(), module.clone());
+                    }
+                }
+                ast::Decl::Class(ref class) => self.conv_class(class)?,
+                _ => {}
+            }
+        }
+        //And so can operators, which call them
+        for decl in &module.decls {
+            if let ast::Decl::Fixity(ref fixity) = *decl.inner() {
+                self.add_fixity(fixity)?;
+            }
+        }
+        let mut decls = Vec::with_capacity(module.decls.len());
+        for decl in &module.decls {
+            decls.extend(self.conv_decl(decl)?);
+        }
+        self.check_marked_calls()?;
+        self.export(module);
+        Ok(idtree::Module::new(
+            module.name.clone(),
+            self.group_funcs(decls),
+        ))
+    }
+
+    //Hidden names can be declared by the importing module
+    fn import(&mut self, import: &ast::Import) -> Result<()> {
+        let (module, span) = (&import.name, import.span);
+        let exports = match self.exports.get(module) {
+            Some(exports) => exports.clone(),
+            None => {
+                let msg = format!("Could not find module {}", module);
+                return Err(Error::spanned(msg, span));
+            }
+        };
+        for name in &import.hiding {
+            if !exports.names.contains_key(name)
+                && !exports.types.contains_key(name)
+                && !exports.classes.contains_key(name)
+                && !exports.fixities.contains_key(name)
+            {
+                let msg = format!("Module {} does not declare {}", module, name);
+                return Err(Error::spanned(msg, span));
+            }
+        }
+        let visible = |name: &String| !import.hiding.contains(name);
+        for (name, sym) in exports.names.into_iter().filter(|(name, _)| visible(name)) {
+            if let Some(prev) = self.names.get(&name) {
+                if prev.id() != sym.id() {
+                    let msg = format!("{} is declared by more than one imported module", name);
+                    return Err(Error::spanned(msg, span));
+                }
+            }
+            self.imported.insert(name.clone(), module.clone());
+            self.names.insert(name, sym);
+        }
+        let types = exports.types.into_iter();
+        self.types.extend(types.filter(|(name, _)| visible(name)));
+        for (field, recs) in exports.labels {
+            let declared = self.labels.entry(field).or_default();
+            for rec in recs {
+                if !declared.iter().any(|r| r.name == rec.name) {
+                    declared.push(rec);
+                }
+            }
+        }
+        let classes = exports.classes.into_iter();
+        self.classes
+            .extend(classes.filter(|(name, _)| visible(name)));
+        for (op, fixity) in exports.fixities.into_iter().filter(|(op, _)| visible(op)) {
+            if let Some(prev) = self.fixities.get(&op) {
+                if prev.func.id() != fixity.func.id() {
+                    let msg = format!(
+                        "Operator {} is declared by more than one imported module",
+                        op
+                    );
+                    return Err(Error::spanned(msg, span));
+                }
+            }
+            self.imported.insert(op.clone(), module.clone());
+            self.fixities.insert(op, fixity);
+        }
+        Ok(())
+    }
+
+    //Add the public declarations of a module to its exports. The repl
+    //  renames a module several times, adding to the same exports
+    fn export(&mut self, module: &ast::Module) {
+        use crate::ast::Decl::*;
+        let mut exports = self.exports.remove(&module.name).unwrap_or_default();
+        for decl in &module.decls {
+            let decl = match *decl {
+                Pub(ref decl) => &**decl,
+                _ => continue,
+            };
+            let names = match *decl {
+                Extern(ref name, ..) | Func(ast::Bind(ref name, ..)) => vec![name],
+                Type(ref data) => {
+                    let entry = self.types[&data.name].clone();
+                    exports.types.insert(data.name.clone(), entry);
+                    Vector::fmap(data.ctors.iter(), |ctor| &ctor.name)
+                }
+                Record(ref rec) => {
+                    let entry = self.types[&rec.name].clone();
+                    exports.types.insert(rec.name.clone(), entry);
+                    for (field, _) in &rec.fields {
+                        let recs = &self.labels[field];
+                        let decl = recs.iter().find(|r| *r.name == rec.name).unwrap();
+                        let recs = exports.labels.entry(field.clone()).or_default();
+                        recs.push(decl.clone());
+                    }
+                    vec![]
+                }
+                Class(ref class) => {
+                    let entry = self.classes[&class.name].clone();
+                    exports.classes.insert(class.name.clone(), entry);
+                    Vector::fmap(class.methods.iter(), |(name, _)| name)
+                }
+                Fixity(ref fixity) => {
+                    let entry = self.fixities[&fixity.op].clone();
+                    exports.fixities.insert(fixity.op.clone(), entry);
+                    vec![]
+                }
+                //Instances are visible everywhere
+                Instance(_) | Pub(_) => vec![],
+            };
+            for name in names {
+                let sym = self.names.get(name).cloned().unwrap();
+                exports.names.insert(name.clone(), sym);
+            }
+        }
+        self.exports.insert(module.name.clone(), exports);
+    }
+
+    //Top level functions are grouped into the strongly connected components
+    //  of the call graph, callees first, so that mutually recursive functions
+    //  are typechecked together. Instance methods can call any function and
+    //  so instances go after all functions
+    fn group_funcs(&mut self, decls: Vec<idtree::Decl>) -> Vec<idtree::Decl> {
+        let mut res = Vec::with_capacity(decls.len());
+        let mut funcs = HashMap::new();
+        let mut instances = Vec::new();
+        for decl in decls {
+            match decl {
+                idtree::Decl::Let(binds) => {
+                    for bind in binds {
+                        funcs.insert(bind.symbol().id(), bind);
+                    }
+                }
+                decl @ idtree::Decl::Instance(..) => instances.push(decl),
+                decl => res.push(decl),
+            }
+        }
+        let graph = std::mem::replace(&mut self.call_ref_graph, Graph::new());
+        self.top_level_funcs.clear();
+        for scc in SCC::run(&graph) {
+            let mut binds: Vec<_> = scc.iter().filter_map(|f| funcs.remove(&f.id())).collect();
+            if !binds.is_empty() {
+                binds.sort_by_key(|bind| bind.symbol().id());
+                res.push(idtree::Decl::Let(binds));
+            }
+        }
+        res.extend(instances);
+        res
     }
 
     fn new_tyvar(&self) -> Type {
@@ -113,85 +438,1095 @@ impl Rename {
         Type::Var(TyVar::fresh(level))
     }
 
-    fn conv_decl(&mut self, decl: &ast::Decl) -> Result<idtree::Decl> {
+    //Fixity declarations only add to the scope of the module
+    fn conv_decl(&mut self, decl: &ast::Decl) -> Result<Option<idtree::Decl>> {
         use crate::ast::Decl::*;
+        self.uses.clear();
+        self.tail_calls.clear();
         let res = match *decl {
-            Extern(ref name, ref ty) => {
-                let ty = self.conv_ty(ty)?;
-                let funcid = self.add_sym(name, ty)?;
-                self.add_top_level(&funcid);
-                idtree::Decl::Extern(funcid)
+            Extern(ref name, _, _, span) => {
+                idtree::Decl::Extern(self.names.get(name).cloned().unwrap(), span)
             }
             Func(ref bind) => {
-                let ast::Bind(ref name, ref expr) = *bind;
-                let ty = self.new_tyvar();
-                let sym = self.add_sym(name, ty)?;
-                self.add_top_level(&sym);
+                let ast::Bind(ref name, ref expr, _) = *bind;
+                let sym = self.names.get(name).cloned().unwrap();
                 let expr = self.conv(expr, &sym)?;
+                self.check_tail_calls(&sym, &expr, 0)?;
                 let bind = idtree::Bind::new(sym, expr);
                 idtree::Decl::Let(vec![bind])
             }
+            Type(ref data) => {
+                let (data, ctors) = self.conv_data(data)?;
+                idtree::Decl::Type(data, ctors)
+            }
+            Record(ref rec) => idtree::Decl::Record(self.conv_record(rec)?),
+            Class(ref class) => {
+                let methods = class.methods.iter();
+                let methods = methods.map(|(name, _)| self.names.get(name).cloned().unwrap());
+                idtree::Decl::Class(self.classes[&class.name].clone(), methods.collect())
+            }
+            Instance(ref inst) => {
+                let (inst, methods) = self.conv_instance(inst)?;
+                idtree::Decl::Instance(inst, methods)
+            }
+            Fixity(_) => return Ok(None),
+            Pub(ref decl) => return self.conv_decl(decl),
+        };
+        Ok(Some(res))
+    }
+
+    //The function is looked up where the operator is declared so local
+    //  variables can not change what an operator means
+    fn add_fixity(&mut self, decl: &ast::FixityDecl) -> Result<()> {
+        if SHORT_CIRCUIT.iter().any(|(op, _)| *op == decl.op) {
+            let msg = format!("Operator {} is built in and can not be declared", decl.op);
+            return Err(Error::spanned(msg, decl.span));
+        }
+        let func = match self.names.get(&decl.func) {
+            Some(sym) => sym.clone(),
+            None => {
+                let msg = format!("Could not find variable {}", decl.func);
+                return Err(Error::spanned(msg, decl.span));
+            }
+        };
+        let fixity = Fixity {
+            assoc: decl.assoc,
+            prec: decl.prec,
+            func,
+        };
+        if self.fixities.insert(decl.op.clone(), fixity).is_some() {
+            let op = &decl.op;
+            let msg = match self.imported.get(op) {
+                Some(module) => format!(
+                    "{} is imported from {}, use `import {} hiding (({}));` to declare it",
+                    op, module, module, op
+                ),
+                None => format!("Operator {} already declared", op),
+            };
+            return Err(Error::spanned(msg, decl.span));
+        }
+        Ok(())
+    }
+
+    fn conv_params(&mut self, names: &[String], span: Span) -> Result<Vec<TyVar>> {
+        let mut params = Vec::with_capacity(names.len());
+        for param in names {
+            let tv = TyVar::fresh(0);
+            if self.tyvars.insert(param.clone(), tv.clone()).is_some() {
+                let msg = format!("Type parameter {} declared twice", param);
+                return Err(Error::spanned(msg, span));
+            }
+            params.push(tv);
+        }
+        Ok(params)
+    }
+
+    fn conv_data(
+        &mut self,
+        data: &ast::TypeDecl,
+    ) -> Result<(Rc<idtree::DataType>, Vec<idtree::Symbol>)> {
+        let params = self.conv_params(&data.params, data.span)?;
+        let name = self.mk_tycon(&data.name);
+        let tycon = Type::Con(TyCon::NewType(name.clone()), Kind::nary(params.len()));
+        let ty = match params.len() {
+            0 => tycon,
+            _ => Type::App(
+                Box::new(tycon),
+                Vector::fmap(params.iter().cloned(), Type::Var),
+            ),
+        };
+
+        let mut ctors = Vec::with_capacity(data.ctors.len());
+        let mut symbols = Vec::with_capacity(data.ctors.len());
+        for (tag, ctor) in data.ctors.iter().enumerate() {
+            let args = Vector::map(&ctor.args, |arg| self.conv_ty(arg));
+            let args = args.map_err(|e| e.at(ctor.span))?;
+            let ctor_ty = match args.len() {
+                0 => ty.clone(),
+                _ => Type::func(args.clone(), ty.clone()),
+            };
+            let sym = self.add_sym(&ctor.name, ctor_ty, ctor.span)?;
+            self.ctors.insert(sym.id(), (tag as u32, args.len()));
+            ctors.push(crate::types::Ctor {
+                name: sym.name().clone(),
+                args,
+            });
+            symbols.push(sym);
+        }
+        self.tyvars.clear();
+
+        let data = crate::types::DataType {
+            name,
+            params,
+            ctors,
+        };
+        Ok((Rc::new(data), symbols))
+    }
+
+    fn conv_record(&mut self, rec: &ast::RecordDecl) -> Result<Rc<idtree::Record>> {
+        let params = self.conv_params(&rec.params, rec.span)?;
+        let mut fields = Vec::with_capacity(rec.fields.len());
+        for (name, ty) in &rec.fields {
+            if fields
+                .iter()
+                .any(|f: &crate::types::Field<TyVar>| *f.name == *name)
+            {
+                let msg = format!("Field {} declared twice", name);
+                return Err(Error::spanned(msg, rec.span));
+            }
+            let ty = self.conv_ty(ty).map_err(|e| e.at(rec.span))?;
+            let name = self.add_uniq_name(name);
+            fields.push(crate::types::Field { name, ty });
+        }
+        self.tyvars.clear();
+
+        let name = self.mk_tycon(&rec.name);
+        let rec = Rc::new(crate::types::Record {
+            name,
+            params,
+            fields,
+        });
+        for field in &rec.fields {
+            let recs = self.labels.entry(field.name.to_string()).or_default();
+            recs.push(rec.clone());
+        }
+        Ok(rec)
+    }
+
+    fn conv_class(&mut self, class: &ast::ClassDecl) -> Result<()> {
+        if !self.class_names.insert(class.name.clone()) {
+            let msg = format!("Class {} already declared", class.name);
+            return Err(Error::spanned(msg, class.span));
+        }
+        let param = self.conv_params(std::slice::from_ref(&class.param), class.span)?;
+        let mut methods: Vec<crate::types::Field<TyVar>> = Vec::new();
+        for (name, ty) in &class.methods {
+            if methods.iter().any(|m| *m.name == *name) {
+                let msg = format!("Method {} declared twice", name);
+                return Err(Error::spanned(msg, class.span));
+            }
+            let ty = self.conv_ty(ty).map_err(|e| e.at(class.span))?;
+            let sym = self.add_sym(name, ty.clone(), class.span)?;
+            self.add_top_level(&sym);
+            methods.push(crate::types::Field {
+                name: sym.name().clone(),
+                ty,
+            });
+        }
+        self.tyvars.clear();
+
+        let class = Rc::new(crate::types::Class {
+            name: self.add_uniq_name(&class.name),
+            param: param[0].clone(),
+            methods,
+        });
+        self.classes.insert(class.name.to_string(), class);
+        Ok(())
+    }
+
+    fn lookup_class(&self, nm: &str, span: Span) -> Result<Rc<idtree::Class>> {
+        match self.classes.get(nm) {
+            Some(class) => Ok(class.clone()),
+            None => Err(Error::spanned(format!("Could not find class {}", nm), span)),
+        }
+    }
+
+    //The constraints of an annotation are on its type parameters
+    //    let f<a: Num>(x: a) -> a { .. }
+    fn conv_annot_preds(&mut self, annot: &ast::Annot) -> Result<Vec<idtree::Pred>> {
+        let mut preds = Vec::with_capacity(annot.preds.len());
+        for (name, ty) in &annot.preds {
+            let class = self.lookup_class(name, annot.span)?;
+            let ty = self.conv_ty(ty).map_err(|e| e.at(annot.span))?;
+            if !matches!(ty, Type::Var(_)) {
+                let msg = "Annotations can only constrain their type parameters";
+                return Err(Error::spanned(msg, annot.span));
+            }
+            preds.push(idtree::Pred {
+                class: class.name.clone(),
+                ty,
+            });
+        }
+        Ok(preds)
+    }
+
+    //The head of an instance is a type constructor applied to distinct type
+    //  variables
+    //    instance Eq<a> => Eq<List<a>>
+    fn conv_instance(
+        &mut self,
+        inst: &ast::InstanceDecl,
+    ) -> Result<(Rc<idtree::Instance>, Vec<idtree::Bind>)> {
+        use crate::types::Type::*;
+        let span = inst.span;
+        let (ref class_name, ref ty) = inst.pred;
+        let class = self.lookup_class(class_name, span)?;
+        let params = match *ty {
+            App(ref con, ref args) if !matches!(**con, Var(_)) => args
+                .iter()
+                .map(|arg| match *arg {
+                    Con(TyCon::NewType(ref nm), _) if !self.types.contains_key(nm.as_str()) => {
+                        Some(nm.to_string())
+                    }
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>(),
+            App(..) | Var(_) => None,
+            Con(..) => Some(vec![]),
+        };
+        let params = match params {
+            Some(params) => self.conv_params(&params, span)?,
+            None => {
+                let msg = "The type of an instance must be a type constructor applied to distinct type variables";
+                return Err(Error::spanned(msg, span));
+            }
+        };
+        let ty = self.conv_ty(ty).map_err(|e| e.at(span))?;
+        let mut context = Vec::with_capacity(inst.context.len());
+        for (name, ty) in &inst.context {
+            let pred_class = self.lookup_class(name, span)?;
+            let ty = self.conv_ty(ty).map_err(|e| e.at(span))?;
+            if !matches!(ty, Var(_)) {
+                let msg = "Instance contexts can only constrain type variables";
+                return Err(Error::spanned(msg, span));
+            }
+            context.push(crate::types::Pred {
+                class: pred_class.name.clone(),
+                ty,
+            });
+        }
+        self.tyvars.clear();
+
+        let head = ty.head().cloned().unwrap();
+        if !self.instances.insert((class.name.clone(), head.clone())) {
+            let msg = format!("Instance {}<{:?}> already declared", class.name, head);
+            return Err(Error::spanned(msg, span));
+        }
+
+        let mut methods: Vec<Option<idtree::Bind>> = class.methods.iter().map(|_| None).collect();
+        for ast::Bind(ref name, ref expr, span) in &inst.methods {
+            let index = match class.methods.iter().position(|m| *m.name == *name) {
+                Some(index) => index,
+                None => {
+                    let msg = format!("{} is not a method of class {}", name, class.name);
+                    return Err(Error::spanned(msg, *span));
+                }
+            };
+            if methods[index].is_some() {
+                let msg = format!("Method {} defined twice", name);
+                return Err(Error::spanned(msg, *span));
+            }
+            //Instance methods are only called through the class
+            let name = format!("{}<{:?}>.{}", class.name, head, name);
+            let sym = idtree::Symbol::new(self.add_uniq_name(&name), self.new_tyvar(), fresh_id());
+            let expr = self.conv(expr, &sym)?;
+            self.check_tail_calls(&sym, &expr, 0)?;
+            methods[index] = Some(idtree::Bind::new(sym, expr));
+        }
+        let methods = class
+            .methods
+            .iter()
+            .zip(methods)
+            .map(|(method, bind)| match bind {
+                Some(bind) => Ok(bind),
+                None => {
+                    let msg = format!(
+                        "Missing method {} in instance of {}",
+                        method.name, class.name
+                    );
+                    Err(Error::spanned(msg, span))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let inst = Rc::new(crate::types::Instance {
+            class: class.name.clone(),
+            params,
+            context,
+            ty,
+        });
+        Ok((inst, methods))
+    }
+
+    //The records declaring all the fields, the last declared last
+    fn lookup_fields(&self, names: &[&String], span: Span) -> Result<Vec<Rc<idtree::Record>>> {
+        let mut recs = Vec::new();
+        for (i, nm) in names.iter().enumerate() {
+            let declared = match self.labels.get(*nm) {
+                Some(declared) => declared,
+                None => return Err(Error::spanned(format!("Could not find field {}", nm), span)),
+            };
+            match i {
+                0 => recs = declared.clone(),
+                _ => recs.retain(|rec| declared.iter().any(|r| Rc::ptr_eq(r, rec))),
+            }
+        }
+        if recs.is_empty() {
+            let names = Vector::fmap(names.iter(), |nm| nm.as_str());
+            let msg = format!("No record has all the fields {}", names.join(", "));
+            return Err(Error::spanned(msg, span));
+        }
+        Ok(recs)
+    }
+
+    //Fields that are not a record's are only known once the type of the
+    //  record is, so they are kept by name
+    fn conv_field_names(
+        &mut self,
+        fields: &[(String, ast::Expr)],
+        func: &idtree::Symbol,
+    ) -> Result<Vec<(Rc<String>, idtree::Expr)>> {
+        let mut res: Vec<(Rc<String>, idtree::Expr)> = Vec::with_capacity(fields.len());
+        for (nm, expr) in fields {
+            if res.iter().any(|(prev, _)| **prev == *nm) {
+                let msg = format!("Field {} is set more than once", nm);
+                return Err(Error::spanned(msg, expr.span()));
+            }
+            let nm = self.add_uniq_name(nm);
+            res.push((nm, self.conv(expr, func)?));
+        }
+        Ok(res)
+    }
+
+    //The fields set in a record expression, all of which must belong to `rec`
+    fn conv_fields(
+        &mut self,
+        rec: &idtree::Record,
+        fields: &[(String, ast::Expr)],
+        func: &idtree::Symbol,
+    ) -> Result<Vec<(u32, idtree::Expr)>> {
+        let mut res: Vec<(u32, idtree::Expr)> = Vec::with_capacity(fields.len());
+        for (nm, expr) in fields {
+            let index = match rec.fields.iter().position(|f| *f.name == *nm) {
+                Some(index) => index as u32,
+                None => {
+                    let msg = format!("Record {} has no field {}", rec.name, nm);
+                    return Err(Error::spanned(msg, expr.span()));
+                }
+            };
+            if res.iter().any(|(i, _)| *i == index) {
+                let msg = format!("Field {} is set more than once", nm);
+                return Err(Error::spanned(msg, expr.span()));
+            }
+            res.push((index, self.conv(expr, func)?));
+        }
+        Ok(res)
+    }
+
+    fn lookup_ctor(&self, nm: &str) -> Option<idtree::Symbol> {
+        self.names
+            .get(nm)
+            .filter(|sym| self.ctors.contains_key(&sym.id()))
+            .cloned()
+    }
+
+    //Variables in a pattern are bound in the current scope
+    fn conv_pat(
+        &mut self,
+        pat: &ast::Pattern,
+        bound: &mut HashSet<String>,
+        span: Span,
+    ) -> Result<idtree::Pattern> {
+        use crate::ast::Pattern::*;
+        let res = match *pat {
+            Wildcard => idtree::Pattern::Wildcard,
+            UnitLit => idtree::Pattern::UnitLit,
+            NumLit(n) => idtree::Pattern::NumLit(n),
+            BoolLit(b) => idtree::Pattern::BoolLit(b),
+            Var(ref nm) => match self.lookup_ctor(nm) {
+                Some(ctor) => self.conv_ctor_pat(&ctor, &[], bound, span)?,
+                None => {
+                    if !bound.insert(nm.clone()) {
+                        let msg = format!("Variable {} is bound more than once in the pattern", nm);
+                        return Err(Error::spanned(msg, span));
+                    }
+                    let tv = self.new_tyvar();
+                    idtree::Pattern::Var(self.add_sym(nm, tv, span)?)
+                }
+            },
+            Ctor(ref nm, ref args) => match self.lookup_ctor(nm) {
+                Some(ctor) => self.conv_ctor_pat(&ctor, args, bound, span)?,
+                None => {
+                    let msg = format!("Could not find constructor {}", nm);
+                    return Err(Error::spanned(msg, span));
+                }
+            },
         };
         Ok(res)
     }
 
+    fn conv_ctor_pat(
+        &mut self,
+        ctor: &idtree::Symbol,
+        args: &[ast::Pattern],
+        bound: &mut HashSet<String>,
+        span: Span,
+    ) -> Result<idtree::Pattern> {
+        let (tag, arity) = self.ctors[&ctor.id()];
+        if args.len() != arity {
+            let msg = format!(
+                "Constructor {} expects {} argument(s), found {}",
+                ctor.name(),
+                arity,
+                args.len()
+            );
+            return Err(Error::spanned(msg, span));
+        }
+        let args = Vector::map(args, |arg| self.conv_pat(arg, bound, span))?;
+        Ok(idtree::Pattern::Ctor(ctor.clone(), tag, args))
+    }
+
+    //A constructor used as a value is wrapped in a lambda
+    //     Some  =>  \(x0) -> Some(x0)
+    fn conv_ctor(
+        &mut self,
+        sym: &idtree::Symbol,
+        args: Option<Vec<idtree::Expr>>,
+        span: Span,
+    ) -> Result<idtree::ExprKind> {
+        let (tag, arity) = self.ctors[&sym.id()];
+        let args = match args {
+            Some(args) if args.len() != arity => {
+                let msg = format!(
+                    "Constructor {} expects {} argument(s), found {}",
+                    sym.name(),
+                    arity,
+                    args.len()
+                );
+                return Err(Error::spanned(msg, span));
+            }
+            Some(args) => args,
+            None if arity == 0 => vec![],
+            None => {
+                let params = Vector::fmap(0..arity, |i| {
+                    let name = self.add_uniq_name(&format!("x{}", i));
+                    idtree::Symbol::new(name, self.new_tyvar(), fresh_id())
+                });
+                let args = Vector::fmap(params.iter(), |p| {
+                    idtree::Expr::new(idtree::ExprKind::Var(p.clone()), span)
+                });
+                let ctor = idtree::Ctor::new(sym.clone(), tag, args);
+                let body = idtree::Expr::new(idtree::ExprKind::Ctor(Box::new(ctor)), span);
+                return Ok(idtree::ExprKind::Lam(params, Box::new(body)));
+            }
+        };
+        let ctor = idtree::Ctor::new(sym.clone(), tag, args);
+        Ok(idtree::ExprKind::Ctor(Box::new(ctor)))
+    }
+
+    //The functions of a let rec are split into the strongly connected
+    //  components of their call graph so that each is generalized before
+    //  the functions that use it are typechecked
+    //    let rec f(x) { g(x) } and g(x) { x } and h(x) { h(f(x)) }; e
+    //  becomes
+    //    let rec g(x) { x }; let rec f(x) { g(x) }; let rec h(x) { h(f(x)) }; e
+    fn conv_let_rec(
+        &mut self,
+        binds: &[ast::Bind],
+        let_expr: &ast::Expr,
+        func: &idtree::Symbol,
+    ) -> Result<idtree::ExprKind> {
+        let mut syms: Vec<idtree::Symbol> = Vec::with_capacity(binds.len());
+        for ast::Bind(ref name, _, span) in binds {
+            if syms.iter().any(|sym| **sym.name() == *name) {
+                let msg = format!("Function {} defined twice", name);
+                return Err(Error::spanned(msg, *span));
+            }
+            let ty = self.new_tyvar();
+            syms.push(self.add_sym(name, ty, *span)?);
+        }
+        let mut graph = Graph::<u32, usize>::new();
+        let mut group = Vec::with_capacity(binds.len());
+        for (i, (bind, sym)) in binds.iter().zip(&syms).enumerate() {
+            graph.add_vertex(i);
+            let start = self.uses.len();
+            let marked = self.tail_calls.len();
+            let expr = self.conv(&bind.1, func)?;
+            self.check_tail_calls(sym, &expr, marked)?;
+            group.push(Some(idtree::Bind::new(sym.clone(), expr)));
+            let callees = self.uses[start..]
+                .iter()
+                .filter_map(|id| syms.iter().position(|sym| sym.id() == *id));
+            for j in callees.collect::<HashSet<_>>() {
+                graph.add_edge(i as u32, j as u32);
+            }
+        }
+        let mut take = |scc: Vec<usize>| {
+            let mut scc = scc;
+            scc.sort_unstable();
+            Vector::fmap(scc.into_iter(), |i| group[i].take().unwrap())
+        };
+
+        let mut sccs = SCC::run(&graph);
+        let first = sccs.remove(0);
+        let mut expr = self.conv(let_expr, func)?;
+        for scc in sccs.into_iter().rev() {
+            let span = expr.span();
+            let let_rec = idtree::ExprKind::LetRec(take(scc), Box::new(expr));
+            expr = idtree::Expr::new(let_rec, span);
+        }
+        Ok(idtree::ExprKind::LetRec(take(first), Box::new(expr)))
+    }
+
+    //A call marked @tailcall has to be in tail position. The marks from
+    //  `start` on are in `func`. Whether the callee is in the same group as
+    //  `func` is only known once the whole module is converted
+    fn check_tail_calls(
+        &mut self,
+        func: &idtree::Symbol,
+        expr: &idtree::Expr,
+        start: usize,
+    ) -> Result<()> {
+        let mut calls = Vec::new();
+        if let Some(body) = lam_body(expr) {
+            tail_calls(body, &mut calls);
+        }
+        for span in self.tail_calls.drain(start..) {
+            match calls.iter().find(|(call, _)| *call == span) {
+                Some(&(_, callee)) => self.marked.push((span, func.clone(), callee)),
+                None => {
+                    let msg = "This call is marked @tailcall but is not in tail position";
+                    return Err(Error::spanned(msg, span));
+                }
+            }
+        }
+        let callees = calls.into_iter().filter_map(|(_, callee)| callee);
+        self.tail_callees.insert(func.id(), callees.collect());
+        Ok(())
+    }
+
+    //Functions calling each other in tail position are compiled together and
+    //  the calls are jumps. Other calls, like those to functions of other
+    //  modules or closures, use stack space as cranelift 0.80 has no tail
+    //  call convention
+    //    let even(n) { if n == 0 { true } else { @tailcall odd(n - 1) } }
+    //    let odd(n) { if n == 0 { false } else { @tailcall even(n - 1) } }
+    fn check_marked_calls(&mut self) -> Result<()> {
+        let mut graph = Graph::<u32, u32>::new();
+        let keys: HashMap<u32, u32> = self
+            .tail_callees
+            .keys()
+            .map(|id| (*id, graph.add_vertex(*id)))
+            .collect();
+        for (id, callees) in &self.tail_callees {
+            for callee in callees.iter().filter_map(|callee| keys.get(callee)) {
+                graph.add_edge(keys[id], *callee);
+            }
+        }
+        let mut groups = HashMap::new();
+        for (i, scc) in SCC::run(&graph).into_iter().enumerate() {
+            groups.extend(scc.into_iter().map(|id| (id, i)));
+        }
+        self.tail_callees.clear();
+        for (span, func, callee) in std::mem::take(&mut self.marked) {
+            let msg = match callee.and_then(|callee| groups.get(&callee)) {
+                None => "Calls of closures and of functions of other modules are not tail calls, cranelift 0.80 has no tail call convention".to_string(),
+                Some(group) if Some(group) != groups.get(&func.id()) => format!(
+                    "Only calls of {} to itself and to functions calling it back in tail position are compiled to tail calls",
+                    func.name()
+                ),
+                Some(_) => continue,
+            };
+            return Err(Error::spanned(msg, span));
+        }
+        Ok(())
+    }
+
     fn conv(&mut self, expr: &ast::Expr, func: &idtree::Symbol) -> Result<idtree::Expr> {
-        use crate::ast::Expr::*;
-        let res = match *expr {
-            UnitLit => idtree::Expr::UnitLit,
-            I32Lit(n) => idtree::Expr::I32Lit(n),
-            BoolLit(b) => idtree::Expr::BoolLit(b),
+        use crate::ast::ExprKind::*;
+        let res = match *expr.kind() {
+            UnitLit => idtree::ExprKind::UnitLit,
+            NumLit(n) => idtree::ExprKind::NumLit(n),
+            BoolLit(b) => idtree::ExprKind::BoolLit(b),
             Lam(ref lam) => {
                 self.names.begin_scope();
                 let params = Vector::map(lam.params(), |p| {
                     let tv = self.new_tyvar();
-                    self.add_sym(p, tv)
+                    self.add_sym(p, tv, expr.span())
                 })?;
                 let body = self.conv(lam.body(), func)?;
                 self.names.end_scope();
-                idtree::Expr::Lam(params, Box::new(body))
+                idtree::ExprKind::Lam(params, Box::new(body))
             }
             If(ref e) => {
                 let if_expr = idtree::If::new(
                     self.conv(e.cond(), func)?,
                     self.conv(e.texpr(), func)?,
-                    self.conv(e.fexpr(), func)?,
+                    e.fexpr().map(|fexpr| self.conv(fexpr, func)).transpose()?,
                 );
-                idtree::Expr::If(Box::new(if_expr))
+                idtree::ExprKind::If(Box::new(if_expr))
+            }
+            //Negation is if e { false } else { true }
+            Not(ref e) => {
+                let bool_lit = |b| idtree::Expr::new(idtree::ExprKind::BoolLit(b), expr.span());
+                let if_expr =
+                    idtree::If::new(self.conv(e, func)?, bool_lit(false), Some(bool_lit(true)));
+                idtree::ExprKind::If(Box::new(if_expr))
             }
             App(ref callee, ref args) => {
-                let callee = Box::new(self.conv(callee, func)?);
-                let args = Vector::map(args, |arg| self.conv(arg, func))?;
-                idtree::Expr::App(callee, args)
+                let ctor = match *callee.kind() {
+                    Var(ref nm) => self.lookup_ctor(nm),
+                    _ => None,
+                };
+                match ctor {
+                    Some(ctor) => {
+                        let args = Vector::map(args, |arg| self.conv(arg, func))?;
+                        self.conv_ctor(&ctor, Some(args), expr.span())?
+                    }
+                    None => {
+                        let callee = Box::new(self.conv(callee, func)?);
+                        let args = Vector::map(args, |arg| self.conv(arg, func))?;
+                        idtree::ExprKind::App(callee, args)
+                    }
+                }
             }
             Var(ref nm) => {
                 let sym = match self.names.get(nm) {
-                    Some(v) => v,
+                    Some(v) => v.clone(),
                     None => {
                         let msg = format!("Could not find variable {}", nm);
-                        return Err(Error::new(msg));
+                        return Err(Error::spanned(msg, expr.span()));
                     }
                 };
-                let v1 = self.top_level_funcs.get(&func.id());
-                let v2 = self.top_level_funcs.get(&sym.id());
-                if let (Some(v1), Some(v2)) = (v1, v2) {
-                    self.call_ref_graph.add_edge(v2.0, v1.0);
+                self.add_use(&sym, func);
+                match self.ctors.contains_key(&sym.id()) {
+                    true => self.conv_ctor(&sym, None, expr.span())?,
+                    false => idtree::ExprKind::Var(sym),
                 }
-                idtree::Expr::Var(sym.clone())
             }
             Let(ref bind, ref let_expr) => {
-                let ast::Bind(ref name, ref bind_expr) = **bind;
+                let ast::Bind(ref name, ref bind_expr, span) = **bind;
                 let ty = self.new_tyvar();
                 //Convert the bound expression before adding the bound symbol
                 let bexpr = self.conv(bind_expr, func)?;
-                let sym = self.add_sym(name, ty)?;
+                let sym = self.add_sym(name, ty, span)?;
                 let bind = idtree::Bind::new(sym, bexpr);
 
                 let expr = self.conv(let_expr, func)?;
                 let let_ = idtree::Let::new(bind, expr);
-                idtree::Expr::Let(Box::new(let_))
+                idtree::ExprKind::Let(Box::new(let_))
+            }
+            LetRec(ref binds, ref let_expr) => self.conv_let_rec(binds, let_expr, func)?,
+            Seq(ref e1, ref e2) => {
+                let e1 = self.conv(e1, func)?;
+                let e2 = self.conv(e2, func)?;
+                idtree::ExprKind::Seq(Box::new(e1), Box::new(e2))
+            }
+            Match(ref scrut, ref arms) => {
+                let scrut = self.conv(scrut, func)?;
+                let arms = Vector::map(arms, |arm| {
+                    self.names.begin_scope();
+                    let pat = self.conv_pat(&arm.pat, &mut HashSet::new(), arm.span)?;
+                    let expr = self.conv(&arm.expr, func)?;
+                    self.names.end_scope();
+                    Ok(idtree::Arm::new(pat, expr, arm.span))
+                })?;
+                idtree::ExprKind::Match(Box::new(scrut), arms)
+            }
+            //The last declared record with exactly these fields, or else
+            //  the last one missing some of them
+            Record(ref fields) => {
+                let names = Vector::fmap(fields.iter(), |(nm, _)| nm);
+                let recs = self.lookup_fields(&names, expr.span())?;
+                let rec = match recs
+                    .iter()
+                    .rev()
+                    .find(|rec| rec.fields.len() == names.len())
+                {
+                    Some(rec) => rec.clone(),
+                    None => recs.last().unwrap().clone(),
+                };
+                let fields = self.conv_fields(&rec, fields, func)?;
+                let missing =
+                    (0..rec.fields.len()).find(|i| fields.iter().all(|f| f.0 != *i as u32));
+                if let Some(i) = missing {
+                    let msg = format!(
+                        "Missing field {} in record {}",
+                        rec.fields[i].name, rec.name
+                    );
+                    return Err(Error::spanned(msg, expr.span()));
+                }
+                idtree::ExprKind::Record(rec, fields)
+            }
+            Project(ref record, ref nm) => {
+                let record = self.conv(record, func)?;
+                let recs = self.lookup_fields(&[nm], expr.span())?;
+                let nm = self.add_uniq_name(nm);
+                idtree::ExprKind::Project(Box::new(record), recs, nm)
+            }
+            Update(ref record, ref fields) => {
+                let record = self.conv(record, func)?;
+                let names = Vector::fmap(fields.iter(), |(nm, _)| nm);
+                let recs = self.lookup_fields(&names, expr.span())?;
+                let fields = self.conv_field_names(fields, func)?;
+                idtree::ExprKind::Update(Box::new(record), recs, fields)
+            }
+            Infix(ref first, ref rest) => return self.conv_infix(first, rest, func),
+            TailCall(ref call) => {
+                let call = self.conv(call, func)?;
+                if let idtree::ExprKind::App(..) = *call.kind() {
+                    self.tail_calls.push(call.span());
+                    return Ok(call);
+                }
+                let msg = "Only function calls can be marked @tailcall";
+                return Err(Error::spanned(msg, expr.span()));
+            }
+            Annot(ref e, ref annot) => {
+                //The type parameters are in scope in the body
+                let mut rigid = Vec::with_capacity(annot.tyvars.len());
+                let mut shadowed = Vec::with_capacity(annot.tyvars.len());
+                for name in &annot.tyvars {
+                    if annot.tyvars.iter().filter(|tv| *tv == name).count() > 1 {
+                        let msg = format!("Type parameter {} declared twice", name);
+                        return Err(Error::spanned(msg, annot.span));
+                    }
+                    let tv = TyVar::fresh(self.names.scope());
+                    shadowed.push((name, self.tyvars.insert(name.clone(), tv.clone())));
+                    rigid.push((name.clone(), tv));
+                }
+                let ty = self.conv_ty(&annot.ty).map_err(|e| e.at(annot.span));
+                let ty = ty.and_then(|ty| Ok((ty, self.conv_annot_preds(annot)?)));
+                let e = ty.and_then(|(ty, preds)| Ok((ty, preds, self.conv(e, func)?)));
+                for (name, prev) in shadowed {
+                    match prev {
+                        Some(tv) => self.tyvars.insert(name.clone(), tv),
+                        None => self.tyvars.remove(name),
+                    };
+                }
+                let (ty, preds, e) = e?;
+                let annot = idtree::Annot {
+                    ty,
+                    rigid,
+                    preds,
+                    span: annot.span,
+                };
+                idtree::ExprKind::Annot(Box::new(e), Box::new(annot))
             }
         };
-        Ok(res)
+        Ok(idtree::Expr::new(res, expr.span()))
+    }
+
+    fn add_use(&mut self, sym: &idtree::Symbol, func: &idtree::Symbol) {
+        let v1 = self.top_level_funcs.get(&func.id());
+        let v2 = self.top_level_funcs.get(&sym.id());
+        if let (Some(v1), Some(v2)) = (v1, v2) {
+            self.call_ref_graph.add_edge(v1.0, v2.0);
+        }
+        self.uses.push(sym.id());
+    }
+
+    //Operator precedence parsing. An operator waits on the stack until an
+    //  operator that binds less tightly follows it
+    fn conv_infix(
+        &mut self,
+        first: &ast::Expr,
+        rest: &[((String, Span), ast::Expr)],
+        func: &idtree::Symbol,
+    ) -> Result<idtree::Expr> {
+        let mut operands = vec![self.conv(first, func)?];
+        let mut ops: Vec<(&str, ast::Assoc, u32, Span)> = Vec::new();
+        for ((op, span), operand) in rest {
+            let (assoc, prec) = match SHORT_CIRCUIT.iter().find(|(name, _)| name == op) {
+                Some(&(_, prec)) => (ast::Assoc::Right, prec),
+                None => match self.fixities.get(op) {
+                    Some(fixity) => (fixity.assoc, fixity.prec),
+                    None => {
+                        let msg = format!("Could not find operator {}", op);
+                        return Err(Error::spanned(msg, *span));
+                    }
+                },
+            };
+            while let Some(&(prev, top_assoc, top_prec, prev_span)) = ops.last() {
+                let reduce = match top_prec.cmp(&prec) {
+                    std::cmp::Ordering::Greater => true,
+                    std::cmp::Ordering::Less => false,
+                    std::cmp::Ordering::Equal => match (top_assoc, assoc) {
+                        (ast::Assoc::Left, ast::Assoc::Left) => true,
+                        (ast::Assoc::Right, ast::Assoc::Right) => false,
+                        _ => {
+                            let msg = format!(
+                                "Operators {} and {} have the same precedence and can not be chained, use braces",
+                                prev, op
+                            );
+                            return Err(Error::spanned(msg, *span));
+                        }
+                    },
+                };
+                if !reduce {
+                    break;
+                }
+                ops.pop();
+                self.apply_op(prev, prev_span, &mut operands, func)?;
+            }
+            ops.push((op, assoc, prec, *span));
+            operands.push(self.conv(operand, func)?);
+        }
+        while let Some((op, _, _, span)) = ops.pop() {
+            self.apply_op(op, span, &mut operands, func)?;
+        }
+        Ok(operands.pop().unwrap())
+    }
+
+    //Replaces the top two operands with the operator applied to them
+    fn apply_op(
+        &mut self,
+        op: &str,
+        op_span: Span,
+        operands: &mut Vec<idtree::Expr>,
+        func: &idtree::Symbol,
+    ) -> Result<()> {
+        let rhs = operands.pop().unwrap();
+        let lhs = operands.pop().unwrap();
+        let span = lhs.span().to(rhs.span());
+        let bool_lit = |b| idtree::Expr::new(idtree::ExprKind::BoolLit(b), span);
+        let expr = match op {
+            //a && b is if a { b } else { false }
+            "&&" => {
+                let if_expr = idtree::If::new(lhs, rhs, Some(bool_lit(false)));
+                idtree::ExprKind::If(Box::new(if_expr))
+            }
+            //a || b is if a { true } else { b }
+            "||" => {
+                let if_expr = idtree::If::new(lhs, bool_lit(true), Some(rhs));
+                idtree::ExprKind::If(Box::new(if_expr))
+            }
+            _ => {
+                let sym = self.fixities[op].func.clone();
+                match self.ctors.contains_key(&sym.id()) {
+                    true => self.conv_ctor(&sym, Some(vec![lhs, rhs]), span)?,
+                    false => {
+                        self.add_use(&sym, func);
+                        let callee = idtree::Expr::new(idtree::ExprKind::Var(sym), op_span);
+                        idtree::ExprKind::App(Box::new(callee), vec![lhs, rhs])
+                    }
+                }
+            }
+        };
+        operands.push(idtree::Expr::new(expr, span));
+        Ok(())
+    }
+}
+
+fn lam_body(expr: &idtree::Expr) -> Option<&idtree::Expr> {
+    match *expr.kind() {
+        idtree::ExprKind::Lam(_, ref body) => Some(body),
+        idtree::ExprKind::Annot(ref e, _) => lam_body(e),
+        _ => None,
+    }
+}
+
+//The calls in tail position and the functions they call
+//    let f(x) { g(x); if x { f(x) } else { h(x) } }
+//  f(x) and h(x) are in tail position, g(x) is not
+fn tail_calls(expr: &idtree::Expr, calls: &mut Vec<(Span, Option<u32>)>) {
+    use crate::idtree::ExprKind::*;
+    match *expr.kind() {
+        If(ref e) => {
+            tail_calls(e.texpr(), calls);
+            if let Some(fexpr) = e.fexpr() {
+                tail_calls(fexpr, calls);
+            }
+        }
+        Let(ref e) => tail_calls(e.expr(), calls),
+        LetRec(_, ref e) | Seq(_, ref e) | Annot(ref e, _) => tail_calls(e, calls),
+        Match(_, ref arms) => {
+            for arm in arms {
+                tail_calls(arm.expr(), calls);
+            }
+        }
+        App(ref callee, _) => {
+            let callee = match *callee.kind() {
+                Var(ref sym) => Some(sym.id()),
+                _ => None,
+            };
+            calls.push((expr.span(), callee));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::idtree::Decl;
+    use crate::testing::{check, error};
+
+    //The functions of each group in the order they are typechecked
+    fn groups(src: &str) -> Vec<Vec<String>> {
+        let module = crate::parser::ModuleParser::new()
+            .parse("test", 0, src)
+            .unwrap();
+        let module = super::Rename::new().conv_module(&module).unwrap();
+        let groups = module.decls().iter().filter_map(|decl| match *decl {
+            Decl::Let(ref binds) => Some(
+                binds
+                    .iter()
+                    .map(|bind| bind.symbol().name().to_string())
+                    .collect(),
+            ),
+            _ => None,
+        });
+        groups.collect()
+    }
+
+    #[test]
+    fn groups_of_functions() {
+        let groups = groups(
+            "let main() { f(even(1)) }
+             let even(n) { odd(n) }
+             let f(x) { x }
+             let odd(n) { if n { even(n) } else { f(n) } }",
+        );
+        let position = |name: &str| groups.iter().position(|g| g.iter().any(|f| f == name));
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[position("even").unwrap()], vec!["even", "odd"]);
+        assert!(position("f") < position("odd"));
+        assert!(position("odd") < position("main"));
+        let src = "let a() { id(1) }\nlet b() { id(true) }\nlet id(x) { x }";
+        assert_eq!(
+            check(&[("test", src)]).unwrap(),
+            vec!["id : forall a. a -> a", "a : () -> i32", "b : () -> bool"]
+        );
+    }
+
+    #[test]
+    fn fixity() {
+        let res = check(&[("test", "let f() { 1 < 2 < 3 }")]);
+        assert_eq!(
+            error(res),
+            "Operators < and < have the same precedence and can not be chained, use braces"
+        );
+        let res = check(&[("test", "let pow(a, n) { a }\ninfixl 6 (+) = pow;")]);
+        assert_eq!(
+            error(res),
+            "+ is imported from prelude, use `import prelude hiding ((+));` to declare it"
+        );
+        //Operators can be used before they and their functions are declared
+        let src = "
+            let f(x) { x <+> 2 <> 3 *** true }
+            infixl 6 (<+>) = combine;
+            infixr 5 (<>) = i32_max;
+            infix 4 (***) = both;
+            let combine(a, b) { a * 10 + b }
+            extern i32_max(a: i32, b: i32) -> i32;
+            class Both<a> { both: (i32, a) -> bool }
+            instance Both<bool> { let both(n, b) { b } }";
+        let res = check(&[("test", src)]).unwrap();
+        assert_eq!(res, vec!["combine : (i32, i32) -> i32", "f : i32 -> bool"]);
+        let res = check(&[(
+            "test",
+            "let f() { 1 <+> 2 }
+infixl 6 (<+>) = missing;",
+        )]);
+        assert_eq!(error(res), "Could not find variable missing");
+        //The functions the operators of the prelude call have names users
+        //  are unlikely to pick
+        let src = "
+            let add(a, b) { a + b }
+            let lt(a, b) { a < b }
+            let f() { add(1, 2) != 3 && lt(1, 2) }";
+        let res = check(&[("test", src)]).unwrap();
+        assert_eq!(
+            res,
+            vec![
+                "add : forall a. Num<a> => (a, a) -> a",
+                "lt : forall a. Ord<a> => (a, a) -> bool",
+                "f : () -> bool"
+            ]
+        );
+    }
+
+    #[test]
+    fn kinds() {
+        let res = check(&[("test", "type T = A(i32<bool>)")]);
+        assert_eq!(error(res), "Type i32 expects 0 argument(s), found 1");
+        let res = check(&[("test", "let f(x: bool<i32, u8>) { x }")]);
+        assert_eq!(error(res), "Type bool expects 0 argument(s), found 2");
+        let res = check(&[("test", "type T<a> = A(a<i32>)")]);
+        assert_eq!(error(res), "Type parameter a can not be applied to types");
+        let res = check(&[("test", "type T<a> = A(a)\nlet f(x: T) { x }")]);
+        assert_eq!(error(res), "Type T expects 1 argument(s), found 0");
+    }
+
+    #[test]
+    fn prelude() {
+        let src = "let i32_print(x) { x }";
+        assert_eq!(
+            error(check(&[("test", src)])),
+            "i32_print is imported from prelude, use `import prelude hiding (i32_print);` to declare it"
+        );
+        let src = "import prelude hiding (i32_print);\nlet i32_print(x) { x }";
+        assert_eq!(
+            check(&[("test", src)]).unwrap(),
+            vec!["i32_print : forall a. a -> a"]
+        );
+        let res = check(&[("test", "let f() {\n  g(1)\n}")]);
+        let span = res.unwrap_err().span().unwrap();
+        assert_eq!((span.file, span.lo), (1, 12));
+    }
+
+    #[test]
+    fn imports() {
+        let list = "pub let size(x) { 1 }\nlet secret() { 2 }";
+        let res = check(&[
+            ("list", list),
+            ("test", "import list;\nlet f() { size(secret()) }"),
+        ]);
+        assert_eq!(error(res), "Could not find variable secret");
+        let res = check(&[("test", "import missing;")]);
+        assert_eq!(error(res), "Could not find module missing");
+        let res = check(&[("list", list), ("test", "import list hiding (length);")]);
+        assert_eq!(error(res), "Module list does not declare length");
+        let res = check(&[
+            ("list", list),
+            ("vec", "pub let size(v) { 2 }"),
+            ("test", "import list;\nimport vec;"),
+        ]);
+        assert_eq!(
+            error(res),
+            "size is declared by more than one imported module"
+        );
+        let test = "import list;\nimport vec hiding (size);\nlet f() { size(true) }";
+        let res = check(&[
+            ("list", list),
+            ("vec", "pub let size(v) { 2 }"),
+            ("test", test),
+        ]);
+        assert_eq!(res.unwrap(), vec!["f : () -> i32"]);
+    }
+
+    #[test]
+    fn tail_calls() {
+        let res = check(&[(
+            "test",
+            "let f(n) { if n == 0 { 0 } else { 1 + @tailcall f(n - 1) } }",
+        )]);
+        assert_eq!(
+            error(res),
+            "This call is marked @tailcall but is not in tail position"
+        );
+        let res = check(&[("test", "let f(n) { n }\nlet g(n) { @tailcall f(n) }")]);
+        let msg = "Only calls of g to itself and to functions calling it back in tail position are compiled to tail calls";
+        assert_eq!(error(res), msg);
+        let src = "let f(n) { g(n) + 1 }\nlet g(n) { if n == 0 { 0 } else { @tailcall f(n - 1) } }";
+        assert_eq!(error(check(&[("test", src)])), msg);
+        let lib = ("lib", "pub let f(n) { n }");
+        let res = check(&[lib, ("test", "import lib;\nlet g(n) { @tailcall f(n) }")]);
+        let indirect = "Calls of closures and of functions of other modules are not tail calls, cranelift 0.80 has no tail call convention";
+        assert_eq!(error(res), indirect);
+        let res = check(&[("test", "let apply(f, x) { @tailcall f(x) }")]);
+        assert_eq!(error(res), indirect);
+        let res = check(&[("test", "let h(n) { @tailcall n }")]);
+        assert_eq!(error(res), "Only function calls can be marked @tailcall");
+
+        let src = "
+            let even(n) { if n == 0 { true } else { @tailcall odd(n - 1) } }
+            let odd(n) { if n == 0 { false } else { step(n) } }
+            let step(n) { @tailcall even(n - 1) }
+            let parity(n) {
+                let rec f(n, acc) { if n == 0 { acc } else { @tailcall g(n - 1, acc) } }
+                and g(n, acc) { @tailcall f(n, !acc) };
+                f(n, true)
+            }";
+        assert!(check(&[("test", src)]).is_ok());
     }
 }
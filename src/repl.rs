@@ -0,0 +1,283 @@
+use crate::ast;
+use crate::codegen::CodeGen;
+use crate::idtree;
+use crate::passes::*;
+use crate::span::{SourceMap, Span};
+use crate::types::Type;
+use crate::xir;
+use crate::{Error, Pass, Result, Vector};
+use std::io::{BufRead, Write};
+
+//Declarations are renamed and typechecked as they are entered and the
+//  environment is kept between inputs. Each expression is wrapped in a
+//  function and compiled in memory together with all the declarations
+//  before it.
+pub struct Repl {
+    sources: SourceMap,
+    rename: Rename,
+    typecheck: TypeChecker,
+    decls: Vec<xir::Decl>,
+    //Number of expressions evaluated, used to name their functions
+    count: u32,
+}
+
+impl Repl {
+    pub fn new() -> Result<Self> {
+        let mut repl = Repl {
+            sources: SourceMap::new(),
+            rename: Rename::new(),
+            typecheck: TypeChecker::new(),
+            decls: Vec::new(),
+            count: 0,
+        };
+        let file = repl
+            .sources
+            .add("prelude.bs", crate::prelude::PRELUDE.to_string());
+        let src = repl.sources.file(file).unwrap().src();
+        let prelude = crate::parser::ModuleParser::new()
+            .parse(crate::prelude::NAME, file, src)
+            .map_err(|lalr_err| Error::from_parse(file, lalr_err))?;
+        repl.load(prelude)?;
+        //Inputs import the declarations entered before them
+        repl.load(ast::Module::new("repl", vec![]))?;
+        Ok(repl)
+    }
+
+    fn load(&mut self, module: ast::Module) -> Result<()> {
+        let module = self.rename.conv_module(&module)?;
+        self.typecheck.add_classes(&module);
+        let typed = self.typecheck.tc_module(&module)?;
+        self.decls.extend(typed.take_decls());
+        Ok(())
+    }
+
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+
+    //Returns the type schemes of the declarations or the value of the
+    //  expression. Nothing is kept if there is an error
+    pub fn eval(&mut self, name: &str, src: &str) -> Result<Vec<String>> {
+        let file = self.sources.add(name, src.to_string());
+        let src = self.sources.file(file).unwrap().src();
+        let input = crate::parser::ReplInputParser::new()
+            .parse("repl", file, src)
+            .map_err(|lalr_err| Error::from_parse(file, lalr_err))?;
+        let rename = self.rename.clone();
+        let typecheck = self.typecheck.clone();
+        let res = match input {
+            ast::ReplInput::Decls(decls) => self.eval_decls(decls),
+            ast::ReplInput::Expr(expr) => self.eval_expr(expr),
+        };
+        if res.is_err() {
+            self.rename = rename;
+            self.typecheck = typecheck;
+        }
+        res
+    }
+
+    fn check(&mut self, decls: Vec<ast::Decl>) -> Result<(idtree::Module, xir::Module)> {
+        let mut module = ast::Module::new("repl", vec![]);
+        module.imports = vec![
+            ast::Import::new(crate::prelude::NAME, Span::default()),
+            ast::Import::new("repl", Span::default()),
+        ];
+        module.decls = Vector::fmap(decls.into_iter(), |decl| ast::Decl::Pub(Box::new(decl)));
+        let module = self.rename.conv_module(&module)?;
+        self.typecheck.add_classes(&module);
+        let typed = self.typecheck.tc_module(&module)?;
+        Ok((module, typed))
+    }
+
+    fn eval_decls(&mut self, decls: Vec<ast::Decl>) -> Result<Vec<String>> {
+        let (module, typed) = self.check(decls)?;
+        let mut res = Vec::new();
+        for decl in module.decls() {
+            let symbols = match *decl {
+                idtree::Decl::Extern(ref sym, _) => vec![sym],
+                idtree::Decl::Let(ref binds) => binds.iter().map(|b| b.symbol()).collect(),
+                idtree::Decl::Class(_, ref methods) => methods.iter().collect(),
+                _ => vec![],
+            };
+            for sym in symbols {
+                let scheme = self.typecheck.lookup(sym)?;
+                res.push(format!("{} : {}", sym.name(), scheme));
+            }
+        }
+        self.decls.extend(typed.take_decls());
+        Ok(res)
+    }
+
+    fn eval_expr(&mut self, expr: ast::Expr) -> Result<Vec<String>> {
+        self.count += 1;
+        //Not a valid identifier so it can not clash with the user's names
+        let name = format!("it.{}", self.count);
+        let span = expr.span();
+        let lam = ast::Lam::new(vec![], expr);
+        let lam = ast::Expr::new(ast::ExprKind::Lam(Box::new(lam)), span);
+        let (module, typed) =
+            self.check(vec![ast::Decl::Func(ast::Bind(name.clone(), lam, span))])?;
+        let scheme = match module.decls().as_slice() {
+            [idtree::Decl::Let(binds)] => self.typecheck.lookup(binds[0].symbol())?,
+            _ => return Err(Error::new("Expected a single function")),
+        };
+        let ty = match scheme.ty() {
+            Type::App(_, args) => args.last().unwrap().clone(),
+            ty => ty.clone(),
+        };
+        //There is no code for a polymorphic value until it is used
+        if !scheme.bound_vars().is_empty() {
+            return Ok(vec![format!("- : {}", ty)]);
+        }
+
+        let mut decls = self.decls.clone();
+        decls.extend(typed.take_decls());
+        let modules = vec![xir::Module::new("repl".to_string(), decls)];
+        let modules = MatchCompile::new().run(modules)?;
+        let modules = Specialize::new().run(modules)?;
+        let modules = LambdaLift::new().run(modules)?;
+        let mut modules = Simplify::new().run(modules)?;
+        let value = CodeGen::jit_run(modules.remove(0), &name)?;
+        Ok(vec![format!("- : {} = {}", ty, value)])
+    }
+}
+
+//An input is read until its brackets are balanced so declarations can span
+//  several lines
+fn is_complete(input: &str) -> bool {
+    let mut depth = 0;
+    for c in input.chars() {
+        match c {
+            '{' | '(' => depth += 1,
+            '}' | ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+pub fn run() -> Result<()> {
+    let mut repl = Repl::new()?;
+    let stdin = std::io::stdin();
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "| " });
+        let _ = std::io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => input.push_str(&line),
+        }
+        if !is_complete(&input) {
+            continue;
+        }
+        let text = std::mem::take(&mut input);
+        if text.trim().is_empty() {
+            continue;
+        }
+        let res = repl.eval("repl", &text);
+        for warning in crate::take_warnings() {
+            eprint!("{}", warning.render(repl.sources()));
+        }
+        match res {
+            Ok(lines) => lines.iter().for_each(|line| println!("{}", line)),
+            Err(e) => eprint!("{}", e.render(repl.sources())),
+        }
+    }
+    println!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Session;
+
+    #[test]
+    fn brackets() {
+        assert!(is_complete("1"));
+        assert!(!is_complete("let f(x) {"));
+        assert!(is_complete("let f(x) {\n  x\n}"));
+    }
+
+    #[test]
+    fn operators() {
+        let mut repl = Session::new();
+        assert_eq!(repl.eval("1 + 2 * 3").unwrap(), "- : i32 = 7");
+        assert_eq!(repl.value("10 - 3 - 2"), "5");
+        assert_eq!(repl.value("2 - -3 >= 5 && 1.5 < 2.0"), "true");
+        repl.eval("let pow(a, n) { if n == 0 { 1 } else { a * pow(a, n - 1) } }")
+            .unwrap();
+        repl.eval("infixr 8 (**) = pow;").unwrap();
+        assert_eq!(repl.value("2 ** 3 ** 2"), "512");
+    }
+
+    #[test]
+    fn conditionals() {
+        let mut repl = Session::new();
+        repl.eval("let loop(n) { loop(n) }").unwrap();
+        assert_eq!(repl.value("false && loop(1)"), "false");
+        assert_eq!(repl.value("true || loop(1)"), "true");
+        assert_eq!(repl.value("!true || 1 < 2 && !{2 < 1}"), "true");
+        repl.eval("let sign(n) { if n < 0 { -1 } else if n == 0 { 0 } else { 1 } }")
+            .unwrap();
+        assert_eq!(repl.value("sign(-5) + sign(7) * 10"), "9");
+        assert_eq!(
+            repl.eval("if false { i32_print(1) }").unwrap(),
+            "- : () = ()"
+        );
+    }
+
+    #[test]
+    fn partial_application() {
+        let mut repl = Session::new();
+        repl.eval("let add(a, b, c) { a + b * 10 + c * 100 }")
+            .unwrap();
+        repl.eval("let adder(n) { \\(x, y) -> { x + y * n } }")
+            .unwrap();
+        assert_eq!(
+            repl.eval("add(1)").unwrap(),
+            "- : (i32, i32) -> i32 = <fun>"
+        );
+        assert_eq!(repl.value("add(1, 2)(3)"), "321");
+        assert_eq!(repl.value("{ let f = add(1); f(2, 3) }"), "321");
+        assert_eq!(repl.value("adder(10, 1, 2)"), "21");
+        assert_eq!(repl.value("adder(10, 1)(2)"), "21");
+    }
+
+    #[test]
+    fn ambiguous() {
+        let mut repl = Session::new();
+        repl.eval("let id(x) { x }").unwrap();
+        assert_eq!(repl.value("{ let f = id(\\x -> x); 1 }"), "1");
+        repl.eval("let size(c) { match c { Overflow => 0, Exact(_) => 1 } }")
+            .unwrap();
+        assert_eq!(repl.value("size(Overflow) + size(Exact(2))"), "1");
+        //The instance does not constrain the type of the elements
+        repl.eval("type List<a> = Nil | Cons(a, List<a>)").unwrap();
+        repl.eval("class Len<a> { len: a -> i32 }").unwrap();
+        repl.eval("instance Len<List<a>> { let len(l) { match l { Nil => 0, Cons(_, t) => 1 + len(t) } } }")
+            .unwrap();
+        assert_eq!(repl.value("len(Cons(Nil, Cons(Nil, Nil)))"), "2");
+        let err = repl
+            .eval("{ let f = \\x -> { x == x }; f; 1 }")
+            .unwrap_err();
+        assert_eq!(err.msg(), "Ambiguous type variable in the constraint Eq<a>");
+    }
+
+    #[test]
+    fn tail_calls() {
+        let mut repl = Session::new();
+        repl.eval(
+            "let count(n, acc) { if n == 0 { acc } else { @tailcall count(n - 1, acc + 1) } }",
+        )
+        .unwrap();
+        assert_eq!(repl.value("count(1000000, 0)"), "1000000");
+        repl.eval(
+            "let even(n) { if n == 0 { true } else { @tailcall odd(n - 1) } }
+             let odd(n) { if n == 0 { false } else { @tailcall even(n - 1) } }",
+        )
+        .unwrap();
+        assert_eq!(repl.value("odd(1000001)"), "true");
+    }
+}
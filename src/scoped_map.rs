@@ -5,14 +5,14 @@ use std::collections::HashMap;
 use std::hash::Hash;
 use std::mem;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Inner<K: Hash + Eq, V> {
     scope: u32,
     curr_map: HashMap<K, V>,
     prev_scope: Option<Box<Inner<K, V>>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ScopedMap<K: Eq + Hash, V> {
     inner: Box<Inner<K, V>>,
 }
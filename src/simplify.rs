@@ -1,12 +1,26 @@
 use crate::monoir;
-use crate::types::{Kind, TyCon};
+use crate::types::{Kind, Subst, TyCon};
 use crate::xir;
-use crate::{Error, Result, Vector};
+use crate::{fresh_id, Error, Result, Vector};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 type Type = crate::types::Type<crate::types::TyVar>;
 
 pub struct Simplify {}
 
+//Data types are lowered to one tagged union per instance
+//    type List<a> = Nil | Cons(a, List<a>)
+//  used at List<i32> becomes the union "List<i32>" with variants [] and
+//  [i32, List<i32>]. Records are similarly lowered to one struct per instance.
+struct Simplifier<'a> {
+    datatypes: &'a HashMap<Rc<String>, Rc<xir::DataType>>,
+    records: &'a HashMap<Rc<String>, Rc<xir::Record>>,
+    unions: Vec<monoir::Union>,
+    structs: Vec<monoir::Record>,
+    seen: HashSet<Rc<String>>,
+}
+
 impl Default for Simplify {
     fn default() -> Self {
         Self::new()
@@ -18,7 +32,22 @@ impl crate::Pass for Simplify {
     type Output = Vec<monoir::Module>;
 
     fn run(self, module_vec: Self::Input) -> Result<Self::Output> {
-        let res = Vector::map(&module_vec, |modl| self.process(modl))?;
+        let mut datatypes = HashMap::new();
+        let mut records = HashMap::new();
+        for module in &module_vec {
+            for decl in module.decls() {
+                match *decl {
+                    xir::Decl::Type(ref data) => {
+                        datatypes.insert(data.name.clone(), data.clone());
+                    }
+                    xir::Decl::Record(ref rec) => {
+                        records.insert(rec.name.clone(), rec.clone());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let res = Vector::map(&module_vec, |modl| self.process(modl, &datatypes, &records))?;
         Ok(res)
     }
 }
@@ -28,126 +57,315 @@ impl Simplify {
         Simplify {}
     }
 
-    fn process(&self, module: &xir::Module) -> Result<monoir::Module> {
+    fn process(
+        &self,
+        module: &xir::Module,
+        datatypes: &HashMap<Rc<String>, Rc<xir::DataType>>,
+        records: &HashMap<Rc<String>, Rc<xir::Record>>,
+    ) -> Result<monoir::Module> {
         let modname = module.name().clone();
         let mut modl = monoir::Module::new(modname);
+        let mut simplifier = Simplifier {
+            datatypes,
+            records,
+            unions: Vec::new(),
+            structs: Vec::new(),
+            seen: HashSet::new(),
+        };
 
         for decl in module.decls() {
             match *decl {
-                xir::Decl::Extern(ref name) => {
-                    modl.ext_funcs.push(process_symbol(name)?);
+                xir::Decl::Extern(ref name, span) => {
+                    let sym = simplifier.process_symbol(name)?;
+                    modl.ext_funcs.push((sym, span));
                 }
                 xir::Decl::Let(ref bindings) => {
                     for bind in bindings {
                         //println!("{:?} ===========\n  \n", bind);
-                        let res = process_bind(bind)?;
+                        let res = simplifier.process_bind(bind)?;
                         //println!("{:?}\n====================\n", res);
                         modl.funcs.push(res);
                     }
                 }
+                xir::Decl::Closure(ref env, ref bind) => {
+                    modl.funcs.push(simplifier.process_closure(env, bind)?);
+                }
+                xir::Decl::Type(_) | xir::Decl::Record(_) => {}
+                xir::Decl::Class(..) | xir::Decl::Instance(..) => {
+                    return Err(Error::new(
+                        "classes should have been resolved by Specialize",
+                    ));
+                }
             }
         }
 
+        modl.types = simplifier.unions;
+        modl.records = simplifier.structs;
         Ok(modl)
     }
 }
 
-fn process_symbol(sym: &xir::Symbol) -> Result<monoir::Symbol> {
-    let ty = get_type(sym.ty())?;
-    let tv = monoir::Symbol::new(sym.name().clone(), ty, sym.id());
-    Ok(tv)
-}
+impl<'a> Simplifier<'a> {
+    fn process_symbol(&mut self, sym: &xir::Symbol) -> Result<monoir::Symbol> {
+        let ty = self.get_type(sym.ty())?;
+        let tv = monoir::Symbol::new(sym.name().clone(), ty, sym.id());
+        Ok(tv)
+    }
 
-fn process_bind(bind: &xir::Bind) -> Result<monoir::Bind> {
-    let sym = process_symbol(bind.symbol())?;
-    let expr = process(bind.expr())?;
-    let bind = monoir::Bind::new(sym, expr);
-    Ok(bind)
-}
+    fn process_bind(&mut self, bind: &xir::Bind) -> Result<monoir::Bind> {
+        let sym = self.process_symbol(bind.symbol())?;
+        let expr = self.process(bind.expr())?;
+        let bind = monoir::Bind::new(sym, expr);
+        Ok(bind)
+    }
 
-fn process(expr: &xir::Expr) -> Result<monoir::Expr> {
-    use crate::xir::Expr::*;
-
-    let expr = match *expr {
-        UnitLit => monoir::Expr::UnitLit,
-        I32Lit(n) => monoir::Expr::I32Lit(n),
-        BoolLit(b) => monoir::Expr::BoolLit(b),
-        Var(ref var) => monoir::Expr::Var(process_symbol(var)?),
-        If(ref e) => monoir::Expr::If(Box::new(monoir::If {
-            cond: process(e.cond())?,
-            texpr: process(e.texpr())?,
-            fexpr: process(e.fexpr())?,
-            ty: get_type(e.ty())?,
-        })),
-        Let(ref e) => {
-            let bind = process_bind(e.bind())?;
-            let expr = process(e.expr())?;
-            monoir::Expr::Let(Box::new(bind), Box::new(expr))
-        }
-        Lam(ref params, ref body, ref _retty) => {
-            let params = Vector::map(params, process_symbol)?;
-            let body = process(body)?;
-            let lam = monoir::Lam::new(params, body);
-            monoir::Expr::Lam(Box::new(lam))
-        }
-        App(ref ty, ref caller, ref args) => {
-            let caller = process(caller)?;
-            let args = Vector::map(args, process)?;
-            monoir::Expr::App(get_type(ty)?, Box::new(caller), args)
-        }
-        _ => {
-            let msg = format!("EXPR not supported {:?}", expr);
-            return Err(Error::new(msg));
-        }
-    };
-    Ok(expr)
-}
+    fn process_closure(&mut self, env: &[xir::Symbol], bind: &xir::Bind) -> Result<monoir::Bind> {
+        let sym = self.process_symbol(bind.symbol())?;
+        let env = Vector::map(env, |v| self.process_symbol(v))?;
+        let lam = match bind.expr().kind() {
+            xir::ExprKind::Lam(ref params, ref body, _) => {
+                let params = Vector::map(params, |p| self.process_symbol(p))?;
+                monoir::Lam::closure(env, params, self.process(body)?)
+            }
+            expr => {
+                let msg = format!("Closure {:?} is not a lambda {:?}", sym, expr);
+                return Err(Error::spanned(msg, bind.expr().span()));
+            }
+        };
+        Ok(monoir::Bind::new(sym, monoir::Expr::Lam(Box::new(lam))))
+    }
 
-fn get_appty(ty: &Type, args: &[Type]) -> Result<monoir::Type> {
-    use crate::types::Type::*;
+    fn process(&mut self, expr: &xir::Expr) -> Result<monoir::Expr> {
+        use crate::xir::ExprKind::*;
 
-    let mut args = Vector::map(args, get_type)?;
-    match *ty {
-        Con(TyCon::Func, _) => {
-            if args.is_empty() {
-                let msg = format!("Function with no return type found {:?}", ty);
-                Err(Error::new(msg))
-            } else {
-                let slice_end = args.len() - 1; //borrow_chk
-                let params_ty = args.drain(..slice_end).collect::<Vec<_>>();
-                let return_ty = Box::new(args.pop().unwrap());
-                Ok(monoir::Type::Function {
-                    params_ty,
-                    return_ty,
-                })
+        let res = match *expr.kind() {
+            UnitLit => monoir::Expr::UnitLit,
+            NumLit(n) => monoir::Expr::NumLit(n),
+            BoolLit(b) => monoir::Expr::BoolLit(b),
+            //Unit is zero sized, all its values are the same
+            Var(ref var) => {
+                let var = self.process_symbol(var).map_err(|e| e.at(expr.span()))?;
+                match var.ty {
+                    monoir::Type::Unit => monoir::Expr::UnitLit,
+                    _ => monoir::Expr::Var(var),
+                }
+            }
+            If(ref e) => monoir::Expr::If(Box::new(monoir::If {
+                cond: self.process(e.cond())?,
+                texpr: self.process(e.texpr())?,
+                fexpr: self.process(e.fexpr())?,
+                ty: self.get_type(e.ty())?,
+            })),
+            Let(ref e) => {
+                let bind = self.process_bind(e.bind())?;
+                let expr = self.process(e.expr())?;
+                match bind.expr {
+                    monoir::Expr::UnitLit => expr,
+                    _ => monoir::Expr::Let(Box::new(bind), Box::new(expr)),
+                }
+            }
+            Seq(ref e1, ref e2) => {
+                let e1 = self.process(e1)?;
+                let e2 = self.process(e2)?;
+                monoir::Expr::Seq(Box::new(e1), Box::new(e2))
+            }
+            LetRec(..) => {
+                let msg = "let rec should have been lifted by LambdaLift";
+                return Err(Error::spanned(msg, expr.span()));
+            }
+            Lam(ref params, ref body, ref _retty) => {
+                let params = Vector::map(params, |p| self.process_symbol(p))?;
+                let body = self.process(body)?;
+                let lam = monoir::Lam::new(params, body);
+                monoir::Expr::Lam(Box::new(lam))
+            }
+            App(ref ty, ref caller, ref args) => {
+                let caller = self.process(caller)?;
+                let args = Vector::map(args, |arg| self.process(arg))?;
+                monoir::Expr::App(self.get_type(ty)?, Box::new(caller), args)
+            }
+            MkClosure(ref code, ref env) => {
+                let env = Vector::map(env, |v| self.process_symbol(v))?;
+                monoir::Expr::MkClosure(self.process_symbol(code)?, env)
+            }
+            Ctor(ref c) => {
+                let ty = self.get_type(c.ty()).map_err(|e| e.at(expr.span()))?;
+                let args = Vector::map(c.args(), |arg| self.process(arg))?;
+                monoir::Expr::Ctor(ty, c.tag(), args)
             }
+            Switch(ref s) => {
+                let cases = Vector::map(s.cases(), |(k, e)| Ok((*k, self.process(e)?)))?;
+                let default = match s.default() {
+                    Some(e) => Some(self.process(e)?),
+                    None => None,
+                };
+                monoir::Expr::Switch(Box::new(monoir::Switch {
+                    scrut: self.process_symbol(s.scrut())?,
+                    cases,
+                    default,
+                    ty: self.get_type(s.ty())?,
+                }))
+            }
+            Field(ref var, tag, index) => {
+                let var = self.process_symbol(var).map_err(|e| e.at(expr.span()))?;
+                monoir::Expr::Field(var, tag, index)
+            }
+            Record(ref ty, ref fields) => {
+                let ty = self.get_type(ty).map_err(|e| e.at(expr.span()))?;
+                let fields = Vector::map(fields, |(i, e)| Ok((*i, self.process(e)?)))?;
+                monoir::Expr::Record(ty, fields)
+            }
+            Project(ref ty, ref record, index) => {
+                let ty = self.get_type(ty).map_err(|e| e.at(expr.span()))?;
+                monoir::Expr::Project(ty, Box::new(self.process(record)?), index)
+            }
+            //    {r with x = e}
+            //  becomes
+            //    let tmp = r; {x = e, y = tmp.y}
+            Update(ref ty, ref record, ref fields) => {
+                let ty = self.get_type(ty).map_err(|e| e.at(expr.span()))?;
+                let len = match ty {
+                    monoir::Type::Record(ref name) => self.record_len(name),
+                    _ => 0,
+                };
+                let tmp = Rc::new("record".to_string());
+                let tmp = monoir::Symbol::new(tmp, ty.clone(), fresh_id());
+                let var = || monoir::Expr::Var(tmp.clone());
+                let mut inits = Vector::map(fields, |(i, e)| Ok((*i, self.process(e)?)))?;
+                for i in 0..len {
+                    if fields.iter().all(|(j, _)| *j != i) {
+                        inits.push((i, monoir::Expr::Project(ty.clone(), Box::new(var()), i)));
+                    }
+                }
+                let bind = monoir::Bind::new(tmp.clone(), self.process(record)?);
+                let rec = monoir::Expr::Record(ty, inits);
+                monoir::Expr::Let(Box::new(bind), Box::new(rec))
+            }
+            _ => {
+                let msg = format!("EXPR not supported {:?}", expr);
+                return Err(Error::spanned(msg, expr.span()));
+            }
+        };
+        Ok(res)
+    }
+
+    fn record_len(&self, name: &Rc<String>) -> u32 {
+        self.structs
+            .iter()
+            .find(|rec| rec.name == *name)
+            .map_or(0, |rec| rec.fields.len() as u32)
+    }
+
+    //The struct for an instance of a record type
+    fn get_record(&mut self, rec: &xir::Record, args: &[Type]) -> Result<monoir::Type> {
+        let name = instance_name(&rec.name, &Vector::map(args, |arg| self.get_type(arg))?);
+        //Recursive records refer to themselves by name
+        if self.seen.insert(name.clone()) {
+            let mut sub = Subst::new();
+            for (param, arg) in rec.params.iter().zip(args) {
+                sub.bind(param, arg.clone());
+            }
+            let fields = Vector::map(&rec.fields, |f| self.get_type(&sub.apply(&f.ty)))?;
+            self.structs.push(monoir::Record {
+                name: name.clone(),
+                fields,
+            });
         }
-        _ => {
-            let msg = format!("not supported {:?}", ty);
-            Err(Error::new(msg))
+        Ok(monoir::Type::Record(name))
+    }
+
+    //The union for an instance of a data type
+    fn get_datatype(&mut self, name: &Rc<String>, args: &[Type]) -> Result<monoir::Type> {
+        if let Some(rec) = self.records.get(name) {
+            return self.get_record(&rec.clone(), args);
+        }
+        let data = match self.datatypes.get(name) {
+            Some(data) => data.clone(),
+            None => return Err(Error::new(format!("Could not find type {}", name))),
+        };
+        let union_name = instance_name(name, &Vector::map(args, |arg| self.get_type(arg))?);
+        //Recursive data types refer to themselves by name
+        if self.seen.insert(union_name.clone()) {
+            let mut sub = Subst::new();
+            for (param, arg) in data.params.iter().zip(args) {
+                sub.bind(param, arg.clone());
+            }
+            let variants = Vector::map(&data.ctors, |ctor| {
+                Vector::map(&ctor.args, |arg| self.get_type(&sub.apply(arg)))
+            })?;
+            self.unions.push(monoir::Union {
+                name: union_name.clone(),
+                variants,
+            });
         }
+        Ok(monoir::Type::Data(union_name))
     }
-}
 
-fn get_type(ty: &Type) -> Result<monoir::Type> {
-    use self::Kind::*;
-    use self::TyCon::*;
-    use crate::types::Type::*;
-    let ty = match ty {
-        App(ty, args) => get_appty(ty, args)?,
-        Con(tycon, k) => match (tycon, k) {
-            (&I32, &Star) => monoir::Type::I32,
-            (&Bool, &Star) => monoir::Type::Bool,
-            (&Unit, &Star) => monoir::Type::Unit,
+    fn get_appty(&mut self, ty: &Type, args: &[Type]) -> Result<monoir::Type> {
+        use crate::types::Type::*;
+
+        match *ty {
+            Con(TyCon::Func, _) => {
+                let mut args = Vector::map(args, |arg| self.get_type(arg))?;
+                if args.is_empty() {
+                    let msg = format!("Function with no return type found {:?}", ty);
+                    Err(Error::new(msg))
+                } else {
+                    let slice_end = args.len() - 1; //borrow_chk
+                    let params_ty = args.drain(..slice_end).collect::<Vec<_>>();
+                    let return_ty = Box::new(args.pop().unwrap());
+                    Ok(monoir::Type::Function {
+                        params_ty,
+                        return_ty,
+                    })
+                }
+            }
+            Con(TyCon::NewType(ref name), _) => self.get_datatype(name, args),
             _ => {
                 let msg = format!("not supported {:?}", ty);
-                return Err(Error::new(msg));
+                Err(Error::new(msg))
             }
-        },
-        _ => {
-            let msg = format!("not supported {:?}", ty);
-            return Err(Error::new(msg));
         }
-    };
-    Ok(ty)
+    }
+
+    fn get_type(&mut self, ty: &Type) -> Result<monoir::Type> {
+        use self::Kind::*;
+        use self::TyCon::*;
+        use crate::types::Type::*;
+        let ty = match ty {
+            App(ty, args) => self.get_appty(ty, args)?,
+            Con(tycon, k) => match (tycon, k) {
+                (&I8, &Star) => monoir::Type::I8,
+                (&I16, &Star) => monoir::Type::I16,
+                (&I32, &Star) => monoir::Type::I32,
+                (&I64, &Star) => monoir::Type::I64,
+                (&U8, &Star) => monoir::Type::U8,
+                (&U16, &Star) => monoir::Type::U16,
+                (&U32, &Star) => monoir::Type::U32,
+                (&U64, &Star) => monoir::Type::U64,
+                (&F32, &Star) => monoir::Type::F32,
+                (&F64, &Star) => monoir::Type::F64,
+                (&Bool, &Star) => monoir::Type::Bool,
+                (&Unit, &Star) => monoir::Type::Unit,
+                (NewType(name), &Star) => self.get_datatype(name, &[])?,
+                _ => {
+                    let msg = format!("not supported {:?}", ty);
+                    return Err(Error::new(msg));
+                }
+            },
+            //Type variables left after specialization are not constrained by
+            //  any class, e.g. the parameter of f in
+            //    let main() { let f = id(\x -> x); 1 }
+            //  No value of such a type is ever built, so they default to ()
+            Var(_) => monoir::Type::Unit,
+        };
+        Ok(ty)
+    }
+}
+
+fn instance_name(name: &Rc<String>, tys: &[monoir::Type]) -> Rc<String> {
+    match tys.len() {
+        0 => name.clone(),
+        _ => Rc::new(format!("{}{:?}", name, tys)),
+    }
 }
@@ -0,0 +1,129 @@
+use std::fmt;
+
+//A range of bytes in one of the files in the SourceMap
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub file: u32,
+    pub lo: u32,
+    pub hi: u32,
+}
+
+pub struct SourceFile {
+    name: String,
+    src: String,
+    //Byte offset of the start of every line
+    lines: Vec<u32>,
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+//1-based line and column of a position in a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loc {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl Span {
+    pub fn new(file: u32, lo: usize, hi: usize) -> Self {
+        Span {
+            file,
+            lo: lo as u32,
+            hi: hi as u32,
+        }
+    }
+
+    //The smallest span covering both spans
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            file: self.file,
+            lo: std::cmp::min(self.lo, other.lo),
+            hi: std::cmp::max(self.hi, other.hi),
+        }
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}[{}..{}]", self.file, self.lo, self.hi)
+    }
+}
+
+impl SourceFile {
+    fn new(name: String, src: String) -> Self {
+        let mut lines = vec![0];
+        for (i, c) in src.bytes().enumerate() {
+            if c == b'\n' {
+                lines.push(i as u32 + 1);
+            }
+        }
+        SourceFile { name, src, lines }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    pub fn loc(&self, pos: u32) -> Loc {
+        let line = match self.lines.binary_search(&pos) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        let start = self.lines[line] as usize;
+        let end = std::cmp::min(pos as usize, self.src.len());
+        let col = self.src[start..end].chars().count();
+        Loc {
+            line: line as u32 + 1,
+            col: col as u32 + 1,
+        }
+    }
+
+    //Text of a line without the newline. Lines are 1-based
+    pub fn line(&self, line: u32) -> &str {
+        let start = self.lines[line as usize - 1] as usize;
+        let end = match self.lines.get(line as usize) {
+            Some(end) => *end as usize,
+            None => self.src.len(),
+        };
+        self.src[start..end].trim_end_matches(['\n', '\r'])
+    }
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: &str, src: String) -> u32 {
+        self.files.push(SourceFile::new(name.to_string(), src));
+        self.files.len() as u32 - 1
+    }
+
+    pub fn file(&self, file: u32) -> Option<&SourceFile> {
+        self.files.get(file as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loc() {
+        let mut map = SourceMap::new();
+        let id = map.add("a.bs", "let main() {\n  10\n}".to_string());
+        let file = map.file(id).unwrap();
+        assert_eq!(file.loc(0), Loc { line: 1, col: 1 });
+        assert_eq!(file.loc(15), Loc { line: 2, col: 3 });
+        assert_eq!(file.loc(18), Loc { line: 3, col: 1 });
+        assert_eq!(file.line(2), "  10");
+        assert_eq!(file.line(3), "}");
+    }
+}
@@ -1,9 +1,9 @@
 use crate::fresh_id;
 use crate::scoped_map::ScopedMap;
-use crate::types::{self, Subst, TyVar};
+use crate::types::{self, Subst, TyCon, TyVar};
 use crate::xir::*;
 use crate::{Error, Result, Vector};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 type Type = types::Type<TyVar>;
@@ -37,17 +37,51 @@ impl Specialize {
     //    let bar(x)    { foo(id, x)}
     //    let main()    { bar(2) }
     //  we specialize in order main, bar, foo, and id. So by the time we
-    //     specialize any function we know all its type instantiations
+    //     specialize any function we know all its type instantiations.
+    //  Calls to class methods are resolved to instance methods which may be
+    //     declared anywhere, so polymorphic functions are specialized until
+    //     no new instantiations are found.
     fn mono_module(&mut self, module: Module) -> Result<Module> {
         let mut spec = Specializer::new();
         let mut decls = Vec::new();
         let mut poly_exps = Vec::new();
         let mut mono_exps = Vec::new();
         let modname = module.name().clone();
+        //Monomorphic methods of the instances of other modules are compiled
+        //  there and declared as externs here by Split
+        let externs: HashSet<u32> = module
+            .decls()
+            .iter()
+            .filter_map(|decl| match *decl {
+                Decl::Extern(ref sym, _) => Some(sym.id()),
+                _ => None,
+            })
+            .collect();
 
         for (i, decl) in module.take_decls().into_iter().enumerate() {
             match decl {
-                e @ Decl::Extern(_) => decls.push((i, e)),
+                e @ (Decl::Extern(..) | Decl::Closure(..) | Decl::Type(_) | Decl::Record(_)) => {
+                    decls.push((i, e))
+                }
+                Decl::Class(class, methods) => {
+                    for (index, method) in methods.iter().enumerate() {
+                        spec.methods
+                            .insert(method.id(), (class.name.clone(), index));
+                    }
+                }
+                Decl::Instance(inst, bindings) => {
+                    let head = inst.ty.head().cloned().unwrap();
+                    let methods = Vector::fmap(bindings.iter(), |b| b.symbol().clone());
+                    spec.instances
+                        .insert((inst.class.clone(), head), (inst, methods));
+                    for b in bindings {
+                        match spec.add_if_poly(&b) {
+                            false if externs.contains(&b.symbol().id()) => {}
+                            false => mono_exps.push((i, b)),
+                            true => poly_exps.push((i, b)),
+                        }
+                    }
+                }
                 Decl::Let(bindings) => {
                     for b in bindings {
                         match spec.add_if_poly(&b) {
@@ -61,14 +95,21 @@ impl Specialize {
 
         for (i, bind) in mono_exps.into_iter().rev() {
             let mut sub = Subst::new();
+            spec.binding = bind.symbol().name().clone();
             let bind = spec.process(&bind, &mut sub, vec![])?;
             decls.push((i, Decl::Let(vec![bind])));
         }
 
-        for (i, bind) in poly_exps.into_iter().rev() {
-            let mut sub = Subst::new();
-            for bind in spec.process_all(&bind, &mut sub)? {
-                decls.push((i, Decl::Let(vec![bind])));
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, bind) in poly_exps.iter().rev() {
+                let mut sub = Subst::new();
+                spec.binding = bind.symbol().name().clone();
+                for bind in spec.process_all(bind, &mut sub)? {
+                    decls.push((*i, Decl::Let(vec![bind])));
+                    changed = true;
+                }
             }
         }
 
@@ -81,6 +122,8 @@ impl Specialize {
 struct Instances {
     tyvars: Vec<TyVar>,
     inner: HashMap<Vec<Type>, Symbol>,
+    //Instantiations that have not been specialized yet
+    pending: Vec<(Vec<Type>, Symbol)>,
 }
 
 impl Instances {
@@ -88,10 +131,15 @@ impl Instances {
         Self {
             tyvars,
             inner: HashMap::new(),
+            pending: Vec::new(),
         }
     }
 
-    fn add(&mut self, var: &Symbol, sub: &mut Subst, args: Vec<Type>) -> Symbol {
+    //The type parameters are bound in a copy of the substitution, as the
+    //  caller can be the function itself at other types
+    //    instance Size<a> => Size<Option<a>> { .. size(x) .. }
+    fn add(&mut self, var: &Symbol, sub: &Subst, args: Vec<Type>) -> Symbol {
+        let mut sub = sub.clone();
         for (tyvar, ty) in self.tyvars.iter().zip(args.into_iter()) {
             sub.bind(tyvar, ty)
         }
@@ -100,23 +148,38 @@ impl Instances {
             .iter()
             .map(|ty| sub.apply(&crate::types::Type::Var(ty.clone())))
             .collect::<Vec<_>>();
+        let pending = &mut self.pending;
         let var = self.inner.entry(args.clone()).or_insert_with(|| {
             let name = format!("{}<{:?}>", var.name(), args);
             let ty = sub.apply(var.ty());
-            Symbol::new(Rc::new(name), ty, fresh_id())
+            let var = Symbol::new(Rc::new(name), ty, fresh_id());
+            pending.push((args, var.clone()));
+            var
         });
         var.clone()
     }
 }
 
+//An instance and the symbols of its methods
+type InstanceMethods = (Rc<Instance>, Vec<Symbol>);
+
 struct Specializer {
     entries: ScopedMap<Symbol, Instances>,
+    //The class and index of class methods
+    methods: HashMap<u32, (Rc<String>, usize)>,
+    //The instance of a class for a type constructor and its methods
+    instances: HashMap<(Rc<String>, TyCon), InstanceMethods>,
+    //The top level binding being specialized
+    binding: Rc<String>,
 }
 
 impl Specializer {
     fn new() -> Self {
         Self {
             entries: ScopedMap::new(),
+            methods: HashMap::new(),
+            instances: HashMap::new(),
+            binding: Rc::new(String::new()),
         }
     }
 
@@ -129,8 +192,8 @@ impl Specializer {
     }
 
     fn add_if_poly(&mut self, b: &Bind) -> bool {
-        use self::Expr::TyLam;
-        match *b.expr() {
+        use self::ExprKind::TyLam;
+        match *b.expr().kind() {
             TyLam(ref tys, _) if !tys.is_empty() => {
                 self.entries
                     .entry(b.symbol().clone())
@@ -158,17 +221,47 @@ impl Specializer {
         }
     }
 
-    fn get(&self, id: &Symbol) -> Option<&Instances> {
-        self.entries.get(id)
+    //A class method used at a type is the method of the instance for the
+    //  type constructor at its head
+    //    eq<List<i32>>  becomes  Eq<List>.eq<i32>
+    //  Unlike other type variables, those left free in a constraint can not
+    //  default to () as they decide which instance is called
+    fn resolve(&mut self, method: &Symbol, sub: &mut Subst, args: Vec<Type>) -> Result<Symbol> {
+        let (class, index) = self.methods[&method.id()].clone();
+        let ty = &args[0];
+        if let types::Type::Var(_) = *ty {
+            let msg = format!(
+                "Ambiguous type variable in the constraint {}<{}> of {}",
+                class, ty, self.binding
+            );
+            return Err(Error::new(msg));
+        }
+        let instance = ty
+            .head()
+            .and_then(|head| self.instances.get(&(class.clone(), head.clone())));
+        let (inst, methods) = match instance {
+            Some(instance) => instance.clone(),
+            None => return Err(Error::new(format!("No instance for {}<{}>", class, ty))),
+        };
+        let symbol = &methods[index];
+        if !self.is_poly(symbol) {
+            return Ok(symbol.clone());
+        }
+        let mut inst_sub = Subst::new();
+        inst_sub.matching(&inst.ty, ty);
+        let args = Vector::fmap(inst.params.iter(), |param| {
+            inst_sub.apply(&types::Type::Var(param.clone()))
+        });
+        self.add_instance(symbol, sub, args)
     }
 
     fn process_all(&mut self, bind: &Bind, sub: &mut Subst) -> Result<Vec<Bind>> {
         let symbol = bind.symbol();
         let expr = bind.expr();
         let mut result = Vec::new();
-        let instances = match self.get(symbol) {
-            None => HashMap::new(),
-            Some(instances) => instances.inner.clone(),
+        let instances = match self.entries.get_mut(symbol) {
+            None => Vec::new(),
+            Some(instances) => std::mem::take(&mut instances.pending),
         };
         for (tys, symbol) in instances {
             let tys = tys.iter().map(|ty| sub.apply(ty)).collect();
@@ -206,12 +299,17 @@ impl Specializer {
         Ok(expr)
     }
 
+    fn run_fields(&mut self, fields: &[(u32, Expr)], sub: &mut Subst) -> Result<Vec<(u32, Expr)>> {
+        Vector::map(fields, |(i, e)| Ok((*i, self.run(e, sub, vec![])?)))
+    }
+
     fn run(&mut self, expr: &Expr, sub: &mut Subst, args: Vec<Type>) -> Result<Expr> {
-        use self::Expr::*;
+        use self::ExprKind::*;
         use crate::xir;
-        let expr = match *expr {
+        let span = expr.span();
+        let kind = match *expr.kind() {
             UnitLit => UnitLit,
-            I32Lit(n) => I32Lit(n),
+            NumLit(n) => NumLit(n),
             BoolLit(b) => BoolLit(b),
             Lam(ref proto, ref body, ref retty) => {
                 let body = self.run(body, sub, vec![])?;
@@ -229,29 +327,75 @@ impl Specializer {
                     self.run(e.fexpr(), sub, vec![])?,
                     ty,
                 );
-                Expr::If(Box::new(if_expr))
+                If(Box::new(if_expr))
             }
             App(ref ty, ref callee, ref args) => {
                 let callee = self.run(callee, sub, vec![])?;
                 let args = Vector::map(args, |arg| self.run(arg, sub, vec![]))?;
                 let ty = sub.apply(ty);
-                xir::Expr::App(ty, Box::new(callee), args)
+                App(ty, Box::new(callee), args)
             }
             TyLam(ref param, ref b) => {
                 for (tyvar, ty) in param.iter().zip(args.into_iter()) {
                     sub.bind(tyvar, ty)
                 }
 
-                self.run(b, sub, vec![])?
+                return self.run(b, sub, vec![]);
             }
             TyApp(ref e, ref args) => {
                 let args = args.iter().map(|ty| sub.apply(ty)).collect::<Vec<Type>>();
-                self.run(e, sub, args)?
+                return self.run(e, sub, args);
+            }
+            MkClosure(ref code, ref env) => {
+                let env = env.iter().map(|v| v.with_ty(sub.apply(v.ty()))).collect();
+                MkClosure(code.with_ty(sub.apply(code.ty())), env)
+            }
+            Ctor(ref c) => {
+                let args = Vector::map(c.args(), |arg| self.run(arg, sub, vec![]))?;
+                let ty = sub.apply(c.ty());
+                Ctor(Box::new(xir::Ctor::new(
+                    c.symbol().clone(),
+                    c.tag(),
+                    args,
+                    ty,
+                )))
+            }
+            Match(_) => {
+                let msg = "match should have been compiled to a decision tree";
+                return Err(Error::spanned(msg, span));
+            }
+            Switch(ref s) => {
+                let cases = Vector::map(s.cases(), |(k, e)| Ok((*k, self.run(e, sub, vec![])?)))?;
+                let default = match s.default() {
+                    Some(e) => Some(self.run(e, sub, vec![])?),
+                    None => None,
+                };
+                let scrut = s.scrut().with_ty(sub.apply(s.scrut().ty()));
+                Switch(Box::new(xir::Switch::new(
+                    scrut,
+                    cases,
+                    default,
+                    sub.apply(s.ty()),
+                )))
+            }
+            Field(ref var, tag, index) => Field(var.with_ty(sub.apply(var.ty())), tag, index),
+            Record(ref ty, ref fields) => Record(sub.apply(ty), self.run_fields(fields, sub)?),
+            Project(ref ty, ref record, index) => Project(
+                sub.apply(ty),
+                Box::new(self.run(record, sub, vec![])?),
+                index,
+            ),
+            Update(ref ty, ref record, ref fields) => {
+                let record = Box::new(self.run(record, sub, vec![])?);
+                Update(sub.apply(ty), record, self.run_fields(fields, sub)?)
             }
             Var(ref id) => {
-                let id = match self.is_poly(id) {
-                    false => id.with_ty(sub.apply(id.ty())),
-                    true => self.add_instance(id, sub, args)?,
+                let id = if self.methods.contains_key(&id.id()) {
+                    self.resolve(id, sub, args).map_err(|e| e.at(span))?
+                } else if self.is_poly(id) {
+                    self.add_instance(id, sub, args).map_err(|e| e.at(span))?
+                } else {
+                    id.with_ty(sub.apply(id.ty()))
                 };
                 Var(id)
             }
@@ -262,17 +406,83 @@ impl Specializer {
                     let res = self.process_all(b, sub)?;
                     for bind in res {
                         let exp = xir::Let::new(bind, let_expr);
-                        let_expr = Expr::Let(Box::new(exp))
+                        let_expr = Expr::new(Let(Box::new(exp)), span)
                     }
-                    let_expr
+                    return Ok(let_expr);
                 } else {
                     let let_body = self.run(exp.expr(), sub, vec![])?;
                     let bind = self.process(b, sub, vec![])?;
                     let exp = xir::Let::new(bind, let_body);
-                    Expr::Let(Box::new(exp))
+                    Let(Box::new(exp))
                 }
             }
+            LetRec(ref binds, ref body) => {
+                let (poly, mono): (Vec<_>, Vec<_>) =
+                    binds.iter().partition(|b| self.add_if_poly(b));
+                let body = self.run(body, sub, vec![])?;
+                let mut res = Vector::map(&mono, |b| self.process(b, sub, vec![]))?;
+                //Specializing one function of the group can instantiate another
+                let mut changed = true;
+                while changed {
+                    changed = false;
+                    for b in &poly {
+                        for bind in self.process_all(b, sub)? {
+                            res.push(bind);
+                            changed = true;
+                        }
+                    }
+                }
+                if res.is_empty() {
+                    return Ok(body);
+                }
+                LetRec(res, Box::new(body))
+            }
+            Seq(ref e1, ref e2) => {
+                let e1 = self.run(e1, sub, vec![])?;
+                let e2 = self.run(e2, sub, vec![])?;
+                Seq(Box::new(e1), Box::new(e2))
+            }
         };
-        Ok(expr)
+        Ok(Expr::new(kind, span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::Span;
+    use crate::Pass;
+
+    #[test]
+    fn ambiguous() {
+        let tyvar = TyVar::fresh(1);
+        let var = types::Type::Var(tyvar.clone());
+        let method_ty = Type::func(vec![var.clone()], Type::unit());
+        let class = Rc::new(types::Class {
+            name: Rc::new("Show".to_string()),
+            param: tyvar,
+            methods: vec![types::Field {
+                name: Rc::new("show".to_string()),
+                ty: method_ty.clone(),
+            }],
+        });
+        let show = Symbol::new(Rc::new("show".to_string()), method_ty, fresh_id());
+        let mk = |kind| Expr::new(kind, Span::default());
+
+        //let main() { show<b> } where b is free
+        let free = types::Type::Var(TyVar::fresh(1));
+        let body = ExprKind::TyApp(Box::new(mk(ExprKind::Var(show.clone()))), vec![free]);
+        let main_ty = Type::func(vec![], Type::unit());
+        let main = mk(ExprKind::Lam(vec![], Box::new(mk(body)), Type::unit()));
+        let main = Bind::new(
+            Symbol::new(Rc::new("main".to_string()), main_ty, fresh_id()),
+            main,
+        );
+        let decls = vec![Decl::Class(class, vec![show]), Decl::Let(vec![main])];
+        let res = Specialize::new().run(vec![Module::new("test".to_string(), decls)]);
+        assert_eq!(
+            res.unwrap_err().msg(),
+            "Ambiguous type variable in the constraint Show<a> of main"
+        );
     }
 }
@@ -0,0 +1,135 @@
+use crate::rename::Rename;
+use crate::xir::{Bind, Decl, ExprKind, Module, Symbol};
+use crate::{Result, Vector};
+use std::collections::HashSet;
+use std::rc::Rc;
+
+//Each module is compiled to its own object file. The declarations of the
+//  modules it depends on are copied into it, with their monomorphic
+//  functions and instance methods turned into externs that are defined by
+//  the objects of those modules. Polymorphic functions and instance methods
+//  have no code of their own and are specialized in every module that uses
+//  them.
+//  Functions are prefixed with the name of their module so that they do
+//  not clash when linked, with each other as a module can hide an imported
+//  name and declare it again, or with the C library. Only main keeps its
+//  name. Private functions are exported too as polymorphic functions of
+//  other modules may call them
+pub struct Split<'a> {
+    rename: &'a Rename,
+}
+
+//A module with the declarations of its dependencies and the names of the
+//  functions it defines for other modules
+pub struct Unit {
+    pub module: Module,
+    pub exports: HashSet<String>,
+}
+
+impl<'a> crate::Pass for Split<'a> {
+    type Input = Vec<Module>; //In dependency order
+    type Output = Vec<Unit>;
+
+    fn run(self, modules: Self::Input) -> Result<Self::Output> {
+        let mut units = Vec::with_capacity(modules.len());
+        for (i, module) in modules.iter().enumerate() {
+            let deps = self.deps(module.name());
+            let mut decls = Vec::new();
+            for dep in modules[..i].iter().filter(|dep| deps.contains(dep.name())) {
+                for decl in dep.decls() {
+                    match *decl {
+                        Decl::Let(ref binds) => {
+                            let (mono, poly): (Vec<_>, Vec<_>) =
+                                binds.iter().cloned().partition(is_mono);
+                            for bind in mono {
+                                let sym = link_symbol(dep.name(), bind.symbol());
+                                decls.push(Decl::Extern(sym, bind.expr().span()));
+                            }
+                            if !poly.is_empty() {
+                                decls.push(Decl::Let(poly));
+                            }
+                        }
+                        //The instance is still needed to resolve the methods,
+                        //  Specialize does not compile those declared extern
+                        Decl::Instance(_, ref binds) => {
+                            for bind in binds.iter().filter(|bind| is_mono(bind)) {
+                                let sym = link_symbol(dep.name(), bind.symbol());
+                                decls.push(Decl::Extern(sym, bind.expr().span()));
+                            }
+                            decls.push(decl.clone());
+                        }
+                        Decl::Extern(ref sym, span) => {
+                            decls.push(Decl::Extern(self.link_extern(sym), span))
+                        }
+                        ref decl => decls.push(decl.clone()),
+                    }
+                }
+            }
+
+            let mut exports = HashSet::new();
+            for decl in module.decls() {
+                let export = |bind: &Bind| {
+                    if !is_mono(bind) {
+                        return bind.clone();
+                    }
+                    let sym = link_symbol(module.name(), bind.symbol());
+                    exports.insert(sym.name().to_string());
+                    Bind::new(sym, bind.expr().clone())
+                };
+                let decl = match *decl {
+                    Decl::Let(ref binds) => Decl::Let(Vector::fmap(binds.iter(), export)),
+                    Decl::Instance(ref inst, ref binds) => {
+                        Decl::Instance(inst.clone(), Vector::fmap(binds.iter(), export))
+                    }
+                    Decl::Extern(ref sym, span) => Decl::Extern(self.link_extern(sym), span),
+                    ref decl => decl.clone(),
+                };
+                decls.push(decl);
+            }
+            let module = Module::new(module.name().clone(), decls);
+            units.push(Unit { module, exports });
+        }
+        Ok(units)
+    }
+}
+
+impl<'a> Split<'a> {
+    pub fn new(rename: &'a Rename) -> Self {
+        Self { rename }
+    }
+
+    //The modules imported by a module directly or indirectly
+    fn deps(&self, module: &str) -> HashSet<String> {
+        let mut deps = HashSet::new();
+        let mut work = vec![module.to_string()];
+        while let Some(module) = work.pop() {
+            for import in self.rename.imports(&module) {
+                if deps.insert(import.name.clone()) {
+                    work.push(import.name.clone());
+                }
+            }
+        }
+        deps
+    }
+
+    //Externs of the functions of a module, as in its interface, are linked
+    //  with the functions
+    fn link_extern(&self, sym: &Symbol) -> Symbol {
+        match self.rename.linked_module(sym.id()) {
+            Some(module) => link_symbol(module, sym),
+            None => sym.clone(),
+        }
+    }
+}
+
+fn link_symbol(module: &str, sym: &Symbol) -> Symbol {
+    if sym.name().as_str() == "main" {
+        return sym.clone();
+    }
+    let name = Rc::new(format!("{}.{}", module, sym.name()));
+    Symbol::new(name, sym.ty().clone(), sym.id())
+}
+
+fn is_mono(bind: &Bind) -> bool {
+    !matches!(*bind.expr().kind(), ExprKind::TyLam(ref tys, _) if !tys.is_empty())
+}
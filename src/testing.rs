@@ -0,0 +1,72 @@
+//Helpers for the tests of the passes
+use crate::ast;
+use crate::idtree;
+use crate::passes::*;
+use crate::repl::Repl;
+use crate::span::{SourceMap, Span};
+use crate::{Error, Result};
+
+//A repl whose results are joined into one string
+pub struct Session {
+    repl: Repl,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            repl: Repl::new().unwrap(),
+        }
+    }
+
+    pub fn eval(&mut self, src: &str) -> Result<String> {
+        self.repl.eval("test", src).map(|res| res.join("\n"))
+    }
+
+    //The value of an expression without its type
+    pub fn value(&mut self, src: &str) -> String {
+        let res = self.eval(src).unwrap();
+        res.rsplit(" = ").next().unwrap().to_string()
+    }
+}
+
+//Renames and typechecks modules in order after the prelude, each of them
+//  importing it. Returns the type schemes of the functions of the last one
+pub fn check(modules: &[(&str, &str)]) -> Result<Vec<String>> {
+    let mut sources = SourceMap::new();
+    let mut rename = Rename::new();
+    let mut typecheck = TypeChecker::new();
+    let mut schemes = Vec::new();
+    let prelude = [(crate::prelude::NAME, crate::prelude::PRELUDE)];
+    for (name, src) in prelude.iter().chain(modules) {
+        let file = sources.add(name, src.to_string());
+        let src = sources.file(file).unwrap().src();
+        let mut module = crate::parser::ModuleParser::new()
+            .parse(name, file, src)
+            .map_err(|lalr_err| Error::from_parse(file, lalr_err))?;
+        let imported = module
+            .imports
+            .iter()
+            .any(|i| i.name == crate::prelude::NAME);
+        if *name != crate::prelude::NAME && !imported {
+            let import = ast::Import::new(crate::prelude::NAME, Span::default());
+            module.imports.insert(0, import);
+        }
+        let module = rename.conv_module(&module)?;
+        typecheck.add_classes(&module);
+        typecheck.tc_module(&module)?;
+        schemes.clear();
+        for decl in module.decls() {
+            if let idtree::Decl::Let(ref binds) = *decl {
+                for bind in binds {
+                    let scheme = typecheck.lookup(bind.symbol())?;
+                    schemes.push(format!("{} : {}", bind.symbol().name(), scheme));
+                }
+            }
+        }
+    }
+    Ok(schemes)
+}
+
+pub fn error<T: std::fmt::Debug>(res: Result<T>) -> String {
+    res.unwrap_err().msg().to_string()
+}
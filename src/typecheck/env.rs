@@ -1,14 +1,27 @@
-use super::unify::UnificationTable;
-use super::{ForAll, Type};
-use crate::idtree::Symbol;
+use super::unify::{Mismatch, UnificationTable};
+use super::{ForAll, Pred, Type};
+use crate::idtree::{Class, DataType, Instance, Symbol};
 use crate::scoped_map::ScopedMap;
-use crate::types::{Subst, TyVar};
+use crate::span::Span;
+use crate::types::{Names, Subst, TyCon, TyVar};
 use crate::{Error, Result};
+use std::collections::HashMap;
+use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(super) struct Env {
     map: ScopedMap<u32, ForAll>,
     unify_table: UnificationTable,
+    //Constraints that have not been resolved or added to a type scheme yet
+    //  and the span of the expression they come from
+    preds: Vec<(Pred, Span)>,
+    classes: HashMap<Rc<String>, Rc<Class>>,
+    instances: HashMap<(Rc<String>, TyCon), Rc<Instance>>,
+    //The types of expressions whose values are discarded by a sequence.
+    //  They are checked once their types are known
+    discarded: Vec<(Type, Span)>,
+    //The type variables of annotations and the types they stand for
+    annotations: HashMap<u32, Type>,
 }
 
 impl Env {
@@ -16,13 +29,36 @@ impl Env {
         Self {
             map: ScopedMap::new(),
             unify_table: UnificationTable::new(),
+            preds: Vec::new(),
+            classes: HashMap::new(),
+            instances: HashMap::new(),
+            discarded: Vec::new(),
+            annotations: HashMap::new(),
+        }
+    }
+
+    pub fn add_class(&mut self, class: &Rc<Class>) {
+        self.classes.insert(class.name.clone(), class.clone());
+    }
+
+    pub fn class(&self, name: &Rc<String>) -> Result<Rc<Class>> {
+        match self.classes.get(name) {
+            Some(class) => Ok(class.clone()),
+            None => Err(Error::new(format!("Could not find class {}", name))),
+        }
+    }
+
+    pub fn add_instance(&mut self, inst: &Rc<Instance>) {
+        if let Some(head) = inst.ty.head() {
+            let key = (inst.class.clone(), head.clone());
+            self.instances.insert(key, inst.clone());
         }
     }
 
     pub fn lookup(&self, id: &Symbol) -> Result<ForAll> {
         match self.map.get(&id.id()) {
             Some(ty) => Ok(ty.clone()),
-            None => Err(Error::new(format!("Could not find {:?}", id))),
+            None => Err(Error::new(format!("Could not find variable {}", id.name()))),
         }
     }
 
@@ -30,6 +66,15 @@ impl Env {
         self.map.insert(id.id(), ty);
     }
 
+    //Constructors are polymorphic in the parameters of their data type
+    //    Some : forall a. a -> Option<a>
+    pub fn add_ctors(&mut self, data: &DataType, ctors: &[Symbol]) {
+        for ctor in ctors {
+            let scheme = ForAll::new(data.params.clone(), ctor.ty().clone());
+            self.extend(ctor, scheme);
+        }
+    }
+
     pub fn begin_scope(&mut self) {
         self.map.begin_scope()
     }
@@ -42,8 +87,84 @@ impl Env {
         self.unify_table.apply_subst(ty)
     }
 
-    pub fn unify<'a>(&mut self, lhs: &'a Type, rhs: &'a Type) -> crate::Result<()> {
-        self.unify_table.unify(lhs, rhs)
+    //The expected type comes from the context of an expression and the
+    //  found type from the expression itself
+    pub fn unify(&mut self, expected: &Type, found: &Type) -> Result<()> {
+        match self.unify_table.unify(expected, found) {
+            Ok(()) => Ok(()),
+            Err(mismatch) => Err(Error::new(self.explain(expected, found, mismatch))),
+        }
+    }
+
+    //The types are named together so that a variable has the same name in
+    //  every part of the message
+    //    Expected (i32, i32) -> i32, found (i32, bool) -> a; bool is not i32
+    fn explain(&mut self, expected: &Type, found: &Type, mismatch: Mismatch) -> String {
+        let mut names = Names::new();
+        let (expected, found) = (self.apply(expected), self.apply(found));
+        let mut msg = format!(
+            "Expected {}, found {}",
+            names.ty(&expected),
+            names.ty(&found)
+        );
+        let reason = match mismatch {
+            Mismatch::Types(l, r) => {
+                let (l, r) = (self.apply(&l), self.apply(&r));
+                match l == expected && r == found {
+                    true => None,
+                    false => Some(format!("{} is not {}", names.ty(&r), names.ty(&l))),
+                }
+            }
+            Mismatch::Infinite(tv, ty) => {
+                let ty = self.apply(&ty);
+                let tv = names.ty(&Type::Var(tv));
+                let msg = format!(
+                    "{} can not be {} as it would contain itself",
+                    tv,
+                    names.ty(&ty)
+                );
+                Some(msg)
+            }
+            Mismatch::Arity(l, r) => match (l.func_parts(), r.func_parts()) {
+                (Some((lparams, _)), Some((rparams, _))) => {
+                    let plural = if rparams.len() == 1 { "" } else { "s" };
+                    Some(format!(
+                        "a function of {} parameter{} is not a function of {}",
+                        rparams.len(),
+                        plural,
+                        lparams.len()
+                    ))
+                }
+                _ => None,
+            },
+        };
+        if let Some(reason) = reason {
+            msg.push_str("; ");
+            msg.push_str(&reason);
+        }
+        msg
+    }
+
+    //Each type variable of an annotation stands for a fresh type variable
+    //  the first time it is seen, so the type parameters of a function are
+    //  the same type in the annotations of its body
+    pub fn annotation(&mut self, ty: &Type, level: u32) -> Type {
+        match *ty {
+            Type::Var(ref tv) => match self.annotations.get(&tv.id) {
+                Some(ty) => ty.clone(),
+                None => {
+                    let ty = Type::Var(self.fresh_tyvar(level));
+                    self.annotations.insert(tv.id, ty.clone());
+                    ty
+                }
+            },
+            Type::Con(..) => ty.clone(),
+            Type::App(ref con, ref args) => {
+                let con = self.annotation(con, level);
+                let args = args.iter().map(|arg| self.annotation(arg, level));
+                Type::App(Box::new(con), args.collect())
+            }
+        }
     }
 
     pub fn fresh_tyvar(&mut self, level: u32) -> TyVar {
@@ -63,4 +184,213 @@ impl Env {
         }
         (tvs, subst.apply(scheme.ty()))
     }
+
+    //The constraints of a type scheme have to hold for the types its
+    //  variables are instantiated with
+    pub(super) fn instantiate_qualified(
+        &mut self,
+        scheme: &ForAll,
+        level: u32,
+        span: Span,
+    ) -> (Vec<TyVar>, Type) {
+        let (tvs, ty) = self.instantiate(scheme, level);
+        let mut subst = Subst::new();
+        for (bv, tv) in scheme.bound_vars().iter().zip(&tvs) {
+            subst.bind(bv, Type::Var(tv.clone()));
+        }
+        for pred in scheme.preds() {
+            let pred = Pred {
+                class: pred.class.clone(),
+                ty: subst.apply(&pred.ty),
+            };
+            self.preds.push((pred, span));
+        }
+        (tvs, ty)
+    }
+
+    pub(super) fn constrain(&mut self, pred: Pred, span: Span) {
+        self.preds.push((pred, span))
+    }
+
+    //Replace the constraints on types with a type constructor at their head
+    //  by the context of the instance for that type constructor. What is
+    //  left are constraints on type variables
+    //    Eq<List<'a>>  becomes  Eq<'a>
+    fn reduce(&mut self) -> Result<Vec<(Pred, Span)>> {
+        let mut work = std::mem::take(&mut self.preds);
+        let mut res: Vec<(Pred, Span)> = Vec::new();
+        while let Some((pred, span)) = work.pop() {
+            let ty = self.apply(&pred.ty);
+            let pred = Pred {
+                class: pred.class,
+                ty,
+            };
+            let key = match pred.ty.head() {
+                Some(head) => (pred.class.clone(), head.clone()),
+                None => {
+                    if !res.iter().any(|(p, _)| *p == pred) {
+                        res.push((pred, span));
+                    }
+                    continue;
+                }
+            };
+            let inst = match self.instances.get(&key) {
+                Some(inst) => inst,
+                None => {
+                    let msg = format!("No instance for {}", pred);
+                    return Err(Error::spanned(msg, span));
+                }
+            };
+            let mut subst = Subst::new();
+            subst.matching(&inst.ty, &pred.ty);
+            for ctx in &inst.context {
+                let pred = Pred {
+                    class: ctx.class.clone(),
+                    ty: subst.apply(&ctx.ty),
+                };
+                work.push((pred, span));
+            }
+        }
+        Ok(res)
+    }
+
+    //Add the constraints on the variables of a type scheme to it
+    pub(super) fn qualify(&mut self, scheme: ForAll) -> Result<ForAll> {
+        let mut schemes = self.qualify_group(vec![scheme])?;
+        Ok(schemes.pop().unwrap())
+    }
+
+    //The type schemes of mutually recursive functions share variables, so a
+    //  constraint goes to every scheme that has its variable
+    pub(super) fn qualify_group(&mut self, schemes: Vec<ForAll>) -> Result<Vec<ForAll>> {
+        let mut preds = vec![Vec::new(); schemes.len()];
+        for (pred, span) in self.reduce()? {
+            let mut used = false;
+            if let Type::Var(ref tv) = pred.ty {
+                for (scheme, preds) in schemes.iter().zip(preds.iter_mut()) {
+                    if scheme.bound_vars().iter().any(|bv| bv.id == tv.id) {
+                        preds.push(pred.clone());
+                        used = true;
+                    }
+                }
+            }
+            if !used {
+                self.preds.push((pred, span))
+            }
+        }
+        let schemes = schemes.into_iter().zip(preds);
+        let schemes = schemes.map(|(scheme, preds)| {
+            ForAll::qualified(scheme.bound_vars().clone(), preds, scheme.ty().clone())
+        });
+        Ok(schemes.collect())
+    }
+
+    //All constraints have to follow from `context`
+    pub(super) fn entail(&mut self, context: &[Pred]) -> Result<()> {
+        for (pred, span) in self.reduce()? {
+            let pred = Pred {
+                class: pred.class,
+                ty: self.apply(&pred.ty),
+            };
+            if !context.contains(&pred) {
+                let msg = format!("Could not deduce {} from the context of the instance", pred);
+                return Err(Error::spanned(msg, span));
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn discard(&mut self, ty: Type, span: Span) {
+        self.discarded.push((ty, span))
+    }
+
+    //Warn about values other than () that are thrown away
+    pub(super) fn check_discarded(&mut self) {
+        for (ty, span) in std::mem::take(&mut self.discarded) {
+            match self.apply(&ty) {
+                Type::Con(TyCon::Unit, _) | Type::Var(_) => {}
+                ty => {
+                    let msg = format!("The result of this expression of type {} is discarded", ty);
+                    crate::warn(Error::warning(msg, span));
+                }
+            }
+        }
+    }
+
+    //Constraints left after the type of a top level function has been
+    //  generalized are on variables that do not appear in its type
+    pub(super) fn check_ambiguous(&mut self) -> Result<()> {
+        match self.reduce()?.pop() {
+            Some((pred, span)) => {
+                let msg = format!("Ambiguous type variable in the constraint {}", pred);
+                Err(Error::spanned(msg, span))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{check, error};
+    use crate::Vector;
+
+    const SHOW: &str = "
+        class Show<a> { show: a -> () }
+        instance Show<i32> { let show(n) { i32_print(n) } }
+        type List<a> = Nil | Cons(a, List<a>)
+    ";
+
+    #[test]
+    fn instances() {
+        let list = "instance Show<a> => Show<List<a>> {
+            let show(l) { match l { Nil => (), Cons(x, _) => show(x) } }
+        }";
+        let src = format!(
+            "{}{}\nlet p(x) {{ show(Cons(x, Nil)) }}\nlet q() {{ p(1) }}",
+            SHOW, list
+        );
+        assert_eq!(
+            check(&[("test", &src)]).unwrap(),
+            vec!["p : forall a. Show<a> => a -> ()", "q : () -> ()"]
+        );
+        let src = format!("{}{}\nlet f() {{ show(Cons(true, Nil)) }}", SHOW, list);
+        assert_eq!(
+            error(check(&[("test", &src)])),
+            "No instance for Show<bool>"
+        );
+        let src = format!("{}let f() {{ show(Cons(1, Nil)) }}", SHOW);
+        assert_eq!(
+            error(check(&[("test", &src)])),
+            "No instance for Show<List<i32>>"
+        );
+        let src = format!("{}{}", SHOW, list.replace("Show<a> => ", ""));
+        assert_eq!(
+            error(check(&[("test", &src)])),
+            "Could not deduce Show<a> from the context of the instance"
+        );
+        let src = format!("{}instance Show<i32> {{ let show(n) {{ () }} }}", SHOW);
+        assert_eq!(
+            error(check(&[("test", &src)])),
+            "Instance Show<i32> already declared"
+        );
+    }
+
+    #[test]
+    fn discarded() {
+        let warnings = |src: &str| {
+            check(&[("test", src)]).unwrap();
+            let warnings = crate::take_warnings();
+            Vector::fmap(warnings.iter(), |w| w.msg().to_string())
+        };
+        assert_eq!(
+            warnings("let f() { 1; i32_print(2); 3 }"),
+            vec!["The result of this expression of type i32 is discarded"]
+        );
+        assert!(warnings("let f(g) { g(1); i32_print(2) }").is_empty());
+        assert_eq!(
+            warnings("let f(g) { g(1); g(2) + 1 }"),
+            vec!["The result of this expression of type i32 is discarded"]
+        );
+    }
 }
@@ -3,10 +3,11 @@
 //    "Efficient and Insightful Generalization" - Oleg Kiselyov
 
 use super::env::Env;
-use super::{ForAll, Type};
-use crate::types::{Kind, TyCon, TyVar};
+use super::{ForAll, Pred, Type};
+use crate::span::Span;
+use crate::types::{Kind, Subst, TyCon, TyVar};
 use crate::xir;
-use crate::Result;
+use crate::{fresh_id, Result};
 use crate::{idtree, Vector};
 use std::rc::Rc;
 
@@ -22,22 +23,73 @@ pub fn mk_func(mut params: Vec<Type>, ret: Type) -> Type {
 pub(super) fn infer(gamma: &mut Env, expr: &idtree::Expr, level: u32) -> Result<(Type, xir::Expr)> {
     use self::Kind::*;
     use self::TyCon::*;
-    use crate::idtree::Expr::*;
-
-    let (ty, expr) = match *expr {
-        UnitLit => (Type::Con(Unit, Star), xir::Expr::UnitLit),
-        I32Lit(n) => (Type::Con(I32, Star), xir::Expr::I32Lit(n)),
-        BoolLit(b) => (Type::Con(Bool, Star), xir::Expr::BoolLit(b)),
-        Var(ref v) => infer_var(gamma, v, level)?,
-        If(ref exp) => infer_if(gamma, exp, level)?,
-        Let(ref exp) => infer_let(gamma, exp, level)?,
-        App(ref callee, ref args) => infer_app(gamma, callee, args, level)?,
+    use crate::idtree::ExprKind::*;
+
+    let span = expr.span();
+    let lit = |kind| xir::Expr::new(kind, span);
+    let (ty, expr) = match *expr.kind() {
+        UnitLit => (Type::Con(Unit, Star), lit(xir::ExprKind::UnitLit)),
+        NumLit(n) => (Type::Con(n.tycon(), Star), lit(xir::ExprKind::NumLit(n))),
+        BoolLit(b) => (Type::Con(Bool, Star), lit(xir::ExprKind::BoolLit(b))),
+        Var(ref v) => infer_var(gamma, v, span, level)?,
+        If(ref exp) => infer_if(gamma, exp, span, level)?,
+        Let(ref exp) => infer_let(gamma, exp, span, level)?,
+        LetRec(ref binds, ref body) => {
+            let binds = infer_fn(gamma, binds, level)?;
+            let (t, body) = infer(gamma, body, level)?;
+            let let_rec = xir::ExprKind::LetRec(binds, Box::new(body));
+            (t, xir::Expr::new(let_rec, span))
+        }
+        Seq(ref e1, ref e2) => {
+            let (t1, e1) = infer(gamma, e1, level)?;
+            gamma.discard(t1, e1.span());
+            let (t2, e2) = infer(gamma, e2, level)?;
+            let seq = xir::ExprKind::Seq(Box::new(e1), Box::new(e2));
+            (t2, xir::Expr::new(seq, span))
+        }
+        App(ref callee, ref args) => infer_app(gamma, callee, args, span, level)?,
+        Ctor(ref ctor) => infer_ctor(gamma, ctor, span, level)?,
+        Match(ref scrut, ref arms) => infer_match(gamma, scrut, arms, span, level)?,
+        Record(ref rec, ref fields) => {
+            let (ty, sub) = record_type(gamma, rec, level);
+            let fields = fields.iter().map(|(index, expr)| (*index, expr));
+            let fields = infer_fields(gamma, rec, fields, &sub, level)?;
+            let expr = xir::Expr::new(xir::ExprKind::Record(ty.clone(), fields), span);
+            (ty, expr)
+        }
+        Project(ref record, ref recs, ref nm) => {
+            let (t1, record) = infer(gamma, record, level)?;
+            let rec = field_record(gamma, &t1, recs);
+            let index = rec.fields.iter().position(|f| f.name == *nm).unwrap() as u32;
+            let (ty, sub) = record_type(gamma, rec, level);
+            gamma.unify(&ty, &t1).map_err(|e| e.at(record.span()))?;
+            let field_ty = gamma.apply(&sub.apply(&rec.fields[index as usize].ty));
+            let ty = gamma.apply(&ty);
+            let project = xir::ExprKind::Project(ty, Box::new(record), index);
+            (field_ty, xir::Expr::new(project, span))
+        }
+        Update(ref record, ref recs, ref fields) => {
+            let (t1, record) = infer(gamma, record, level)?;
+            let rec = field_record(gamma, &t1, recs);
+            let (ty, sub) = record_type(gamma, rec, level);
+            gamma.unify(&ty, &t1).map_err(|e| e.at(record.span()))?;
+            let fields = fields.iter().map(|(nm, expr)| {
+                let index = rec.fields.iter().position(|f| f.name == *nm).unwrap();
+                (index as u32, expr)
+            });
+            let fields = infer_fields(gamma, rec, fields, &sub, level)?;
+            let ty = gamma.apply(&ty);
+            let update = xir::ExprKind::Update(ty.clone(), Box::new(record), fields);
+            (ty, xir::Expr::new(update, span))
+        }
         Lam(ref params, ref body) => {
+            let params_ty = Vector::fmap(params.iter(), |_| Type::Var(gamma.fresh_tyvar(level)));
             gamma.begin_scope();
-            let (ty, expr) = infer_lam(gamma, params, body, level)?;
+            let res = infer_lam(gamma, params, params_ty, body, span, level);
             gamma.end_scope();
-            (ty, expr)
+            res?
         }
+        Annot(ref e, ref annot) => infer_annot(gamma, e, annot, level)?,
     };
     Ok((ty, expr))
 }
@@ -55,21 +107,26 @@ pub fn into_xir_symbol(var: &idtree::Symbol, ty: &Type) -> xir::Symbol {
 //   (foo {a1, b1}) inc_i32 1
 //   read as TyApp(Var(foo),
 //                 [a1, b1])
-fn translate_var(sigma: &ForAll, var: &idtree::Symbol, tvs: Vec<TyVar>) -> xir::Expr {
-    use crate::xir::Expr::*;
+fn translate_var(sigma: &ForAll, var: &idtree::Symbol, tvs: Vec<TyVar>, span: Span) -> xir::Expr {
+    use crate::xir::ExprKind::*;
     let ty_args = Vector::fmap(tvs.into_iter(), Type::Var);
     let var = into_xir_symbol(var, sigma.ty());
-    let var = xir::Expr::Var(var);
+    let var = xir::Expr::new(Var(var), span);
     match ty_args.len() {
         0 => var,
-        _ => TyApp(Box::new(var), ty_args),
+        _ => xir::Expr::new(TyApp(Box::new(var), ty_args), span),
     }
 }
 
-fn infer_var(gamma: &mut Env, var: &idtree::Symbol, level: u32) -> Result<(Type, xir::Expr)> {
-    let sigma = gamma.lookup(var)?;
-    let (tvs, ty) = gamma.instantiate(&sigma, level);
-    let expr = translate_var(&sigma, var, tvs);
+fn infer_var(
+    gamma: &mut Env,
+    var: &idtree::Symbol,
+    span: Span,
+    level: u32,
+) -> Result<(Type, xir::Expr)> {
+    let sigma = gamma.lookup(var).map_err(|e| e.at(span))?;
+    let (tvs, ty) = gamma.instantiate_qualified(&sigma, level, span);
+    let expr = translate_var(&sigma, var, tvs, span);
     Ok((ty, expr))
 }
 
@@ -78,35 +135,91 @@ fn translate_lam(
     params: &[idtree::Symbol],
     params_ty: &[Type],
     retty: Type,
+    span: Span,
 ) -> xir::Expr {
     let params = params
         .iter()
         .zip(params_ty)
         .map(|(v, ty)| into_xir_symbol(v, ty))
         .collect::<Vec<_>>();
-    xir::Expr::Lam(params, Box::new(body), retty)
+    xir::Expr::new(xir::ExprKind::Lam(params, Box::new(body), retty), span)
 }
 
 fn infer_lam(
     gamma: &mut Env,
     params: &[idtree::Symbol],
+    params_ty: Vec<Type>,
     body: &idtree::Expr,
+    span: Span,
     level: u32,
 ) -> Result<(Type, xir::Expr)> {
-    use crate::types::Type::*;
-    let f = |v| {
-        let tv = gamma.fresh_tyvar(level);
-        gamma.extend(v, ForAll::new(vec![], Var(tv.clone())));
-        Var(tv)
-    };
-    let params_ty = Vector::fmap(params.iter(), f);
+    for (param, ty) in params.iter().zip(&params_ty) {
+        gamma.extend(param, ForAll::new(vec![], ty.clone()));
+    }
     let (t1, body) = infer(gamma, body, level + 1)?;
-    let expr = translate_lam(body, params, &params_ty, t1.clone());
+    let expr = translate_lam(body, params, &params_ty, t1.clone(), span);
     let fnty = mk_func(params_ty, t1);
     let fnty = gamma.apply(&fnty);
     Ok((fnty, expr))
 }
 
+//The parameters of an annotated function have their annotated types in
+//  its body. A function whose annotation has type parameters must be at
+//  least as polymorphic as the annotation, so its body can not make them
+//  a type or the same variable
+fn infer_annot(
+    gamma: &mut Env,
+    expr: &idtree::Expr,
+    annot: &idtree::Annot,
+    level: u32,
+) -> Result<(Type, xir::Expr)> {
+    use crate::idtree::ExprKind::Lam;
+    let expected = gamma.annotation(&annot.ty, level);
+    let (t1, e) = match (expr.kind(), expected.func_parts()) {
+        (Lam(ref params, ref body), Some((params_ty, _))) if params.len() == params_ty.len() => {
+            let params_ty = params_ty.to_vec();
+            gamma.begin_scope();
+            let res = infer_lam(gamma, params, params_ty, body, expr.span(), level);
+            gamma.end_scope();
+            res?
+        }
+        _ => infer(gamma, expr, level)?,
+    };
+    if let Err(err) = gamma.unify(&expected, &t1) {
+        let msg = format!("has type {}", gamma.apply(&t1));
+        return Err(err.at(annot.span).label(expr.span(), msg));
+    }
+    let mut rigid: Vec<(&String, TyVar)> = Vec::with_capacity(annot.rigid.len());
+    for (name, tv) in &annot.rigid {
+        let ty = gamma.annotation(&Type::Var(tv.clone()), level);
+        let ty = gamma.apply(&ty);
+        let found = match ty {
+            Type::Var(ref tv) => match rigid.iter().find(|(_, v)| v.id == tv.id) {
+                Some((other, _)) => other.to_string(),
+                None => {
+                    rigid.push((name, tv.clone()));
+                    continue;
+                }
+            },
+            ref ty => format!("{}", ty),
+        };
+        let msg = format!(
+            "Type parameter {} can not be {}, the annotation is more polymorphic than the expression",
+            name, found
+        );
+        return Err(crate::Error::spanned(msg, annot.span));
+    }
+    for pred in &annot.preds {
+        let ty = gamma.annotation(&pred.ty, level);
+        let pred = Pred {
+            class: pred.class.clone(),
+            ty,
+        };
+        gamma.constrain(pred, annot.span);
+    }
+    Ok((gamma.apply(&t1), e))
+}
+
 fn infer_args(
     gamma: &mut Env,
     args: &[idtree::Expr],
@@ -127,38 +240,183 @@ fn infer_app(
     gamma: &mut Env,
     caller: &idtree::Expr,
     args: &[idtree::Expr],
+    span: Span,
     level: u32,
 ) -> Result<(Type, xir::Expr)> {
+    let callee_span = caller.span();
     let (t1, caller) = infer(gamma, caller, level)?;
-    let retty = Type::Var(gamma.fresh_tyvar(level));
     let (t2, args) = infer_args(gamma, args, level)?;
-    let fnty = mk_func(t2, retty.clone());
-    gamma.unify(&t1, &fnty)?;
+    if arity(gamma, &t1, args.len()) == args.len() {
+        return call(gamma, (t1, caller), t2, args, (callee_span, 0), span, level);
+    }
+    //The callee and the arguments are evaluated once, before any call
+    let mut temps = Vec::with_capacity(args.len() + 1);
+    let mut temp = |expr: xir::Expr, ty: &Type| match is_trivial(&expr) {
+        true => expr,
+        false => {
+            let var = xir::Symbol::new(Rc::new("arg".to_string()), ty.clone(), fresh_id());
+            temps.push(xir::Bind::new(var.clone(), expr));
+            xir::Expr::new(xir::ExprKind::Var(var), span)
+        }
+    };
+    let caller = temp(caller, &t1);
+    let args = Vector::fmap(args.into_iter().zip(&t2), |(arg, ty)| temp(arg, ty));
+    let callee = (t1, caller);
+    let (t, mut expr) = apply_args(gamma, callee, t2, args, (callee_span, 0), span, level)?;
+    for bind in temps.into_iter().rev() {
+        let let_exp = xir::Let::new(bind, expr);
+        expr = xir::Expr::new(xir::ExprKind::Let(Box::new(let_exp)), span);
+    }
+    Ok((t, expr))
+}
+
+//The number of parameters of the callee when its type is known to be a
+//  function. Calls with no arguments and calls of functions without
+//  parameters always use all arguments
+fn arity(gamma: &mut Env, ty: &Type, args: usize) -> usize {
+    match gamma.apply(ty).func_parts() {
+        Some((params, _)) if !params.is_empty() && args != 0 => params.len(),
+        _ => args,
+    }
+}
+
+fn is_trivial(expr: &xir::Expr) -> bool {
+    use crate::xir::ExprKind::*;
+    match *expr.kind() {
+        UnitLit | NumLit(_) | BoolLit(_) | Var(_) => true,
+        TyApp(ref e, _) => is_trivial(e),
+        _ => false,
+    }
+}
+
+//Functions are applied to as many arguments as they have parameters, the
+//  result is applied to the rest
+//    f(a, b, c)  becomes  f(a, b)(c)     when f has two parameters
+//  and a function applied to fewer arguments is wrapped in a lambda taking
+//  the others, so the call in the wrapper is at full arity
+//    f(a)        becomes  \x0 -> f(a, x0)
+fn apply_args(
+    gamma: &mut Env,
+    callee: (Type, xir::Expr),
+    mut tys: Vec<Type>,
+    mut args: Vec<xir::Expr>,
+    site: (Span, usize),
+    span: Span,
+    level: u32,
+) -> Result<(Type, xir::Expr)> {
+    let arity = arity(gamma, &callee.0, args.len());
+    if arity < args.len() {
+        let rest_tys = tys.split_off(arity);
+        let rest = args.split_off(arity);
+        let callee = call(gamma, callee, tys, args, site, span, level)?;
+        let site = (site.0, site.1 + arity);
+        return apply_args(gamma, callee, rest_tys, rest, site, span, level);
+    }
+    let params = match gamma.apply(&callee.0).func_parts() {
+        Some((params, _)) if arity > args.len() => params[args.len()..].to_vec(),
+        _ => return call(gamma, callee, tys, args, site, span, level),
+    };
+    let params = params.into_iter().enumerate();
+    let params = Vector::fmap(params, |(i, ty)| {
+        xir::Symbol::new(Rc::new(format!("x{}", i)), ty, fresh_id())
+    });
+    for param in &params {
+        tys.push(param.ty().clone());
+        args.push(xir::Expr::new(xir::ExprKind::Var(param.clone()), span));
+    }
+    let (retty, body) = call(gamma, callee, tys, args, site, span, level)?;
+    let fnty = mk_func(
+        Vector::fmap(params.iter(), |p| p.ty().clone()),
+        retty.clone(),
+    );
+    let lam = xir::ExprKind::Lam(params, Box::new(body), retty);
+    Ok((gamma.apply(&fnty), xir::Expr::new(lam, span)))
+}
+
+fn call(
+    gamma: &mut Env,
+    (t1, callee): (Type, xir::Expr),
+    tys: Vec<Type>,
+    args: Vec<xir::Expr>,
+    //The callee written in the source and the number of arguments it was
+    //  applied to before this call
+    (callee_span, applied): (Span, usize),
+    span: Span,
+    level: u32,
+) -> Result<(Type, xir::Expr)> {
+    let retty = Type::Var(gamma.fresh_tyvar(level));
+    let fnty = mk_func(tys, retty.clone());
+    if let Err(e) = gamma.unify(&t1, &fnty) {
+        let callee_ty = gamma.apply(&t1);
+        let msg = match applied {
+            0 => format!("has type {}", callee_ty),
+            1 => format!("has type {} when applied to 1 argument", callee_ty),
+            n => format!("has type {} when applied to {} arguments", callee_ty, n),
+        };
+        return Err(e.at(span).label(callee_span, msg));
+    }
     let t = gamma.apply(&retty);
-    let app = xir::Expr::App(t1, Box::new(caller), args);
+    let app = xir::Expr::new(xir::ExprKind::App(t1, Box::new(callee), args), span);
     Ok((t, app))
 }
 
+fn infer_ctor(
+    gamma: &mut Env,
+    ctor: &idtree::Ctor,
+    span: Span,
+    level: u32,
+) -> Result<(Type, xir::Expr)> {
+    let sigma = gamma.lookup(ctor.symbol()).map_err(|e| e.at(span))?;
+    let (_, ctor_ty) = gamma.instantiate(&sigma, level);
+    let (tys, args) = infer_args(gamma, ctor.args(), level)?;
+    let ty = match tys.is_empty() {
+        true => ctor_ty,
+        false => {
+            let retty = Type::Var(gamma.fresh_tyvar(level));
+            let fnty = mk_func(tys, retty.clone());
+            gamma.unify(&ctor_ty, &fnty).map_err(|e| e.at(span))?;
+            gamma.apply(&retty)
+        }
+    };
+    let symbol = into_xir_symbol(ctor.symbol(), sigma.ty());
+    let ctor = xir::Ctor::new(symbol, ctor.tag(), args, ty.clone());
+    let expr = xir::Expr::new(xir::ExprKind::Ctor(Box::new(ctor)), span);
+    Ok((ty, expr))
+}
+
 fn is_value(expr: &idtree::Expr) -> bool {
-    use crate::idtree::Expr::*;
-    matches!(*expr, UnitLit | BoolLit(_) | I32Lit(_) | Lam(..) | Var(_))
+    use crate::idtree::ExprKind::*;
+    match *expr.kind() {
+        UnitLit | BoolLit(_) | NumLit(_) | Lam(..) | Var(_) => true,
+        Annot(ref e, _) => is_value(e),
+        Ctor(ref ctor) => ctor.args().iter().all(is_value),
+        Record(_, ref fields) => fields.iter().all(|(_, expr)| is_value(expr)),
+        _ => false,
+    }
 }
 
-fn infer_let(gamma: &mut Env, let_exp: &idtree::Let, level: u32) -> Result<(Type, xir::Expr)> {
+fn infer_let(
+    gamma: &mut Env,
+    let_exp: &idtree::Let,
+    span: Span,
+    level: u32,
+) -> Result<(Type, xir::Expr)> {
     let bind = let_exp.bind();
     let (t1, e1) = infer(gamma, bind.expr(), level + 1)?;
 
+    let t1 = gamma.apply(&t1);
     let name = into_xir_symbol(bind.symbol(), &t1);
     // Do value restriction: Don't generalize unless the bind expr is a value
     let t2 = match is_value(bind.expr()) {
-        true => t1.generalize(level),
-        false => ForAll::new(vec![], t1),
+        true => gamma.qualify(t1.generalize(level))?,
+        false => t1.monomorphic(level),
     };
     gamma.extend(bind.symbol(), t2.clone());
     let (t, e2) = infer(gamma, let_exp.expr(), level)?;
-    let tylam = xir::Expr::TyLam(t2.bound_vars().clone(), Box::new(e1));
+    let tylam = xir::ExprKind::TyLam(t2.bound_vars().clone(), Box::new(e1));
+    let tylam = xir::Expr::new(tylam, bind.expr().span());
     let let_exp = xir::Let::new(xir::Bind::new(name, tylam), e2);
-    let expr = xir::Expr::Let(Box::new(let_exp));
+    let expr = xir::Expr::new(xir::ExprKind::Let(Box::new(let_exp)), span);
 
     Ok((t, expr))
 }
@@ -183,16 +441,28 @@ pub(super) fn infer_fn(
 
     let mut betas = Vec::with_capacity(bindings.len());
     for bind in bindings {
-        let beta = Type::Var(gamma.fresh_tyvar(level));
+        let beta = Type::Var(gamma.fresh_tyvar(level + 1));
         gamma.extend(bind.symbol(), ForAll::new(vec![], beta.clone()));
         betas.push(beta);
     }
 
-    let mut new_binds = Vec::with_capacity(bindings.len());
-    for (bind, beta) in bindings.iter().zip(betas) {
-        let (t1, e) = infer(gamma, bind.expr(), level)?;
-        gamma.unify(&beta, &t1)?;
+    //Functions of the group are monomorphic in each other's bodies so they
+    //  are only generalized once all of them have been inferred
+    let mut exprs = Vec::with_capacity(bindings.len());
+    for (bind, beta) in bindings.iter().zip(&betas) {
+        let (t1, e) = infer(gamma, bind.expr(), level + 1)?;
+        gamma
+            .unify(beta, &t1)
+            .map_err(|e| e.at(bind.expr().span()))?;
+        exprs.push(e);
+    }
+
+    let tys = Vector::fmap(betas.iter(), |beta| gamma.apply(beta));
+    let schemes = Vector::fmap(tys.iter(), |t1| t1.generalize(level));
+    let schemes = gamma.qualify_group(schemes)?;
 
+    let mut new_binds = Vec::with_capacity(bindings.len());
+    for (((bind, e), t1), t2) in bindings.iter().zip(exprs).zip(tys).zip(schemes) {
         //Add type abstraction to close over the free type variables
         //   in the body of a lambda. This adds polymorphism to expressions
         //e.g. the following gets translated as
@@ -200,12 +470,12 @@ pub(super) fn infer_fn(
         //into
         //   let foo = Λ a b. ( λf. λy. f x )
         //
-        let t2 = t1.generalize(level);
         let bv = t2.bound_vars().clone();
-        let e = xir::Expr::TyLam(bv.clone(), Box::new(e));
+        let span = e.span();
+        let e = xir::Expr::new(xir::ExprKind::TyLam(bv, Box::new(e)), span);
         let name = into_xir_symbol(bind.symbol(), &t1);
 
-        gamma.extend(bind.symbol(), ForAll::new(bv, t1));
+        gamma.extend(bind.symbol(), t2);
 
         let bind = xir::Bind::new(name, e);
         new_binds.push(bind);
@@ -213,16 +483,430 @@ pub(super) fn infer_fn(
     Ok(new_binds)
 }
 
-fn infer_if(gamma: &mut Env, if_expr: &idtree::If, level: u32) -> Result<(Type, xir::Expr)> {
+//The methods of an instance have the types declared in the class with the
+//  parameter of the class replaced by the type of the instance. They are
+//  polymorphic in the variables of the instance
+//    instance Eq<a> => Eq<List<a>> { let eq(l, r) { ... } }
+//  gives
+//    Eq<List>.eq : forall a. Eq<a> => List<a> -> List<a> -> bool
+pub(super) fn infer_instance(
+    gamma: &mut Env,
+    inst: &idtree::Instance,
+    bindings: &[idtree::Bind],
+    level: u32,
+) -> Result<Vec<xir::Bind>> {
+    let class = gamma.class(&inst.class)?;
+    let mut sub = Subst::new();
+    let params = Vector::fmap(inst.params.iter(), |param| {
+        let tv = gamma.fresh_tyvar(level + 1);
+        sub.bind(param, Type::Var(tv.clone()));
+        tv
+    });
+    let ty = sub.apply(&inst.ty);
+    let context = Vector::fmap(inst.context.iter(), |pred| Pred {
+        class: pred.class.clone(),
+        ty: sub.apply(&pred.ty),
+    });
+
+    let mut new_binds = Vec::with_capacity(bindings.len());
+    for (bind, method) in bindings.iter().zip(&class.methods) {
+        let mut method_sub = Subst::new();
+        method_sub.bind(&class.param, ty.clone());
+        let expected = method_sub.apply(&method.ty);
+
+        let declared = format!("{} is declared with type {}", method.name, expected);
+
+        let (t1, e) = infer(gamma, bind.expr(), level + 1)?;
+        let span = e.span();
+        if let Err(err) = gamma.unify(&expected, &t1) {
+            return Err(err.at(span).label(span, declared));
+        }
+        //The variables of the instance can not be instantiated by a method
+        let mut bv: Vec<TyVar> = Vec::with_capacity(params.len());
+        for param in &params {
+            match gamma.apply(&Type::Var(param.clone())) {
+                Type::Var(tv) if bv.iter().all(|v| v.id != tv.id) => bv.push(tv),
+                _ => {
+                    let msg = format!(
+                        "{} is less polymorphic than its class requires",
+                        method.name
+                    );
+                    return Err(crate::Error::spanned(msg, span).label(span, declared));
+                }
+            }
+        }
+        let context = Vector::fmap(context.iter(), |pred| Pred {
+            class: pred.class.clone(),
+            ty: gamma.apply(&pred.ty),
+        });
+        gamma.entail(&context)?;
+
+        let t1 = gamma.apply(&t1);
+        let e = xir::Expr::new(xir::ExprKind::TyLam(bv, Box::new(e)), span);
+        new_binds.push(xir::Bind::new(into_xir_symbol(bind.symbol(), &t1), e));
+    }
+    Ok(new_binds)
+}
+
+fn infer_if(
+    gamma: &mut Env,
+    if_expr: &idtree::If,
+    span: Span,
+    level: u32,
+) -> Result<(Type, xir::Expr)> {
     let (t1, cond) = infer(gamma, if_expr.cond(), level)?;
     let (t2, texp) = infer(gamma, if_expr.texpr(), level)?;
-    let (t3, fexp) = infer(gamma, if_expr.fexpr(), level)?;
 
-    gamma.unify(&t1, &Type::Con(TyCon::Bool, Kind::Star))?;
-    gamma.unify(&t2, &t3)?;
+    gamma
+        .unify(&Type::Con(TyCon::Bool, Kind::Star), &t1)
+        .map_err(|e| e.at(cond.span()))?;
+    let (t3, fexp) = match if_expr.fexpr() {
+        Some(fexpr) => infer(gamma, fexpr, level)?,
+        None => {
+            let ty = gamma.apply(&t2);
+            if gamma.unify(&Type::unit(), &t2).is_err() {
+                let msg = format!(
+                    "An if without an else must have type (), this branch has type {}",
+                    ty
+                );
+                return Err(crate::Error::spanned(msg, texp.span()));
+            }
+            (Type::unit(), xir::Expr::new(xir::ExprKind::UnitLit, span))
+        }
+    };
+    if let Err(e) = gamma.unify(&t2, &t3) {
+        let msg = format!("this branch has type {}", gamma.apply(&t2));
+        return Err(e.at(fexp.span()).label(texp.span(), msg));
+    }
 
     let ty = gamma.apply(&t2);
     let if_expr = xir::If::new(cond, texp, fexp, ty.clone());
-    let if_expr = xir::Expr::If(Box::new(if_expr));
+    let if_expr = xir::Expr::new(xir::ExprKind::If(Box::new(if_expr)), span);
     Ok((ty, if_expr))
 }
+
+//The scrutinee is bound to a variable so that the decision tree built by
+//  MatchCompile can refer to it
+//    match e { p1 => e1 ... }
+//  becomes
+//    let scrut = e in match scrut { p1 => e1 ... }
+fn infer_match(
+    gamma: &mut Env,
+    scrut: &idtree::Expr,
+    arms: &[idtree::Arm],
+    span: Span,
+    level: u32,
+) -> Result<(Type, xir::Expr)> {
+    let (t1, scrut) = infer(gamma, scrut, level)?;
+    let retty = Type::Var(gamma.fresh_tyvar(level));
+    let mut prev_span = None;
+    let mut new_arms = Vec::with_capacity(arms.len());
+    for arm in arms {
+        gamma.begin_scope();
+        let pat = infer_pat(gamma, arm.pat(), &t1, level).map_err(|e| e.at(arm.span()))?;
+        let (t2, expr) = infer(gamma, arm.expr(), level)?;
+        gamma.end_scope();
+        if let Err(e) = gamma.unify(&retty, &t2) {
+            let msg = format!("this arm has type {}", gamma.apply(&retty));
+            let e = e.at(expr.span());
+            return Err(match prev_span {
+                Some(prev_span) => e.label(prev_span, msg),
+                None => e,
+            });
+        }
+        prev_span = Some(expr.span());
+        new_arms.push(xir::Arm::new(pat, expr, arm.span()));
+    }
+
+    let ty = gamma.apply(&retty);
+    let t1 = gamma.apply(&t1);
+    let var = xir::Symbol::new(Rc::new("scrut".to_string()), t1, fresh_id());
+    let match_expr = xir::Match::new(var.clone(), new_arms, ty.clone());
+    let match_expr = xir::Expr::new(xir::ExprKind::Match(Box::new(match_expr)), span);
+    let let_exp = xir::Let::new(xir::Bind::new(var, scrut), match_expr);
+    let expr = xir::Expr::new(xir::ExprKind::Let(Box::new(let_exp)), span);
+    Ok((ty, expr))
+}
+
+//Variables bound by a pattern are monomorphic
+fn infer_pat(
+    gamma: &mut Env,
+    pat: &idtree::Pattern,
+    ty: &Type,
+    level: u32,
+) -> Result<xir::Pattern> {
+    use crate::idtree::Pattern::*;
+    let con = |tycon| Type::Con(tycon, Kind::Star);
+    let pat = match *pat {
+        Wildcard => xir::Pattern::Wildcard,
+        Var(ref var) => {
+            gamma.extend(var, ForAll::new(vec![], ty.clone()));
+            xir::Pattern::Var(into_xir_symbol(var, ty))
+        }
+        UnitLit => {
+            gamma.unify(ty, &con(TyCon::Unit))?;
+            xir::Pattern::UnitLit
+        }
+        NumLit(n) => {
+            gamma.unify(ty, &con(n.tycon()))?;
+            xir::Pattern::NumLit(n)
+        }
+        BoolLit(b) => {
+            gamma.unify(ty, &con(TyCon::Bool))?;
+            xir::Pattern::BoolLit(b)
+        }
+        Ctor(ref ctor, tag, ref args) => {
+            let sigma = gamma.lookup(ctor)?;
+            let (_, ctor_ty) = gamma.instantiate(&sigma, level);
+            let tys = Vector::fmap(args.iter(), |_| Type::Var(gamma.fresh_tyvar(level)));
+            let fnty = match tys.is_empty() {
+                true => ty.clone(),
+                false => mk_func(tys.clone(), ty.clone()),
+            };
+            gamma.unify(&ctor_ty, &fnty)?;
+            let mut new_args = Vec::with_capacity(args.len());
+            for (arg, ty) in args.iter().zip(tys) {
+                new_args.push(infer_pat(gamma, arg, &ty, level)?);
+            }
+            xir::Pattern::Ctor(into_xir_symbol(ctor, sigma.ty()), tag, new_args)
+        }
+    };
+    Ok(pat)
+}
+
+//The record a field is taken from is the type of the record expression
+//  when it is already known, and the last declared record otherwise
+fn field_record<'a>(
+    gamma: &mut Env,
+    ty: &Type,
+    recs: &'a [Rc<idtree::Record>],
+) -> &'a idtree::Record {
+    let ty = gamma.apply(ty);
+    let known = recs.iter().find(|rec| match ty.head() {
+        Some(TyCon::NewType(name)) => rec.name == *name,
+        _ => false,
+    });
+    known.unwrap_or_else(|| recs.last().unwrap())
+}
+
+//An instance of a record type with fresh type variables for its parameters
+fn record_type(gamma: &mut Env, rec: &idtree::Record, level: u32) -> (Type, Subst) {
+    let mut sub = Subst::new();
+    let con = Type::Con(
+        TyCon::NewType(rec.name.clone()),
+        Kind::nary(rec.params.len()),
+    );
+    if rec.params.is_empty() {
+        return (con, sub);
+    }
+    let args = Vector::fmap(rec.params.iter(), |param| {
+        let tv = Type::Var(gamma.fresh_tyvar(level));
+        sub.bind(param, tv.clone());
+        tv
+    });
+    (Type::App(Box::new(con), args), sub)
+}
+
+fn infer_fields<'a>(
+    gamma: &mut Env,
+    rec: &idtree::Record,
+    fields: impl Iterator<Item = (u32, &'a idtree::Expr)>,
+    sub: &Subst,
+    level: u32,
+) -> Result<Vec<(u32, xir::Expr)>> {
+    let mut res = Vec::new();
+    for (index, expr) in fields {
+        let (t1, expr) = infer(gamma, expr, level)?;
+        let field = &rec.fields[index as usize];
+        if let Err(e) = gamma.unify(&sub.apply(&field.ty), &t1) {
+            return Err(e.at(expr.span()));
+        }
+        res.push((index, expr));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{check, error};
+
+    #[test]
+    fn annotations() {
+        let res = check(&[(
+            "test",
+            "let first<a, b>(x: a, y: b) -> a { let z: a = x; z }",
+        )]);
+        assert_eq!(res.unwrap(), vec!["first : forall a b. (a, b) -> a"]);
+        let res = check(&[("test", "let inc(n: i64) { n + 1i64 }\nlet f() { inc(1) }")]);
+        assert_eq!(
+            error(res),
+            "Expected i64 -> i64, found i32 -> a; i32 is not i64"
+        );
+        let res = check(&[("test", "let bad<a>(x: a) -> a { x + 1 }")]);
+        assert_eq!(
+            error(res),
+            "Type parameter a can not be i32, the annotation is more polymorphic than the expression"
+        );
+        let res = check(&[("test", "let swap<a, b>(x: a, y: b) -> a { y }")]);
+        assert_eq!(
+            error(res),
+            "Type parameter b can not be a, the annotation is more polymorphic than the expression"
+        );
+        let res = check(&[("test", "let f() { let x: bool = 1; x }")]);
+        assert_eq!(error(res), "Expected bool, found i32");
+        //Annotations are written the way types are printed
+        let src = "
+            let apply<a, b>(f: (a, a) -> b, x: a) -> b { f(x, x) }
+            let thunk<a>(x: a) -> () -> a { let rec f() { x }; f }
+            let adder(n: i32) -> i32 -> i32 { \\m -> n + m }
+            let twice<a: Num>(f: a -> a, x: a) -> a { f(f(x)) }
+            let unit(f: (()) -> i32) -> i32 { f(()) }
+            class Def<a> { def: () -> a }
+            instance Def<i32> { let def() { 7 } }
+            let seven() -> i32 { def() }
+            let sorted<a: Eq + Ord>(x: a, y: a, z: a) -> bool { x < y && y < z }";
+        assert_eq!(
+            check(&[("test", src)]).unwrap(),
+            vec![
+                "apply : forall a b. ((a, a) -> b, a) -> b",
+                "thunk : forall a. a -> () -> a",
+                "adder : i32 -> i32 -> i32",
+                "twice : forall a. Num<a> => (a -> a, a) -> a",
+                "unit : ((()) -> i32) -> i32",
+                "seven : () -> i32",
+                "sorted : forall a. (Eq<a>, Ord<a>) => (a, a, a) -> bool",
+            ]
+        );
+        let res = check(&[("test", "let f<a: Show>(x: a) -> a { x }")]);
+        assert_eq!(error(res), "Could not find class Show");
+    }
+
+    //A printed type scheme annotates the function it was printed for
+    #[test]
+    fn printed_schemes() {
+        let src = "
+            let compose(f, g) { \\x -> f(g(x)) }
+            let thunk(x) { let rec f() { x }; f }
+            let unit(f) { f(()) }
+            let sum3(a, b, c) { a + b + c == c }";
+        let schemes = check(&[("test", src)]).unwrap();
+        assert_eq!(
+            schemes,
+            vec![
+                "compose : forall a b c. (a -> b, c -> a) -> c -> b",
+                "thunk : forall a. a -> () -> a",
+                "unit : forall a. ((()) -> a) -> a",
+                "sum3 : forall a. (Eq<a>, Num<a>) => (a, a, a) -> bool",
+            ]
+        );
+        let mut annotated = src.to_string();
+        for scheme in &schemes {
+            let (name, scheme) = scheme.split_once(" : ").unwrap();
+            let check = format!("\nlet {0}_again() {{ let g: {1} = {0}; g }}", name, scheme);
+            annotated.push_str(&check);
+        }
+        let res = check(&[("test", &annotated)]).unwrap();
+        assert_eq!(res[..schemes.len()], schemes[..]);
+        assert_eq!(
+            res[4],
+            "compose_again : forall a b c. () -> (a -> b, c -> a) -> c -> b"
+        );
+        assert_eq!(
+            res[7],
+            "sum3_again : forall a. (Eq<a>, Num<a>) => () -> (a, a, a) -> bool"
+        );
+        let wrong = "forall a b. (a -> b, a -> b) -> a -> b";
+        let res = check(&[(
+            "test",
+            &format!("{}\nlet f() {{ let g: {} = compose; g }}", src, wrong),
+        )]);
+        assert_eq!(
+            error(res),
+            "Type parameter b can not be a, the annotation is more polymorphic than the expression"
+        );
+        let res = check(&[("test", "let f() { let x: Eq<i32, i32> => i32 = 1; x }")]);
+        assert_eq!(
+            error(res),
+            "Only classes applied to a type, like Eq<a>, can be constraints"
+        );
+    }
+
+    #[test]
+    fn records() {
+        let decls = "type Pt = {x: i32, y: i32}\ntype Pt2 = {x: bool, z: i32}\n";
+        let src = format!(
+            "{}{}",
+            decls,
+            "let a() { {x = 3, y = 1} }
+             let b(p: Pt) { p.x }
+             let c() { let p = {x = 1, y = 2}; p.x + {p with x = 4}.y }
+             let d(p) { p.x }"
+        );
+        assert_eq!(
+            check(&[("test", &src)]).unwrap(),
+            vec![
+                "a : () -> Pt",
+                "b : Pt -> i32",
+                "c : () -> i32",
+                "d : Pt2 -> bool"
+            ]
+        );
+        let res = check(&[("test", &format!("{}let f() {{ {{x = true}} }}", decls))]);
+        assert_eq!(error(res), "Missing field z in record Pt2");
+        let res = check(&[("test", &format!("{}let f() {{ {{y = 1, z = 2}} }}", decls))]);
+        assert_eq!(error(res), "No record has all the fields y, z");
+        let res = check(&[("test", &format!("{}let f(p: Pt) {{ p.z }}", decls))]);
+        assert_eq!(error(res), "Expected Pt2, found Pt");
+    }
+
+    #[test]
+    fn type_errors() {
+        let compose = "let compose(f, g) { \\x -> f(g(x)) }";
+        let res = check(&[("test", compose)]);
+        assert_eq!(
+            res.unwrap(),
+            vec!["compose : forall a b c. (a -> b, c -> a) -> c -> b"]
+        );
+        let res = check(&[("test", &format!("{}\nlet t() {{ compose(1, 2) }}", compose))]);
+        assert_eq!(
+            error(res),
+            "Expected (a -> b, c -> a) -> c -> b, found (i32, i32) -> d; i32 is not a -> b"
+        );
+        let res = check(&[("test", "let f(x) { x(x) }")]);
+        assert_eq!(
+            error(res),
+            "Expected a, found a -> b; a can not be a -> b as it would contain itself"
+        );
+        let src = format!("{}\nlet t() {{ compose(i32_add, \\x -> x) }}", compose);
+        assert_eq!(
+            error(check(&[("test", &src)])),
+            "Expected (a -> b, c -> a) -> c -> b, found ((i32, i32) -> i32, d -> d) -> e; \
+             a function of 2 parameters is not a function of 1"
+        );
+        let res = check(&[("test", "let t() { foo(1) }")]);
+        assert_eq!(error(res), "Could not find variable foo");
+        let res = check(&[("test", "let t() { if true { 1 } }")]);
+        assert_eq!(
+            error(res),
+            "An if without an else must have type (), this branch has type i32"
+        );
+    }
+
+    #[test]
+    fn partial_application() {
+        let add = "let add(a, b, c) { a + b * 10 + c * 100 }";
+        let src = format!("{}\nlet t() {{ add(1) }}\nlet u() {{ add(1, 2)(3) }}", add);
+        assert_eq!(
+            check(&[("test", &src)]).unwrap(),
+            vec![
+                "add : (i32, i32, i32) -> i32",
+                "t : () -> (i32, i32) -> i32",
+                "u : () -> i32"
+            ]
+        );
+        let src = format!("{}\nlet t() {{ add(1, true) }}", add);
+        assert!(check(&[("test", &src)]).is_err());
+        let res = check(&[("test", "let t() { i32_add(1, 2, 3) }")]);
+        assert_eq!(error(res), "Expected i32, found i32 -> a");
+    }
+}
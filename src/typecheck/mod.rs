@@ -5,5 +5,6 @@ mod unify;
 
 type Type = crate::types::Type<crate::types::TyVar>;
 type ForAll = crate::types::ForAll<crate::types::TyVar>;
+type Pred = crate::types::Pred<crate::types::TyVar>;
 
 pub use self::typecheck::TypeChecker;
@@ -1,10 +1,11 @@
 use super::env::Env;
-use super::hm::{infer_fn, into_xir_symbol};
+use super::hm::{infer_fn, infer_instance, into_xir_symbol};
 use crate::idtree;
-use crate::types::ForAll;
+use crate::types::{ForAll, Pred, Subst, TyVar, Type};
 use crate::xir;
 use crate::{Result, Vector};
 
+#[derive(Clone)]
 pub struct TypeChecker {
     gamma: Env,
 }
@@ -20,6 +21,9 @@ impl crate::Pass for TypeChecker {
     type Output = Vec<xir::Module>;
 
     fn run(mut self, module_vec: Self::Input) -> Result<Self::Output> {
+        for module in &module_vec {
+            self.add_classes(module);
+        }
         let res = Vector::map(&module_vec, |module| self.tc_module(module))?;
         Ok(res)
     }
@@ -30,20 +34,37 @@ impl TypeChecker {
         TypeChecker { gamma: Env::new() }
     }
 
-    fn tc_module(&mut self, module: &idtree::Module) -> Result<xir::Module> {
+    //Instances can be used before they are declared
+    pub fn add_classes(&mut self, module: &idtree::Module) {
+        for decl in module.decls() {
+            match *decl {
+                idtree::Decl::Class(ref class, _) => self.gamma.add_class(class),
+                idtree::Decl::Instance(ref inst, _) => self.gamma.add_instance(inst),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn lookup(&self, sym: &idtree::Symbol) -> Result<ForAll<TyVar>> {
+        self.gamma.lookup(sym)
+    }
+
+    pub fn tc_module(&mut self, module: &idtree::Module) -> Result<xir::Module> {
         let decls = Vector::map(module.decls(), |decl| self.tc_decl(decl))?;
         Ok(xir::Module::new(module.name().clone(), decls))
     }
 
     fn tc_decl(&mut self, decl: &idtree::Decl) -> Result<xir::Decl> {
         let res = match *decl {
-            idtree::Decl::Extern(ref v) => {
+            idtree::Decl::Extern(ref v, span) => {
                 self.gamma.extend(v, ForAll::new(vec![], v.ty().clone()));
                 let v = into_xir_symbol(v, v.ty());
-                xir::Decl::Extern(v)
+                xir::Decl::Extern(v, span)
             }
             idtree::Decl::Let(ref bind) => {
-                let b = infer_fn(&mut self.gamma, bind, 1)?;
+                let b = infer_fn(&mut self.gamma, bind, 0)?;
+                self.gamma.check_ambiguous()?;
+                self.gamma.check_discarded();
                 let r = b.iter().map(|b| bind_subst(b, &mut self.gamma)).collect();
                 /*
                 println!("{:?}", bind);
@@ -52,6 +73,37 @@ impl TypeChecker {
                 */
                 xir::Decl::Let(r)
             }
+            idtree::Decl::Type(ref data, ref ctors) => {
+                self.gamma.add_ctors(data, ctors);
+                xir::Decl::Type(data.clone())
+            }
+            idtree::Decl::Record(ref rec) => xir::Decl::Record(rec.clone()),
+            //    class Eq<a> { eq: a -> a -> bool }
+            //  gives
+            //    eq : forall a. Eq<a> => a -> a -> bool
+            idtree::Decl::Class(ref class, ref methods) => {
+                let param = self.gamma.fresh_tyvar(0);
+                let mut sub = Subst::new();
+                sub.bind(&class.param, Type::Var(param.clone()));
+                let pred = Pred {
+                    class: class.name.clone(),
+                    ty: Type::Var(param.clone()),
+                };
+                for method in methods {
+                    let params = vec![param.clone()];
+                    let ty = sub.apply(method.ty());
+                    let scheme = ForAll::qualified(params, vec![pred.clone()], ty);
+                    self.gamma.extend(method, scheme);
+                }
+                let methods = Vector::fmap(methods.iter(), |m| into_xir_symbol(m, m.ty()));
+                xir::Decl::Class(class.clone(), methods)
+            }
+            idtree::Decl::Instance(ref inst, ref binds) => {
+                let b = infer_instance(&mut self.gamma, inst, binds, 0)?;
+                self.gamma.check_discarded();
+                let r = b.iter().map(|b| bind_subst(b, &mut self.gamma)).collect();
+                xir::Decl::Instance(inst.clone(), r)
+            }
         };
         Ok(res)
     }
@@ -68,11 +120,10 @@ fn bind_subst(bind: &xir::Bind, sub: &mut Env) -> xir::Bind {
 }
 
 fn subst(expr: &xir::Expr, sub: &mut Env) -> xir::Expr {
-    use crate::xir::Expr::*;
-    use crate::xir::*;
-    match *expr {
+    use crate::xir::ExprKind::*;
+    let kind = match *expr.kind() {
         UnitLit => UnitLit,
-        I32Lit(n) => I32Lit(n),
+        NumLit(n) => NumLit(n),
         BoolLit(b) => BoolLit(b),
         Var(ref id) => Var(mk_symbol(id, sub)),
         Lam(ref proto, ref body, ref retty) => {
@@ -85,22 +136,27 @@ fn subst(expr: &xir::Expr, sub: &mut Env) -> xir::Expr {
                 subst(e.cond(), sub),
                 subst(e.texpr(), sub),
                 subst(e.fexpr(), sub),
-                e.ty().clone(),
+                sub.apply(e.ty()),
             );
-            Expr::If(Box::new(if_expr))
+            If(Box::new(if_expr))
         }
         App(ref ty, ref callee, ref args) => {
             let callee = subst(callee, sub);
             let args = args.iter().map(|arg| subst(arg, sub)).collect::<Vec<_>>();
             let ty = sub.apply(ty);
-            xir::Expr::App(ty, Box::new(callee), args)
+            App(ty, Box::new(callee), args)
         }
         Let(ref le) => {
             let bind = bind_subst(le.bind(), sub);
             let expr = subst(le.expr(), sub);
             let expr = xir::Let::new(bind, expr);
-            Expr::Let(Box::new(expr))
+            Let(Box::new(expr))
+        }
+        LetRec(ref binds, ref e) => {
+            let binds = binds.iter().map(|b| bind_subst(b, sub)).collect();
+            LetRec(binds, Box::new(subst(e, sub)))
         }
+        Seq(ref e1, ref e2) => Seq(Box::new(subst(e1, sub)), Box::new(subst(e2, sub))),
         TyLam(ref args, ref b) => {
             let body = subst(b, sub);
             TyLam(args.clone(), Box::new(body))
@@ -110,5 +166,60 @@ fn subst(expr: &xir::Expr, sub: &mut Env) -> xir::Expr {
             let args = args.iter().map(|ty| sub.apply(ty)).collect();
             TyApp(Box::new(e), args)
         }
+        MkClosure(ref code, ref env) => {
+            let env = env.iter().map(|v| mk_symbol(v, sub)).collect();
+            MkClosure(mk_symbol(code, sub), env)
+        }
+        Ctor(ref c) => {
+            let args = c.args().iter().map(|arg| subst(arg, sub)).collect();
+            let ctor = xir::Ctor::new(c.symbol().clone(), c.tag(), args, sub.apply(c.ty()));
+            Ctor(Box::new(ctor))
+        }
+        Match(ref m) => {
+            let arms = m.arms().iter().map(|arm| {
+                let pat = pat_subst(arm.pat(), sub);
+                xir::Arm::new(pat, subst(arm.expr(), sub), arm.span())
+            });
+            let arms = arms.collect();
+            let match_expr = xir::Match::new(mk_symbol(m.scrut(), sub), arms, sub.apply(m.ty()));
+            Match(Box::new(match_expr))
+        }
+        Switch(ref s) => {
+            let cases = s.cases().iter().map(|(k, e)| (*k, subst(e, sub))).collect();
+            let default = s.default().map(|e| subst(e, sub));
+            let scrut = mk_symbol(s.scrut(), sub);
+            Switch(Box::new(xir::Switch::new(
+                scrut,
+                cases,
+                default,
+                sub.apply(s.ty()),
+            )))
+        }
+        Field(ref var, tag, index) => Field(mk_symbol(var, sub), tag, index),
+        Record(ref ty, ref fields) => Record(sub.apply(ty), fields_subst(fields, sub)),
+        Project(ref ty, ref record, index) => {
+            Project(sub.apply(ty), Box::new(subst(record, sub)), index)
+        }
+        Update(ref ty, ref record, ref fields) => {
+            let record = Box::new(subst(record, sub));
+            Update(sub.apply(ty), record, fields_subst(fields, sub))
+        }
+    };
+    xir::Expr::new(kind, expr.span())
+}
+
+fn fields_subst(fields: &[(u32, xir::Expr)], sub: &mut Env) -> Vec<(u32, xir::Expr)> {
+    fields.iter().map(|(i, e)| (*i, subst(e, sub))).collect()
+}
+
+fn pat_subst(pat: &xir::Pattern, sub: &mut Env) -> xir::Pattern {
+    use crate::xir::Pattern::*;
+    match *pat {
+        Var(ref var) => Var(mk_symbol(var, sub)),
+        Ctor(ref ctor, tag, ref args) => {
+            let args = args.iter().map(|arg| pat_subst(arg, sub)).collect();
+            Ctor(ctor.clone(), tag, args)
+        }
+        ref pat => pat.clone(),
     }
 }
@@ -1,17 +1,36 @@
 use super::Type;
 use crate::types::TyVar;
 use crate::utils::{DisjointSet, DisjointSetValue};
-use crate::Error;
 use std;
 use std::collections::HashMap;
 
+//The part of two types that could not be unified
 #[derive(Debug)]
+pub(super) enum Mismatch {
+    Types(Type, Type),
+    //A variable would have to contain itself
+    Infinite(TyVar, Type),
+    //Applications of a different number of types, e.g. functions with a
+    //  different number of parameters
+    Arity(Type, Type),
+}
+
+#[derive(Debug, Clone)]
 pub(super) struct UnificationTable {
     subst: DisjointSet<u32, Type>,
     indices: HashMap<u32, u32>,
 }
 
-impl DisjointSetValue for Type {}
+impl DisjointSetValue for Type {
+    //Keep the type when merging a bound variable with an unbound one
+    fn unify(val1: &Self, val2: &Self) -> Option<Self> {
+        match (val1, val2) {
+            (Type::Var(_), _) => None,
+            (ty, Type::Var(_)) => Some(ty.clone()),
+            _ => None,
+        }
+    }
+}
 
 fn occurs(tv1: &TyVar, ty: &Type, top_level: bool) -> bool {
     use crate::types::Type::*;
@@ -70,17 +89,17 @@ impl UnificationTable {
         res
     }
 
-    pub fn unify<'a>(&mut self, lhs: &'a Type, rhs: &'a Type) -> crate::Result<()> {
+    pub fn unify<'a>(&mut self, lhs: &'a Type, rhs: &'a Type) -> Result<(), Mismatch> {
         use crate::types::Type::*;
         match (lhs, rhs) {
             (&Con(ref l, ref lk), &Con(ref r, ref rk)) => {
                 if *l != *r || lk != rk {
-                    return cannot_unify(lhs, rhs);
+                    return Err(Mismatch::Types(lhs.clone(), rhs.clone()));
                 }
             }
             (&App(ref lty, ref largs), &App(ref rty, ref rargs)) => {
                 if largs.len() != rargs.len() {
-                    return cannot_unify(lhs, rhs);
+                    return Err(Mismatch::Arity(lhs.clone(), rhs.clone()));
                 }
                 self.unify(lty, rty)?;
                 for (larg, rarg) in largs.iter().zip(rargs) {
@@ -90,28 +109,47 @@ impl UnificationTable {
             (&Var(ref tyvar1), &Var(ref tyvar2)) => {
                 let key1 = *self.indices.get(&tyvar1.id).unwrap();
                 let key2 = *self.indices.get(&tyvar2.id).unwrap();
-                self.subst.merge(key1, key2);
-            }
-            (&Var(ref tyvar), ty) | (ty, &Var(ref tyvar)) => {
-                if occurs(tyvar, ty, true) {
-                    return cannot_unify(&Var(tyvar.clone()), ty);
+                let ty1 = self.subst.find(key1).clone();
+                let ty2 = self.subst.find(key2).clone();
+                match (ty1, ty2) {
+                    (Var(tv1), Var(tv2)) => {
+                        //The merged variable belongs to the outermost level
+                        let level =
+                            std::cmp::min(tv1.inner.borrow().level, tv2.inner.borrow().level);
+                        tv1.inner.borrow_mut().level = level;
+                        tv2.inner.borrow_mut().level = level;
+                        self.subst.merge(key1, key2);
+                    }
+                    (Var(_), ty) => self.unify(lhs, &ty)?,
+                    (ty, Var(_)) => self.unify(&ty, rhs)?,
+                    (ty1, ty2) => self.unify(&ty1, &ty2)?,
                 }
-                let old = {
-                    let key = *self.indices.get(&tyvar.id).unwrap();
-                    let new_ty = self.subst.find(key);
-                    std::mem::replace(new_ty, ty.clone())
-                };
-                self.unify(&old, ty)?;
             }
+            (Var(tyvar), ty) => self.bind(tyvar, ty, false)?,
+            (ty, Var(tyvar)) => self.bind(tyvar, ty, true)?,
             _ => {
-                return cannot_unify(lhs, rhs);
+                return Err(Mismatch::Types(lhs.clone(), rhs.clone()));
             }
         };
         Ok(())
     }
-}
 
-fn cannot_unify(lhs: &Type, rhs: &Type) -> crate::Result<()> {
-    let msg = format!("Can not unify {:?} with {:?}", lhs, rhs);
-    Err(Error::new(msg))
+    //The type the variable was bound to is put back when it does not unify
+    //  with `ty` so that errors show the types as they were. Mismatches keep
+    //  the sides they had in the call to unify
+    fn bind(&mut self, tyvar: &TyVar, ty: &Type, flipped: bool) -> Result<(), Mismatch> {
+        if occurs(tyvar, ty, true) {
+            return Err(Mismatch::Infinite(tyvar.clone(), ty.clone()));
+        }
+        let key = *self.indices.get(&tyvar.id).unwrap();
+        let old = std::mem::replace(self.subst.find(key), ty.clone());
+        let res = match flipped {
+            false => self.unify(&old, ty),
+            true => self.unify(ty, &old),
+        };
+        if res.is_err() {
+            *self.subst.find(key) = old;
+        }
+        res
+    }
 }
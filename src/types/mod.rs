@@ -1,7 +1,11 @@
+mod num;
+mod pretty;
 mod subst;
 mod tvar;
 mod tycon;
 
+pub use self::num::Num;
+pub use self::pretty::Names;
 pub use self::subst::Subst;
 pub use self::tvar::TyVar;
 pub use self::tycon::*;
@@ -22,13 +26,24 @@ impl TVar for String {}
 #[derive(Clone, Hash, PartialEq, Eq)]
 pub enum Type<T: TVar> {
     App(Box<Type<T>>, Vec<Type<T>>),
-    Con(TyCon<T>, Kind),
+    Con(TyCon, Kind),
     Var(T),
 }
 
+//A class constraint on a type e.g. Eq<a>
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct Pred<T: TVar> {
+    pub class: Rc<String>,
+    pub ty: Type<T>,
+}
+
+//A type scheme. The bound variables can only be instantiated with types
+//  that satisfy the constraints in `preds`
+//    forall a. Eq<a> => a -> List<a> -> bool
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct ForAll<T: TVar> {
     bound_vars: Vec<T>,
+    preds: Vec<Pred<T>>,
     ty: Type<T>,
 }
 
@@ -41,12 +56,17 @@ impl fmt::Debug for Kind {
     }
 }
 
+impl Kind {
+    //The kind of a type constructor taking n types, * => .. => *
+    pub fn nary(n: usize) -> Kind {
+        (0..n).fold(Kind::Star, |kind, _| Kind::Fun(Rc::new((Kind::Star, kind))))
+    }
+}
+
 impl<T: TVar> Type<T> {
     pub fn func(mut params: Vec<Type<T>>, ret: Type<T>) -> Type<T> {
-        use self::Kind::*;
         use self::Type::*;
-        let mk_kind = |n| (0..(n + 1)).fold(Star, |kind, _| Fun(Rc::new((Star, kind))));
-        let con = Con(TyCon::Func, mk_kind(params.len()));
+        let con = Con(TyCon::Func, Kind::nary(params.len() + 1));
         params.push(ret);
         App(Box::new(con), params)
     }
@@ -54,15 +74,36 @@ impl<T: TVar> Type<T> {
     pub fn unit() -> Type<T> {
         Type::Con(TyCon::Unit, Kind::Star)
     }
+
+    //The type constructor at the head of a type, None for type variables
+    pub fn head(&self) -> Option<&TyCon> {
+        match *self {
+            Type::Con(ref tycon, _) => Some(tycon),
+            Type::App(ref con, _) => con.head(),
+            Type::Var(_) => None,
+        }
+    }
+
+    //Split a function type into its parameter types and return type
+    pub fn func_parts(&self) -> Option<(&[Type<T>], &Type<T>)> {
+        match *self {
+            Type::App(ref con, ref args) => match **con {
+                Type::Con(TyCon::Func, _) => args.split_last().map(|(ret, params)| (params, ret)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 }
 
 impl Type<TyVar> {
+    //The type variables introduced at a level deeper than `curr_level`
     fn free_tyvars(&self, curr_level: u32, res: &mut HashSet<TyVar>) {
         use self::Type::*;
         match *self {
             Con(_, _) => {}
             Var(ref v) => {
-                if v.inner.borrow().level <= curr_level {
+                if v.inner.borrow().level > curr_level {
                     res.insert(v.clone());
                 }
             }
@@ -81,6 +122,15 @@ impl Type<TyVar> {
         let ftv = tyvars.into_iter().collect();
         ForAll::new(ftv, self.clone())
     }
+
+    //Type variables that are not generalized belong to the enclosing level
+    //  so that they are not generalized by a later let either
+    pub(super) fn monomorphic(&self, level: u32) -> ForAll<TyVar> {
+        for tv in self.generalize(level).bound_vars() {
+            tv.inner.borrow_mut().level = level;
+        }
+        ForAll::new(vec![], self.clone())
+    }
 }
 
 impl<T: TVar> fmt::Debug for Type<T> {
@@ -94,15 +144,33 @@ impl<T: TVar> fmt::Debug for Type<T> {
     }
 }
 
+impl<T: TVar> fmt::Debug for Pred<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}<{:?}>", self.class, self.ty)
+    }
+}
+
 impl<T: TVar> ForAll<T> {
     pub fn new(bound_vars: Vec<T>, ty: Type<T>) -> Self {
-        ForAll { bound_vars, ty }
+        Self::qualified(bound_vars, vec![], ty)
+    }
+
+    pub fn qualified(bound_vars: Vec<T>, preds: Vec<Pred<T>>, ty: Type<T>) -> Self {
+        ForAll {
+            bound_vars,
+            preds,
+            ty,
+        }
     }
 
     pub fn bound_vars(&self) -> &Vec<T> {
         &self.bound_vars
     }
 
+    pub fn preds(&self) -> &Vec<Pred<T>> {
+        &self.preds
+    }
+
     pub fn ty(&self) -> &Type<T> {
         &self.ty
     }
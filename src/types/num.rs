@@ -0,0 +1,127 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::TyCon;
+
+//A number literal. The suffix gives its type, numbers without one are i32
+//  and numbers with a fraction are f64
+//    10u8  -3  1.5  2f32
+#[derive(Clone, Copy, PartialEq)]
+pub enum Num {
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+}
+
+const SUFFIXES: [&str; 10] = [
+    "i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64", "f32", "f64",
+];
+
+impl FromStr for Num {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let suffix = SUFFIXES.iter().find(|suffix| s.ends_with(*suffix));
+        let (digits, suffix) = match suffix {
+            Some(suffix) => (&s[..s.len() - suffix.len()], *suffix),
+            None if s.contains('.') => (s, "f64"),
+            None => (s, "i32"),
+        };
+        if digits.contains('.') && !suffix.starts_with('f') {
+            return Err("Integer literals can not have a fraction");
+        }
+        let num = match suffix {
+            "i8" => digits.parse().map(Num::I8).ok(),
+            "i16" => digits.parse().map(Num::I16).ok(),
+            "i32" => digits.parse().map(Num::I32).ok(),
+            "i64" => digits.parse().map(Num::I64).ok(),
+            "u8" => digits.parse().map(Num::U8).ok(),
+            "u16" => digits.parse().map(Num::U16).ok(),
+            "u32" => digits.parse().map(Num::U32).ok(),
+            "u64" => digits.parse().map(Num::U64).ok(),
+            "f32" => digits.parse().map(Num::F32).ok(),
+            _ => digits.parse().map(Num::F64).ok(),
+        };
+        num.ok_or("Number literal out of range for its type")
+    }
+}
+
+impl Num {
+    pub fn tycon(&self) -> TyCon {
+        match *self {
+            Num::I8(_) => TyCon::I8,
+            Num::I16(_) => TyCon::I16,
+            Num::I32(_) => TyCon::I32,
+            Num::I64(_) => TyCon::I64,
+            Num::U8(_) => TyCon::U8,
+            Num::U16(_) => TyCon::U16,
+            Num::U32(_) => TyCon::U32,
+            Num::U64(_) => TyCon::U64,
+            Num::F32(_) => TyCon::F32,
+            Num::F64(_) => TyCon::F64,
+        }
+    }
+
+    //The bits of an integer, sign extended to 64 bits for signed types
+    pub fn bits(&self) -> Option<i64> {
+        let bits = match *self {
+            Num::I8(n) => n as i64,
+            Num::I16(n) => n as i64,
+            Num::I32(n) => n as i64,
+            Num::I64(n) => n,
+            Num::U8(n) => n as i64,
+            Num::U16(n) => n as i64,
+            Num::U32(n) => n as i64,
+            Num::U64(n) => n as i64,
+            Num::F32(_) | Num::F64(_) => return None,
+        };
+        Some(bits)
+    }
+}
+
+impl fmt::Debug for Num {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Num::I8(n) => write!(f, "{}i8", n),
+            Num::I16(n) => write!(f, "{}i16", n),
+            Num::I32(n) => write!(f, "{}", n),
+            Num::I64(n) => write!(f, "{}i64", n),
+            Num::U8(n) => write!(f, "{}u8", n),
+            Num::U16(n) => write!(f, "{}u16", n),
+            Num::U32(n) => write!(f, "{}u32", n),
+            Num::U64(n) => write!(f, "{}u64", n),
+            Num::F32(n) => write!(f, "{:?}f32", n),
+            Num::F64(n) => write!(f, "{:?}", n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Num;
+
+    #[test]
+    fn suffixes() {
+        assert_eq!("10".parse(), Ok(Num::I32(10)));
+        assert_eq!("-3i8".parse(), Ok(Num::I8(-3)));
+        assert_eq!("255u8".parse(), Ok(Num::U8(255)));
+        assert_eq!("1.5".parse(), Ok(Num::F64(1.5)));
+        assert_eq!("2f32".parse(), Ok(Num::F32(2.0)));
+        assert_eq!("18446744073709551615u64".parse(), Ok(Num::U64(u64::MAX)));
+    }
+
+    #[test]
+    fn errors() {
+        assert!("256u8".parse::<Num>().is_err());
+        assert!("-1u32".parse::<Num>().is_err());
+        assert!("2147483648".parse::<Num>().is_err());
+        assert!("1.5i32".parse::<Num>().is_err());
+    }
+}
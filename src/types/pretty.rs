@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{ForAll, Pred, TyCon, TyVar, Type};
+use crate::Vector;
+
+//Types as they are shown to the programmer, which is also how they are
+//  written in annotations. Type variables are named in the order they are
+//  first printed, so types printed with the same names agree on their
+//  variables
+//    forall a b. Eq<a> => (a -> b, List<a>) -> List<b>
+//  A lone parameter is put in parentheses when it is a function or (), so
+//  that (i32 -> i32) -> i32 and (()) -> i32 keep their arity
+#[derive(Default)]
+pub struct Names {
+    names: HashMap<u32, String>,
+}
+
+impl Names {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn var(&mut self, tv: &TyVar) -> String {
+        let next = self.names.len();
+        let name = self.names.entry(tv.id).or_insert_with(|| match next {
+            0..=25 => ((b'a' + next as u8) as char).to_string(),
+            _ => format!("t{}", next),
+        });
+        name.clone()
+    }
+
+    pub fn ty(&mut self, ty: &Type<TyVar>) -> String {
+        match *ty {
+            Type::Var(ref tv) => self.var(tv),
+            Type::Con(ref tycon, _) => format!("{:?}", tycon),
+            Type::App(ref con, ref args) => match **con {
+                Type::Con(TyCon::Func, _) => {
+                    let (ret, params) = args.split_last().unwrap();
+                    let params = match params {
+                        [param] if param.func_parts().is_none() && *param != Type::unit() => {
+                            self.ty(param)
+                        }
+                        _ => format!("({})", self.list(params)),
+                    };
+                    format!("{} -> {}", params, self.ty(ret))
+                }
+                _ => {
+                    let con = self.ty(con);
+                    format!("{}<{}>", con, self.list(args))
+                }
+            },
+        }
+    }
+
+    fn list(&mut self, tys: &[Type<TyVar>]) -> String {
+        let tys = tys.iter().map(|ty| self.ty(ty)).collect::<Vec<_>>();
+        tys.join(", ")
+    }
+
+    pub fn pred(&mut self, pred: &Pred<TyVar>) -> String {
+        format!("{}<{}>", pred.class, self.ty(&pred.ty))
+    }
+
+    //The bound variables are listed in the order they appear in the type
+    //  and the constraints in alphabetical order
+    pub fn scheme(&mut self, scheme: &ForAll<TyVar>) -> String {
+        let ty = self.ty(scheme.ty());
+        let preds = scheme.preds().iter().map(|pred| self.pred(pred));
+        let mut preds = preds.collect::<Vec<_>>();
+        preds.sort();
+        let mut bound = Vector::fmap(scheme.bound_vars().iter(), |tv| self.var(tv));
+        bound.sort_by_key(|name| (name.len(), name.clone()));
+        let mut res = String::new();
+        if !bound.is_empty() {
+            res.push_str(&format!("forall {}. ", bound.join(" ")));
+        }
+        match preds.len() {
+            0 => {}
+            1 => res.push_str(&format!("{} => ", preds[0])),
+            _ => res.push_str(&format!("({}) => ", preds.join(", "))),
+        }
+        res.push_str(&ty);
+        res
+    }
+}
+
+impl fmt::Display for Type<TyVar> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Names::new().ty(self))
+    }
+}
+
+impl fmt::Display for Pred<TyVar> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Names::new().pred(self))
+    }
+}
+
+impl fmt::Display for ForAll<TyVar> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", Names::new().scheme(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ForAll, Kind, TyCon, TyVar, Type};
+
+    #[test]
+    fn schemes() {
+        let (a, b) = (TyVar::fresh(1), TyVar::fresh(1));
+        let i32 = Type::Con(TyCon::I32, Kind::Star);
+        let f = Type::func(vec![Type::Var(a.clone())], Type::Var(b.clone()));
+        let ty = Type::func(vec![f.clone(), Type::Var(a.clone())], Type::Var(b.clone()));
+        let scheme = ForAll::new(vec![b, a], ty);
+        assert_eq!(format!("{}", scheme), "forall a b. (a -> b, a) -> b");
+        let ty = Type::func(vec![f], i32.clone());
+        assert_eq!(format!("{}", ty), "(a -> b) -> i32");
+        let thunk = Type::func(vec![], Type::func(vec![Type::unit()], i32));
+        assert_eq!(format!("{}", thunk), "() -> (()) -> i32");
+    }
+}
@@ -1,7 +1,7 @@
 use crate::types::{TyVar, Type};
 use std::collections::HashMap;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Subst {
     map: HashMap<u32, Type<TyVar>>,
 }
@@ -23,6 +23,23 @@ impl Subst {
         self.map.insert(tyvar.id, ty);
     }
 
+    //Bind the variables of `pat` to the corresponding parts of `ty`. Used
+    //  with instance heads which are type constructors applied to distinct
+    //  variables
+    pub fn matching(&mut self, pat: &Type<TyVar>, ty: &Type<TyVar>) {
+        use self::Type::*;
+        match (pat, ty) {
+            (Var(tv), _) => self.bind(tv, ty.clone()),
+            (App(pcon, pargs), App(con, args)) => {
+                self.matching(pcon, con);
+                for (parg, arg) in pargs.iter().zip(args) {
+                    self.matching(parg, arg);
+                }
+            }
+            _ => {}
+        }
+    }
+
     pub fn apply(&self, ty: &Type<TyVar>) -> Type<TyVar> {
         use self::Type::*;
         match *ty {
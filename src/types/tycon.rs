@@ -1,16 +1,24 @@
 use std::fmt;
 use std::rc::Rc;
 
-use super::{TVar, Type};
+use super::{Pred, TVar, Type};
 
 #[derive(Clone, Hash, PartialEq, Eq)]
-pub enum TyCon<T: TVar> {
+pub enum TyCon {
     NewType(Rc<String>),
+    I8,
+    I16,
     I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
     Bool,
     Unit,
     Func,
-    Record(Rc<Record<T>>),
 }
 
 #[derive(Clone, Hash, PartialEq, Eq)]
@@ -22,30 +30,73 @@ pub struct NewType<T: TVar> {
 
 #[derive(Clone, Hash, PartialEq, Eq)]
 pub struct Field<T: TVar> {
-    name: Rc<String>,
-    ty: Type<T>,
+    pub name: Rc<String>,
+    pub ty: Type<T>,
 }
 
+//A record type. Like data types, records are referred to by name
+//    type Point<a> = {x: a, y: a}
 #[derive(Clone, Hash, PartialEq, Eq)]
 pub struct Record<T: TVar> {
-    name: Rc<String>,
-    fields: Vec<Field<T>>,
+    pub name: Rc<String>,
+    pub params: Vec<T>,
+    pub fields: Vec<Field<T>>,
+}
+
+//An algebraic data type
+//    type Option<a> = None | Some(a)
+//  The tag of a constructor is its position in `ctors`
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct DataType<T: TVar> {
+    pub name: Rc<String>,
+    pub params: Vec<T>,
+    pub ctors: Vec<Ctor<T>>,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct Ctor<T: TVar> {
+    pub name: Rc<String>,
+    pub args: Vec<Type<T>>,
+}
+
+//A type class with a single parameter
+//    class Eq<a> { eq: a -> a -> bool }
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct Class<T: TVar> {
+    pub name: Rc<String>,
+    pub param: T,
+    pub methods: Vec<Field<T>>,
+}
+
+//An instance of a class for a type constructor applied to distinct type
+//  variables. The constraints in `context` must hold for these variables
+//    instance Eq<a> => Eq<List<a>>
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct Instance<T: TVar> {
+    pub class: Rc<String>,
+    pub params: Vec<T>,
+    pub context: Vec<Pred<T>>,
+    pub ty: Type<T>,
 }
 
-impl<T: TVar> fmt::Debug for TyCon<T> {
+impl fmt::Debug for TyCon {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::TyCon::*;
-        let res;
         let v = match *self {
+            I8 => "i8",
+            I16 => "i16",
             I32 => "i32",
+            I64 => "i64",
+            U8 => "u8",
+            U16 => "u16",
+            U32 => "u32",
+            U64 => "u64",
+            F32 => "f32",
+            F64 => "f64",
             Bool => "bool",
             Unit => "()",
             Func => "->",
             NewType(ref nm) => nm.as_str(),
-            Record(ref rec) => {
-                res = format!("{:?}", rec);
-                &res
-            }
         };
         write!(f, "{}", v)
     }
@@ -59,9 +110,39 @@ impl<T: TVar> fmt::Debug for Field<T> {
 
 impl<T: TVar> fmt::Debug for Record<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:}", self.name)?;
-        for field in &self.fields {
-            write!(f, "{:?}", field)?;
+        write!(
+            f,
+            "type {}{:?} = {{{:?}}}",
+            self.name, self.params, self.fields
+        )
+    }
+}
+
+impl<T: TVar> fmt::Debug for Class<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "class {}<{:?}> {{{:?}}}",
+            self.name, self.param, self.methods
+        )
+    }
+}
+
+impl<T: TVar> fmt::Debug for Instance<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "instance {:?} => {}<{:?}>",
+            self.context, self.class, self.ty
+        )
+    }
+}
+
+impl<T: TVar> fmt::Debug for DataType<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "type {}{:?} =", self.name, self.params)?;
+        for ctor in &self.ctors {
+            write!(f, " | {}{:?}", ctor.name, ctor.args)?;
         }
         Ok(())
     }
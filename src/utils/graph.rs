@@ -1,5 +1,6 @@
 use std::marker::PhantomData;
 
+#[derive(Clone)]
 pub(super) struct Vertex<Data> {
     pub(super) data: Data,
     pub(super) edges: Vec<u32>,
@@ -19,6 +20,7 @@ impl VertexKey for u32 {
     }
 }
 
+#[derive(Clone)]
 pub struct Graph<K, Data> {
     phantom: PhantomData<K>,
     pub(super) vertices: Vec<Vertex<Data>>,
@@ -25,7 +25,7 @@ impl<'a, K: VertexKey, Data: Clone> SCC<'a, K, Data> {
         for _ in 0..len {
             indices.push(usize::MAX);
             lowlink.push(usize::MAX);
-            onstack.push(true);
+            onstack.push(false);
         }
         let curr_index = 0;
         let mut scc = SCC {
@@ -75,6 +75,7 @@ impl<'a, K: VertexKey, Data: Clone> SCC<'a, K, Data> {
         if self.indices[v] == self.lowlink[v] {
             let mut scc = Vec::new();
             while let Some(w) = self.stack.pop() {
+                self.onstack[w] = false;
                 scc.push(self.graph.vertices[w].data.clone());
                 if w == v {
                     break;
@@ -118,6 +119,17 @@ mod tests {
 
         let res = SCC::run(&graph);
 
-        println!("{:#?}", res);
+        //Components come out callees first
+        let res: Vec<Vec<u32>> = res
+            .into_iter()
+            .map(|mut scc| {
+                scc.sort();
+                scc
+            })
+            .collect();
+        assert_eq!(
+            res,
+            vec![vec![4], vec![10], vec![3, 5, 6, 7, 8, 9], vec![2], vec![1]]
+        );
     }
 }
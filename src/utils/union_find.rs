@@ -21,14 +21,14 @@ pub trait DisjointSetValue: Sized {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Node<V> {
     parent: u32,
     rank: u32,
     value: V,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DisjointSet<K, V> {
     nodes: Vec<Node<V>>,
     phantom: PhantomData<K>,
@@ -3,23 +3,39 @@
 //   "On The Type Structure of Standard ML" Robert Harper.
 //System F like.
 
-use crate::types::{self, TyVar};
+use crate::span::Span;
+use crate::types::{self, Num, TyVar};
+use std::collections::HashSet;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 
 type Type = types::Type<TyVar>;
+pub type DataType = types::DataType<TyVar>;
+pub type Record = types::Record<TyVar>;
+pub type Class = types::Class<TyVar>;
+pub type Instance = types::Instance<TyVar>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Module {
     name: String,
     decls: Vec<Decl>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Decl {
-    Extern(Symbol),
+    //An extern and the span of its name
+    Extern(Symbol, Span),
     Let(Vec<Bind>),
+    //Code of a lambda lifted to the top level by closure conversion. The
+    //   captured variables are read from the environment record of the
+    //   closure it is called through.
+    Closure(Vec<Symbol>, Bind),
+    Type(Rc<DataType>),
+    Record(Rc<Record>),
+    //Classes and instances are resolved by Specialize
+    Class(Rc<Class>, Vec<Symbol>),
+    Instance(Rc<Instance>, Vec<Bind>),
 }
 
 #[derive(Clone, Eq, PartialEq)]
@@ -29,17 +45,64 @@ pub struct Symbol {
     ty: Type,
 }
 
+#[derive(Clone)]
 pub struct Bind {
     symbol: Symbol,
     expr: Expr,
 }
 
+#[derive(Clone)]
 pub struct Let {
     bind: Bind,
     expr: Expr,
 }
 
-#[derive(Debug)]
+//A constructor applied to all its arguments. `ty` is the constructed type
+#[derive(Debug, Clone)]
+pub struct Ctor {
+    symbol: Symbol,
+    tag: u32,
+    args: Vec<Expr>,
+    ty: Type,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Wildcard,
+    Var(Symbol),
+    //The constructor, its tag and the patterns for its arguments
+    Ctor(Symbol, u32, Vec<Pattern>),
+    UnitLit,
+    NumLit(Num),
+    BoolLit(bool),
+}
+
+#[derive(Debug, Clone)]
+pub struct Arm {
+    pat: Pattern,
+    expr: Expr,
+    span: Span,
+}
+
+//Removed by MatchCompile
+#[derive(Debug, Clone)]
+pub struct Match {
+    scrut: Symbol,
+    arms: Vec<Arm>,
+    ty: Type,
+}
+
+//A node of a decision tree. Branches on the tag of a data type or on the
+//  value of an integer or bool (false is 0 and true is 1)
+#[derive(Debug, Clone)]
+pub struct Switch {
+    scrut: Symbol,
+    cases: Vec<(i64, Expr)>,
+    default: Option<Expr>,
+    ty: Type,
+}
+
+#[derive(Debug, Clone)]
 pub struct If {
     cond: Expr,
     texpr: Expr,
@@ -47,18 +110,46 @@ pub struct If {
     ty: Type,
 }
 
-#[derive(Debug)]
-pub enum Expr {
+#[derive(Clone)]
+pub struct Expr {
+    kind: ExprKind,
+    span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
     UnitLit,
-    I32Lit(i32),
+    NumLit(Num),
     BoolLit(bool),
     Var(Symbol),
     If(Box<If>),
     Let(Box<Let>),
+    //A group of mutually recursive functions
+    LetRec(Vec<Bind>, Box<Expr>),
+    //Evaluate the first expression for its effects, then the second
+    Seq(Box<Expr>, Box<Expr>),
     Lam(Vec<Symbol>, Box<Expr>, Type),
     App(Type, Box<Expr>, Vec<Expr>),
     TyLam(Vec<TyVar>, Box<Expr>),
     TyApp(Box<Expr>, Vec<Type>),
+    //Allocate a closure record for the lifted code and the captured variables
+    MkClosure(Symbol, Vec<Symbol>),
+    Ctor(Box<Ctor>),
+    Match(Box<Match>),
+    Switch(Box<Switch>),
+    //Field of a value of a data type given the tag of its variant and
+    //  the index of the field
+    Field(Symbol, u32, u32),
+    //The type is that of the record. Fields are referred to by their index
+    Record(Type, Vec<(u32, Expr)>),
+    Project(Type, Box<Expr>, u32),
+    Update(Type, Box<Expr>, Vec<(u32, Expr)>),
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)
+    }
 }
 
 impl Hash for Symbol {
@@ -113,6 +204,124 @@ impl fmt::Debug for Symbol {
     }
 }
 
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+    pub fn kind(&self) -> &ExprKind {
+        &self.kind
+    }
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    //Variables referenced in the expression but not bound by it, in order
+    //   of first occurrence.
+    pub fn free_vars(&self) -> Vec<Symbol> {
+        let mut fv = FreeVars::default();
+        fv.visit(self);
+        fv.vars
+    }
+}
+
+#[derive(Default)]
+struct FreeVars {
+    bound: HashSet<u32>,
+    seen: HashSet<u32>,
+    vars: Vec<Symbol>,
+}
+
+impl FreeVars {
+    fn add(&mut self, var: &Symbol) {
+        if !self.bound.contains(&var.id) && self.seen.insert(var.id) {
+            self.vars.push(var.clone())
+        }
+    }
+
+    fn visit(&mut self, expr: &Expr) {
+        use self::ExprKind::*;
+        match *expr.kind() {
+            UnitLit | NumLit(_) | BoolLit(_) => {}
+            Var(ref var) => self.add(var),
+            If(ref e) => {
+                self.visit(e.cond());
+                self.visit(e.texpr());
+                self.visit(e.fexpr());
+            }
+            Let(ref e) => {
+                self.visit(e.bind().expr());
+                self.bound.insert(e.bind().symbol().id);
+                self.visit(e.expr());
+            }
+            LetRec(ref binds, ref e) => {
+                for bind in binds {
+                    self.bound.insert(bind.symbol().id);
+                }
+                for bind in binds {
+                    self.visit(bind.expr());
+                }
+                self.visit(e);
+            }
+            Seq(ref e1, ref e2) => {
+                self.visit(e1);
+                self.visit(e2);
+            }
+            Lam(ref params, ref body, _) => {
+                for param in params {
+                    self.bound.insert(param.id);
+                }
+                self.visit(body);
+            }
+            App(_, ref callee, ref args) => {
+                self.visit(callee);
+                for arg in args {
+                    self.visit(arg);
+                }
+            }
+            TyLam(_, ref e) | TyApp(ref e, _) => self.visit(e),
+            Ctor(ref c) => {
+                for arg in c.args() {
+                    self.visit(arg);
+                }
+            }
+            Match(ref m) => {
+                self.add(m.scrut());
+                for arm in m.arms() {
+                    arm.pat().bind_vars(&mut self.bound);
+                    self.visit(arm.expr());
+                }
+            }
+            Switch(ref s) => {
+                self.add(s.scrut());
+                for (_, expr) in s.cases() {
+                    self.visit(expr);
+                }
+                if let Some(expr) = s.default() {
+                    self.visit(expr);
+                }
+            }
+            Field(ref var, _, _) => self.add(var),
+            Record(_, ref fields) => {
+                for (_, expr) in fields {
+                    self.visit(expr);
+                }
+            }
+            Project(_, ref record, _) => self.visit(record),
+            Update(_, ref record, ref fields) => {
+                self.visit(record);
+                for (_, expr) in fields {
+                    self.visit(expr);
+                }
+            }
+            MkClosure(_, ref env) => {
+                for var in env {
+                    self.add(var)
+                }
+            }
+        }
+    }
+}
+
 impl If {
     pub fn new(cond: Expr, texpr: Expr, fexpr: Expr, ty: Type) -> Self {
         If {
@@ -136,6 +345,98 @@ impl If {
     }
 }
 
+impl Ctor {
+    pub fn new(symbol: Symbol, tag: u32, args: Vec<Expr>, ty: Type) -> Self {
+        Ctor {
+            symbol,
+            tag,
+            args,
+            ty,
+        }
+    }
+    pub fn symbol(&self) -> &Symbol {
+        &self.symbol
+    }
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+    pub fn args(&self) -> &Vec<Expr> {
+        &self.args
+    }
+    pub fn ty(&self) -> &Type {
+        &self.ty
+    }
+}
+
+impl Pattern {
+    fn bind_vars(&self, bound: &mut HashSet<u32>) {
+        match *self {
+            Pattern::Var(ref var) => {
+                bound.insert(var.id);
+            }
+            Pattern::Ctor(_, _, ref args) => {
+                for arg in args {
+                    arg.bind_vars(bound)
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Arm {
+    pub fn new(pat: Pattern, expr: Expr, span: Span) -> Self {
+        Arm { pat, expr, span }
+    }
+    pub fn pat(&self) -> &Pattern {
+        &self.pat
+    }
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Match {
+    pub fn new(scrut: Symbol, arms: Vec<Arm>, ty: Type) -> Self {
+        Match { scrut, arms, ty }
+    }
+    pub fn scrut(&self) -> &Symbol {
+        &self.scrut
+    }
+    pub fn arms(&self) -> &Vec<Arm> {
+        &self.arms
+    }
+    pub fn ty(&self) -> &Type {
+        &self.ty
+    }
+}
+
+impl Switch {
+    pub fn new(scrut: Symbol, cases: Vec<(i64, Expr)>, default: Option<Expr>, ty: Type) -> Self {
+        Switch {
+            scrut,
+            cases,
+            default,
+            ty,
+        }
+    }
+    pub fn scrut(&self) -> &Symbol {
+        &self.scrut
+    }
+    pub fn cases(&self) -> &Vec<(i64, Expr)> {
+        &self.cases
+    }
+    pub fn default(&self) -> Option<&Expr> {
+        self.default.as_ref()
+    }
+    pub fn ty(&self) -> &Type {
+        &self.ty
+    }
+}
+
 impl Let {
     pub fn new(bind: Bind, expr: Expr) -> Self {
         Let { bind, expr }
@@ -173,3 +474,45 @@ impl fmt::Debug for Let {
         write!(f, "let {:?}\n{:#?}", self.bind, self.expr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sym(name: &str, id: u32) -> Symbol {
+        Symbol::new(Rc::new(name.to_string()), Type::unit(), id)
+    }
+
+    #[test]
+    fn free_vars() {
+        let (f, g, n, x, y) = (
+            sym("f", 1),
+            sym("g", 2),
+            sym("n", 3),
+            sym("x", 4),
+            sym("y", 5),
+        );
+        let mk = |kind| Expr::new(kind, Span::default());
+        let var = |s: &Symbol| mk(ExprKind::Var(s.clone()));
+
+        //  \x -> let y = f(x, n); g(y, n, mkclosure(c, [x, f]))
+        let bexpr = ExprKind::App(Type::unit(), Box::new(var(&f)), vec![var(&x), var(&n)]);
+        let closure = ExprKind::MkClosure(sym("c", 6), vec![x.clone(), f.clone()]);
+        let body = ExprKind::App(
+            Type::unit(),
+            Box::new(var(&g)),
+            vec![var(&y), var(&n), mk(closure)],
+        );
+        let body = ExprKind::Let(Box::new(Let::new(
+            Bind::new(y.clone(), mk(bexpr)),
+            mk(body),
+        )));
+        let lam = mk(ExprKind::Lam(
+            vec![x.clone()],
+            Box::new(mk(body)),
+            Type::unit(),
+        ));
+
+        assert_eq!(lam.free_vars(), vec![f, n, g]);
+    }
+}
@@ -0,0 +1,397 @@
+//Programs compiled with the babel binary, linked and run. The repl's jit
+//  applies relocations itself, so some bugs only show up here
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+//Each test works in a directory of its own as tests run in parallel
+fn dir(test: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn babel(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_babel"))
+        .args(args)
+        .current_dir(dir)
+        .env("BABEL_CACHE_DIR", env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .unwrap()
+}
+
+fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).to_string()
+}
+
+//The exit code and output of the executable built from the files
+fn run_files(test: &str, files: &[(&str, &str)]) -> (i32, String) {
+    let dir = dir(test);
+    for (name, src) in files {
+        std::fs::write(dir.join(name), src).unwrap();
+    }
+    let out = babel(&dir, &[files[0].0]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let exe = dir.join(files[0].0.trim_end_matches(".bs"));
+    let out = Command::new(exe).output().unwrap();
+    let code = out.status.code().expect("killed by a signal");
+    (code, String::from_utf8(out.stdout).unwrap())
+}
+
+//Like run_files but the other modules are compiled on their own first and
+//  their sources removed, so only their interfaces are left
+fn run_separately(test: &str, files: &[(&str, &str)]) -> (i32, String) {
+    let dir = dir(test);
+    for (name, src) in &files[1..] {
+        std::fs::write(dir.join(name), src).unwrap();
+        let out = babel(&dir, &["-c", name]);
+        assert!(out.status.success(), "{}", stderr(&out));
+        std::fs::remove_file(dir.join(name)).unwrap();
+    }
+    let (name, src) = files[0];
+    std::fs::write(dir.join(name), src).unwrap();
+    let out = babel(&dir, &[name]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let out = Command::new(dir.join(name.trim_end_matches(".bs")))
+        .output()
+        .unwrap();
+    let code = out.status.code().expect("killed by a signal");
+    (code, String::from_utf8(out.stdout).unwrap())
+}
+
+fn run(test: &str, src: &str) -> (i32, String) {
+    run_files(test, &[("main.bs", src)])
+}
+
+fn example(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("examples")
+        .join(name);
+    std::fs::read_to_string(path).unwrap()
+}
+
+#[test]
+fn closures() {
+    assert_eq!(run("fib", &example("fib.bs")), (0, "89".to_string()));
+    assert_eq!(
+        run("closure", &example("closure.bs")),
+        (0, "110\n42\n".to_string())
+    );
+    let src = "
+        class Size<a> { size: a -> i32 }
+        instance Size<i32> { let size(n) { n * 2 } }
+        let inc(x) { x + 1 }
+        let apply(f, x) { f(x) }
+        let main() {
+            let f = inc;
+            let g = size;
+            f(7) + apply(\\x -> { x + 10 }, 3) + g(3)
+        }";
+    assert_eq!(run("static_closures", src).0, 8 + 13 + 6);
+}
+
+#[test]
+fn data_types() {
+    let src = "
+        type Shape = Dot | Circle(f64) | Rect(i8, i64, bool)
+        type Tree<a> = Leaf | Node(Tree<a>, a, Tree<a>)
+        let area(s) {
+            match s {
+                Dot => 0,
+                Circle(r) => f64_to_i32(r * r * 3.0),
+                Rect(w, h, filled) => if filled { i8_to_i32(w) * i64_to_i32(h) } else { 1 }
+            }
+        }
+        let sum(t) {
+            match t {
+                Leaf => 0,
+                Node(l, x, r) => sum(l) + area(x) + sum(r)
+            }
+        }
+        let main() {
+            let t = Node(Node(Leaf, Circle(2.0), Leaf), Rect(3i8, 5i64, true), Node(Leaf, Dot, Leaf));
+            i32_print(sum(t));
+            i32_print(area(Rect(3i8, 5i64, false)))
+        }";
+    assert_eq!(run("data_types", src), (0, "271".to_string()));
+    let out = "42\n3\n1\n347\n1?\n".to_string();
+    assert_eq!(run("match", &example("match.bs")), (0, out));
+}
+
+#[test]
+fn records() {
+    let src = "
+        type Mixed = {a: i8, b: i64, c: bool, d: f64, e: u16}
+        type Point = {x: i32, y: i32}
+        type Line = {from: Point, to: Point, width: u8}
+        let len(l) { l.to.x - l.from.x + l.to.y - l.from.y }
+        let main() {
+            let m = {e = 7u16, d = 2.5, c = true, b = 1000i64, a = -3i8};
+            let n = {m with a = 4i8, d = 0.5};
+            i32_print(i8_to_i32(m.a) + i8_to_i32(n.a) + u16_to_i32(n.e));
+            i32_print(i64_to_i32(n.b) + f64_to_i32({m.d + n.d} * 2.0));
+            let l = {from = {x = 1, y = 2}, to = {x = 4, y = 6}, width = 3u8};
+            let moved = {l with to = {l.to with y = 10}};
+            i32_print(len(l) * 100 + len(moved));
+            if n.c { u8_to_i32(moved.width) } else { 0 }
+        }";
+    assert_eq!(run("records", src), (3, "81006711".to_string()));
+    let out = "1122\n722\n42\n42\n".to_string();
+    assert_eq!(run("record", &example("record.bs")), (0, out));
+}
+
+#[test]
+fn classes() {
+    let out = "3 30 9 .\n42\n42\nT F .\nT\nF\nT F .\n".to_string();
+    assert_eq!(run("classes", &example("classes.bs")), (0, out));
+    let src = "
+        type Option<a> = None | Some(a)
+        class Size<a> { size: a -> i32 }
+        instance Size<i32> { let size(n) { 4 } }
+        instance Size<bool> { let size(b) { 1 } }
+        instance Size<a> => Size<Option<a>> {
+            let size(o) { match o { None => 1, Some(x) => 1 + size(x) } }
+        }
+        let both(x, y) { size(x) * 10 + size(y) }
+        let main() { both(Some(Some(true)), Some(7)) }";
+    assert_eq!(run("instances", src).0, 35);
+}
+
+#[test]
+fn mutual_recursion() {
+    let out = "10\n7100\n111\n".to_string();
+    assert_eq!(run("mutual", &example("mutual.bs")), (0, out));
+}
+
+#[test]
+fn tail_calls() {
+    //Deep enough to overflow the stack unless the calls are jumps
+    let src = "
+        let even(n) { if n == 0 { true } else { @tailcall odd(n - 1) } }
+        let odd(n) { if n == 0 { false } else { @tailcall even(n - 1) } }
+        let count(n) {
+            let rec up(n, acc) {
+                if n == 0 { acc } else { @tailcall down(n - 1, acc + 2) }
+            } and down(n, acc) {
+                if n == 0 { acc } else { @tailcall up(n - 1, acc - 1) }
+            };
+            up(n, 0)
+        }
+        let main() {
+            i32_print(count(10000000));
+            if even(10000000) { 1 } else { 2 }
+        }";
+    assert_eq!(run("tail_calls", src), (1, "5000000".to_string()));
+
+    //The bodies of a group are emitted once, in a function the functions of
+    //  the group call
+    let dir = dir("tail_call_groups");
+    let src = "
+        let a(n) { if n == 0 { 101 } else { b(n - 1) } }
+        let b(n) { if n == 0 { 102 } else { c(n - 1) } }
+        let c(n) { if n == 0 { 103 } else { a(n - 1) } }
+        let main() { a(5) - 100 }";
+    std::fs::write(dir.join("main.bs"), src).unwrap();
+    let out = babel(&dir, &["--emit=clif,exe", "main.bs"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let clif = std::fs::read_to_string(dir.join("main.clif")).unwrap();
+    let funcs = clif.lines().filter(|line| line.starts_with("function "));
+    assert_eq!(funcs.count(), 5, "{}", clif);
+    for n in ["101", "102", "103"] {
+        let consts = clif
+            .lines()
+            .filter(|line| line.ends_with(&format!("iconst.i32 {}", n)));
+        assert_eq!(consts.count(), 1, "{}", clif);
+    }
+    let out = Command::new(dir.join("main")).output().unwrap();
+    assert_eq!(out.status.code(), Some(3));
+}
+
+#[test]
+fn unit() {
+    let out = "1\n2\n3\n4\n5\n5\n".to_string();
+    assert_eq!(run("unit", &example("unit.bs")), (0, out));
+    let src = "
+        type List<a> = Nil | Cons(a, List<a>)
+        type Pair<a, b> = {fst: a, snd: b}
+        let length(l) { match l { Nil => 0, Cons(_, rest) => 1 + length(rest) } }
+        let id(x) { x }
+        let main() {
+            let u = id(());
+            let units = Cons(u, Cons((), Nil));
+            let p = {fst = (), snd = length(units)};
+            let f = \\x -> { u; x + p.snd };
+            f(40)
+        }";
+    assert_eq!(run("unit_values", src).0, 42);
+}
+
+#[test]
+fn modules() {
+    let list = example("modules/list.bs");
+    let main = example("modules/main.bs");
+    let out = "1 2 3 4 .\n4\n10\n4950\n".to_string();
+    let files = [("main.bs", main.as_str()), ("list.bs", list.as_str())];
+    assert_eq!(run_files("modules", &files), (0, out.clone()));
+    assert_eq!(run_separately("interfaces", &files), (0, out));
+
+    //Private types, operators and functions used by a polymorphic function
+    let shapes = "
+        type Box<a> = {value: a, weight: i32}
+        infixl 6 (<+>) = combine;
+        let combine(a, b) { a * 10 + b }
+        let heavy(x) { {value = x, weight = 7} }
+        pub let weigh(x) { heavy(x).weight <+> 3 }";
+    let files = [
+        ("main.bs", "import shapes;\nlet main() { weigh(true) }"),
+        ("shapes.bs", shapes),
+    ];
+    assert_eq!(run_separately("private", &files).0, 73);
+}
+
+#[test]
+fn hidden_names() {
+    //Each module defines a helper, and exit would replace the one of the C
+    //  library if functions kept their names
+    let files = [
+        (
+            "main.bs",
+            "import a;\nimport b hiding (helper);\nlet main() { twice() + helper() }",
+        ),
+        (
+            "b.bs",
+            "pub let helper() { 20 }\npub let b_helper() { helper() }\npub let exit() { 3 }",
+        ),
+        (
+            "a.bs",
+            "import b hiding (helper);\npub let helper() { 1 }\npub let twice() { b_helper() * 2 }",
+        ),
+    ];
+    assert_eq!(run_files("hidden_names", &files).0, 41);
+    assert_eq!(run_separately("hidden_names_separately", &files).0, 41);
+}
+
+#[test]
+fn prelude_instances() {
+    //The methods of the instances of the prelude are in its cached object
+    let dir = dir("prelude_instances");
+    let src = "let main() { if 1 + 2 == 3 { 0 } else { 1 } }";
+    std::fs::write(dir.join("main.bs"), src).unwrap();
+    let out = babel(&dir, &["--emit=clif,exe", "main.bs"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let clif = std::fs::read_to_string(dir.join("main.clif")).unwrap();
+    let funcs = clif.lines().filter(|line| line.starts_with("function "));
+    assert_eq!(funcs.count(), 1, "{}", clif);
+    let out = Command::new(dir.join("main")).output().unwrap();
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[test]
+fn import_cycles() {
+    let dir = dir("import_cycles");
+    std::fs::write(dir.join("a.bs"), "import b;\nlet main() { 0 }").unwrap();
+    std::fs::write(dir.join("b.bs"), "import a;").unwrap();
+    let out = babel(&dir, &["a.bs"]);
+    assert!(!out.status.success());
+    assert!(
+        stderr(&out).contains("Import cycle a -> b -> a"),
+        "{}",
+        stderr(&out)
+    );
+}
+
+#[test]
+fn linking() {
+    let dir = dir("linking");
+    std::fs::write(dir.join("buggy3.bs"), example("buggy3.bs")).unwrap();
+    let out = babel(&dir, &["buggy3.bs"]);
+    assert!(!out.status.success());
+    assert!(
+        stderr(&out).contains("Linking buggy3 with cc failed"),
+        "{}",
+        stderr(&out)
+    );
+    assert!(stderr(&out).contains("main"), "{}", stderr(&out));
+    let out = babel(&dir, &["--linker", "./missing-cc", "buggy3.bs"]);
+    assert!(
+        stderr(&out).contains("Could not run ./missing-cc"),
+        "{}",
+        stderr(&out)
+    );
+
+    //Text relocations are an error with -z text, the objects must be PIC
+    let linker = dir.join("cc-z-text");
+    std::fs::write(&linker, "#!/bin/sh\nexec cc -Wl,-z,text \"$@\"\n").unwrap();
+    let mut perms = std::fs::metadata(&linker).unwrap().permissions();
+    std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o755);
+    std::fs::set_permissions(&linker, perms).unwrap();
+    std::fs::write(dir.join("fib.bs"), example("fib.bs")).unwrap();
+    let out = babel(&dir, &["--linker", "./cc-z-text", "fib.bs"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let out = Command::new(dir.join("fib")).output().unwrap();
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "89");
+}
+
+#[test]
+fn cross_compile() {
+    let dir = dir("cross_compile");
+    std::fs::write(dir.join("fib.bs"), example("fib.bs")).unwrap();
+    let out = babel(
+        &dir,
+        &["-c", "--target", "aarch64-unknown-linux-gnu", "fib.bs"],
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    //The machine of an ELF file is at offset 18, EM_AARCH64 is 183
+    let obj = std::fs::read(dir.join("fib.o")).unwrap();
+    assert_eq!(&obj[..4], b"\x7fELF");
+    assert_eq!(u16::from_le_bytes([obj[18], obj[19]]), 183);
+    let out = babel(
+        &dir,
+        &["-c", "--target", "riscv64gc-unknown-linux-gnu", "fib.bs"],
+    );
+    assert!(
+        stderr(&out).contains("Unsupported target riscv64gc-unknown-linux-gnu"),
+        "{}",
+        stderr(&out)
+    );
+}
+
+#[test]
+fn intrinsic_signatures() {
+    let dir = dir("intrinsic_signatures");
+    let src = "import prelude hiding (i32_add);
+extern i32_add(a: i32, b: i64) -> i32;
+let main() { i32_add(1, 2i64) }";
+    std::fs::write(dir.join("main.bs"), src).unwrap();
+    let out = babel(&dir, &["main.bs"]);
+    assert!(!out.status.success());
+    let msg = "error: The intrinsic i32_add must be declared as `extern i32_add(p0: i32, p1: i32) -> i32;`
+ --> main.bs:2:8";
+    assert!(stderr(&out).starts_with(msg), "{}", stderr(&out));
+}
+
+#[test]
+fn float_remainder() {
+    let src = "let main() {
+        f64_to_i32(f64_mod(100000000000000000.0, 3.0)) * 10 + f32_to_i32(f32_mod(7.5f32, 2.0f32))
+    }";
+    assert_eq!(run("float_remainder", src).0, 11);
+}
+
+#[test]
+fn partial_application() {
+    let src = "
+        let add(a, b, c) { a * 100 + b * 10 + c }
+        let apply(f, x) { f(x) }
+        let twice(f) { \\x -> { f(f(x)) } }
+        let adder(n) { \\(a, b) -> { n + a + b } }
+        let main() {
+            let inc = add(0, 0);
+            i32_print(apply(add(4, 5), 6));
+            i32_print(twice(\\y -> { y + 1 })(5));
+            i32_print(apply(inc, 7) + adder(10, 1, 2) + adder(20)(1, 2));
+            apply(twice(inc), 3)
+        }";
+    assert_eq!(run("partial_application", src), (3, "456743".to_string()));
+}